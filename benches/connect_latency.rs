@@ -0,0 +1,341 @@
+//! Compares the latency of a connection relayed over QUIC, from IN deciding to
+//! connect to the first response of the destination, with a new tunnel (1-RTT
+//! handshake), a tunnel resumed with 0-RTT, and a stream opened on demand or
+//! ahead on an established tunnel. A proxy delays datagrams to emulate a tunnel
+//! round trip of `ROUND_TRIP`, and OUT waits `DESTINATION_ROUND_TRIP` to connect
+//! to the destination and again for its response:
+//!
+//! ```sh
+//! cargo bench --bench connect_latency
+//! ```
+//!
+//! As with IN, only the head (the destination) is sent as early data, data of
+//! the client waits for the handshake to complete.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::pki_types::CertificateDer;
+
+const ROUND_TRIP: Duration = Duration::from_millis(50);
+const DESTINATION_ROUND_TRIP: Duration = Duration::from_millis(20);
+const ITERATIONS: usize = 20;
+
+const SERVER_NAME: &str = "localhost";
+const ALPN: &[u8] = b"bench";
+
+const HEAD: &[u8] = b"head";
+const STATUS_CONNECTED: u8 = 0;
+const REQUEST: &[u8] = b"ping";
+
+#[derive(Clone, Copy)]
+enum Connect {
+    Handshake,
+    ZeroRtt,
+    Stream,
+    PooledStream,
+}
+
+#[allow(clippy::disallowed_macros)]
+fn main() -> anyhow::Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .map_err(|_| anyhow::anyhow!("failed to install crypto provider."))?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    runtime.block_on(async {
+        let (server_address, cert) = spawn_out().await?;
+        let proxy_address = spawn_delay_proxy(server_address).await?;
+
+        println!(
+            "tunnel round trip: {ROUND_TRIP:?}, destination round trip: {DESTINATION_ROUND_TRIP:?}"
+        );
+
+        for (name, connect) in [
+            ("1-RTT tunnel", Connect::Handshake),
+            ("0-RTT tunnel", Connect::ZeroRtt),
+            ("stream", Connect::Stream),
+            ("pooled stream", Connect::PooledStream),
+        ] {
+            let mut latencies = Vec::with_capacity(ITERATIONS);
+
+            for _ in 0..ITERATIONS {
+                latencies.push(measure(connect, proxy_address, &cert).await?);
+            }
+
+            latencies.sort();
+
+            println!(
+                "{name:>14}: median {:>8.1?}, min {:>8.1?}, max {:>8.1?}",
+                latencies[ITERATIONS / 2],
+                latencies[0],
+                latencies[ITERATIONS - 1],
+            );
+        }
+
+        anyhow::Ok(())
+    })
+}
+
+async fn measure(
+    connect: Connect,
+    address: SocketAddr,
+    cert: &CertificateDer<'static>,
+) -> anyhow::Result<Duration> {
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+
+    endpoint.set_default_client_config(create_client_config(cert, true)?);
+
+    // Gets a session ticket to resume, or the tunnel to open streams on.
+    let warm_connection = endpoint.connect(address, SERVER_NAME)?.await?;
+
+    let (mut send_stream, recv_stream) = warm_connection.open_bi().await?;
+
+    send_stream.write_all(HEAD).await?;
+    relay(send_stream, recv_stream).await?;
+
+    let pooled_stream = warm_connection.open_bi().await?;
+
+    let started_at = Instant::now();
+
+    let (send_stream, recv_stream) = match connect {
+        // Without a session to resume, as IN does with 0-RTT disabled.
+        Connect::Handshake => {
+            let connection = endpoint
+                .connect_with(create_client_config(cert, false)?, address, SERVER_NAME)?
+                .await?;
+
+            let mut stream = connection.open_bi().await?;
+
+            stream.0.write_all(HEAD).await?;
+
+            stream
+        }
+        Connect::ZeroRtt => {
+            let (connection, accepted) = endpoint
+                .connect(address, SERVER_NAME)?
+                .into_0rtt()
+                .map_err(|_| anyhow::anyhow!("no session to resume."))?;
+
+            let mut stream = connection.open_bi().await?;
+
+            stream.0.write_all(HEAD).await?;
+
+            anyhow::ensure!(accepted.await, "0-RTT rejected.");
+
+            stream
+        }
+        Connect::Stream => {
+            let mut stream = warm_connection.open_bi().await?;
+
+            stream.0.write_all(HEAD).await?;
+
+            stream
+        }
+        Connect::PooledStream => {
+            let mut stream = pooled_stream;
+
+            stream.0.write_all(HEAD).await?;
+
+            stream
+        }
+    };
+
+    relay(send_stream, recv_stream).await?;
+
+    let elapsed = started_at.elapsed();
+
+    endpoint.close(0u32.into(), b"");
+
+    Ok(elapsed)
+}
+
+/// Sends the request of the client after the head, and waits for OUT to report
+/// the destination connected and relay its response.
+async fn relay(
+    mut send_stream: quinn::SendStream,
+    mut recv_stream: quinn::RecvStream,
+) -> anyhow::Result<()> {
+    send_stream.write_all(REQUEST).await?;
+    send_stream.finish()?;
+
+    let mut status = [0; 1];
+
+    recv_stream.read_exact(&mut status).await?;
+
+    anyhow::ensure!(status[0] == STATUS_CONNECTED, "unexpected status.");
+
+    let mut response = [0; REQUEST.len()];
+
+    recv_stream.read_exact(&mut response).await?;
+
+    anyhow::ensure!(response == REQUEST, "unexpected response.");
+
+    Ok(())
+}
+
+/// OUT accepting early data, returning its address and cert. The destination
+/// echoes requests.
+async fn spawn_out() -> anyhow::Result<(SocketAddr, CertificateDer<'static>)> {
+    let cert = rcgen::generate_simple_self_signed([SERVER_NAME.to_owned()])?;
+
+    let cert_der = cert.cert.der().clone();
+    let key_der = rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der())
+        .map_err(|error| anyhow::anyhow!(error))?;
+
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der.clone()], key_der)?;
+
+    server_crypto.alpn_protocols = vec![ALPN.to_vec()];
+    server_crypto.max_early_data_size = u32::MAX;
+
+    let server_config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_crypto)?));
+
+    let endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse()?)?;
+    let address = endpoint.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            tokio::spawn(async move {
+                let connecting = incoming.accept()?;
+
+                // Serves early data before the handshake completes.
+                let connection = match connecting.into_0rtt() {
+                    Ok((connection, _)) => connection,
+                    Err(connecting) => connecting.await?,
+                };
+
+                while let Ok((mut send_stream, mut recv_stream)) = connection.accept_bi().await {
+                    tokio::spawn(async move {
+                        let mut head = [0; HEAD.len()];
+
+                        recv_stream.read_exact(&mut head).await?;
+
+                        // Connects to the destination.
+                        tokio::time::sleep(DESTINATION_ROUND_TRIP).await;
+
+                        send_stream.write_all(&[STATUS_CONNECTED]).await?;
+
+                        let request = recv_stream.read_to_end(REQUEST.len()).await?;
+
+                        tokio::time::sleep(DESTINATION_ROUND_TRIP).await;
+
+                        send_stream.write_all(&request).await?;
+                        send_stream.finish()?;
+
+                        anyhow::Ok(())
+                    });
+                }
+
+                anyhow::Ok(())
+            });
+        }
+    });
+
+    Ok((address, cert_der))
+}
+
+/// Client config trusting the cert, resuming sessions with early data if
+/// `resumption`.
+fn create_client_config(
+    cert: &CertificateDer<'static>,
+    resumption: bool,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+
+    root_store.add(cert.clone())?;
+
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    client_crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    if resumption {
+        client_crypto.enable_early_data = true;
+    } else {
+        client_crypto.resumption = rustls::client::Resumption::disabled();
+    }
+
+    Ok(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(client_crypto)?,
+    )))
+}
+
+/// Forwards datagrams between clients and the server in order, delaying each by
+/// half of `ROUND_TRIP`, with a socket per client towards the server.
+async fn spawn_delay_proxy(server_address: SocketAddr) -> anyhow::Result<SocketAddr> {
+    let front_socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let address = front_socket.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut back_senders = HashMap::new();
+        let mut buffer = vec![0; u16::MAX as usize];
+
+        while let Ok((length, client_address)) = front_socket.recv_from(&mut buffer).await {
+            let back_sender = match back_senders.get(&client_address) {
+                Some(back_sender) => back_sender,
+                None => {
+                    let back_socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+
+                    back_socket.connect(server_address).await?;
+
+                    tokio::spawn({
+                        let back_socket = back_socket.clone();
+                        let front_sender =
+                            spawn_delayed_sender(front_socket.clone(), client_address);
+
+                        async move {
+                            let mut buffer = vec![0; u16::MAX as usize];
+
+                            while let Ok(length) = back_socket.recv(&mut buffer).await {
+                                front_sender
+                                    .send((Instant::now(), buffer[..length].to_vec()))
+                                    .ok();
+                            }
+                        }
+                    });
+
+                    back_senders
+                        .entry(client_address)
+                        .or_insert(spawn_delayed_sender(back_socket, server_address))
+                }
+            };
+
+            back_sender
+                .send((Instant::now(), buffer[..length].to_vec()))
+                .ok();
+        }
+
+        anyhow::Ok(())
+    });
+
+    Ok(address)
+}
+
+/// Sends datagrams received at the paired instants to the address half of
+/// `ROUND_TRIP` later, in order.
+fn spawn_delayed_sender(
+    socket: Arc<tokio::net::UdpSocket>,
+    address: SocketAddr,
+) -> tokio::sync::mpsc::UnboundedSender<(Instant, Vec<u8>)> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+
+    tokio::spawn(async move {
+        while let Some((received_at, data)) = receiver.recv().await {
+            tokio::time::sleep_until((received_at + ROUND_TRIP / 2).into()).await;
+
+            socket.send_to(&data, address).await.ok();
+        }
+    });
+
+    sender
+}
@@ -0,0 +1,143 @@
+//! Measures match latency and Redis commands per match of the Redis match
+//! server, against a local redis-server (or `REDIS_URL`):
+//!
+//! ```sh
+//! redis-server &
+//! cargo bench --bench redis_match
+//! ```
+
+use std::time::{Duration, Instant};
+
+use plug2proxy::{
+    bandwidth::BandwidthLimit,
+    match_server::{
+        redis_match_server::{
+            RedisDeployment, RedisInMatchServer, RedisMatchClient, RedisOutMatchServer,
+        },
+        InMatchServer as _, MatchOutId, MatchPair, OutIdentity, OutMatchServerTrait as _,
+    },
+};
+
+const MATCHES: usize = 500;
+const KEY: &str = "bench";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BenchInData {}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BenchOutData {}
+
+impl MatchPair<BenchInData, BenchOutData> for (BenchInData, BenchOutData) {
+    fn get_match_name() -> &'static str {
+        "bench"
+    }
+
+    fn get_redis_out_key(out_id: &MatchOutId) -> String {
+        format!("bench:out:{out_id}")
+    }
+
+    fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
+        format!("bench:in:out:{out_id}")
+    }
+}
+
+#[allow(clippy::disallowed_macros)]
+fn main() -> anyhow::Result<()> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    let (latencies, commands) = runtime.block_on(run(&url))?;
+
+    let total = latencies.iter().sum::<Duration>();
+
+    println!(
+        "{MATCHES} matches: {:>8.2}ms average, {:>8.2}ms p50, {:>8.2}ms p99, {:.1} commands per match",
+        total.as_secs_f64() * 1000.0 / MATCHES as f64,
+        latencies[MATCHES / 2].as_secs_f64() * 1000.0,
+        latencies[MATCHES * 99 / 100].as_secs_f64() * 1000.0,
+        commands as f64 / MATCHES as f64,
+    );
+
+    Ok(())
+}
+
+/// Matches IN to one OUT `MATCHES` times, returning the sorted latencies and
+/// the commands Redis processed meanwhile.
+async fn run(url: &str) -> anyhow::Result<(Vec<Duration>, u64)> {
+    // A namespace of its own, so that the benchmark does not match others.
+    let namespace = format!("bench-{}", uuid::Uuid::new_v4());
+
+    let new_client = || {
+        RedisMatchClient::new(
+            RedisDeployment::Standalone(redis::Client::open(format!("{url}?protocol=resp3"))?),
+            Some(namespace.clone()),
+        )
+    };
+
+    let in_match_server = RedisInMatchServer::new(new_client()?, vec![KEY.to_owned()])?;
+
+    let out_match_server = RedisOutMatchServer::new(
+        new_client()?,
+        vec![KEY.to_owned()],
+        OutIdentity::load_or_generate(None)?,
+        Vec::new(),
+        BandwidthLimit::default(),
+    )
+    .await?;
+
+    let out_task = tokio::spawn(async move {
+        loop {
+            out_match_server
+                .match_in::<BenchInData, BenchOutData>(BenchOutData {}, None, &[], 0)
+                .await?;
+        }
+
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+
+    let out_id = in_match_server
+        .accept_out::<BenchInData, BenchOutData>()
+        .await?;
+
+    let mut stats_connection = redis::Client::open(url)?
+        .get_multiplexed_async_connection()
+        .await?;
+
+    let commands_before = get_total_commands_processed(&mut stats_connection).await?;
+
+    let mut latencies = Vec::with_capacity(MATCHES);
+
+    for _ in 0..MATCHES {
+        let started_at = Instant::now();
+
+        in_match_server
+            .match_out::<BenchInData, BenchOutData>(out_id, BenchInData {})
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("OUT {out_id} gone."))?;
+
+        latencies.push(started_at.elapsed());
+    }
+
+    // Less the INFO command itself.
+    let commands = get_total_commands_processed(&mut stats_connection).await? - commands_before - 1;
+
+    out_task.abort();
+
+    latencies.sort();
+
+    Ok((latencies, commands))
+}
+
+async fn get_total_commands_processed(
+    connection: &mut redis::aio::MultiplexedConnection,
+) -> anyhow::Result<u64> {
+    let info: redis::InfoDict = redis::cmd("INFO")
+        .arg("stats")
+        .query_async(connection)
+        .await?;
+
+    info.get("total_commands_processed")
+        .ok_or_else(|| anyhow::anyhow!("total_commands_processed missing."))
+}
@@ -0,0 +1,167 @@
+//! Compares relaying between two TCP streams through userspace buffers and with
+//! `splice(2)`, in throughput and CPU time of the process:
+//!
+//! ```sh
+//! cargo bench --bench relay
+//! ```
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use plug2proxy::{
+    bandwidth::BandwidthLimiters,
+    utils::io::{copy_bidirectional, copy_bidirectional_tcp, StreamTimeoutConfig, TcpWriteHalf},
+};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+const TRANSFER_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+const WRITE_CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Clone, Copy)]
+enum Relay {
+    Copy,
+    Splice,
+}
+
+#[allow(clippy::disallowed_macros)]
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    for (name, relay) in [("copy", Relay::Copy), ("splice", Relay::Splice)] {
+        let cpu_time_before = get_cpu_time();
+
+        let elapsed = runtime.block_on(run(relay))?;
+
+        let cpu_time = get_cpu_time() - cpu_time_before;
+
+        println!(
+            "{name:>8}: {:>8.1} MiB/s, {:>6.2}s CPU ({:.0}% of wall time)",
+            TRANSFER_BYTES as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64(),
+            cpu_time.as_secs_f64(),
+            cpu_time.as_secs_f64() / elapsed.as_secs_f64() * 100.0,
+        );
+    }
+
+    Ok(())
+}
+
+/// Sends `TRANSFER_BYTES` from a client through the relay to a sink, returning
+/// the time it took. CPU time includes the client and the sink, which is the
+/// same for both relays.
+async fn run(relay: Relay) -> anyhow::Result<Duration> {
+    let sink_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let relay_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+    let sink_address = sink_listener.local_addr()?;
+    let relay_address = relay_listener.local_addr()?;
+
+    let sink_task = tokio::spawn(async move {
+        let (mut stream, _) = sink_listener.accept().await?;
+
+        let mut buffer = vec![0; WRITE_CHUNK_SIZE];
+        let mut bytes = 0;
+
+        loop {
+            let length = stream.read(&mut buffer).await?;
+
+            if length == 0 {
+                break;
+            }
+
+            bytes += length as u64;
+        }
+
+        anyhow::ensure!(bytes == TRANSFER_BYTES, "sink received {bytes} bytes.");
+
+        anyhow::Ok(())
+    });
+
+    let relay_task = tokio::spawn(async move {
+        let (stream, _) = relay_listener.accept().await?;
+
+        relay_to(relay, stream, sink_address).await
+    });
+
+    let started_at = Instant::now();
+
+    let mut client = tokio::net::TcpStream::connect(relay_address).await?;
+
+    let buffer = vec![0; WRITE_CHUNK_SIZE];
+    let mut remaining = TRANSFER_BYTES;
+
+    while remaining > 0 {
+        let length = remaining.min(WRITE_CHUNK_SIZE as u64) as usize;
+
+        client.write_all(&buffer[..length]).await?;
+
+        remaining -= length as u64;
+    }
+
+    client.shutdown().await?;
+
+    sink_task.await??;
+
+    let elapsed = started_at.elapsed();
+
+    relay_task.await??;
+
+    Ok(elapsed)
+}
+
+async fn relay_to(
+    relay: Relay,
+    stream: tokio::net::TcpStream,
+    destination: SocketAddr,
+) -> anyhow::Result<()> {
+    let destination_stream = tokio::net::TcpStream::connect(destination).await?;
+
+    let limiters = BandwidthLimiters::default();
+    let timeouts = StreamTimeoutConfig::default();
+
+    match relay {
+        Relay::Copy => {
+            let (read_stream, write_stream) = stream.into_split();
+            let (destination_read_stream, destination_write_stream) =
+                destination_stream.into_split();
+
+            copy_bidirectional(
+                "copy",
+                (
+                    read_stream,
+                    TcpWriteHalf::new(destination_write_stream),
+                    false,
+                ),
+                (destination_read_stream, TcpWriteHalf::new(write_stream)),
+                &limiters,
+                &timeouts,
+            )
+            .await?;
+        }
+        Relay::Splice => {
+            copy_bidirectional_tcp(
+                "splice",
+                (stream, false),
+                destination_stream,
+                &limiters,
+                &timeouts,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_cpu_time() -> Duration {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+
+    let to_duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
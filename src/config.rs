@@ -1,19 +1,38 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use plug2proxy::{
+    bandwidth::{parse_bandwidth, BandwidthConfig, BandwidthLimit},
     config::MatchServerUrlOrConfig,
+    match_server::{p2p_match_protocol::P2P_MATCH_ALPN, MatchOutId, OutIdentityKey},
+    r#in::out_policy::OutPolicy,
     route::{
         config::{InFallbackRuleConfig, InRuleConfig, OutOutputConfig, OutRuleConfig},
         rule::{BuiltInLabel, Label},
     },
-    utils::OneOrMany,
+    tunnel::{
+        http2::{Http2FlowControl, Http2TransportConfig, HTTP2_MAX_WINDOW_SIZE},
+        quic::{QuicCongestionController, QuicTransportConfig},
+        tls_name_default, TunnelTlsConfig, TunnelTlsIdentityPaths,
+    },
+    utils::{
+        io::StreamTimeoutConfig,
+        port_mapping::{PortMappingConfig, PortMappingMethod},
+        OneOrMany,
+    },
 };
 
 use crate::constants::{
     constant_false, constant_true, fake_ip_dns_address_default, geolite2_url_default,
-    transparent_proxy_address_default, transparent_proxy_traffic_mark_default,
-    tunneling_http2_connections_default, tunneling_plug_http2_connections_default,
-    tunneling_plug_http2_listen_address_default,
+    match_listen_address_default, transparent_proxy_address_default,
+    transparent_proxy_traffic_mark_default, tunneling_http2_connections_default,
+    tunneling_plug_http2_connections_default, tunneling_plug_http2_listen_address_default,
+    tunneling_port_mapping_lifetime_default, tunneling_port_mapping_methods_default,
+    tunneling_websocket_connections_default, tunneling_websocket_listen_address_default,
 };
 
 #[derive(serde::Deserialize)]
@@ -77,11 +96,69 @@ pub struct InTunnelingConfig {
     pub stun_server: Option<OneOrMany<String>>,
     pub match_server: MatchServerUrlOrConfig,
     #[serde(default)]
+    pub out_policy: InTunnelingOutPolicyConfig,
+    #[serde(default)]
+    pub port_mapping: TunnelingPortMappingConfig,
+    #[serde(default)]
+    pub bandwidth: TunnelingBandwidthConfig,
+    #[serde(default)]
+    pub timeout: TunnelingTimeoutConfig,
+    #[serde(default)]
     pub http2: InTunnelingHttp2Config,
     #[serde(default)]
     pub plug_http2: InTunnelingPlugHttp2Config,
     #[serde(default)]
     pub quic: InTunnelingQuicConfig,
+    #[serde(default)]
+    pub websocket: InTunnelingWebSocketConfig,
+}
+
+/// OUT accepted by IN and what they may claim, see `OutPolicy`.
+#[derive(Default, serde::Deserialize)]
+pub struct InTunnelingOutPolicyConfig {
+    pub ids: Option<OneOrMany<MatchOutId>>,
+    pub labels: Option<OneOrMany<Label>>,
+    pub identity_keys: Option<OneOrMany<OutIdentityKey>>,
+    pub claimable_labels: Option<OneOrMany<Label>>,
+    pub routing_priority: Option<RoutingPriorityRangeConfig>,
+}
+
+impl InTunnelingOutPolicyConfig {
+    pub fn into_out_policy(self) -> anyhow::Result<OutPolicy> {
+        let routing_priority_range = self
+            .routing_priority
+            .map(|range| {
+                let range = range.min.unwrap_or(i64::MIN)..=range.max.unwrap_or(i64::MAX);
+
+                anyhow::ensure!(!range.is_empty(), "invalid routing priority range.");
+
+                Ok(range)
+            })
+            .transpose()?;
+
+        // Ids and labels are declared by OUT itself, so anyone with the match
+        // server key could claim them without an identity key to prove it.
+        anyhow::ensure!(
+            self.identity_keys.is_some() || (self.ids.is_none() && self.claimable_labels.is_none()),
+            "out policy with ids or claimable labels requires identity keys."
+        );
+
+        Ok(OutPolicy {
+            ids: self.ids.map_or_else(Vec::new, OneOrMany::into_vec),
+            labels: self.labels.map_or_else(Vec::new, OneOrMany::into_vec),
+            identity_keys: self
+                .identity_keys
+                .map_or_else(Vec::new, OneOrMany::into_vec),
+            claimable_labels: self.claimable_labels.map(OneOrMany::into_vec),
+            routing_priority_range,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct RoutingPriorityRangeConfig {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
 }
 
 #[derive(serde::Deserialize)]
@@ -91,6 +168,8 @@ pub struct InTunnelingHttp2Config {
     #[serde(default = "tunneling_http2_connections_default")]
     pub connections: usize,
     pub priority: Option<i64>,
+    #[serde(flatten)]
+    pub transport: TunnelingHttp2TransportConfig,
 }
 
 impl Default for InTunnelingHttp2Config {
@@ -99,6 +178,7 @@ impl Default for InTunnelingHttp2Config {
             enabled: false,
             connections: tunneling_http2_connections_default(),
             priority: None,
+            transport: Default::default(),
         }
     }
 }
@@ -113,6 +193,10 @@ pub struct InTunnelingPlugHttp2Config {
     #[serde(default = "tunneling_plug_http2_connections_default")]
     pub connections: usize,
     pub priority: Option<i64>,
+    #[serde(default)]
+    pub tls: TunnelingTlsConfig,
+    #[serde(flatten)]
+    pub transport: TunnelingHttp2TransportConfig,
 }
 
 impl Default for InTunnelingPlugHttp2Config {
@@ -123,6 +207,8 @@ impl Default for InTunnelingPlugHttp2Config {
             external_port: None,
             connections: tunneling_plug_http2_connections_default(),
             priority: None,
+            tls: Default::default(),
+            transport: Default::default(),
         }
     }
 }
@@ -133,6 +219,11 @@ pub struct InTunnelingQuicConfig {
     #[serde(default = "constant_true")]
     pub enabled: bool,
     pub priority: Option<i64>,
+    /// Streams kept opened ahead of connections per tunnel.
+    #[serde(default)]
+    pub stream_pool: usize,
+    #[serde(flatten)]
+    pub transport: TunnelingQuicTransportConfig,
 }
 
 impl Default for InTunnelingQuicConfig {
@@ -140,6 +231,31 @@ impl Default for InTunnelingQuicConfig {
         Self {
             enabled: true,
             priority: None,
+            stream_pool: 0,
+            transport: Default::default(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct InTunnelingWebSocketConfig {
+    #[serde(default = "constant_false")]
+    pub enabled: bool,
+    #[serde(default = "tunneling_websocket_connections_default")]
+    pub connections: usize,
+    pub priority: Option<i64>,
+    /// Streams kept opened ahead of connections per tunnel.
+    #[serde(default)]
+    pub stream_pool: usize,
+}
+
+impl Default for InTunnelingWebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            connections: tunneling_websocket_connections_default(),
+            priority: None,
+            stream_pool: 0,
         }
     }
 }
@@ -192,26 +308,320 @@ pub struct OutTunnelingConfig {
     pub stun_server: Option<OneOrMany<String>>,
     pub match_server: MatchServerUrlOrConfig,
     #[serde(default)]
+    pub identity: OutTunnelingIdentityConfig,
+    #[serde(default)]
+    pub port_mapping: TunnelingPortMappingConfig,
+    #[serde(default)]
+    pub bandwidth: TunnelingBandwidthConfig,
+    #[serde(default)]
+    pub timeout: TunnelingTimeoutConfig,
+    #[serde(default)]
     pub http2: OutTunnelingHttp2Config,
     #[serde(default)]
     pub plug_http2: OutTunnelingPlugHttp2Config,
     #[serde(default)]
     pub quic: OutTunnelingQuicConfig,
+    #[serde(default)]
+    pub websocket: OutTunnelingWebSocketConfig,
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct OutTunnelingIdentityConfig {
+    /// Keep the OUT id and identity key in the data dir instead of generating new
+    /// ones on every start.
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+impl OutTunnelingIdentityConfig {
+    pub fn into_identity_path(self, path: PathBuf) -> Option<PathBuf> {
+        self.persistent.then_some(path)
+    }
 }
 
 #[derive(Default, serde::Deserialize)]
 pub struct OutTunnelingHttp2Config {
     pub priority: Option<i64>,
+    #[serde(default)]
+    pub tls: TunnelingTlsConfig,
+    /// Flow control of data from IN, lifetime limits only apply on IN.
+    #[serde(flatten)]
+    pub transport: TunnelingHttp2TransportConfig,
 }
 
 #[derive(Default, serde::Deserialize)]
 pub struct OutTunnelingPlugHttp2Config {
     pub priority: Option<i64>,
+    /// Flow control of data from IN, lifetime limits only apply on IN.
+    #[serde(flatten)]
+    pub transport: TunnelingHttp2TransportConfig,
 }
 
 #[derive(Default, serde::Deserialize)]
 pub struct OutTunnelingQuicConfig {
     pub priority: Option<i64>,
+    #[serde(default)]
+    pub tls: TunnelingTlsConfig,
+    #[serde(flatten)]
+    pub transport: TunnelingQuicTransportConfig,
+}
+
+#[derive(serde::Deserialize)]
+pub struct OutTunnelingWebSocketConfig {
+    /// Public URL (`wss://` or `ws://`) through which IN reaches the listen address,
+    /// e.g. via a CDN or a reverse proxy. The transport is disabled if not set.
+    pub url: Option<String>,
+    #[serde(default = "tunneling_websocket_listen_address_default")]
+    pub listen: SocketAddr,
+    pub sni: Option<String>,
+    pub priority: Option<i64>,
+}
+
+impl Default for OutTunnelingWebSocketConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            listen: tunneling_websocket_listen_address_default(),
+            sni: None,
+            priority: None,
+        }
+    }
+}
+
+/// TLS settings of the side presenting the certificate of a transport.
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingTlsConfig {
+    /// Server name in the certificate and the SNI sent by the peer, defaults to
+    /// "localhost".
+    pub sni: Option<String>,
+    pub alpn: Option<OneOrMany<String>>,
+    /// Keep the certificate and key in the data dir instead of generating new
+    /// ones on every start.
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+impl TunnelingTlsConfig {
+    pub fn into_tunnel_tls_config(
+        self,
+        alpn_default: &[&str],
+        identity_paths: (PathBuf, PathBuf),
+    ) -> TunnelTlsConfig {
+        TunnelTlsConfig {
+            server_name: self.sni.unwrap_or_else(tls_name_default),
+            alpn_protocols: self.alpn.map_or_else(
+                || alpn_default.iter().map(|&alpn| alpn.to_owned()).collect(),
+                OneOrMany::into_vec,
+            ),
+            identity_paths: self.persistent.then(|| {
+                let (cert_path, key_path) = identity_paths;
+                TunnelTlsIdentityPaths::Generated(cert_path, key_path)
+            }),
+        }
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingHttp2TransportConfig {
+    /// "socket" (following the TCP receive buffer) or "adaptive" (BDP estimated
+    /// from PING round trips).
+    pub flow_control: Option<Http2FlowControl>,
+    pub min_window_size: Option<u32>,
+    pub max_window_size: Option<u32>,
+    pub window_size_check_interval: Option<String>,
+    /// 0 for no limit.
+    pub max_lifetime_streams: Option<usize>,
+    pub max_lifetime: Option<String>,
+    pub max_lifetime_bytes: Option<u64>,
+}
+
+impl TunnelingHttp2TransportConfig {
+    pub fn into_http2_transport_config(self) -> anyhow::Result<Http2TransportConfig> {
+        let default = Http2TransportConfig::default();
+
+        let min_window_size = self.min_window_size.unwrap_or(default.min_window_size);
+        let max_window_size = self.max_window_size.unwrap_or(default.max_window_size);
+
+        anyhow::ensure!(
+            0 < min_window_size
+                && min_window_size <= max_window_size
+                && max_window_size <= HTTP2_MAX_WINDOW_SIZE,
+            "window sizes must satisfy 0 < min_window_size <= max_window_size <= {HTTP2_MAX_WINDOW_SIZE}."
+        );
+
+        Ok(Http2TransportConfig {
+            flow_control: self.flow_control.unwrap_or(default.flow_control),
+            min_window_size,
+            max_window_size,
+            window_size_check_interval: match self.window_size_check_interval {
+                Some(duration) => humantime::parse_duration(&duration)?,
+                None => default.window_size_check_interval,
+            },
+            max_lifetime_streams: match self.max_lifetime_streams {
+                Some(0) => None,
+                Some(streams) => Some(streams),
+                None => default.max_lifetime_streams,
+            },
+            max_lifetime: self
+                .max_lifetime
+                .map(|duration| humantime::parse_duration(&duration))
+                .transpose()?,
+            max_lifetime_bytes: self.max_lifetime_bytes,
+        })
+    }
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingQuicTransportConfig {
+    /// "newreno", "cubic" or "bbr". OUT's choice wins if both ends set one.
+    pub congestion_controller: Option<QuicCongestionController>,
+    pub max_concurrent_bidi_streams: Option<u32>,
+    pub keep_alive_interval: Option<String>,
+    pub max_idle_timeout: Option<String>,
+    pub stream_receive_window: Option<u64>,
+    pub receive_window: Option<u64>,
+    pub send_window: Option<u64>,
+    /// Resumes tunnels to a known OUT with 0-RTT, needs both ends enabled.
+    pub zero_rtt: Option<bool>,
+}
+
+impl TunnelingQuicTransportConfig {
+    pub fn into_quic_transport_config(self) -> anyhow::Result<QuicTransportConfig> {
+        let default = QuicTransportConfig::default();
+
+        Ok(QuicTransportConfig {
+            congestion_controller: self.congestion_controller,
+            max_concurrent_bidi_streams: self
+                .max_concurrent_bidi_streams
+                .unwrap_or(default.max_concurrent_bidi_streams),
+            keep_alive_interval: match self.keep_alive_interval {
+                Some(duration) => humantime::parse_duration(&duration)?,
+                None => default.keep_alive_interval,
+            },
+            max_idle_timeout: match self.max_idle_timeout {
+                Some(duration) => humantime::parse_duration(&duration)?,
+                None => default.max_idle_timeout,
+            },
+            stream_receive_window: self.stream_receive_window,
+            receive_window: self.receive_window,
+            send_window: self.send_window,
+            zero_rtt: self.zero_rtt.unwrap_or(default.zero_rtt),
+        })
+    }
+}
+
+/// Maps the plug-http2 listen port (IN) and QUIC sockets on the LAN gateway.
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingPortMappingConfig {
+    #[serde(default = "constant_false")]
+    pub enabled: bool,
+    /// "upnp", "nat-pmp" or "pcp", tried in order.
+    pub method: Option<OneOrMany<PortMappingMethod>>,
+    pub gateway: Option<IpAddr>,
+    pub lifetime: Option<String>,
+}
+
+impl TunnelingPortMappingConfig {
+    pub fn into_port_mapping_config(self) -> anyhow::Result<Option<PortMappingConfig>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        Ok(Some(PortMappingConfig {
+            methods: self
+                .method
+                .map_or_else(tunneling_port_mapping_methods_default, OneOrMany::into_vec),
+            gateway: self.gateway,
+            lifetime: match self.lifetime {
+                Some(duration) => humantime::parse_duration(&duration)?,
+                None => tunneling_port_mapping_lifetime_default(),
+            },
+        }))
+    }
+}
+
+/// Limits of traffic through this side, applying to both TCP connections and UDP
+/// datagrams. The top level limit applies to all traffic, and is also advertised
+/// to IN by OUT.
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingBandwidthConfig {
+    #[serde(flatten)]
+    pub limit: BandwidthLimitConfig,
+    /// Limits by tunnel type ("http2", "plug-http2", "quic", "websocket", or
+    /// "direct" for IN).
+    #[serde(default)]
+    pub tunnels: HashMap<String, BandwidthLimitConfig>,
+    /// Limits by routing tag.
+    #[serde(default)]
+    pub tags: HashMap<String, BandwidthLimitConfig>,
+}
+
+impl TunnelingBandwidthConfig {
+    pub fn into_bandwidth_config(self) -> anyhow::Result<BandwidthConfig> {
+        let into_limits = |limits: HashMap<String, BandwidthLimitConfig>| {
+            limits
+                .into_iter()
+                .map(|(key, limit)| Ok((key, limit.into_bandwidth_limit()?)))
+                .collect::<anyhow::Result<HashMap<_, _>>>()
+        };
+
+        Ok(BandwidthConfig {
+            limit: self.limit.into_bandwidth_limit()?,
+            tunnels: into_limits(self.tunnels)?,
+            tags: into_limits(self.tags)?,
+        })
+    }
+}
+
+/// Rates like "10MB/s" or "100Mbps" (see `parse_bandwidth`), upload being the
+/// direction from IN to the destination.
+#[derive(Default, serde::Deserialize)]
+pub struct BandwidthLimitConfig {
+    pub upload: Option<String>,
+    pub download: Option<String>,
+}
+
+impl BandwidthLimitConfig {
+    pub fn into_bandwidth_limit(self) -> anyhow::Result<BandwidthLimit> {
+        Ok(BandwidthLimit {
+            upload: self.upload.as_deref().map(parse_bandwidth).transpose()?,
+            download: self.download.as_deref().map(parse_bandwidth).transpose()?,
+        })
+    }
+}
+
+/// Timeouts of relayed connections like "15m", none by default and "0s" for no
+/// limit.
+#[derive(Default, serde::Deserialize)]
+pub struct TunnelingTimeoutConfig {
+    /// Without data in either direction.
+    pub idle: Option<String>,
+    /// Without upload data, unless upload ended.
+    pub upload_idle: Option<String>,
+    /// Without download data, unless download ended.
+    pub download_idle: Option<String>,
+    pub max_lifetime: Option<String>,
+}
+
+impl TunnelingTimeoutConfig {
+    pub fn into_stream_timeout_config(self) -> anyhow::Result<StreamTimeoutConfig> {
+        let default = StreamTimeoutConfig::default();
+
+        let parse = |duration: Option<String>, default: Option<Duration>| {
+            anyhow::Ok(match duration {
+                Some(duration) => Some(humantime::parse_duration(&duration)?)
+                    .filter(|duration| !duration.is_zero()),
+                None => default,
+            })
+        };
+
+        Ok(StreamTimeoutConfig {
+            idle_timeout: parse(self.idle, default.idle_timeout)?,
+            upload_idle_timeout: parse(self.upload_idle, default.upload_idle_timeout)?,
+            download_idle_timeout: parse(self.download_idle, default.download_idle_timeout)?,
+            max_lifetime: parse(self.max_lifetime, default.max_lifetime)?,
+        })
+    }
 }
 
 #[derive(Default, serde::Deserialize)]
@@ -222,9 +632,149 @@ pub struct OutRoutingConfig {
     pub rules: Vec<OutRuleConfig>,
 }
 
+/// IN and OUT in one process, typically matching through the memory match
+/// server.
+#[derive(serde::Deserialize)]
+pub struct AllInOneConfig {
+    #[serde(rename = "in")]
+    pub r#in: InConfig,
+    pub out: OutConfig,
+}
+
+#[derive(serde::Deserialize)]
+pub struct MatchConfig {
+    #[serde(default = "match_listen_address_default")]
+    pub listen: SocketAddr,
+    /// Address to serve the HTTP rendezvous API (`http` match server) on, over
+    /// plain HTTP for a reverse proxy to terminate TLS.
+    pub http_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub tls: MatchTlsConfig,
+    /// Keys IN and OUT authenticate with (`access_key` of the `p2p` match
+    /// server), any of them accepted so that it can be rotated.
+    pub access_key: OneOrMany<String>,
+}
+
+#[derive(Default, serde::Deserialize)]
+pub struct MatchTlsConfig {
+    /// Server name in the generated certificate, defaults to "localhost".
+    pub sni: Option<String>,
+    /// Cert and key PEM files, generated in the data dir if not configured.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl MatchTlsConfig {
+    pub fn into_tunnel_tls_config(
+        self,
+        identity_paths_default: (PathBuf, PathBuf),
+    ) -> TunnelTlsConfig {
+        TunnelTlsConfig {
+            server_name: self.sni.unwrap_or_else(tls_name_default),
+            alpn_protocols: vec![P2P_MATCH_ALPN.to_owned()],
+            identity_paths: Some(match (self.cert, self.key) {
+                (Some(cert), Some(key)) => TunnelTlsIdentityPaths::Configured(cert, key),
+                _ => {
+                    let (cert_path, key_path) = identity_paths_default;
+                    TunnelTlsIdentityPaths::Generated(cert_path, key_path)
+                }
+            }),
+        }
+    }
+}
+
 fn in_routing_rules_default() -> Vec<InRuleConfig> {
     vec![InRuleConfig::Fallback(InFallbackRuleConfig {
         out: OneOrMany::One(Label::BuiltIn(BuiltInLabel::Direct)),
         tag: None,
     })]
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parses_out_http2_transport() {
+        let config: OutTunnelingHttp2Config = serde_json::from_value(serde_json::json!({
+            "flow_control": "adaptive",
+            "min_window_size": 1048576,
+            "max_window_size": 16777216,
+            "window_size_check_interval": "100ms",
+            "max_lifetime_streams": 0,
+        }))
+        .unwrap();
+
+        let transport = config.transport.into_http2_transport_config().unwrap();
+
+        assert_eq!(transport.flow_control, Http2FlowControl::Adaptive);
+        assert_eq!(transport.min_window_size, 1024 * 1024);
+        assert_eq!(transport.max_window_size, 16 * 1024 * 1024);
+        assert_eq!(
+            transport.window_size_check_interval,
+            Duration::from_millis(100)
+        );
+        assert_eq!(transport.max_lifetime_streams, None);
+    }
+
+    #[test]
+    fn defaults_plug_http2_transport() {
+        let config: OutTunnelingPlugHttp2Config =
+            serde_json::from_value(serde_json::json!({})).unwrap();
+
+        let transport = config.transport.into_http2_transport_config().unwrap();
+        let default = Http2TransportConfig::default();
+
+        assert_eq!(transport.flow_control, default.flow_control);
+        assert_eq!(transport.min_window_size, default.min_window_size);
+        assert_eq!(transport.max_lifetime_streams, default.max_lifetime_streams);
+    }
+
+    #[test]
+    fn rejects_invalid_http2_transport() {
+        assert!(
+            serde_json::from_value::<OutTunnelingHttp2Config>(serde_json::json!({
+                "flow_control": "bdp",
+            }))
+            .is_err()
+        );
+
+        for config in [
+            serde_json::json!({ "window_size_check_interval": "often" }),
+            serde_json::json!({ "min_window_size": 0 }),
+            serde_json::json!({ "min_window_size": 2097152, "max_window_size": 1048576 }),
+            serde_json::json!({ "max_window_size": 2147483648u32 }),
+        ] {
+            let config: OutTunnelingHttp2Config = serde_json::from_value(config).unwrap();
+
+            assert!(config.transport.into_http2_transport_config().is_err());
+        }
+    }
+
+    #[test]
+    fn requires_identity_keys_in_out_policy() {
+        for config in [
+            serde_json::json!({ "ids": "00000000-0000-0000-0000-000000000000" }),
+            serde_json::json!({ "claimable_labels": "PROXY" }),
+        ] {
+            let config: InTunnelingOutPolicyConfig = serde_json::from_value(config).unwrap();
+
+            assert!(config.into_out_policy().is_err());
+        }
+
+        let config: InTunnelingOutPolicyConfig = serde_json::from_value(serde_json::json!({
+            "ids": "00000000-0000-0000-0000-000000000000",
+            "labels": "us",
+            "identity_keys": "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+            "claimable_labels": ["PROXY", "us"],
+        }))
+        .unwrap();
+
+        let out_policy = config.into_out_policy().unwrap();
+
+        assert_eq!(out_policy.identity_keys.len(), 1);
+        assert!(out_policy.accepts_labels(&[Label::Custom("us".to_owned())]));
+    }
+}
@@ -4,6 +4,8 @@ use std::{
     time::Duration,
 };
 
+use plug2proxy::utils::port_mapping::PortMappingMethod;
+
 pub const DATA_DIR_DEFAULT: &str = ".plug2proxy";
 
 pub fn constant_true() -> bool {
@@ -45,6 +47,18 @@ pub fn stun_server_addresses_default() -> Vec<String> {
     ]
 }
 
+pub fn tunneling_port_mapping_methods_default() -> Vec<PortMappingMethod> {
+    vec![
+        PortMappingMethod::Pcp,
+        PortMappingMethod::NatPmp,
+        PortMappingMethod::Upnp,
+    ]
+}
+
+pub fn tunneling_port_mapping_lifetime_default() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
 pub fn tunneling_http2_connections_default() -> usize {
     3
 }
@@ -69,6 +83,22 @@ pub fn tunneling_quic_priority_default() -> i64 {
     101
 }
 
+pub fn tunneling_websocket_connections_default() -> usize {
+    3
+}
+
+pub fn tunneling_websocket_priority_default() -> i64 {
+    102
+}
+
+pub fn tunneling_websocket_listen_address_default() -> SocketAddr {
+    "127.0.0.1:8080".parse().unwrap()
+}
+
+pub fn match_listen_address_default() -> SocketAddr {
+    "0.0.0.0:4433".parse().unwrap()
+}
+
 pub fn fake_ip_dns_db_path_default(data_dir: Option<&str>) -> PathBuf {
     Path::new(data_dir.unwrap_or(DATA_DIR_DEFAULT)).join("fake_ip_dns.db")
 }
@@ -77,6 +107,22 @@ pub fn geolite2_cache_path_default(data_dir: Option<&str>) -> PathBuf {
     Path::new(data_dir.unwrap_or(DATA_DIR_DEFAULT)).join("geolite2.mmdb")
 }
 
+pub fn out_identity_path_default(data_dir: Option<&str>) -> PathBuf {
+    Path::new(data_dir.unwrap_or(DATA_DIR_DEFAULT)).join("out_identity.json")
+}
+
+pub fn tunneling_tls_identity_paths_default(
+    data_dir: Option<&str>,
+    transport: &str,
+) -> (PathBuf, PathBuf) {
+    let data_dir = Path::new(data_dir.unwrap_or(DATA_DIR_DEFAULT));
+
+    (
+        data_dir.join(format!("{transport}.cert.pem")),
+        data_dir.join(format!("{transport}.key.pem")),
+    )
+}
+
 pub fn geolite2_url_default() -> String {
     "https://github.com/P3TERX/GeoLite.mmdb/raw/download/GeoLite2-Country.mmdb".to_string()
 }
@@ -0,0 +1,108 @@
+/// Rates in bytes per second. Upload is the direction from IN to the destination,
+/// download the opposite.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BandwidthLimit {
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub upload: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_rate")]
+    pub download: Option<u64>,
+}
+
+/// Rejects zero rates, as the limit may come from the peer (e.g. advertised by
+/// OUT through the match server).
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let rate = <Option<u64> as serde::Deserialize>::deserialize(deserializer)?;
+
+    if rate == Some(0) {
+        return Err(serde::de::Error::custom("bandwidth must be positive."));
+    }
+
+    Ok(rate)
+}
+
+/// Parses a rate like "100Mbps", "10MB/s", "512KiB/s" or "65536" (bytes per
+/// second) into bytes per second.
+pub fn parse_bandwidth(bandwidth: &str) -> anyhow::Result<u64> {
+    let bandwidth = bandwidth.trim();
+
+    let unit_index = bandwidth
+        .find(|char: char| !char.is_ascii_digit() && char != '.')
+        .unwrap_or(bandwidth.len());
+
+    let (value, unit) = bandwidth.split_at(unit_index);
+
+    let value: f64 = value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid bandwidth {bandwidth}."))?;
+
+    let multiplier = match unit.trim() {
+        "" | "B/s" => 1.0,
+        "KB/s" | "kB/s" => 1e3,
+        "MB/s" => 1e6,
+        "GB/s" => 1e9,
+        "KiB/s" => 1024.0,
+        "MiB/s" => 1024.0 * 1024.0,
+        "GiB/s" => 1024.0 * 1024.0 * 1024.0,
+        "bps" => 1.0 / 8.0,
+        "Kbps" | "kbps" => 1e3 / 8.0,
+        "Mbps" => 1e6 / 8.0,
+        "Gbps" => 1e9 / 8.0,
+        unit => anyhow::bail!("unknown bandwidth unit {unit}."),
+    };
+
+    let bandwidth = (value * multiplier) as u64;
+
+    anyhow::ensure!(bandwidth > 0, "bandwidth must be positive.");
+
+    Ok(bandwidth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bandwidth_units() {
+        for (bandwidth, expected) in [
+            ("65536", 65536),
+            ("100B/s", 100),
+            ("10KB/s", 10_000),
+            ("10kB/s", 10_000),
+            ("10MB/s", 10_000_000),
+            ("1GB/s", 1_000_000_000),
+            ("512KiB/s", 512 * 1024),
+            ("1.5MiB/s", 1024 * 1024 * 3 / 2),
+            ("1GiB/s", 1024 * 1024 * 1024),
+            ("800bps", 100),
+            ("8Kbps", 1000),
+            ("100Mbps", 12_500_000),
+            ("1Gbps", 125_000_000),
+            (" 10 MB/s ", 10_000_000),
+        ] {
+            assert_eq!(parse_bandwidth(bandwidth).unwrap(), expected, "{bandwidth}");
+        }
+    }
+
+    #[test]
+    fn rejects_zero_bandwidth_limit() {
+        let limit: BandwidthLimit =
+            serde_json::from_value(serde_json::json!({ "upload": 100, "download": null })).unwrap();
+
+        assert_eq!(limit.upload, Some(100));
+        assert_eq!(limit.download, None);
+
+        assert!(
+            serde_json::from_value::<BandwidthLimit>(serde_json::json!({ "upload": 0 })).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_bandwidth() {
+        for bandwidth in ["", "MB/s", "10Mb", "10 mbps", "1.2.3MB/s", "0", "1bps"] {
+            assert!(parse_bandwidth(bandwidth).is_err(), "{bandwidth}");
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{borrow::Borrow, num::NonZeroU64, sync::Arc, time::Duration};
+
+use super::BandwidthLimit;
+
+/// Token bucket shared by the streams it limits. Consumers wait in a FIFO queue
+/// while the bucket is in debt, so that concurrent streams relaying in chunks
+/// share the bandwidth fairly.
+pub struct BandwidthLimiter {
+    rate: u64,
+    bucket: tokio::sync::Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: tokio::time::Instant,
+}
+
+impl BandwidthLimiter {
+    /// Allows a burst of one second worth of bytes.
+    pub fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get();
+
+        Self {
+            rate,
+            bucket: tokio::sync::Mutex::new(TokenBucket {
+                tokens: rate as f64,
+                updated_at: tokio::time::Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn consume(&self, bytes: usize) {
+        let rate = self.rate as f64;
+
+        // The lock is held while waiting to keep the order of consumers.
+        let mut bucket = self.bucket.lock().await;
+
+        bucket.refill(rate);
+
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-bucket.tokens / rate)).await;
+
+            bucket.tokens = 0.0;
+            bucket.updated_at = tokio::time::Instant::now();
+        }
+    }
+
+    /// Consumes the bytes if available right away, for datagrams which are
+    /// dropped rather than delayed beyond the limit. Streams waiting for the
+    /// bucket to refill hold it, in which case there is nothing available.
+    pub fn try_consume(&self, bytes: usize) -> bool {
+        try_consume_all(std::slice::from_ref(self), bytes)
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: f64) {
+        let now = tokio::time::Instant::now();
+
+        self.tokens =
+            (self.tokens + now.duration_since(self.updated_at).as_secs_f64() * rate).min(rate);
+        self.updated_at = now;
+    }
+}
+
+/// Consumes the bytes from all limiters only if each of them has them available,
+/// so that a datagram dropped by one limiter is not charged to the others.
+fn try_consume_all<T: Borrow<BandwidthLimiter>>(limiters: &[T], bytes: usize) -> bool {
+    let mut buckets = Vec::with_capacity(limiters.len());
+
+    for limiter in limiters {
+        let limiter = limiter.borrow();
+
+        let Ok(mut bucket) = limiter.bucket.try_lock() else {
+            return false;
+        };
+
+        bucket.refill(limiter.rate as f64);
+
+        if bucket.tokens < bytes as f64 {
+            return false;
+        }
+
+        buckets.push(bucket);
+    }
+
+    for mut bucket in buckets {
+        bucket.tokens -= bytes as f64;
+    }
+
+    true
+}
+
+/// Limiters applying to a relayed stream in both directions.
+#[derive(Clone, Default)]
+pub struct BandwidthLimiters {
+    pub upload: Vec<Arc<BandwidthLimiter>>,
+    pub download: Vec<Arc<BandwidthLimiter>>,
+}
+
+impl BandwidthLimiters {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            upload: limit
+                .upload
+                .and_then(NonZeroU64::new)
+                .map(|rate| Arc::new(BandwidthLimiter::new(rate)))
+                .into_iter()
+                .collect(),
+            download: limit
+                .download
+                .and_then(NonZeroU64::new)
+                .map(|rate| Arc::new(BandwidthLimiter::new(rate)))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upload.is_empty() && self.download.is_empty()
+    }
+
+    pub fn extend(&mut self, other: &BandwidthLimiters) {
+        self.upload.extend(other.upload.iter().cloned());
+        self.download.extend(other.download.iter().cloned());
+    }
+
+    /// Whether an uploaded datagram is within all limits.
+    pub fn try_consume_upload(&self, bytes: usize) -> bool {
+        try_consume_all(&self.upload, bytes)
+    }
+
+    /// Whether a downloaded datagram is within all limits.
+    pub fn try_consume_download(&self, bytes: usize) -> bool {
+        try_consume_all(&self.download, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drops_beyond_available_bytes() {
+        let limiter = BandwidthLimiter::new(NonZeroU64::new(100).unwrap());
+
+        assert!(limiter.try_consume(60));
+        assert!(!limiter.try_consume(60));
+        assert!(limiter.try_consume(40));
+
+        // Refills about 50 bytes.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert!(limiter.try_consume(40));
+        assert!(!limiter.try_consume(40));
+    }
+
+    #[tokio::test]
+    async fn drops_while_streams_wait() {
+        let limiter = Arc::new(BandwidthLimiter::new(NonZeroU64::new(1000).unwrap()));
+
+        limiter.consume(1000).await;
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+
+            async move { limiter.consume(100).await }
+        });
+
+        tokio::task::yield_now().await;
+
+        assert!(!limiter.try_consume(1));
+
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn charges_all_limiters_or_none() {
+        let tunnel_limiters = BandwidthLimiters::new(BandwidthLimit {
+            upload: Some(100),
+            download: None,
+        });
+
+        let out_limiters = BandwidthLimiters::new(BandwidthLimit {
+            upload: Some(1000),
+            download: None,
+        });
+
+        let mut limiters = tunnel_limiters.clone();
+
+        limiters.extend(&out_limiters);
+
+        assert!(out_limiters.try_consume_upload(1000));
+
+        // The OUT limit is exhausted, the tunnel limit checked before it is not
+        // charged.
+        assert!(!limiters.try_consume_upload(100));
+        assert!(tunnel_limiters.try_consume_upload(100));
+
+        assert!(!tunnel_limiters.try_consume_upload(1));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::match_server::MatchOutId;
+
+use super::{BandwidthLimit, BandwidthLimiters};
+
+#[derive(Clone, Default)]
+pub struct BandwidthConfig {
+    /// Limit of all traffic through this side. OUT also advertises it to IN,
+    /// which applies it to all tunnels to that OUT.
+    pub limit: BandwidthLimit,
+    /// Limits by tunnel type, e.g. "quic".
+    pub tunnels: HashMap<String, BandwidthLimit>,
+    /// Limits by routing tag.
+    pub tags: HashMap<String, BandwidthLimit>,
+}
+
+/// Keeps limiters shared by all streams of the same scope (everything, tunnel
+/// type, routing tag or OUT).
+pub struct BandwidthManager {
+    all: BandwidthLimiters,
+    tunnels: HashMap<String, BandwidthLimiters>,
+    tags: HashMap<String, BandwidthLimiters>,
+    /// Limiters of OUT shared by tunnel types, with the count of registrations.
+    outs: Mutex<HashMap<MatchOutId, (usize, BandwidthLimiters)>>,
+}
+
+impl BandwidthManager {
+    pub fn new(config: BandwidthConfig) -> Self {
+        let to_limiters_map = |limits: HashMap<String, BandwidthLimit>| {
+            limits
+                .into_iter()
+                .map(|(key, limit)| (key, BandwidthLimiters::new(limit)))
+                .collect()
+        };
+
+        Self {
+            all: BandwidthLimiters::new(config.limit),
+            tunnels: to_limiters_map(config.tunnels),
+            tags: to_limiters_map(config.tags),
+            outs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers the limit advertised by OUT (IN only), shared by all its tunnels
+    /// of all types. Each registration is paired with an `unregister_out` call.
+    pub fn register_out(&self, out_id: MatchOutId, limit: BandwidthLimit) {
+        let mut outs = self.outs.lock().unwrap();
+
+        let (count, _) = outs
+            .entry(out_id)
+            .or_insert_with(|| (0, BandwidthLimiters::new(limit)));
+
+        *count += 1;
+    }
+
+    pub fn unregister_out(&self, out_id: MatchOutId) {
+        let mut outs = self.outs.lock().unwrap();
+
+        if let Some((count, _)) = outs.get_mut(&out_id) {
+            *count -= 1;
+
+            if *count == 0 {
+                outs.remove(&out_id);
+            }
+        }
+    }
+
+    pub fn get_limiters(
+        &self,
+        tunnel_type: &str,
+        tag: Option<&str>,
+        out_id: Option<MatchOutId>,
+    ) -> BandwidthLimiters {
+        let mut limiters = self.all.clone();
+
+        if let Some(tunnel_limiters) = self.tunnels.get(tunnel_type) {
+            limiters.extend(tunnel_limiters);
+        }
+
+        if let Some(tag_limiters) = tag.and_then(|tag| self.tags.get(tag)) {
+            limiters.extend(tag_limiters);
+        }
+
+        if let Some(out_id) = out_id {
+            if let Some((_, out_limiters)) = self.outs.lock().unwrap().get(&out_id) {
+                limiters.extend(out_limiters);
+            }
+        }
+
+        limiters
+    }
+}
@@ -0,0 +1,7 @@
+mod bandwidth_limit;
+mod bandwidth_limiter;
+mod bandwidth_manager;
+
+pub use bandwidth_limit::*;
+pub use bandwidth_limiter::*;
+pub use bandwidth_manager::*;
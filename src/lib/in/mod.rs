@@ -1,5 +1,6 @@
 pub mod dns_resolver;
 pub mod fake_ip_dns;
+pub mod out_policy;
 pub mod transparent_proxy;
 pub mod tunnel_manager;
 mod udp_forwarder;
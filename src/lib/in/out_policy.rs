@@ -0,0 +1,183 @@
+use std::ops::RangeInclusive;
+
+use itertools::Itertools as _;
+
+use crate::{
+    match_server::{MatchOutId, OutIdentityKey},
+    route::{config::OutRuleConfig, rule::Label},
+};
+
+/// Trust policy of IN for OUT matched through the match server, which anyone
+/// with the match server URL and key could otherwise join as an exit.
+#[derive(Clone, Default)]
+pub struct OutPolicy {
+    /// OUT accepted, any if empty.
+    pub ids: Vec<MatchOutId>,
+    /// Labels of which OUT has to claim at least one to be accepted, any if
+    /// empty.
+    pub labels: Vec<Label>,
+    /// Identity keys of which OUT has to present one to be accepted, any if
+    /// empty.
+    pub identity_keys: Vec<OutIdentityKey>,
+    /// Labels OUT may claim, others being ignored, any if `None`.
+    pub claimable_labels: Option<Vec<Label>>,
+    /// Priorities routing rules of OUT may use, others being clamped into the
+    /// range, any if `None`.
+    pub routing_priority_range: Option<RangeInclusive<i64>>,
+}
+
+impl OutPolicy {
+    pub fn accepts_id(&self, out_id: MatchOutId) -> bool {
+        self.ids.is_empty() || self.ids.contains(&out_id)
+    }
+
+    /// Only labels OUT may claim count, so that it can not be accepted by one
+    /// it is not allowed to claim.
+    pub fn accepts_labels(&self, labels: &[Label]) -> bool {
+        self.labels.is_empty()
+            || labels
+                .iter()
+                .any(|label| self.labels.contains(label) && self.is_claimable(label))
+    }
+
+    pub fn accepts_identity_key(&self, key: Option<&OutIdentityKey>) -> bool {
+        self.identity_keys.is_empty() || key.is_some_and(|key| self.identity_keys.contains(key))
+    }
+
+    pub fn filter_labels(&self, labels: &[Label]) -> Vec<Label> {
+        labels
+            .iter()
+            .filter(|label| self.is_claimable(label))
+            .cloned()
+            .collect_vec()
+    }
+
+    fn is_claimable(&self, label: &Label) -> bool {
+        self.claimable_labels
+            .as_ref()
+            .is_none_or(|claimable_labels| claimable_labels.contains(label))
+    }
+
+    pub fn restrict_routing(
+        &self,
+        out_id: MatchOutId,
+        mut rules: Vec<OutRuleConfig>,
+        priority: i64,
+    ) -> (Vec<OutRuleConfig>, i64) {
+        let Some(range) = &self.routing_priority_range else {
+            return (rules, priority);
+        };
+
+        let clamp = |priority: i64| priority.clamp(*range.start(), *range.end());
+
+        let mut clamped = clamp(priority) != priority;
+
+        for rule in &mut rules {
+            if let Some(Some(priority)) = rule.priority_mut() {
+                if clamp(*priority) != *priority {
+                    *priority = clamp(*priority);
+                    clamped = true;
+                }
+            }
+        }
+
+        if clamped {
+            log::warn!(
+                "clamped routing priorities of OUT {out_id} into {}..={}.",
+                range.start(),
+                range.end()
+            );
+        }
+
+        (rules, clamp(priority))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::route::rule::BuiltInLabel;
+
+    use super::*;
+
+    fn custom_label(label: &str) -> Label {
+        Label::Custom(label.to_owned())
+    }
+
+    #[test]
+    fn accepts_any_by_default() {
+        let policy = OutPolicy::default();
+
+        assert!(policy.accepts_id(MatchOutId::new()));
+        assert!(policy.accepts_labels(&[]));
+        assert!(policy.accepts_identity_key(None));
+        assert_eq!(
+            policy.filter_labels(&[custom_label("a")]),
+            [custom_label("a")]
+        );
+    }
+
+    #[test]
+    fn accepts_listed_out() {
+        let out_id = MatchOutId::new();
+
+        let policy = OutPolicy {
+            ids: vec![out_id],
+            labels: vec![custom_label("a")],
+            ..OutPolicy::default()
+        };
+
+        assert!(policy.accepts_id(out_id));
+        assert!(!policy.accepts_id(MatchOutId::new()));
+
+        assert!(policy.accepts_labels(&[custom_label("b"), custom_label("a")]));
+        assert!(!policy.accepts_labels(&[custom_label("b")]));
+    }
+
+    #[test]
+    fn filters_claimable_labels() {
+        let policy = OutPolicy {
+            labels: vec![custom_label("a"), Label::BuiltIn(BuiltInLabel::Proxy)],
+            claimable_labels: Some(vec![Label::BuiltIn(BuiltInLabel::Proxy)]),
+            ..OutPolicy::default()
+        };
+
+        let labels = [custom_label("a"), Label::BuiltIn(BuiltInLabel::Proxy)];
+
+        assert_eq!(
+            policy.filter_labels(&labels),
+            [Label::BuiltIn(BuiltInLabel::Proxy)]
+        );
+
+        // Not accepted by a label it may not claim.
+        assert!(policy.accepts_labels(&labels));
+        assert!(!policy.accepts_labels(&[custom_label("a")]));
+    }
+
+    #[test]
+    fn clamps_routing_priorities() -> anyhow::Result<()> {
+        let policy = OutPolicy {
+            routing_priority_range: Some(-10..=10),
+            ..OutPolicy::default()
+        };
+
+        let rules: Vec<OutRuleConfig> = serde_json::from_value(serde_json::json!([
+            { "type": "domain", "match": "a.com", "priority": 100 },
+            { "type": "domain", "match": "b.com", "priority": 5 },
+            { "type": "domain", "match": "c.com" },
+            { "type": "fallback" },
+        ]))?;
+
+        let (mut rules, priority) = policy.restrict_routing(MatchOutId::new(), rules, -100);
+
+        assert_eq!(priority, -10);
+        assert_eq!(
+            rules
+                .iter_mut()
+                .map(|rule| rule.priority_mut().copied())
+                .collect_vec(),
+            [Some(Some(10)), Some(Some(5)), Some(None), None]
+        );
+
+        Ok(())
+    }
+}
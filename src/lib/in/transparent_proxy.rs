@@ -1,4 +1,9 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use futures::future::try_join_all;
 use itertools::Itertools;
@@ -6,25 +11,35 @@ use lits::duration;
 use tokio::io::AsyncWriteExt;
 
 use crate::{
+    bandwidth::{BandwidthConfig, BandwidthManager},
     common::get_destination_string,
     config::MatchServerConfig,
     r#in::{
         dns_resolver::convert_to_socket_addresses,
         fake_ip_dns::FakeIpResolver,
+        out_policy::OutPolicy,
         udp_forwarder::{UdpForwarder, UDP_BUFFER_SIZE},
     },
     route::{config::InRuleConfig, geolite2::GeoLite2, router::Router, rule::Label},
     tunnel::{
         http2::{
-            Http2InTunnelConfig, Http2InTunnelProvider, PlugHttp2InTunnelConfig,
-            PlugHttp2InTunnelProvider,
+            Http2InTunnelConfig, Http2InTunnelProvider, Http2TransportConfig,
+            PlugHttp2InTunnelConfig, PlugHttp2InTunnelProvider,
+        },
+        quic::{
+            QuicInTunnelConfig, QuicInTunnelProvider, QuicTransportConfig,
+            StaticQuicInTunnelConfig, StaticQuicInTunnelProvider,
         },
-        quic::{QuicInTunnelConfig, QuicInTunnelProvider},
-        InTunnelLike as _, InTunnelProvider,
+        websocket::{WebSocketInTunnelConfig, WebSocketInTunnelProvider},
+        AnyInTunnelLikeArc, InTunnelProvider, TunnelTlsConfig,
     },
     utils::{
-        io::copy_bidirectional,
+        io::{
+            copy_bidirectional, copy_bidirectional_tcp, AsyncWriteReset, StreamTimeoutConfig,
+            TcpWriteHalf,
+        },
         net::socket::{get_socket_original_destination, set_keepalive_options, IpFamily},
+        port_mapping::{PortMappingClient, PortMappingConfig},
     },
 };
 
@@ -38,19 +53,35 @@ pub struct Options<'a> {
     pub fake_ipv6_net: ipnet::Ipv6Net,
     pub stun_server_addresses: Vec<String>,
     pub match_server_config: MatchServerConfig,
+    pub out_policy: OutPolicy,
     pub tunneling_http2_enabled: bool,
     pub tunneling_http2_connections: usize,
     pub tunneling_http2_priority: Option<i64>,
     pub tunneling_http2_priority_default: i64,
+    pub tunneling_http2_transport: Http2TransportConfig,
     pub tunneling_plug_http2_enabled: bool,
     pub tunneling_plug_http2_listen_address: SocketAddr,
     pub tunneling_plug_http2_external_port: Option<u16>,
     pub tunneling_plug_http2_connections: usize,
     pub tunneling_plug_http2_priority: Option<i64>,
     pub tunneling_plug_http2_priority_default: i64,
+    pub tunneling_plug_http2_tls: TunnelTlsConfig,
+    pub tunneling_plug_http2_transport: Http2TransportConfig,
     pub tunneling_quic_enabled: bool,
     pub tunneling_quic_priority: Option<i64>,
+    pub tunneling_quic_stream_pool: usize,
     pub tunneling_quic_priority_default: i64,
+    pub tunneling_quic_transport: QuicTransportConfig,
+    /// Identity presented to static OUT pinning certificates.
+    pub tunneling_static_tls: TunnelTlsConfig,
+    pub tunneling_websocket_enabled: bool,
+    pub tunneling_websocket_connections: usize,
+    pub tunneling_websocket_priority: Option<i64>,
+    pub tunneling_websocket_stream_pool: usize,
+    pub tunneling_websocket_priority_default: i64,
+    pub tunneling_port_mapping: Option<PortMappingConfig>,
+    pub bandwidth: BandwidthConfig,
+    pub stream_timeout: StreamTimeoutConfig,
     pub routing_rules: Vec<InRuleConfig>,
     pub geolite2_cache_path: &'a PathBuf,
     pub geolite2_url: String,
@@ -67,19 +98,34 @@ pub async fn up(
         fake_ipv6_net,
         stun_server_addresses,
         match_server_config,
+        out_policy,
         tunneling_http2_enabled,
         tunneling_http2_connections,
         tunneling_http2_priority,
         tunneling_http2_priority_default,
+        tunneling_http2_transport,
         tunneling_plug_http2_enabled,
         tunneling_plug_http2_listen_address,
         tunneling_plug_http2_external_port,
         tunneling_plug_http2_connections,
         tunneling_plug_http2_priority,
         tunneling_plug_http2_priority_default,
+        tunneling_plug_http2_tls,
+        tunneling_plug_http2_transport,
         tunneling_quic_enabled,
         tunneling_quic_priority,
+        tunneling_quic_stream_pool,
         tunneling_quic_priority_default,
+        tunneling_quic_transport,
+        tunneling_static_tls,
+        tunneling_websocket_enabled,
+        tunneling_websocket_connections,
+        tunneling_websocket_priority,
+        tunneling_websocket_stream_pool,
+        tunneling_websocket_priority_default,
+        tunneling_port_mapping,
+        bandwidth,
+        stream_timeout,
         routing_rules,
         geolite2_cache_path,
         geolite2_url,
@@ -88,7 +134,20 @@ pub async fn up(
 ) -> anyhow::Result<()> {
     log::info!("starting IN transparent proxy...");
 
-    let tunnel_providers = {
+    let tunnel_providers = if let MatchServerConfig::Static(static_config) = match_server_config {
+        let config = StaticQuicInTunnelConfig {
+            priority: tunneling_quic_priority,
+            priority_default: tunneling_quic_priority_default,
+            stream_pool_size: tunneling_quic_stream_pool,
+            transport: tunneling_quic_transport,
+            tls: tunneling_static_tls,
+        };
+
+        vec![
+            Box::new(StaticQuicInTunnelProvider::new(static_config.outs, config)?)
+                as Box<dyn InTunnelProvider + Send>,
+        ]
+    } else {
         let match_server = Arc::new(match_server_config.new_in_match_server()?);
 
         let stun_server_addresses = {
@@ -102,6 +161,9 @@ pub async fn up(
             resolved_addresses
         };
 
+        let port_mapping_client =
+            tunneling_port_mapping.map(|config| Arc::new(PortMappingClient::new(config)));
+
         let mut tunnel_providers = Vec::<Box<dyn InTunnelProvider + Send>>::new();
 
         if tunneling_http2_enabled {
@@ -110,6 +172,7 @@ pub async fn up(
                 priority: tunneling_http2_priority,
                 priority_default: tunneling_http2_priority_default,
                 traffic_mark,
+                transport: tunneling_http2_transport,
             };
 
             tunnel_providers.push(Box::new(
@@ -121,11 +184,14 @@ pub async fn up(
             let config = PlugHttp2InTunnelConfig {
                 listen_address: tunneling_plug_http2_listen_address,
                 external_port: tunneling_plug_http2_external_port,
+                port_mapping: port_mapping_client.clone(),
+                tls: tunneling_plug_http2_tls,
                 connections: tunneling_plug_http2_connections,
                 priority: tunneling_plug_http2_priority,
                 priority_default: tunneling_plug_http2_priority_default,
                 stun_server_addresses: stun_server_addresses.clone(),
                 traffic_mark,
+                transport: tunneling_plug_http2_transport,
             };
 
             tunnel_providers.push(Box::new(
@@ -137,14 +203,33 @@ pub async fn up(
             let config = QuicInTunnelConfig {
                 priority: tunneling_quic_priority,
                 priority_default: tunneling_quic_priority_default,
+                stream_pool_size: tunneling_quic_stream_pool,
                 stun_server_addresses: stun_server_addresses.clone(),
+                transport: tunneling_quic_transport,
+                port_mapping: port_mapping_client.clone(),
                 traffic_mark,
             };
 
             tunnel_providers.push(Box::new(QuicInTunnelProvider::new(
                 match_server.clone(),
                 config,
-            )));
+            )?));
+        }
+
+        if tunneling_websocket_enabled {
+            let config = WebSocketInTunnelConfig {
+                connections: tunneling_websocket_connections,
+                priority: tunneling_websocket_priority,
+                priority_default: tunneling_websocket_priority_default,
+                stream_pool_size: tunneling_websocket_stream_pool,
+                dns_resolver: dns_resolver.clone(),
+                traffic_mark,
+            };
+
+            tunnel_providers.push(Box::new(WebSocketInTunnelProvider::new(
+                match_server.clone(),
+                config,
+            )?));
         }
 
         tunnel_providers
@@ -155,6 +240,8 @@ pub async fn up(
     let tunnel_manager = Arc::new(TunnelManager::new(
         tunnel_providers,
         router.clone(),
+        Arc::new(BandwidthManager::new(bandwidth)),
+        out_policy,
         traffic_mark,
     ));
 
@@ -255,6 +342,7 @@ pub async fn up(
                         name,
                         labels_groups,
                         tunnel_manager,
+                        stream_timeout,
                     )
                     .await;
                 });
@@ -273,33 +361,57 @@ pub async fn up(
         while let Ok((length, source, original_destination)) =
             udp_forwarder.receive(&mut buffer).await
         {
-            let real_destination = udp_forwarder
+            let route = match udp_forwarder
                 .get_associated_destination(&source, &original_destination)
                 .await
-                .or_else(|| {
-                    let (real_destination, name, _) = resolve_udp_destination(
+            {
+                Some(route) => Some(route),
+                None => {
+                    let (real_destination, name, labels_groups) = resolve_udp_destination(
                         original_destination,
                         &fake_ip_resolver,
                         &geolite2,
                         &router,
                     );
 
-                    real_destination.inspect(|&real_destination| {
-                        let destination_string = get_destination_string(real_destination, &name);
+                    match real_destination {
+                        Some(real_destination) => {
+                            let (relay_route, limiters) =
+                                tunnel_manager.select_udp_relay_route(&labels_groups).await;
 
-                        log::info!("redirect datagrams from {source} to {destination_string}...");
-                    })
-                });
+                            let destination_string =
+                                get_destination_string(real_destination, &name);
 
-            if let Some(real_destination) = real_destination {
-                udp_forwarder
+                            match &relay_route {
+                                Some((tunnel_id, _, _)) => log::info!(
+                                    "relay datagrams from {source} to {destination_string} via tunnel {tunnel_id}..."
+                                ),
+                                None => log::info!(
+                                    "redirect datagrams from {source} to {destination_string}..."
+                                ),
+                            }
+
+                            Some((real_destination, relay_route, limiters))
+                        }
+                        None => None,
+                    }
+                }
+            };
+
+            if let Some((real_destination, relay_route, limiters)) = route {
+                if let Err(error) = udp_forwarder
                     .send(
                         source,
                         original_destination,
                         real_destination,
+                        relay_route,
+                        limiters,
                         &buffer[..length],
                     )
-                    .await?;
+                    .await
+                {
+                    log::warn!("datagram from {source} to {real_destination} dropped: {error}");
+                }
             }
         }
 
@@ -488,6 +600,7 @@ async fn handle_in_tcp_stream(
     name: Option<String>,
     labels_groups: Vec<Vec<(Label, Option<String>)>>,
     tunnel_manager: Arc<TunnelManager>,
+    stream_timeout: StreamTimeoutConfig,
 ) {
     let destination_string = get_destination_string(destination, &name);
 
@@ -496,7 +609,9 @@ async fn handle_in_tcp_stream(
         stringify_labels_groups(&labels_groups)
     );
 
-    let Some((tunnel, tag)) = tunnel_manager.select_tunnel(&labels_groups).await else {
+    let candidates = tunnel_manager.select_tunnels(&labels_groups).await;
+
+    if candidates.is_empty() {
         log::warn!(
             "connection from {source} to {destination_string} via {} rejected cause no matching tunnel.",
             stringify_labels_groups(&labels_groups)
@@ -505,34 +620,105 @@ async fn handle_in_tcp_stream(
         let _ = stream.shutdown().await;
 
         return;
-    };
+    }
 
-    log::info!(
-        "connect {source} to {destination_string} via {tunnel}{tagged}...",
-        tagged = tag
-            .as_deref()
-            .map_or_else(|| "".to_owned(), |tag| format!(" ({tag})"))
-    );
+    let mut connected = None;
+
+    for (tunnel, tag) in candidates {
+        let limiters = tunnel_manager.get_bandwidth_limiters(&tunnel, tag.as_deref());
+
+        log::info!(
+            "connect {source} to {destination_string} via {tunnel}{tagged}...",
+            tagged = tag
+                .as_deref()
+                .map_or_else(|| "".to_owned(), |tag| format!(" ({tag})"))
+        );
+
+        let connect_started_at = Instant::now();
+
+        // The sniff buffer is only consumed by the tunnel once connected, so it can
+        // be replayed to the next candidate if this one fails to open.
+        let result = match &tunnel {
+            AnyInTunnelLikeArc::Direct(tunnel) => tunnel
+                .connect_tcp(destination, sniff_buffer.clone())
+                .await
+                .map(ConnectedStreams::Direct),
+            AnyInTunnelLikeArc::InTunnel(tunnel) => tunnel
+                .connect(destination, name.clone(), tag, sniff_buffer.clone())
+                .await
+                .map(|(read_stream, write_stream, stream_closed_sender)| {
+                    ConnectedStreams::Tunnel(read_stream, write_stream, stream_closed_sender)
+                }),
+        };
+
+        match result {
+            Ok(streams) => {
+                // Includes OUT connecting to the destination if it reports the
+                // status, the latency a client sees before its first byte is sent.
+                log::debug!(
+                    "connection from {source} to {destination_string} via {tunnel} opened in {:?}.",
+                    connect_started_at.elapsed()
+                );
+
+                connected = Some((streams, limiters));
+                break;
+            }
+            Err(error) => {
+                log::warn!(
+                    "connection from {source} to {destination_string} via {tunnel} failed to open: {error}"
+                );
+            }
+        }
+    }
+
+    let Some((streams, limiters)) = connected else {
+        log::warn!(
+            "connection from {source} to {destination_string} rejected cause all matching tunnels failed."
+        );
+
+        // Reset instead of closing gracefully, so that the client sees the connection
+        // refused rather than an empty response.
+        let _ = stream.set_linger(Some(Duration::ZERO));
+
+        return;
+    };
 
     if let Err(error) = {
         let destination_string = destination_string.clone();
 
         async move {
-            let (mut tunnel_read_stream, mut tunnel_write_stream, stream_closed_sender) =
-                tunnel.connect(destination, name, tag, sniff_buffer).await?;
-
-            let (mut read_stream, mut write_stream) = stream.into_split();
-
-            let copy_result = copy_bidirectional(
-                &destination_string,
-                (&mut read_stream, &mut tunnel_write_stream, end),
-                (&mut tunnel_read_stream, &mut write_stream),
-            )
-            .await;
+            match streams {
+                ConnectedStreams::Tunnel(
+                    mut tunnel_read_stream,
+                    mut tunnel_write_stream,
+                    stream_closed_sender,
+                ) => {
+                    let (mut read_stream, write_stream) = stream.into_split();
+
+                    let copy_result = copy_bidirectional(
+                        &destination_string,
+                        (&mut read_stream, &mut tunnel_write_stream, end),
+                        (&mut tunnel_read_stream, TcpWriteHalf::new(write_stream)),
+                        &limiters,
+                        &stream_timeout,
+                    )
+                    .await;
 
-            stream_closed_sender.send(()).ok();
+                    stream_closed_sender.send(()).ok();
 
-            copy_result?;
+                    copy_result?;
+                }
+                ConnectedStreams::Direct(direct_stream) => {
+                    copy_bidirectional_tcp(
+                        &destination_string,
+                        (stream, end),
+                        direct_stream,
+                        &limiters,
+                        &stream_timeout,
+                    )
+                    .await?;
+                }
+            }
 
             anyhow::Ok(())
         }
@@ -543,6 +729,16 @@ async fn handle_in_tcp_stream(
     }
 }
 
+enum ConnectedStreams {
+    Tunnel(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+        tokio::sync::oneshot::Sender<()>,
+    ),
+    /// Plain TCP stream of DIRECT, relayed with zero-copy.
+    Direct(tokio::net::TcpStream),
+}
+
 fn stringify_labels_groups(labels_groups: &[Vec<(Label, Option<String>)>]) -> String {
     labels_groups
         .iter()
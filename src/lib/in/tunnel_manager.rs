@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
         atomic::{self, AtomicUsize},
         Arc, Mutex,
@@ -10,24 +10,38 @@ use std::{
 use itertools::Itertools;
 
 use crate::{
-    match_server::MatchOutId,
+    bandwidth::{BandwidthLimiters, BandwidthManager},
+    match_server::{
+        MatchOutId, MatchOutIdentity, OutIdentityKey, OutLoad, SignedOutInstance, OUT_LOAD_INTERVAL,
+    },
+    r#in::out_policy::OutPolicy,
     route::{
         router::Router,
         rule::{BuiltInLabel, Label},
     },
     tunnel::{
-        direct_tunnel::DirectInTunnel, AnyInTunnelLikeArc, InTunnel, InTunnelLike,
-        InTunnelProvider, TunnelId,
+        direct_tunnel::DirectInTunnel, AnyInTunnelLikeArc, InTunnel, InTunnelLike as _,
+        InTunnelProvider, TunnelId, UdpRelayRoute,
     },
 };
 
-type TunnelMap = HashMap<TunnelId, Arc<Box<dyn InTunnel>>>;
+/// Tunnels with the labels of OUT allowed by the policy, and the instance of OUT
+/// they connect to if it has an identity.
+type TunnelMap = HashMap<TunnelId, (Arc<Box<dyn InTunnel>>, Vec<Label>, Option<uuid::Uuid>)>;
 type LabelToTunnelsMap = HashMap<Label, Vec<Arc<Box<dyn InTunnel>>>>;
+type OutLoadMap = HashMap<MatchOutId, OutLoad>;
+/// Identity key presented by OUT, pinned until IN exits once presented, so that
+/// the id can not be taken over by another OUT with or without identity. `None`
+/// for OUT without identity, pinned only while it has active tunnels.
+type OutIdentityMap = HashMap<MatchOutId, Option<OutIdentityKey>>;
 
 pub struct TunnelManager {
     pub accept_handles: Mutex<Option<Vec<tokio::task::JoinHandle<()>>>>,
-    direct_tunnel: Arc<Box<dyn InTunnelLike>>,
+    direct_tunnel: Arc<DirectInTunnel>,
     label_to_tunnels_map: Arc<tokio::sync::Mutex<LabelToTunnelsMap>>,
+    /// Latest load published by OUT with active tunnels.
+    out_load_map: Arc<Mutex<OutLoadMap>>,
+    bandwidth_manager: Arc<BandwidthManager>,
     select_index: AtomicUsize,
 }
 
@@ -35,10 +49,16 @@ impl TunnelManager {
     pub fn new(
         tunnel_providers: Vec<Box<dyn InTunnelProvider + Send>>,
         router: Arc<Router>,
+        bandwidth_manager: Arc<BandwidthManager>,
+        out_policy: OutPolicy,
         traffic_mark: u32,
     ) -> Self {
+        let out_policy = Arc::new(out_policy);
+
         let tunnel_map = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
         let label_to_tunnels_map = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+        let out_load_map = Arc::new(Mutex::new(HashMap::new()));
+        let out_identity_map = Arc::new(Mutex::new(HashMap::new()));
 
         let accept_handles = tunnel_providers
             .into_iter()
@@ -46,32 +66,74 @@ impl TunnelManager {
                 let router = router.clone();
                 let tunnel_map = tunnel_map.clone();
                 let label_to_tunnels_map = label_to_tunnels_map.clone();
+                let out_load_map = out_load_map.clone();
+                let out_identity_map = out_identity_map.clone();
+                let bandwidth_manager = bandwidth_manager.clone();
+                let out_policy = out_policy.clone();
 
                 tokio::spawn(Self::handle_tunnel_provider(
                     tunnel_provider,
                     router,
                     tunnel_map,
                     label_to_tunnels_map,
+                    out_load_map,
+                    out_identity_map,
+                    bandwidth_manager,
+                    out_policy,
                 ))
             })
             .collect_vec();
 
         Self {
             accept_handles: Mutex::new(Some(accept_handles)),
-            direct_tunnel: Arc::new(Box::new(DirectInTunnel::new(traffic_mark))),
+            direct_tunnel: Arc::new(DirectInTunnel::new(traffic_mark)),
             label_to_tunnels_map,
+            out_load_map,
+            bandwidth_manager,
             select_index: AtomicUsize::new(0),
         }
     }
 
-    pub async fn select_tunnel(
+    /// Returns limiters for a stream through the tunnel, including the ones of the
+    /// OUT it connects to.
+    pub fn get_bandwidth_limiters(
+        &self,
+        tunnel: &AnyInTunnelLikeArc,
+        tag: Option<&str>,
+    ) -> BandwidthLimiters {
+        let out_id = match tunnel {
+            AnyInTunnelLikeArc::InTunnel(tunnel) => Some(tunnel.out_id()),
+            AnyInTunnelLikeArc::Direct(_) => None,
+        };
+
+        self.bandwidth_manager
+            .get_limiters(tunnel.name(), tag, out_id)
+    }
+
+    /// Returns candidate tunnels in the order they should be tried, so that a
+    /// connection can fall back to the next one if opening a stream fails.
+    pub async fn select_tunnels(
         &self,
         labels_groups: &[Vec<(Label, Option<String>)>],
-    ) -> Option<(AnyInTunnelLikeArc, Option<String>)> {
+    ) -> Vec<(AnyInTunnelLikeArc, Option<String>)> {
         let index = self.select_index.fetch_add(1, atomic::Ordering::Relaxed);
 
         let label_to_tunnels_map = self.label_to_tunnels_map.lock().await;
 
+        let out_load_map = self.out_load_map.lock().unwrap().clone();
+
+        let mut candidates = Vec::new();
+        let mut selected_tunnel_id_set = HashSet::new();
+
+        let mut push_tunnels =
+            |candidates: &mut Vec<_>, tunnels: &[Arc<Box<dyn InTunnel>>], tag: &Option<String>| {
+                for tunnel in select_from_tunnels(tunnels, index, &out_load_map) {
+                    if selected_tunnel_id_set.insert(tunnel.id()) {
+                        candidates.push((tunnel.into(), tag.clone()));
+                    }
+                }
+            };
+
         let mut direct_tag = None;
 
         for labels in labels_groups {
@@ -81,7 +143,8 @@ impl TunnelManager {
                 match label {
                     Label::BuiltIn(label) => match label {
                         BuiltInLabel::Direct => {
-                            return Some((self.direct_tunnel.clone().into(), tag.clone()));
+                            candidates.push((self.direct_tunnel.clone().into(), tag.clone()));
+                            return candidates;
                         }
                         BuiltInLabel::Proxy => {
                             if proxy_tag.is_none() {
@@ -98,32 +161,52 @@ impl TunnelManager {
                     },
                     _ => {
                         if let Some(tunnels) = label_to_tunnels_map.get(label) {
-                            let tunnel = select_from_tunnels(tunnels, index);
-
-                            if tunnel.is_some() {
-                                return tunnel.map(|tunnel| (tunnel, tag.clone()));
-                            }
+                            push_tunnels(&mut candidates, tunnels, tag);
                         }
                     }
                 }
             }
 
-            let proxy_tunnel = label_to_tunnels_map
-                .get(&Label::BuiltIn(BuiltInLabel::Proxy))
-                .and_then(|tunnels| select_from_tunnels(tunnels, index));
-
             if let Some(tag) = proxy_tag {
-                if let Some(tunnel) = proxy_tunnel {
-                    return Some((tunnel, tag));
+                if let Some(tunnels) =
+                    label_to_tunnels_map.get(&Label::BuiltIn(BuiltInLabel::Proxy))
+                {
+                    push_tunnels(&mut candidates, tunnels, &tag);
                 }
             }
         }
 
         if let Some(tag) = direct_tag {
-            return Some((self.direct_tunnel.clone().into(), tag));
+            candidates.push((self.direct_tunnel.clone().into(), tag));
         }
 
-        None
+        candidates
+    }
+
+    /// Returns the first candidate tunnel able to relay UDP, or `None` to send
+    /// datagrams directly, either as routed or cause no matching tunnel relays UDP,
+    /// with the limiters of the datagrams.
+    pub async fn select_udp_relay_route(
+        &self,
+        labels_groups: &[Vec<(Label, Option<String>)>],
+    ) -> (Option<UdpRelayRoute>, BandwidthLimiters) {
+        for (tunnel, tag) in self.select_tunnels(labels_groups).await {
+            let limiters = self.get_bandwidth_limiters(&tunnel, tag.as_deref());
+
+            match &tunnel {
+                AnyInTunnelLikeArc::InTunnel(in_tunnel) => {
+                    if let Some(relay) = in_tunnel.udp_relay() {
+                        return (Some((in_tunnel.id(), relay, tag)), limiters);
+                    }
+                }
+                AnyInTunnelLikeArc::Direct(_) => return (None, limiters),
+            }
+        }
+
+        (
+            None,
+            self.get_bandwidth_limiters(&self.direct_tunnel.clone().into(), None),
+        )
     }
 
     async fn handle_tunnel_provider(
@@ -131,12 +214,24 @@ impl TunnelManager {
         router: Arc<Router>,
         tunnel_map: Arc<tokio::sync::Mutex<TunnelMap>>,
         label_to_tunnels_map: Arc<tokio::sync::Mutex<LabelToTunnelsMap>>,
+        out_load_map: Arc<Mutex<OutLoadMap>>,
+        out_identity_map: Arc<Mutex<OutIdentityMap>>,
+        bandwidth_manager: Arc<BandwidthManager>,
+        out_policy: Arc<OutPolicy>,
     ) {
         let tunnel_provider = Arc::new(tunnel_provider);
 
         loop {
             match tunnel_provider.accept_out().await {
                 Ok((out_id, connections)) => {
+                    // Kept accepted by the match server, so that it is not offered
+                    // again.
+                    if !out_policy.accepts_id(out_id) {
+                        log::warn!("refused OUT {out_id}: not in accepted OUT ids.");
+
+                        continue;
+                    }
+
                     tokio::spawn(Self::handle_out(
                         out_id,
                         connections,
@@ -144,6 +239,10 @@ impl TunnelManager {
                         router.clone(),
                         tunnel_map.clone(),
                         label_to_tunnels_map.clone(),
+                        out_load_map.clone(),
+                        out_identity_map.clone(),
+                        bandwidth_manager.clone(),
+                        out_policy.clone(),
                     ));
                 }
                 Err(error) => {
@@ -161,31 +260,125 @@ impl TunnelManager {
         router: Arc<Router>,
         tunnel_map: Arc<tokio::sync::Mutex<TunnelMap>>,
         label_to_tunnels_map: Arc<tokio::sync::Mutex<LabelToTunnelsMap>>,
+        out_load_map: Arc<Mutex<OutLoadMap>>,
+        out_identity_map: Arc<Mutex<OutIdentityMap>>,
+        bandwidth_manager: Arc<BandwidthManager>,
+        out_policy: Arc<OutPolicy>,
     ) {
         let tunnel_name = tunnel_provider.name();
 
         let semaphore = Arc::new(tokio::sync::Semaphore::new(connections));
 
+        let mut bandwidth_registered = false;
+
+        let load_refresh_handle = tokio::spawn({
+            let tunnel_provider = tunnel_provider.clone();
+            let tunnel_map = tunnel_map.clone();
+            let out_load_map = out_load_map.clone();
+            let out_identity_map = out_identity_map.clone();
+
+            async move {
+                loop {
+                    match tunnel_provider.get_out_load(out_id).await {
+                        Ok(Some(load)) => {
+                            if let Some(out_instance) = &load.instance {
+                                Self::release_out_instance_tunnels(
+                                    out_id,
+                                    out_instance,
+                                    &out_identity_map,
+                                    &*tunnel_map.lock().await,
+                                );
+                            }
+
+                            out_load_map.lock().unwrap().insert(out_id, load);
+                        }
+                        Ok(None) => {}
+                        Err(error) => {
+                            log::debug!("failed to get load of OUT {out_id}: {error}");
+                        }
+                    }
+
+                    tokio::time::sleep(OUT_LOAD_INTERVAL).await;
+                }
+            }
+        });
+
         loop {
             let permit = semaphore.clone().acquire_owned().await.unwrap();
 
+            // Tunnels beyond the first one are added to OUT only while it has
+            // capacity left, so that more of them go to less loaded OUT.
+            if semaphore.available_permits() + 1 < connections {
+                let mut logged = false;
+
+                while out_load_map
+                    .lock()
+                    .unwrap()
+                    .get(&out_id)
+                    .is_some_and(OutLoad::is_saturated)
+                {
+                    if !logged {
+                        log::info!("OUT {out_id} saturated, holding off {tunnel_name} tunnels.");
+                        logged = true;
+                    }
+
+                    tokio::time::sleep(OUT_LOAD_INTERVAL).await;
+                }
+            }
+
             log::info!("accepting {tunnel_name} tunnel...");
 
             match tunnel_provider.accept(out_id).await {
-                Ok(Some((tunnel, (out_routing_rules, out_routing_priority)))) => {
+                Ok(Some((
+                    tunnel,
+                    (out_routing_rules, out_routing_priority),
+                    out_bandwidth_limit,
+                    out_identity,
+                ))) => {
+                    if !out_policy.accepts_labels(tunnel.labels()) {
+                        log::warn!("refused OUT {out_id}: none of its labels accepted.");
+
+                        break;
+                    }
+
+                    // Not ending the OUT, as the tunnel might come from another
+                    // OUT claiming its id.
+                    if let Err(error) = Self::check_out_identity(
+                        out_id,
+                        out_identity.as_ref(),
+                        &out_policy,
+                        &out_identity_map,
+                    ) {
+                        log::warn!("refused {tunnel_name} tunnel of OUT {out_id}: {error}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+
+                        continue;
+                    }
+
+                    let out_instance_id = out_identity.map(|identity| identity.instance_id);
+
+                    let labels = out_policy.filter_labels(tunnel.labels());
+
+                    let (out_routing_rules, out_routing_priority) = out_policy.restrict_routing(
+                        out_id,
+                        out_routing_rules,
+                        out_routing_priority,
+                    );
+
                     tunnel.set_active_permit(permit);
 
+                    if !bandwidth_registered {
+                        bandwidth_manager.register_out(out_id, out_bandwidth_limit);
+                        bandwidth_registered = true;
+                    }
+
                     let tunnel_id = tunnel.id();
                     let tunnel = Arc::new(tunnel);
 
                     {
                         let mut tunnel_map = tunnel_map.lock().await;
 
-                        tunnel_map.insert(tunnel_id, tunnel.clone());
-
-                        let mut label_to_tunnels_map = label_to_tunnels_map.lock().await;
-
-                        Self::update_label_to_tunnels_map(&tunnel_map, &mut label_to_tunnels_map);
+                        tunnel_map.insert(tunnel_id, (tunnel.clone(), labels, out_instance_id));
 
                         router.register_tunnel(
                             out_id,
@@ -193,11 +386,26 @@ impl TunnelManager {
                             out_routing_rules,
                             out_routing_priority,
                         );
+
+                        if let Some(out_instance_id) = out_instance_id {
+                            Self::replace_out_instance_tunnels(
+                                out_id,
+                                out_instance_id,
+                                &router,
+                                &mut tunnel_map,
+                            );
+                        }
+
+                        let mut label_to_tunnels_map = label_to_tunnels_map.lock().await;
+
+                        Self::update_label_to_tunnels_map(&tunnel_map, &mut label_to_tunnels_map);
                     }
 
                     tokio::spawn({
                         let tunnel_map = tunnel_map.clone();
                         let label_to_tunnels_map = label_to_tunnels_map.clone();
+                        let out_load_map = out_load_map.clone();
+                        let out_identity_map = out_identity_map.clone();
 
                         let router = router.clone();
 
@@ -217,6 +425,19 @@ impl TunnelManager {
                                 &mut label_to_tunnels_map,
                             );
 
+                            if !tunnel_map
+                                .values()
+                                .any(|(tunnel, ..)| tunnel.out_id() == out_id)
+                            {
+                                out_load_map.lock().unwrap().remove(&out_id);
+
+                                let mut out_identity_map = out_identity_map.lock().unwrap();
+
+                                if out_identity_map.get(&out_id).is_some_and(Option::is_none) {
+                                    out_identity_map.remove(&out_id);
+                                }
+                            }
+
                             router.unregister_tunnel(out_id, tunnel_id);
                         }
                     });
@@ -231,6 +452,119 @@ impl TunnelManager {
                 }
             }
         }
+
+        load_refresh_handle.abort();
+
+        if bandwidth_registered {
+            bandwidth_manager.unregister_out(out_id);
+        }
+    }
+
+    /// Checks the identity of OUT against the policy, and against the key it
+    /// presented first (see `OutIdentityMap`).
+    fn check_out_identity(
+        out_id: MatchOutId,
+        identity: Option<&MatchOutIdentity>,
+        out_policy: &OutPolicy,
+        out_identity_map: &Mutex<OutIdentityMap>,
+    ) -> anyhow::Result<()> {
+        let key = identity.map(|identity| &identity.key);
+
+        anyhow::ensure!(
+            out_policy.accepts_identity_key(key),
+            "identity key not accepted."
+        );
+
+        let mut out_identity_map = out_identity_map.lock().unwrap();
+
+        match out_identity_map.get(&out_id) {
+            Some(Some(pinned_key)) => match key {
+                Some(key) => anyhow::ensure!(
+                    key == pinned_key,
+                    "identity key differs from the pinned one."
+                ),
+                None => anyhow::bail!("identity missing while a key is pinned."),
+            },
+            Some(None) => anyhow::ensure!(
+                key.is_none(),
+                "identity presented while tunnels without identity are active."
+            ),
+            None => {
+                out_identity_map.insert(out_id, key.cloned());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Releases the permits of tunnels to a previous instance of a restarted OUT as
+    /// soon as its load tells, so that tunnels to the new instance are matched
+    /// without waiting for the old connections to time out. The instance must be
+    /// signed with the pinned key of OUT, and newer than the one of the tunnels
+    /// (instance ids are ordered by time), so that a load replayed from an
+    /// earlier instance releases nothing.
+    fn release_out_instance_tunnels(
+        out_id: MatchOutId,
+        out_instance: &SignedOutInstance,
+        out_identity_map: &Mutex<OutIdentityMap>,
+        tunnel_map: &TunnelMap,
+    ) {
+        let Some(Some(key)) = out_identity_map.lock().unwrap().get(&out_id).cloned() else {
+            return;
+        };
+
+        if let Err(error) = out_instance.verify(out_id, &key) {
+            log::warn!("ignored instance in load of OUT {out_id}: {error}");
+
+            return;
+        }
+
+        for (tunnel, _, instance_id) in tunnel_map.values() {
+            if tunnel.out_id() == out_id
+                && instance_id.is_some_and(|instance_id| instance_id < out_instance.instance_id)
+                && tunnel.is_active()
+            {
+                log::info!("OUT {out_id} restarted, releasing tunnel {tunnel}.");
+
+                tunnel.deactivate();
+            }
+        }
+    }
+
+    /// Removes tunnels to a previous instance of a restarted OUT at once, rather
+    /// than leaving them until their connections time out. Its load and bandwidth
+    /// limits are kept, as they are tracked by OUT id. Tunnels to a newer
+    /// instance are kept, in case a match of an earlier one comes late.
+    fn replace_out_instance_tunnels(
+        out_id: MatchOutId,
+        out_instance_id: uuid::Uuid,
+        router: &Router,
+        tunnel_map: &mut TunnelMap,
+    ) {
+        let replaced_tunnel_ids = tunnel_map
+            .iter()
+            .filter(|(_, (tunnel, _, instance_id))| {
+                tunnel.out_id() == out_id && *instance_id < Some(out_instance_id)
+            })
+            .map(|(&tunnel_id, _)| tunnel_id)
+            .collect_vec();
+
+        if replaced_tunnel_ids.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "OUT {out_id} restarted, replacing {} tunnels.",
+            replaced_tunnel_ids.len()
+        );
+
+        for tunnel_id in replaced_tunnel_ids {
+            if let Some((tunnel, ..)) = tunnel_map.remove(&tunnel_id) {
+                tunnel.deactivate();
+
+                router.unregister_tunnel(out_id, tunnel_id);
+            }
+        }
     }
 
     fn update_label_to_tunnels_map(
@@ -239,13 +573,13 @@ impl TunnelManager {
     ) {
         label_to_tunnels_map.clear();
 
-        for (_, tunnel) in tunnel_map.iter() {
+        for (tunnel, labels, _) in tunnel_map.values() {
             let extra_labels = [
                 Label::BuiltIn(BuiltInLabel::Proxy),
                 Label::Custom(tunnel.out_id().to_string()),
             ];
 
-            let labels = tunnel.labels().iter().chain(&extra_labels);
+            let labels = labels.iter().chain(&extra_labels);
 
             for label in labels {
                 label_to_tunnels_map
@@ -271,25 +605,121 @@ impl Drop for TunnelManager {
     }
 }
 
+/// Active tunnels ordered by priority, with tunnels of the top priority ordered
+/// by the load of their OUT, and rotated by `index` among equally loaded ones to
+/// balance the load among them.
 fn select_from_tunnels(
     tunnels: &[Arc<Box<dyn InTunnel>>],
     index: usize,
-) -> Option<AnyInTunnelLikeArc> {
+    out_load_map: &OutLoadMap,
+) -> Vec<Arc<Box<dyn InTunnel>>> {
     let tunnels = tunnels
         .iter()
         .filter(|tunnel| tunnel.is_active())
         .collect_vec();
 
     if tunnels.is_empty() {
-        return None;
+        return Vec::new();
     }
 
     let top_priority = tunnels.first().unwrap().priority();
 
-    let tunnels_with_top_priority = tunnels
+    let top_priority_count = tunnels
         .iter()
         .take_while(|tunnel| tunnel.priority() == top_priority)
-        .collect_vec();
+        .count();
+
+    let (tunnels_with_top_priority, other_tunnels) = tunnels.split_at(top_priority_count);
+
+    let offset = index % top_priority_count;
+
+    tunnels_with_top_priority[offset..]
+        .iter()
+        .chain(&tunnels_with_top_priority[..offset])
+        // Stable sort keeps the rotation among tunnels of the same rank.
+        .sorted_by_key(|tunnel| {
+            out_load_map
+                .get(&tunnel.out_id())
+                .map(OutLoad::rank)
+                .unwrap_or_default()
+        })
+        .chain(other_tunnels)
+        .map(|&tunnel| Arc::clone(tunnel))
+        .collect_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bandwidth::BandwidthLimit,
+        match_server::{create_match_out, OutIdentity},
+    };
+
+    use super::*;
+
+    fn sign_match(identity: &OutIdentity) -> MatchOutIdentity {
+        create_match_out(
+            identity,
+            &[],
+            None,
+            &[],
+            0,
+            BandwidthLimit::default(),
+            &serde_json::Value::Null,
+        )
+        .identity
+        .unwrap()
+    }
+
+    #[test]
+    fn pins_out_identity_key() -> anyhow::Result<()> {
+        let out_policy = OutPolicy::default();
+        let out_identity_map = Mutex::new(OutIdentityMap::new());
+
+        let identity = OutIdentity::load_or_generate(None)?;
+        let other_identity = OutIdentity::load_or_generate(None)?;
+
+        let out_id = identity.id();
 
-    Some(Arc::clone(tunnels_with_top_priority[index % tunnels_with_top_priority.len()]).into())
+        let check = |match_identity: Option<&MatchOutIdentity>| {
+            TunnelManager::check_out_identity(
+                out_id,
+                match_identity,
+                &out_policy,
+                &out_identity_map,
+            )
+        };
+
+        check(Some(&sign_match(&identity)))?;
+        check(Some(&sign_match(&identity)))?;
+
+        // Another key or no identity at all for the pinned id.
+        assert!(check(Some(&sign_match(&other_identity))).is_err());
+        assert!(check(None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn pins_out_without_identity() -> anyhow::Result<()> {
+        let out_policy = OutPolicy::default();
+        let out_identity_map = Mutex::new(OutIdentityMap::new());
+
+        let identity = OutIdentity::load_or_generate(None)?;
+
+        let out_id = MatchOutId::new();
+
+        TunnelManager::check_out_identity(out_id, None, &out_policy, &out_identity_map)?;
+        TunnelManager::check_out_identity(out_id, None, &out_policy, &out_identity_map)?;
+
+        assert!(TunnelManager::check_out_identity(
+            out_id,
+            Some(&sign_match(&identity)),
+            &out_policy,
+            &out_identity_map,
+        )
+        .is_err());
+
+        Ok(())
+    }
 }
@@ -4,12 +4,18 @@ use std::{
     net::SocketAddr,
     os::fd::{AsFd as _, AsRawFd as _},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use futures::FutureExt;
 
-use crate::utils::net::{get_any_address, socket::receive_udp_data_with_source_and_destination};
+use crate::{
+    bandwidth::BandwidthLimiters,
+    tunnel::{
+        InUdpRelay, TunnelId, UdpFlowId, UdpFlowResponseSender, UdpRelayRoute, UDP_FLOW_EXPIRATION,
+    },
+    utils::net::{get_any_address, socket::receive_udp_data_with_source_and_destination},
+};
 
 pub struct UdpForwarder {
     proxy_socket: tokio::io::unix::AsyncFd<socket2::Socket>,
@@ -76,11 +82,15 @@ impl UdpForwarder {
         Ok(receive_udp_data_with_source_and_destination(&self.proxy_socket, buffer).await?)
     }
 
+    /// Sends datagrams directly, or through the tunnel of `relay_route` if any.
+    /// Datagrams beyond the limits of `limiters` are dropped, in both directions.
     pub async fn send(
         &self,
         source_address: SocketAddr,
         original_destination_address: SocketAddr,
         real_destination_address: SocketAddr,
+        relay_route: Option<UdpRelayRoute>,
+        limiters: BandwidthLimiters,
         buffer: &[u8],
     ) -> anyhow::Result<()> {
         let mut association_map = self.association_map.lock().await;
@@ -118,6 +128,8 @@ impl UdpForwarder {
                 buffer,
                 &original_destination_address,
                 &real_destination_address,
+                relay_route,
+                limiters,
             )
             .await?;
 
@@ -131,26 +143,39 @@ impl UdpForwarder {
         &self,
         source_address: &SocketAddr,
         original_destination_address: &SocketAddr,
-    ) -> Option<SocketAddr> {
+    ) -> Option<(SocketAddr, Option<UdpRelayRoute>, BandwidthLimiters)> {
         let association_map = self.association_map.lock().await;
 
-        if let Some(association) = association_map.get(source_address) {
-            association
-                .original_to_real_destination_map
-                .read()
-                .await
-                .get(original_destination_address)
-                .cloned()
-        } else {
-            None
+        let association = association_map.get(source_address)?;
+
+        let (real_destination, relay_route, limiters) = association
+            .original_to_real_destination_map
+            .read()
+            .await
+            .get(original_destination_address)
+            .cloned()?;
+
+        // Route again if the tunnel has gone.
+        if relay_route
+            .as_ref()
+            .is_some_and(|(_, relay, _)| relay.is_closed())
+        {
+            return None;
         }
+
+        Some((real_destination, relay_route, limiters))
     }
 }
 
+type OriginalToRealDestinationMap =
+    HashMap<SocketAddr, (SocketAddr, Option<UdpRelayRoute>, BandwidthLimiters)>;
+
 struct Association {
     delegate_socket: Arc<tokio::net::UdpSocket>,
-    original_to_real_destination_map: Arc<tokio::sync::RwLock<HashMap<SocketAddr, SocketAddr>>>,
+    original_to_real_destination_map: Arc<tokio::sync::RwLock<OriginalToRealDestinationMap>>,
     real_to_original_destination_map: Arc<tokio::sync::RwLock<HashMap<SocketAddr, SocketAddr>>>,
+    relay_flow_map: Mutex<RelayFlowMap>,
+    relay_response_sender: tokio::sync::mpsc::UnboundedSender<(bytes::Bytes, SocketAddr)>,
     send_signal_sender: tokio::sync::mpsc::UnboundedSender<()>,
     handle: tokio::task::JoinHandle<()>,
 }
@@ -182,17 +207,22 @@ impl Association {
             Arc::new(tokio::net::UdpSocket::from_std(delegate_socket.into()).unwrap());
 
         let real_to_original_destination_map = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
-        let original_to_real_destination_map = Arc::new(tokio::sync::RwLock::new(HashMap::new()));
+        let original_to_real_destination_map =
+            Arc::new(tokio::sync::RwLock::new(OriginalToRealDestinationMap::new()));
 
         let (activity_signal_sender, mut activity_signal_receiver) =
             tokio::sync::mpsc::unbounded_channel();
 
+        let (relay_response_sender, mut relay_response_receiver) =
+            tokio::sync::mpsc::unbounded_channel::<(bytes::Bytes, SocketAddr)>();
+
         let related_original_destination_to_response_socket_map = Mutex::new(HashMap::new());
 
         let read_task = {
             let delegate_socket = delegate_socket.clone();
 
             let real_to_original_destination_map = real_to_original_destination_map.clone();
+            let original_to_real_destination_map = original_to_real_destination_map.clone();
 
             let receive_signal_sender = activity_signal_sender.clone();
 
@@ -200,7 +230,14 @@ impl Association {
                 let mut buffer = [0u8; UDP_BUFFER_SIZE];
 
                 loop {
-                    let (length, real_destination) = delegate_socket.recv_from(&mut buffer).await?;
+                    let (data, real_destination) = tokio::select! {
+                        result = delegate_socket.recv_from(&mut buffer) => {
+                            let (length, real_destination) = result?;
+
+                            (bytes::Bytes::copy_from_slice(&buffer[..length]), real_destination)
+                        }
+                        Some(response) = relay_response_receiver.recv() => response,
+                    };
 
                     let _ = receive_signal_sender.send(());
 
@@ -210,6 +247,20 @@ impl Association {
                         .get(&real_destination)
                         .unwrap_or(&real_destination);
 
+                    let within_limits = original_to_real_destination_map
+                        .read()
+                        .await
+                        .get(&original_destination)
+                        .is_none_or(|(_, _, limiters)| limiters.try_consume_download(data.len()));
+
+                    if !within_limits {
+                        log::debug!(
+                            "datagram from {original_destination} dropped beyond bandwidth limit."
+                        );
+
+                        continue;
+                    }
+
                     let response_socket = Self::assign_response_socket(
                         &mut original_destination_to_response_socket_map.lock().unwrap(),
                         &original_destination,
@@ -221,9 +272,7 @@ impl Association {
                         .unwrap()
                         .insert(original_destination, response_socket.clone());
 
-                    response_socket
-                        .send_to(&buffer[..length], source_address)
-                        .await?;
+                    response_socket.send_to(&data, source_address).await?;
                 }
 
                 #[allow(unreachable_code)]
@@ -256,6 +305,8 @@ impl Association {
             delegate_socket,
             real_to_original_destination_map,
             original_to_real_destination_map,
+            relay_flow_map: Mutex::new(RelayFlowMap::default()),
+            relay_response_sender,
             send_signal_sender: activity_signal_sender,
             handle,
         }
@@ -266,20 +317,50 @@ impl Association {
         buffer: &[u8],
         original_destination: &SocketAddr,
         real_destination: &SocketAddr,
+        relay_route: Option<UdpRelayRoute>,
+        limiters: BandwidthLimiters,
     ) -> anyhow::Result<()> {
         self.send_signal_sender.send(())?;
 
-        let mut real_to_original_destination_map =
-            self.real_to_original_destination_map.write().await;
-        let mut original_to_real_destination_map =
-            self.original_to_real_destination_map.write().await;
+        let within_limits = limiters.try_consume_upload(buffer.len());
 
-        real_to_original_destination_map.insert(*real_destination, *original_destination);
-        original_to_real_destination_map.insert(*original_destination, *real_destination);
+        {
+            let mut real_to_original_destination_map =
+                self.real_to_original_destination_map.write().await;
+            let mut original_to_real_destination_map =
+                self.original_to_real_destination_map.write().await;
+
+            real_to_original_destination_map.insert(*real_destination, *original_destination);
+            original_to_real_destination_map.insert(
+                *original_destination,
+                (*real_destination, relay_route.clone(), limiters),
+            );
+        }
 
-        self.delegate_socket
-            .send_to(buffer, real_destination)
-            .await?;
+        if !within_limits {
+            log::debug!("datagram to {real_destination} dropped beyond bandwidth limit.");
+
+            return Ok(());
+        }
+
+        match relay_route {
+            Some((tunnel_id, relay, tag)) => {
+                let flow_id = self.relay_flow_map.lock().unwrap().get_or_open(
+                    tunnel_id,
+                    &relay,
+                    tag,
+                    &self.relay_response_sender,
+                    Instant::now(),
+                );
+
+                relay.send(flow_id, *real_destination, buffer).await?;
+            }
+            None => {
+                self.delegate_socket
+                    .send_to(buffer, real_destination)
+                    .await?;
+            }
+        }
 
         Ok(())
     }
@@ -323,6 +404,67 @@ impl Association {
     }
 }
 
+/// Flows opened on tunnels relaying datagrams of an association, by tunnel and
+/// tag. Flows of closed tunnels, or not sent through for `UDP_FLOW_EXPIRATION`
+/// (e.g. the destination routed elsewhere since), are closed on the next send.
+#[derive(Default)]
+struct RelayFlowMap {
+    flows: HashMap<(TunnelId, Option<String>), RelayFlow>,
+}
+
+struct RelayFlow {
+    relay: Arc<dyn InUdpRelay>,
+    flow_id: UdpFlowId,
+    sent_at: Instant,
+}
+
+impl RelayFlowMap {
+    fn get_or_open(
+        &mut self,
+        tunnel_id: TunnelId,
+        relay: &Arc<dyn InUdpRelay>,
+        tag: Option<String>,
+        response_sender: &UdpFlowResponseSender,
+        now: Instant,
+    ) -> UdpFlowId {
+        self.close_expired(now);
+
+        let flow = self
+            .flows
+            .entry((tunnel_id, tag.clone()))
+            .or_insert_with(|| RelayFlow {
+                relay: relay.clone(),
+                flow_id: relay.open_flow(tag, response_sender.clone()),
+                sent_at: now,
+            });
+
+        flow.sent_at = now;
+
+        flow.flow_id
+    }
+
+    fn close_expired(&mut self, now: Instant) {
+        self.flows.retain(|_, flow| {
+            let expired = flow.relay.is_closed()
+                || now.saturating_duration_since(flow.sent_at) >= UDP_FLOW_EXPIRATION;
+
+            if expired {
+                flow.relay.close_flow(flow.flow_id);
+            }
+
+            !expired
+        });
+    }
+}
+
+impl Drop for RelayFlowMap {
+    fn drop(&mut self) {
+        for flow in self.flows.values() {
+            flow.relay.close_flow(flow.flow_id);
+        }
+    }
+}
+
 impl Drop for UdpForwarder {
     fn drop(&mut self) {
         let association_map = self.association_map.clone();
@@ -336,3 +478,151 @@ impl Drop for UdpForwarder {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeRelay {
+        opened: Mutex<Vec<(UdpFlowId, Option<String>)>>,
+        closed: Mutex<Vec<UdpFlowId>>,
+        is_closed: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl InUdpRelay for FakeRelay {
+        fn open_flow(
+            &self,
+            tag: Option<String>,
+            _response_sender: UdpFlowResponseSender,
+        ) -> UdpFlowId {
+            let mut opened = self.opened.lock().unwrap();
+
+            let flow_id = opened.len() as UdpFlowId;
+
+            opened.push((flow_id, tag));
+
+            flow_id
+        }
+
+        fn close_flow(&self, flow_id: UdpFlowId) {
+            self.closed.lock().unwrap().push(flow_id);
+        }
+
+        async fn send(
+            &self,
+            _flow_id: UdpFlowId,
+            _address: SocketAddr,
+            _data: &[u8],
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn is_closed(&self) -> bool {
+            self.is_closed.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn relay_flows_reused_by_tunnel_and_tag() {
+        let fake_relay = Arc::new(FakeRelay::default());
+        let relay: Arc<dyn InUdpRelay> = fake_relay.clone();
+        let (response_sender, _response_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let tunnel_id = TunnelId::new();
+        let now = Instant::now();
+
+        let mut flow_map = RelayFlowMap::default();
+
+        let untagged = flow_map.get_or_open(tunnel_id, &relay, None, &response_sender, now);
+        let tagged = flow_map.get_or_open(
+            tunnel_id,
+            &relay,
+            Some("proxy".to_owned()),
+            &response_sender,
+            now,
+        );
+
+        assert_ne!(untagged, tagged);
+        assert_eq!(
+            flow_map.get_or_open(tunnel_id, &relay, None, &response_sender, now),
+            untagged
+        );
+        assert_eq!(
+            *fake_relay.opened.lock().unwrap(),
+            [(untagged, None), (tagged, Some("proxy".to_owned()))]
+        );
+
+        drop(flow_map);
+
+        assert_eq!(fake_relay.closed.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn relay_flows_closed_once_expired() {
+        let fake_relay = Arc::new(FakeRelay::default());
+        let relay: Arc<dyn InUdpRelay> = fake_relay.clone();
+        let (response_sender, _response_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let tunnel_id = TunnelId::new();
+        let now = Instant::now();
+
+        let mut flow_map = RelayFlowMap::default();
+
+        let idle = flow_map.get_or_open(tunnel_id, &relay, None, &response_sender, now);
+        let active = flow_map.get_or_open(
+            tunnel_id,
+            &relay,
+            Some("proxy".to_owned()),
+            &response_sender,
+            now + UDP_FLOW_EXPIRATION / 2,
+        );
+
+        // Sending through the active flow closes the idle one.
+        flow_map.get_or_open(
+            tunnel_id,
+            &relay,
+            Some("proxy".to_owned()),
+            &response_sender,
+            now + UDP_FLOW_EXPIRATION,
+        );
+
+        assert_eq!(*fake_relay.closed.lock().unwrap(), [idle]);
+        assert_eq!(flow_map.flows.len(), 1);
+
+        let reopened = flow_map.get_or_open(
+            tunnel_id,
+            &relay,
+            None,
+            &response_sender,
+            now + UDP_FLOW_EXPIRATION,
+        );
+
+        assert_ne!(reopened, idle);
+        assert_ne!(reopened, active);
+    }
+
+    #[test]
+    fn relay_flows_of_closed_tunnels_closed() {
+        let fake_relay = Arc::new(FakeRelay::default());
+        let relay: Arc<dyn InUdpRelay> = fake_relay.clone();
+        let other_relay: Arc<dyn InUdpRelay> = Arc::new(FakeRelay::default());
+        let (response_sender, _response_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let now = Instant::now();
+
+        let mut flow_map = RelayFlowMap::default();
+
+        let flow_id = flow_map.get_or_open(TunnelId::new(), &relay, None, &response_sender, now);
+
+        fake_relay.is_closed.store(true, Ordering::Relaxed);
+
+        flow_map.get_or_open(TunnelId::new(), &other_relay, None, &response_sender, now);
+
+        assert_eq!(*fake_relay.closed.lock().unwrap(), [flow_id]);
+        assert_eq!(flow_map.flows.len(), 1);
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    route::{config::OutRuleConfig, rule::Label},
+    tunnel::TunnelProtocol,
+};
+
+use super::{
+    match_cipher::MatchCipher,
+    match_server::{
+        create_match_out, negotiate_match_out, InMatchServer, MatchIn, MatchOut,
+        OutMatchServerTrait,
+    },
+    MatchInId, MatchOutId, MatchPair, OutIdentity, OutLoad, OUT_LOAD_EXPIRATION,
+    OUT_LOAD_MATCH_NAME,
+};
+
+/// OUT stays registered this long after its last refresh, and IN waits this
+/// long for OUT to reply to its announcement before withdrawing the match.
+const MATCH_TIMEOUT: Duration = Duration::from_secs(5);
+const LONG_POLL_WAIT_SECONDS: u64 = 30;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(LONG_POLL_WAIT_SECONDS + 10);
+
+/// Backoff of OUT polling for IN while the server does not know it (`404`).
+const POLL_RETRY_INTERVAL_MIN: Duration = Duration::from_millis(500);
+const POLL_RETRY_INTERVAL_MAX: Duration = Duration::from_secs(10);
+
+/// Client of an HTTP(S) rendezvous API, for networks allowing only outbound
+/// HTTP(S). Paths are relative to the configured URL:
+///
+/// - `PUT {name}/outs/{out_id}?ttl=` registers or refreshes an OUT.
+/// - `GET {name}/outs?wait=&exclude=` lists OUT ids not excluded, waiting for
+///   one if there is none.
+/// - `POST {name}/outs/{out_id}/ins/{match_key}` announces an IN to an OUT,
+///   `404` if the OUT is gone.
+/// - `GET {name}/outs/{out_id}/ins?wait=` takes an IN announcement.
+/// - `PUT {name}/matches/{match_key}` posts the match result, `404` if IN
+///   withdrew the match.
+/// - `GET {name}/matches/{match_key}?wait=` takes the match result, `404` if
+///   the OUT expired before posting it.
+/// - `DELETE {name}/matches/{match_key}` withdraws a match, responding with
+///   the match result if posted already.
+/// - `PUT out-loads/{out_id}?ttl=` publishes the load of an OUT.
+/// - `GET out-loads/{out_id}` gets the load of an OUT, `404` if expired.
+///
+/// Long polls respond with `204` if nothing arrived in time. Bodies are opaque
+/// to the rendezvous server, encrypted with the key shared by IN and OUT.
+/// Requests carry the access key if any as a bearer token.
+///
+/// The match server serves this API with `http_listen`, see `serve_http`.
+pub struct HttpMatchClient {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl HttpMatchClient {
+    pub fn new(url: &str, access_key: Option<&str>) -> anyhow::Result<Self> {
+        let parsed_url = url::Url::parse(url)?;
+
+        anyhow::ensure!(
+            matches!(parsed_url.scheme(), "http" | "https"),
+            "unsupported match server url."
+        );
+
+        let mut headers = reqwest::header::HeaderMap::new();
+
+        if let Some(access_key) = access_key {
+            let mut authorization =
+                reqwest::header::HeaderValue::from_str(&format!("Bearer {access_key}"))?;
+
+            authorization.set_sensitive(true);
+
+            headers.insert(reqwest::header::AUTHORIZATION, authorization);
+        }
+
+        Ok(Self {
+            base_url: url.trim_end_matches('/').to_owned(),
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.base_url)
+    }
+
+    /// Long polls until the response has a body, `None` if not found.
+    async fn poll(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> anyhow::Result<Option<bytes::Bytes>> {
+        loop {
+            let response = self
+                .client
+                .get(self.url(path))
+                .query(&[("wait", LONG_POLL_WAIT_SECONDS.to_string())])
+                .query(query)
+                .send()
+                .await?;
+
+            match response.status() {
+                reqwest::StatusCode::NO_CONTENT => continue,
+                reqwest::StatusCode::NOT_FOUND => return Ok(None),
+                _ => return Ok(Some(response.error_for_status()?.bytes().await?)),
+            }
+        }
+    }
+
+    /// Deletes, returning the response body if any.
+    async fn take(&self, path: &str) -> anyhow::Result<Option<bytes::Bytes>> {
+        let response = self
+            .client
+            .delete(self.url(path))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        if response.status() == reqwest::StatusCode::OK {
+            Ok(Some(response.bytes().await?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+pub struct HttpInMatchServer {
+    id: MatchInId,
+    client: HttpMatchClient,
+    cipher: MatchCipher,
+    match_name_to_active_out_id_set_map:
+        tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<HashSet<MatchOutId>>>>>,
+}
+
+impl HttpInMatchServer {
+    pub fn new(client: HttpMatchClient, keys: Vec<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: MatchInId::new(),
+            client,
+            cipher: MatchCipher::new(keys)?,
+            match_name_to_active_out_id_set_map: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn get_active_out_id_set(
+        &self,
+        match_name: &str,
+    ) -> Arc<tokio::sync::Mutex<HashSet<MatchOutId>>> {
+        self.match_name_to_active_out_id_set_map
+            .lock()
+            .await
+            .entry(match_name.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl InMatchServer for HttpInMatchServer {
+    async fn accept_out<TInData, TOutData>(&self) -> anyhow::Result<MatchOutId>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        log::info!("accepting {match_name} OUT...");
+
+        let active_out_id_set = self.get_active_out_id_set(match_name).await;
+
+        loop {
+            let excluded = active_out_id_set
+                .lock()
+                .await
+                .iter()
+                .map(|out_id| ("exclude", out_id.to_string()))
+                .collect::<Vec<_>>();
+
+            let Some(out_ids) = self
+                .client
+                .poll(&format!("{match_name}/outs"), &excluded)
+                .await?
+            else {
+                anyhow::bail!("failed to list {match_name} OUT.");
+            };
+
+            let out_ids: Vec<MatchOutId> = serde_json::from_slice(&out_ids)?;
+
+            let mut active_out_id_set = active_out_id_set.lock().await;
+
+            // Another accepting task might have got the same OUT in the meantime.
+            if let Some(out_id) = out_ids
+                .into_iter()
+                .find(|out_id| !active_out_id_set.contains(out_id))
+            {
+                log::debug!("accepting OUT {match_name} {out_id}...");
+
+                active_out_id_set.insert(out_id);
+
+                return Ok(out_id);
+            }
+        }
+    }
+
+    async fn match_out<TInData, TOutData>(
+        &self,
+        out_id: MatchOutId,
+        in_data: TInData,
+    ) -> anyhow::Result<Option<MatchOut<TOutData>>>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        let match_key = uuid::Uuid::new_v4().to_string();
+
+        let announcement = InAnnouncement {
+            id: self.id,
+            match_key: match_key.clone(),
+            protocol: TunnelProtocol::current(),
+            data: serde_json::to_value(in_data)?,
+        };
+
+        log::debug!(
+            "announcing {match_name} IN {} with match key {match_key}...",
+            self.id
+        );
+
+        let response = self
+            .client
+            .client
+            .post(
+                self.client
+                    .url(&format!("{match_name}/outs/{out_id}/ins/{match_key}")),
+            )
+            .body(self.cipher.encrypt(
+                match_name,
+                out_id,
+                serde_json::to_string(&announcement)?.as_bytes(),
+            ))
+            .send()
+            .await?;
+
+        let payload = if response.status() == reqwest::StatusCode::NOT_FOUND {
+            None
+        } else {
+            response.error_for_status()?;
+
+            let match_path = format!("{match_name}/matches/{match_key}");
+
+            // OUT not replying in time is taken as gone, as it might have stopped
+            // matching without the server telling. The match is withdrawn for OUT
+            // not to set up a tunnel nobody takes, unless OUT replied meanwhile.
+            match tokio::time::timeout(MATCH_TIMEOUT, self.client.poll(&match_path, &[])).await {
+                Ok(payload) => payload?,
+                Err(_) => self.client.take(&match_path).await?,
+            }
+        };
+
+        let Some(payload) = payload else {
+            self.get_active_out_id_set(match_name)
+                .await
+                .lock()
+                .await
+                .remove(&out_id);
+
+            log::info!("{match_name} OUT {out_id} no longer active.");
+
+            return Ok(None);
+        };
+
+        // Data is parsed after checking the protocol, as an incompatible OUT might
+        // send data in another format.
+        let match_out: MatchOut<serde_json::Value> =
+            serde_json::from_slice(&self.cipher.decrypt(match_name, out_id, &payload)?)?;
+
+        negotiate_match_out(match_name, match_out)
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        let response = self
+            .client
+            .client
+            .get(self.client.url(&format!("out-loads/{out_id}")))
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let load = response.error_for_status()?.bytes().await?;
+
+        Ok(Some(serde_json::from_slice(&self.cipher.decrypt_stored(
+            OUT_LOAD_MATCH_NAME,
+            out_id,
+            &load,
+        )?)?))
+    }
+}
+
+pub struct HttpOutMatchServer {
+    id: MatchOutId,
+    identity: OutIdentity,
+    labels: Vec<Label>,
+    bandwidth_limit: BandwidthLimit,
+    cipher: MatchCipher,
+    client: HttpMatchClient,
+}
+
+impl HttpOutMatchServer {
+    pub fn new(
+        client: HttpMatchClient,
+        keys: Vec<String>,
+        identity: OutIdentity,
+        labels: Vec<Label>,
+        bandwidth_limit: BandwidthLimit,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: identity.id(),
+            identity,
+            labels,
+            bandwidth_limit,
+            cipher: MatchCipher::new(keys)?,
+            client,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl OutMatchServerTrait for HttpOutMatchServer {
+    async fn match_in<TInData, TOutData>(
+        &self,
+        out_data: TOutData,
+        out_priority: Option<i64>,
+        out_routing_rules: &[OutRuleConfig],
+        out_routing_priority: i64,
+    ) -> anyhow::Result<MatchIn<TInData>>
+    where
+        TInData: serde::de::DeserializeOwned + Send,
+        TOutData: serde::Serialize + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        // Serialized upfront, as it is posted again to every incompatible IN.
+        let out_data = serde_json::to_value(out_data)?;
+
+        let announce_task = async {
+            loop {
+                self.client
+                    .client
+                    .put(self.client.url(&format!("{match_name}/outs/{}", self.id)))
+                    .query(&[("ttl", MATCH_TIMEOUT.as_secs())])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        };
+
+        let match_task = async {
+            let mut poll_retry_interval = POLL_RETRY_INTERVAL_MIN;
+
+            loop {
+                let Some(announcement) = self
+                    .client
+                    .poll(&format!("{match_name}/outs/{}/ins", self.id), &[])
+                    .await?
+                else {
+                    // Not registered yet, or expired in between.
+                    tokio::time::sleep(poll_retry_interval).await;
+
+                    poll_retry_interval = (poll_retry_interval * 2).min(POLL_RETRY_INTERVAL_MAX);
+
+                    continue;
+                };
+
+                poll_retry_interval = POLL_RETRY_INTERVAL_MIN;
+
+                let announcement = self
+                    .cipher
+                    .decrypt(match_name, self.id, &announcement)
+                    .and_then(|announcement| {
+                        Ok(serde_json::from_slice::<InAnnouncement>(&announcement)?)
+                    });
+
+                let InAnnouncement {
+                    id,
+                    match_key,
+                    protocol,
+                    data,
+                } = match announcement {
+                    Ok(announcement) => announcement,
+                    Err(error) => {
+                        log::warn!("invalid {match_name} IN announcement: {error}");
+
+                        continue;
+                    }
+                };
+
+                let negotiation = TunnelProtocol::current().negotiate(&protocol);
+
+                let in_data = match negotiation {
+                    Ok(_) => Some(serde_json::from_value::<TInData>(data)?),
+                    Err(_) => None,
+                };
+
+                let match_out = create_match_out(
+                    &self.identity,
+                    &self.labels,
+                    out_priority,
+                    out_routing_rules,
+                    out_routing_priority,
+                    self.bandwidth_limit,
+                    &out_data,
+                );
+                let tunnel_id = match_out.tunnel_id;
+
+                log::debug!("matching IN {match_key}...");
+
+                let response = self
+                    .client
+                    .client
+                    .put(
+                        self.client
+                            .url(&format!("{match_name}/matches/{match_key}")),
+                    )
+                    .body(self.cipher.encrypt(
+                        match_name,
+                        self.id,
+                        serde_json::to_string(&match_out)?.as_bytes(),
+                    ))
+                    .send()
+                    .await?;
+
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    log::debug!("IN {match_name} {id} withdrew the match.");
+
+                    continue;
+                }
+
+                response.error_for_status()?;
+
+                let Some(in_data) = in_data else {
+                    log::warn!("refused IN {match_name} {id}: {}", negotiation.unwrap_err());
+
+                    continue;
+                };
+
+                log::info!("matched IN {match_name} {id} as tunnel {tunnel_id}.");
+
+                return Ok(MatchIn {
+                    id,
+                    tunnel_id,
+                    protocol,
+                    data: in_data,
+                });
+            }
+        };
+
+        tokio::select! {
+            match_in = match_task => match_in,
+            result = announce_task => result,
+        }
+    }
+
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()> {
+        self.client
+            .client
+            .put(self.client.url(&format!("out-loads/{}", self.id)))
+            .query(&[("ttl", OUT_LOAD_EXPIRATION.as_secs())])
+            .body(self.cipher.encrypt(
+                OUT_LOAD_MATCH_NAME,
+                self.id,
+                serde_json::to_string(&load)?.as_bytes(),
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct InAnnouncement {
+    id: MatchInId,
+    match_key: String,
+    protocol: TunnelProtocol,
+    data: serde_json::Value,
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    AeadCore, KeyInit as _,
+};
+use itertools::Itertools as _;
+
+use super::MatchOutId;
+
+const HKDF_SALT: &[u8] = b"plug2proxy match";
+
+const NONCE_LENGTH: usize = 12;
+const TIMESTAMP_LENGTH: usize = 8;
+
+/// Messages timestamped further than this from now (either way) are rejected,
+/// which also bounds how long nonces are remembered against replays.
+const FRESHNESS_WINDOW_SECONDS: u64 = 60;
+
+/// Encrypts match data exchanged between IN and OUT through a match server.
+///
+/// Each message is encrypted with a key derived (HKDF-SHA256) from the shared
+/// key and bound to the match name and OUT id, carrying a timestamp and a
+/// nonce checked for freshness. Messages are encrypted with the first key and
+/// decrypted with any of them, so keys can be rotated by adding the new key
+/// after the old one on all ends, then moving it first, then removing the old
+/// one.
+pub struct MatchCipher {
+    keys: Vec<ring::hkdf::Prk>,
+    seen_nonces: Mutex<HashMap<[u8; NONCE_LENGTH], u64>>,
+}
+
+impl MatchCipher {
+    pub fn new(keys: Vec<String>) -> anyhow::Result<Self> {
+        anyhow::ensure!(!keys.is_empty(), "match server key is required.");
+
+        anyhow::ensure!(
+            keys.iter().all(|key| !key.is_empty()),
+            "match server key must not be empty."
+        );
+
+        let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, HKDF_SALT);
+
+        Ok(Self {
+            keys: keys
+                .iter()
+                .map(|key| salt.extract(key.as_bytes()))
+                .collect(),
+            seen_nonces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn encrypt(&self, match_name: &str, out_id: MatchOutId, data: &[u8]) -> Vec<u8> {
+        self.encrypt_at(get_timestamp(), match_name, out_id, data)
+    }
+
+    fn encrypt_at(
+        &self,
+        timestamp: u64,
+        match_name: &str,
+        out_id: MatchOutId,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let context = get_context(match_name, out_id);
+
+        let cipher = derive_cipher(&self.keys[0], &context);
+
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
+
+        let plaintext = timestamp
+            .to_be_bytes()
+            .into_iter()
+            .chain(data.iter().copied())
+            .collect_vec();
+
+        let data = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .unwrap();
+
+        nonce.iter().copied().chain(data).collect_vec()
+    }
+
+    /// Decrypts a message delivered once, rejecting replays.
+    pub fn decrypt(
+        &self,
+        match_name: &str,
+        out_id: MatchOutId,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_with_replay_check(match_name, out_id, data, true)
+    }
+
+    /// Decrypts a value stored on the match server and read repeatedly until
+    /// replaced (e.g. OUT load), only checked for freshness.
+    pub fn decrypt_stored(
+        &self,
+        match_name: &str,
+        out_id: MatchOutId,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_with_replay_check(match_name, out_id, data, false)
+    }
+
+    fn decrypt_with_replay_check(
+        &self,
+        match_name: &str,
+        out_id: MatchOutId,
+        data: &[u8],
+        replay_check: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let context = get_context(match_name, out_id);
+
+        let Some((nonce, data)) = data.split_at_checked(NONCE_LENGTH) else {
+            anyhow::bail!("match decryption failed, data too short.");
+        };
+
+        let nonce = aes_gcm::Nonce::from_slice(nonce);
+
+        let plaintext = self
+            .keys
+            .iter()
+            .find_map(|key| {
+                derive_cipher(key, &context)
+                    .decrypt(
+                        nonce,
+                        Payload {
+                            msg: data,
+                            aad: context.as_bytes(),
+                        },
+                    )
+                    .ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("match decryption failed, wrong key?"))?;
+
+        let Some((timestamp, data)) = plaintext.split_at_checked(TIMESTAMP_LENGTH) else {
+            anyhow::bail!("match decryption failed, timestamp missing.");
+        };
+
+        let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+
+        let now = get_timestamp();
+
+        anyhow::ensure!(
+            timestamp.abs_diff(now) <= FRESHNESS_WINDOW_SECONDS,
+            "match data not fresh, clock skewed?"
+        );
+
+        if replay_check {
+            let mut seen_nonces = self.seen_nonces.lock().unwrap();
+
+            seen_nonces
+                .retain(|_, &mut timestamp| timestamp.abs_diff(now) <= FRESHNESS_WINDOW_SECONDS);
+
+            anyhow::ensure!(
+                seen_nonces.insert((*nonce).into(), timestamp).is_none(),
+                "match data replayed."
+            );
+        }
+
+        Ok(data.to_vec())
+    }
+}
+
+fn get_context(match_name: &str, out_id: MatchOutId) -> String {
+    format!("{match_name}:{out_id}")
+}
+
+fn derive_cipher(key: &ring::hkdf::Prk, context: &str) -> aes_gcm::Aes256Gcm {
+    let mut derived_key = [0; 32];
+
+    key.expand(&[context.as_bytes()], AesKeyLength)
+        .and_then(|okm| okm.fill(&mut derived_key))
+        .unwrap();
+
+    aes_gcm::Aes256Gcm::new(aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&derived_key))
+}
+
+fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+struct AesKeyLength;
+
+impl ring::hkdf::KeyType for AesKeyLength {
+    fn len(&self) -> usize {
+        32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "test";
+
+    #[test]
+    fn decrypts_with_any_key() -> anyhow::Result<()> {
+        let out_id = MatchOutId::new();
+
+        let old_cipher = MatchCipher::new(vec!["old".to_owned()])?;
+        let new_cipher = MatchCipher::new(vec!["new".to_owned(), "old".to_owned()])?;
+
+        let data = old_cipher.encrypt(NAME, out_id, b"old");
+
+        assert_eq!(new_cipher.decrypt(NAME, out_id, &data)?, b"old");
+
+        let data = new_cipher.encrypt(NAME, out_id, b"new");
+
+        assert!(old_cipher.decrypt(NAME, out_id, &data).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_context() -> anyhow::Result<()> {
+        let out_id = MatchOutId::new();
+
+        let cipher = MatchCipher::new(vec!["key".to_owned()])?;
+
+        let data = cipher.encrypt(NAME, out_id, b"data");
+
+        assert!(cipher.decrypt("other", out_id, &data).is_err());
+        assert!(cipher.decrypt(NAME, MatchOutId::new(), &data).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_replays() -> anyhow::Result<()> {
+        let out_id = MatchOutId::new();
+
+        let cipher = MatchCipher::new(vec!["key".to_owned()])?;
+
+        let data = cipher.encrypt(NAME, out_id, b"data");
+
+        assert_eq!(cipher.decrypt(NAME, out_id, &data)?, b"data");
+        assert!(cipher.decrypt(NAME, out_id, &data).is_err());
+
+        // Stored values are read repeatedly.
+        let data = cipher.encrypt(NAME, out_id, b"stored");
+
+        assert_eq!(cipher.decrypt_stored(NAME, out_id, &data)?, b"stored");
+        assert_eq!(cipher.decrypt_stored(NAME, out_id, &data)?, b"stored");
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_stale_data() -> anyhow::Result<()> {
+        let out_id = MatchOutId::new();
+
+        let cipher = MatchCipher::new(vec!["key".to_owned()])?;
+
+        let now = get_timestamp();
+
+        for timestamp in [
+            now - FRESHNESS_WINDOW_SECONDS - 10,
+            now + FRESHNESS_WINDOW_SECONDS + 10,
+        ] {
+            let data = cipher.encrypt_at(timestamp, NAME, out_id, b"data");
+
+            assert!(cipher.decrypt_stored(NAME, out_id, &data).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn requires_key() {
+        assert!(MatchCipher::new(Vec::new()).is_err());
+        assert!(MatchCipher::new(vec![String::new()]).is_err());
+    }
+}
@@ -1,10 +1,13 @@
 use std::str::FromStr;
 
 use crate::{
+    bandwidth::BandwidthLimit,
     route::{config::OutRuleConfig, rule::Label},
-    tunnel::TunnelId,
+    tunnel::{TunnelId, TunnelProtocol},
 };
 
+use super::{MatchOutIdentity, OutIdentity, OutLoad};
+
 #[async_trait::async_trait]
 pub trait InMatchServer {
     async fn accept_out<TInData, TOutData>(&self) -> anyhow::Result<MatchOutId>
@@ -22,6 +25,9 @@ pub trait InMatchServer {
         TInData: serde::Serialize + Send,
         TOutData: serde::de::DeserializeOwned + Send,
         (TInData, TOutData): MatchPair<TInData, TOutData>;
+
+    /// Latest load published by OUT, `None` if not published or expired.
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>>;
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -32,9 +38,103 @@ pub struct MatchOut<TData> {
     pub tunnel_priority: Option<i64>,
     pub routing_priority: i64,
     pub routing_rules: Vec<OutRuleConfig>,
+    /// Limit of all traffic through the OUT, absent for OUT of earlier versions.
+    #[serde(default)]
+    pub bandwidth_limit: BandwidthLimit,
+    #[serde(default = "TunnelProtocol::legacy")]
+    pub protocol: TunnelProtocol,
+    /// Signed identity of OUT, absent for OUT of earlier versions and static
+    /// peering. Verified by `negotiate_match_out` on IN.
+    #[serde(default)]
+    pub identity: Option<MatchOutIdentity>,
     pub data: TData,
 }
 
+impl<TData> MatchOut<TData> {
+    pub fn try_map_data<TMappedData, TError>(
+        self,
+        map: impl FnOnce(TData) -> Result<TMappedData, TError>,
+    ) -> Result<MatchOut<TMappedData>, TError> {
+        Ok(MatchOut {
+            id: self.id,
+            tunnel_id: self.tunnel_id,
+            tunnel_labels: self.tunnel_labels,
+            tunnel_priority: self.tunnel_priority,
+            routing_priority: self.routing_priority,
+            routing_rules: self.routing_rules,
+            bandwidth_limit: self.bandwidth_limit,
+            protocol: self.protocol,
+            identity: self.identity,
+            data: map(self.data)?,
+        })
+    }
+}
+
+/// Negotiates the protocol with a matched OUT, verifies its identity and parses
+/// its data, `None` if the OUT is refused.
+pub(super) fn negotiate_match_out<TOutData>(
+    match_name: &str,
+    match_out: MatchOut<serde_json::Value>,
+) -> anyhow::Result<Option<MatchOut<TOutData>>>
+where
+    TOutData: serde::de::DeserializeOwned,
+{
+    match TunnelProtocol::current().negotiate(&match_out.protocol) {
+        Ok(protocol) => {
+            log::debug!(
+                "negotiated protocol {protocol} with {match_name} OUT {}.",
+                match_out.id
+            );
+        }
+        Err(error) => {
+            // The OUT is kept in the active set, so that it is not accepted again.
+            log::error!("refused {match_name} OUT {}: {error}", match_out.id);
+
+            return Ok(None);
+        }
+    }
+
+    if let Some(identity) = &match_out.identity {
+        identity.verify(&match_out)?;
+    }
+
+    let match_out = match_out.try_map_data(serde_json::from_value)?;
+
+    log::info!(
+        "matched {match_name} OUT {} as tunnel {}.",
+        match_out.id,
+        match_out.tunnel_id
+    );
+
+    Ok(Some(match_out))
+}
+
+/// Creates the reply of OUT to a matched IN for a new tunnel, signed with the
+/// OUT identity. Incompatible IN are replied to as well, for them to report the
+/// refusal.
+pub(crate) fn create_match_out(
+    identity: &OutIdentity,
+    tunnel_labels: &[Label],
+    tunnel_priority: Option<i64>,
+    routing_rules: &[OutRuleConfig],
+    routing_priority: i64,
+    bandwidth_limit: BandwidthLimit,
+    data: &serde_json::Value,
+) -> MatchOut<serde_json::Value> {
+    identity.sign(MatchOut {
+        id: identity.id(),
+        tunnel_id: TunnelId::new(),
+        tunnel_labels: tunnel_labels.to_vec(),
+        tunnel_priority,
+        routing_priority,
+        routing_rules: routing_rules.to_vec(),
+        bandwidth_limit,
+        protocol: TunnelProtocol::current(),
+        identity: None,
+        data: data.clone(),
+    })
+}
+
 #[async_trait::async_trait]
 pub trait OutMatchServerTrait: Send {
     async fn match_in<TInData, TOutData>(
@@ -48,12 +148,16 @@ pub trait OutMatchServerTrait: Send {
         TInData: serde::de::DeserializeOwned + Send,
         TOutData: serde::Serialize + Send,
         (TInData, TOutData): MatchPair<TInData, TOutData>;
+
+    /// Publishes the load of OUT for IN, expiring after `OUT_LOAD_EXPIRATION`.
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()>;
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct MatchIn<TData> {
     pub id: MatchInId,
     pub tunnel_id: TunnelId,
+    pub protocol: TunnelProtocol,
     pub data: TData,
 }
 
@@ -110,8 +214,6 @@ impl FromStr for MatchOutId {
 
 pub trait MatchPair<TInData, TOutData> {
     fn get_match_name() -> &'static str;
-    fn get_redis_out_pattern() -> &'static str;
-    fn get_out_id_from_redis_out_key(out_key: &str) -> anyhow::Result<MatchOutId>;
     fn get_redis_out_key(out_id: &MatchOutId) -> String;
     fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String;
 }
@@ -1,13 +1,19 @@
 use crate::route::config::OutRuleConfig;
 
 use super::{
+    http_match_server::{HttpInMatchServer, HttpOutMatchServer},
+    memory_match_server::{MemoryInMatchServer, MemoryOutMatchServer},
+    p2p_match_server::{P2pInMatchServer, P2pOutMatchServer},
     redis_match_server::{RedisInMatchServer, RedisOutMatchServer},
-    InMatchServer, MatchIn, MatchOut, MatchOutId, MatchPair, OutMatchServerTrait,
+    InMatchServer, MatchIn, MatchOut, MatchOutId, MatchPair, OutLoad, OutMatchServerTrait,
 };
 
 #[derive(derive_more::From)]
 pub enum AnyInMatchServer {
     Redis(RedisInMatchServer),
+    P2p(P2pInMatchServer),
+    Http(HttpInMatchServer),
+    Memory(MemoryInMatchServer),
 }
 
 #[async_trait::async_trait]
@@ -20,6 +26,9 @@ impl InMatchServer for AnyInMatchServer {
     {
         match self {
             Self::Redis(redis) => redis.accept_out::<TInData, TOutData>().await,
+            Self::P2p(p2p) => p2p.accept_out::<TInData, TOutData>().await,
+            Self::Http(http) => http.accept_out::<TInData, TOutData>().await,
+            Self::Memory(memory) => memory.accept_out::<TInData, TOutData>().await,
         }
     }
 
@@ -35,13 +44,29 @@ impl InMatchServer for AnyInMatchServer {
     {
         match self {
             Self::Redis(redis) => redis.match_out(out_id, in_data).await,
+            Self::P2p(p2p) => p2p.match_out(out_id, in_data).await,
+            Self::Http(http) => http.match_out(out_id, in_data).await,
+            Self::Memory(memory) => memory.match_out(out_id, in_data).await,
+        }
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        match self {
+            Self::Redis(redis) => redis.get_out_load(out_id).await,
+            Self::P2p(p2p) => p2p.get_out_load(out_id).await,
+            Self::Http(http) => http.get_out_load(out_id).await,
+            Self::Memory(memory) => memory.get_out_load(out_id).await,
         }
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(derive_more::From)]
 pub enum OutMatchServer {
     Redis(RedisOutMatchServer),
+    P2p(P2pOutMatchServer),
+    Http(HttpOutMatchServer),
+    Memory(MemoryOutMatchServer),
 }
 
 #[async_trait::async_trait]
@@ -69,6 +94,43 @@ impl OutMatchServerTrait for OutMatchServer {
                     )
                     .await
             }
+            Self::P2p(p2p) => {
+                p2p.match_in(
+                    out_data,
+                    out_priority,
+                    out_routing_rules,
+                    out_routing_priority,
+                )
+                .await
+            }
+            Self::Http(http) => {
+                http.match_in(
+                    out_data,
+                    out_priority,
+                    out_routing_rules,
+                    out_routing_priority,
+                )
+                .await
+            }
+            Self::Memory(memory) => {
+                memory
+                    .match_in(
+                        out_data,
+                        out_priority,
+                        out_routing_rules,
+                        out_routing_priority,
+                    )
+                    .await
+            }
+        }
+    }
+
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()> {
+        match self {
+            Self::Redis(redis) => redis.publish_load(load).await,
+            Self::P2p(p2p) => p2p.publish_load(load).await,
+            Self::Http(http) => http.publish_load(load).await,
+            Self::Memory(memory) => memory.publish_load(load).await,
         }
     }
 }
@@ -0,0 +1,454 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+    time::Instant,
+};
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    route::{config::OutRuleConfig, rule::Label},
+    tunnel::TunnelProtocol,
+};
+
+use super::{
+    match_server::{
+        create_match_out, negotiate_match_out, InMatchServer, MatchIn, MatchOut,
+        OutMatchServerTrait,
+    },
+    MatchInId, MatchOutId, MatchPair, OutIdentity, OutLoad, OUT_LOAD_EXPIRATION,
+};
+
+/// Match server within the process, for IN and OUT running in the same process
+/// (e.g. the all-in-one mode, or tests). IN and OUT match through channels, and
+/// data is not encrypted as it never leaves the process.
+#[derive(Clone, Default)]
+pub struct MemoryMatchHub {
+    state: Arc<Mutex<MemoryMatchHubState>>,
+    /// Notified whenever OUT starts waiting for IN.
+    out_waiting: Arc<tokio::sync::Notify>,
+}
+
+#[derive(Default)]
+struct MemoryMatchHubState {
+    /// Senders of IN requests to OUT, closed once OUT is dropped.
+    match_name_to_out_map: HashMap<String, HashMap<MatchOutId, InRequestSender>>,
+    out_load_map: HashMap<MatchOutId, (OutLoad, Instant)>,
+}
+
+type InRequestSender = tokio::sync::mpsc::UnboundedSender<InRequest>;
+type InRequestReceiver = tokio::sync::mpsc::UnboundedReceiver<InRequest>;
+
+struct InRequest {
+    id: MatchInId,
+    protocol: TunnelProtocol,
+    data: serde_json::Value,
+    match_out_sender: tokio::sync::oneshot::Sender<MatchOut<serde_json::Value>>,
+}
+
+impl MemoryMatchHub {
+    /// Hub of its own, e.g. for tests running several setups in one process.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hub shared by IN and OUT configured with the memory match server.
+    pub fn global() -> Self {
+        static HUB: OnceLock<MemoryMatchHub> = OnceLock::new();
+
+        HUB.get_or_init(Self::new).clone()
+    }
+}
+
+pub struct MemoryInMatchServer {
+    id: MatchInId,
+    hub: MemoryMatchHub,
+    match_name_to_active_out_id_set_map: Mutex<HashMap<String, HashSet<MatchOutId>>>,
+}
+
+impl MemoryInMatchServer {
+    pub fn new(hub: MemoryMatchHub) -> Self {
+        Self {
+            id: MatchInId::new(),
+            hub,
+            match_name_to_active_out_id_set_map: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl InMatchServer for MemoryInMatchServer {
+    async fn accept_out<TInData, TOutData>(&self) -> anyhow::Result<MatchOutId>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        log::info!("accepting {match_name} OUT...");
+
+        loop {
+            let out_waiting = self.hub.out_waiting.notified();
+
+            tokio::pin!(out_waiting);
+
+            // Enabled before looking for OUT, so that OUT waiting in between is
+            // notified.
+            out_waiting.as_mut().enable();
+
+            {
+                let state = self.hub.state.lock().unwrap();
+
+                let mut active_out_id_set_map =
+                    self.match_name_to_active_out_id_set_map.lock().unwrap();
+
+                let active_out_id_set = active_out_id_set_map
+                    .entry(match_name.to_owned())
+                    .or_default();
+
+                let out_id = state
+                    .match_name_to_out_map
+                    .get(match_name)
+                    .into_iter()
+                    .flatten()
+                    .find(|(out_id, sender)| {
+                        !sender.is_closed() && !active_out_id_set.contains(out_id)
+                    })
+                    .map(|(&out_id, _)| out_id);
+
+                if let Some(out_id) = out_id {
+                    log::debug!("accepting OUT {match_name} {out_id}...");
+
+                    active_out_id_set.insert(out_id);
+
+                    return Ok(out_id);
+                }
+            }
+
+            out_waiting.await;
+        }
+    }
+
+    async fn match_out<TInData, TOutData>(
+        &self,
+        out_id: MatchOutId,
+        in_data: TInData,
+    ) -> anyhow::Result<Option<MatchOut<TOutData>>>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        let sender = self
+            .hub
+            .state
+            .lock()
+            .unwrap()
+            .match_name_to_out_map
+            .get(match_name)
+            .and_then(|out_map| out_map.get(&out_id))
+            .cloned();
+
+        let (match_out_sender, match_out_receiver) = tokio::sync::oneshot::channel();
+
+        let request = InRequest {
+            id: self.id,
+            protocol: TunnelProtocol::current(),
+            data: serde_json::to_value(in_data)?,
+            match_out_sender,
+        };
+
+        if sender.is_none_or(|sender| sender.send(request).is_err()) {
+            self.match_name_to_active_out_id_set_map
+                .lock()
+                .unwrap()
+                .entry(match_name.to_owned())
+                .or_default()
+                .remove(&out_id);
+
+            log::info!("{match_name} OUT {out_id} no longer active.");
+
+            return Ok(None);
+        }
+
+        let match_out = match_out_receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("OUT {out_id} gone before match."))?;
+
+        negotiate_match_out(match_name, match_out)
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        Ok(self
+            .hub
+            .state
+            .lock()
+            .unwrap()
+            .out_load_map
+            .get(&out_id)
+            .filter(|(_, published_at)| published_at.elapsed() < OUT_LOAD_EXPIRATION)
+            .map(|(load, _)| load.clone()))
+    }
+}
+
+pub struct MemoryOutMatchServer {
+    id: MatchOutId,
+    identity: OutIdentity,
+    labels: Vec<Label>,
+    bandwidth_limit: BandwidthLimit,
+    hub: MemoryMatchHub,
+    /// Receivers of IN requests, registered to the hub on first match.
+    match_name_to_receiver_map:
+        tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<InRequestReceiver>>>>,
+}
+
+impl MemoryOutMatchServer {
+    pub fn new(
+        hub: MemoryMatchHub,
+        identity: OutIdentity,
+        labels: Vec<Label>,
+        bandwidth_limit: BandwidthLimit,
+    ) -> Self {
+        Self {
+            id: identity.id(),
+            identity,
+            labels,
+            bandwidth_limit,
+            hub,
+            match_name_to_receiver_map: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutMatchServerTrait for MemoryOutMatchServer {
+    async fn match_in<TInData, TOutData>(
+        &self,
+        out_data: TOutData,
+        out_priority: Option<i64>,
+        out_routing_rules: &[OutRuleConfig],
+        out_routing_priority: i64,
+    ) -> anyhow::Result<MatchIn<TInData>>
+    where
+        TInData: serde::de::DeserializeOwned + Send,
+        TOutData: serde::Serialize + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        let receiver = self
+            .match_name_to_receiver_map
+            .lock()
+            .await
+            .entry(match_name.to_owned())
+            .or_insert_with(|| {
+                let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+                self.hub
+                    .state
+                    .lock()
+                    .unwrap()
+                    .match_name_to_out_map
+                    .entry(match_name.to_owned())
+                    .or_default()
+                    .insert(self.id, sender);
+
+                Arc::new(tokio::sync::Mutex::new(receiver))
+            })
+            .clone();
+
+        // Matches of the same name take IN requests one after another.
+        let mut receiver = receiver.lock().await;
+
+        self.hub.out_waiting.notify_waiters();
+
+        let out_data = serde_json::to_value(out_data)?;
+
+        while let Some(InRequest {
+            id,
+            protocol,
+            data,
+            match_out_sender,
+        }) = receiver.recv().await
+        {
+            let negotiation = TunnelProtocol::current().negotiate(&protocol);
+
+            let in_data = match negotiation {
+                Ok(_) => Some(serde_json::from_value::<TInData>(data)?),
+                Err(_) => None,
+            };
+
+            let match_out = create_match_out(
+                &self.identity,
+                &self.labels,
+                out_priority,
+                out_routing_rules,
+                out_routing_priority,
+                self.bandwidth_limit,
+                &out_data,
+            );
+            let tunnel_id = match_out.tunnel_id;
+
+            if match_out_sender.send(match_out).is_err() {
+                log::debug!("IN {match_name} {id} gone before match.");
+
+                continue;
+            }
+
+            let Some(in_data) = in_data else {
+                log::warn!("refused IN {match_name} {id}: {}", negotiation.unwrap_err());
+
+                continue;
+            };
+
+            log::info!("matched IN {match_name} {id} as tunnel {tunnel_id}.");
+
+            return Ok(MatchIn {
+                id,
+                tunnel_id,
+                protocol,
+                data: in_data,
+            });
+        }
+
+        anyhow::bail!("IN request channel closed.");
+    }
+
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()> {
+        let out_load_map = &mut self.hub.state.lock().unwrap().out_load_map;
+
+        out_load_map.retain(|_, (_, published_at)| published_at.elapsed() < OUT_LOAD_EXPIRATION);
+
+        out_load_map.insert(self.id, (load, Instant::now()));
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryOutMatchServer {
+    fn drop(&mut self) {
+        // Closed first, so that senders of another OUT with the same id (e.g.
+        // restarted with a persistent identity) are kept.
+        for receiver in self.match_name_to_receiver_map.get_mut().values() {
+            if let Ok(mut receiver) = receiver.try_lock() {
+                receiver.close();
+            }
+        }
+
+        for out_map in self
+            .hub
+            .state
+            .lock()
+            .unwrap()
+            .match_name_to_out_map
+            .values_mut()
+        {
+            out_map.retain(|_, sender| !sender.is_closed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::match_server::testing::{TestInData, TestOutData};
+
+    use super::*;
+
+    fn new_out_match_server(hub: &MemoryMatchHub) -> anyhow::Result<MemoryOutMatchServer> {
+        Ok(MemoryOutMatchServer::new(
+            hub.clone(),
+            OutIdentity::load_or_generate(None)?,
+            Vec::new(),
+            BandwidthLimit::default(),
+        ))
+    }
+
+    async fn match_in_and_out(
+        in_match_server: &MemoryInMatchServer,
+        out_match_server: &MemoryOutMatchServer,
+    ) -> anyhow::Result<(MatchIn<TestInData>, MatchOut<TestOutData>)> {
+        let out_task = out_match_server.match_in::<TestInData, TestOutData>(
+            TestOutData {
+                name: "out".to_owned(),
+            },
+            None,
+            &[],
+            0,
+        );
+
+        let in_task = async {
+            let out_id = in_match_server
+                .accept_out::<TestInData, TestOutData>()
+                .await?;
+
+            in_match_server
+                .match_out::<TestInData, TestOutData>(
+                    out_id,
+                    TestInData {
+                        name: "in".to_owned(),
+                    },
+                )
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("OUT gone."))
+        };
+
+        tokio::try_join!(out_task, in_task)
+    }
+
+    #[tokio::test]
+    async fn matches_in_and_out() -> anyhow::Result<()> {
+        let hub = MemoryMatchHub::new();
+
+        let in_match_server = MemoryInMatchServer::new(hub.clone());
+        let out_match_server = new_out_match_server(&hub)?;
+
+        let (match_in, match_out) = match_in_and_out(&in_match_server, &out_match_server).await?;
+
+        assert_eq!(match_in.data.name, "in");
+        assert_eq!(match_out.data.name, "out");
+        assert!(match_in.tunnel_id == match_out.tunnel_id);
+
+        let load = OutLoad {
+            tunnels: 1,
+            ..OutLoad::default()
+        };
+
+        out_match_server.publish_load(load.clone()).await?;
+
+        assert_eq!(
+            in_match_server.get_out_load(match_out.id).await?,
+            Some(load)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forgets_dropped_out() -> anyhow::Result<()> {
+        let hub = MemoryMatchHub::new();
+
+        let in_match_server = MemoryInMatchServer::new(hub.clone());
+        let out_match_server = new_out_match_server(&hub)?;
+
+        let (_, match_out) = match_in_and_out(&in_match_server, &out_match_server).await?;
+
+        drop(out_match_server);
+
+        assert!(hub.state.lock().unwrap().match_name_to_out_map["test"].is_empty());
+
+        let match_out = in_match_server
+            .match_out::<TestInData, TestOutData>(
+                match_out.id,
+                TestInData {
+                    name: "in".to_owned(),
+                },
+            )
+            .await?;
+
+        assert!(match_out.is_none());
+
+        Ok(())
+    }
+}
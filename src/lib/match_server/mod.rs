@@ -1,7 +1,21 @@
+mod match_cipher;
 #[allow(clippy::module_inception)]
 mod match_server;
 mod match_servers;
+mod out_identity;
+mod out_load;
+
+pub mod http_match_server;
+pub mod memory_match_server;
+pub mod p2p_match_protocol;
+pub mod p2p_match_server;
 pub mod redis_match_server;
+pub mod standalone_http_match_server;
+pub mod standalone_match_server;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub use match_server::*;
 pub use match_servers::*;
+pub use out_identity::*;
+pub use out_load::*;
@@ -0,0 +1,386 @@
+use std::{fmt, fs, io::Write as _, os::unix::fs::OpenOptionsExt as _, path::Path, str::FromStr};
+
+use base64::Engine as _;
+use ring::signature::KeyPair as _;
+
+use super::{MatchOut, MatchOutId};
+
+const SIGNATURE_CONTEXT: &str = "plug2proxy out identity";
+
+/// Identity of OUT, signing its matches with an Ed25519 key, so that IN can tell
+/// a restarted OUT from another one claiming the same id.
+pub struct OutIdentity {
+    id: MatchOutId,
+    key_pair: ring::signature::Ed25519KeyPair,
+    /// Changes on every start, for IN to replace tunnels of the previous run.
+    /// Ordered by time (UUID v7), so that IN tells the latest instance.
+    instance_id: uuid::Uuid,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OutIdentityFile {
+    id: MatchOutId,
+    /// PKCS#8 document of the key pair.
+    #[serde(with = "base64_bytes")]
+    key: Vec<u8>,
+}
+
+impl OutIdentity {
+    /// Loads the identity saved at `path`, or generates a new one and saves it
+    /// there if missing. Without a path, the identity only lasts until OUT exits.
+    pub fn load_or_generate(path: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            if path.exists() {
+                log::info!("loading OUT identity from {}...", path.display());
+
+                let OutIdentityFile { id, key } = serde_json::from_slice(&fs::read(path)?)?;
+
+                return Self::new(id, &key);
+            }
+        }
+
+        let id = MatchOutId::new();
+
+        let key = ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("failed to generate OUT identity key."))?;
+
+        if let Some(path) = path {
+            log::info!("saving OUT identity to {}...", path.display());
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)?
+                .write_all(&serde_json::to_vec(&OutIdentityFile {
+                    id,
+                    key: key.as_ref().to_vec(),
+                })?)?;
+        }
+
+        Self::new(id, key.as_ref())
+    }
+
+    fn new(id: MatchOutId, key: &[u8]) -> anyhow::Result<Self> {
+        let key_pair = ring::signature::Ed25519KeyPair::from_pkcs8(key)
+            .map_err(|error| anyhow::anyhow!("invalid OUT identity key: {error}"))?;
+
+        Ok(Self {
+            id,
+            key_pair,
+            instance_id: uuid::Uuid::now_v7(),
+        })
+    }
+
+    pub fn id(&self) -> MatchOutId {
+        self.id
+    }
+
+    pub fn instance_id(&self) -> uuid::Uuid {
+        self.instance_id
+    }
+
+    pub fn key(&self) -> OutIdentityKey {
+        OutIdentityKey(self.key_pair.public_key().as_ref().to_vec())
+    }
+
+    /// Signs the reply of OUT to a match, all of it but the signature itself, so
+    /// that neither the tunnel and data IN connects with, nor the labels, rules,
+    /// priorities and limits IN applies, can be reused or forged.
+    pub fn sign(&self, mut match_out: MatchOut<serde_json::Value>) -> MatchOut<serde_json::Value> {
+        match_out.identity = Some(MatchOutIdentity {
+            key: self.key(),
+            instance_id: self.instance_id,
+            signature: Vec::new(),
+        });
+
+        let signature = self.key_pair.sign(&get_signed_message(&match_out));
+
+        if let Some(identity) = &mut match_out.identity {
+            identity.signature = signature.as_ref().to_vec();
+        }
+
+        match_out
+    }
+
+    /// Signs the instance, published along with the load of OUT.
+    pub fn sign_instance(&self) -> SignedOutInstance {
+        let message = get_signed_instance_message(self.id, self.instance_id);
+
+        SignedOutInstance {
+            instance_id: self.instance_id,
+            signature: self.key_pair.sign(&message).as_ref().to_vec(),
+        }
+    }
+}
+
+/// Identity presented by OUT in a match.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct MatchOutIdentity {
+    pub key: OutIdentityKey,
+    pub instance_id: uuid::Uuid,
+    #[serde(with = "base64_bytes")]
+    signature: Vec<u8>,
+}
+
+impl MatchOutIdentity {
+    /// Verifies the signature of the reply this identity is presented with.
+    pub fn verify(&self, match_out: &MatchOut<serde_json::Value>) -> anyhow::Result<()> {
+        let message = get_signed_message(match_out);
+
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &self.key.0)
+            .verify(&message, &self.signature)
+            .map_err(|_| anyhow::anyhow!("invalid identity signature of OUT {}.", match_out.id))
+    }
+}
+
+/// Instance of OUT published with its load, telling IN early that it restarted.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SignedOutInstance {
+    pub instance_id: uuid::Uuid,
+    #[serde(with = "base64_bytes")]
+    signature: Vec<u8>,
+}
+
+impl SignedOutInstance {
+    pub fn verify(&self, out_id: MatchOutId, key: &OutIdentityKey) -> anyhow::Result<()> {
+        let message = get_signed_instance_message(out_id, self.instance_id);
+
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &key.0)
+            .verify(&message, &self.signature)
+            .map_err(|_| anyhow::anyhow!("invalid instance signature of OUT {out_id}."))
+    }
+}
+
+/// Public key of an OUT identity, in base64.
+#[derive(Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct OutIdentityKey(Vec<u8>);
+
+impl fmt::Display for OutIdentityKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "{}",
+            base64::engine::general_purpose::STANDARD.encode(&self.0)
+        )
+    }
+}
+
+impl FromStr for OutIdentityKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = base64::engine::general_purpose::STANDARD.decode(s)?;
+
+        anyhow::ensure!(key.len() == 32, "invalid OUT identity key {s}.");
+
+        Ok(Self(key))
+    }
+}
+
+impl From<OutIdentityKey> for String {
+    fn from(key: OutIdentityKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for OutIdentityKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn get_signed_message(match_out: &MatchOut<serde_json::Value>) -> Vec<u8> {
+    // Object keys of `serde_json::Value` are sorted, so both ends serialize the
+    // reply the same way.
+    let mut match_out = serde_json::to_value(match_out).unwrap();
+
+    // The key and instance presented are signed, the signature is not.
+    if let Some(identity) = match_out
+        .get_mut("identity")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        identity.remove("signature");
+    }
+
+    let mut message = format!("{SIGNATURE_CONTEXT}:match:").into_bytes();
+
+    message.extend(serde_json::to_vec(&match_out).unwrap());
+
+    message
+}
+
+fn get_signed_instance_message(out_id: MatchOutId, instance_id: uuid::Uuid) -> Vec<u8> {
+    format!("{SIGNATURE_CONTEXT}:{out_id}:{instance_id}:instance").into_bytes()
+}
+
+mod base64_bytes {
+    use base64::Engine as _;
+
+    pub fn serialize<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let data = <String as serde::Deserialize>::deserialize(deserializer)?;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use crate::{
+        bandwidth::BandwidthLimit,
+        match_server::create_match_out,
+        route::{
+            config::OutRuleConfig,
+            rule::{BuiltInLabel, Label},
+        },
+        tunnel::TunnelId,
+    };
+
+    use super::*;
+
+    #[test]
+    fn persists_identity() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("plug2proxy-test-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("out_identity.json");
+
+        let identity = OutIdentity::load_or_generate(Some(&path))?;
+
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        let loaded_identity = OutIdentity::load_or_generate(Some(&path))?;
+
+        fs::remove_dir_all(&dir)?;
+
+        assert!(loaded_identity.id() == identity.id());
+        assert!(loaded_identity.key() == identity.key());
+        assert_ne!(loaded_identity.instance_id(), identity.instance_id());
+
+        // Not persisted without a path.
+        let identity = OutIdentity::load_or_generate(None)?;
+        let other_identity = OutIdentity::load_or_generate(None)?;
+
+        assert!(other_identity.id() != identity.id());
+        assert!(other_identity.key() != identity.key());
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_signed_match() -> anyhow::Result<()> {
+        let identity = OutIdentity::load_or_generate(None)?;
+
+        let routing_rules = serde_json::from_value::<Vec<OutRuleConfig>>(serde_json::json!([
+            { "type": "domain", "match": "example.com", "priority": 1 }
+        ]))?;
+
+        let match_out = serde_json::to_vec(&create_match_out(
+            &identity,
+            &[Label::Custom("us".to_owned())],
+            Some(1),
+            &routing_rules,
+            0,
+            BandwidthLimit::default(),
+            &serde_json::json!({ "address": "127.0.0.1:443" }),
+        ))?;
+
+        // Verified as received by IN.
+        let verify = |forge: fn(&mut MatchOut<serde_json::Value>)| {
+            let mut match_out = serde_json::from_slice::<MatchOut<serde_json::Value>>(&match_out)?;
+
+            forge(&mut match_out);
+
+            match_out.identity.as_ref().unwrap().verify(&match_out)
+        };
+
+        verify(|_| {})?;
+
+        // Bound to all of the reply.
+        let forgeries: [fn(&mut MatchOut<serde_json::Value>); 10] = [
+            |match_out| match_out.id = MatchOutId::new(),
+            |match_out| match_out.tunnel_id = TunnelId::new(),
+            |match_out| match_out.tunnel_labels.push(BuiltInLabel::Proxy.into()),
+            |match_out| match_out.tunnel_priority = Some(100),
+            |match_out| match_out.routing_priority = 100,
+            |match_out| match_out.routing_rules.clear(),
+            |match_out| match_out.bandwidth_limit.upload = Some(1),
+            |match_out| match_out.data = serde_json::json!({ "address": "127.0.0.2:443" }),
+            |match_out| match_out.identity.as_mut().unwrap().instance_id = uuid::Uuid::now_v7(),
+            // Not verified by the key of another OUT.
+            |match_out| {
+                match_out.identity.as_mut().unwrap().key =
+                    OutIdentity::load_or_generate(None).unwrap().key()
+            },
+        ];
+
+        for forge in forgeries {
+            assert!(verify(forge).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_signed_instance() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("plug2proxy-test-{}.json", uuid::Uuid::new_v4()));
+
+        let identity = OutIdentity::load_or_generate(Some(&path))?;
+        let restarted_identity = OutIdentity::load_or_generate(Some(&path))?;
+
+        fs::remove_file(&path)?;
+
+        let instance = identity.sign_instance();
+        let restarted_instance = restarted_identity.sign_instance();
+
+        instance.verify(identity.id(), &identity.key())?;
+        restarted_instance.verify(identity.id(), &identity.key())?;
+
+        // Later instances are ordered after earlier ones.
+        assert!(restarted_instance.instance_id > instance.instance_id);
+
+        // Bound to the OUT and its key.
+        assert!(instance.verify(MatchOutId::new(), &identity.key()).is_err());
+        assert!(instance
+            .verify(identity.id(), &OutIdentity::load_or_generate(None)?.key())
+            .is_err());
+
+        let forged_instance = SignedOutInstance {
+            instance_id: uuid::Uuid::now_v7(),
+            ..instance
+        };
+
+        assert!(forged_instance
+            .verify(identity.id(), &identity.key())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_identity_key() -> anyhow::Result<()> {
+        let key = OutIdentity::load_or_generate(None)?.key();
+
+        assert!(key.to_string().parse::<OutIdentityKey>()? == key);
+        assert!("c2hvcnQ=".parse::<OutIdentityKey>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use crate::bandwidth::BandwidthLimit;
+
+use super::SignedOutInstance;
+
+/// Interval OUT publishes its load at, and IN refreshes it at.
+pub const OUT_LOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Load published by OUT expires if not refreshed in time (e.g. OUT gone).
+pub const OUT_LOAD_EXPIRATION: Duration = Duration::from_secs(15);
+
+/// Name OUT load is encrypted with, in place of a match name.
+pub(super) const OUT_LOAD_MATCH_NAME: &str = "out-load";
+
+/// Utilization of the advertised capacity from which OUT is considered
+/// saturated, and no more tunnels are added to it.
+const SATURATION_UTILIZATION: f64 = 0.9;
+
+/// Load of an OUT published through the match server, shared by all its
+/// tunnels and IN.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OutLoad {
+    pub tunnels: usize,
+    pub streams: usize,
+    /// Throughput in bytes per second, in the same directions as the limit.
+    pub throughput: Throughput,
+    /// Bandwidth limit of all traffic configured on OUT.
+    pub capacity: BandwidthLimit,
+    /// Instance of OUT with an identity.
+    #[serde(default)]
+    pub instance: Option<SignedOutInstance>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Throughput {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl OutLoad {
+    /// Highest ratio of throughput to capacity of both directions, `None` if no
+    /// capacity is configured.
+    pub fn utilization(&self) -> Option<f64> {
+        [
+            (self.throughput.upload, self.capacity.upload),
+            (self.throughput.download, self.capacity.download),
+        ]
+        .into_iter()
+        .filter_map(|(throughput, capacity)| {
+            capacity.map(|capacity| throughput as f64 / capacity as f64)
+        })
+        .reduce(f64::max)
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.utilization()
+            .is_some_and(|utilization| utilization >= SATURATION_UTILIZATION)
+    }
+
+    /// Orders OUT from the least loaded: by utilization in steps of 10% (so that
+    /// close ones are still balanced by rotation), then by streams per tunnel.
+    pub fn rank(&self) -> (u64, usize) {
+        (
+            self.utilization()
+                .map_or(0, |utilization| (utilization * 10.0) as u64),
+            self.streams / self.tunnels.max(1),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_load(upload: u64, streams: usize, upload_capacity: Option<u64>) -> OutLoad {
+        OutLoad {
+            tunnels: 2,
+            streams,
+            throughput: Throughput {
+                upload,
+                download: 0,
+            },
+            capacity: BandwidthLimit {
+                upload: upload_capacity,
+                download: None,
+            },
+            instance: None,
+        }
+    }
+
+    #[test]
+    fn saturates_near_capacity() {
+        assert_eq!(new_load(500, 0, None).utilization(), None);
+        assert!(!new_load(500, 0, None).is_saturated());
+
+        assert_eq!(new_load(500, 0, Some(1000)).utilization(), Some(0.5));
+        assert!(!new_load(500, 0, Some(1000)).is_saturated());
+        assert!(new_load(900, 0, Some(1000)).is_saturated());
+    }
+
+    #[test]
+    fn ranks_by_utilization_then_streams() {
+        // Close utilization ranks the same, and falls back to streams per tunnel.
+        assert_eq!(new_load(510, 8, Some(1000)).rank(), (5, 4));
+        assert_eq!(new_load(590, 8, Some(1000)).rank(), (5, 4));
+
+        assert!(new_load(100, 100, Some(1000)).rank() < new_load(500, 0, Some(1000)).rank());
+        assert!(new_load(0, 2, None).rank() < new_load(0, 4, None).rank());
+    }
+}
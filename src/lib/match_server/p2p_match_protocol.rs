@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject as _;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::tunnel::common::{
+    create_rustls_client_config_with_native_roots, parse_fingerprint, FingerprintCertVerifier,
+};
+
+use super::MatchOutId;
+
+pub const P2P_MATCH_ALPN: &str = "p2p-match";
+
+const FRAME_SIZE_MAX: usize = 1024 * 1024;
+
+const AUTHENTICATION_LABEL: &[u8] = b"plug2proxy p2p match authentication";
+const AUTHENTICATION_CONTEXT_LENGTH: usize = 32;
+
+/// Sent by IN and OUT on a stream opened to the match server. A stream lasts for
+/// one request, and closing it early cancels the request.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum P2pMatchRequest {
+    /// Sent by IN and OUT on the first stream of a connection, before any other
+    /// request, answered with `Authenticated`. See `sign_p2p_match_connection`.
+    Authenticate {
+        #[serde(with = "payload")]
+        mac: Vec<u8>,
+    },
+    /// IN waiting for an OUT it has not accepted yet, answered with `Out`.
+    AcceptOut {
+        name: String,
+        excluded: Vec<MatchOutId>,
+    },
+    /// IN announcing itself to an OUT, answered with `Matched` or `OutGone`.
+    MatchOut {
+        name: String,
+        out_id: MatchOutId,
+        #[serde(with = "payload")]
+        announcement: Vec<u8>,
+    },
+    /// OUT waiting for IN, which keeps the OUT announced while the stream is
+    /// open. Each `In` received is answered with a `Reply`.
+    MatchIn { name: String, out_id: MatchOutId },
+    Reply {
+        #[serde(with = "payload")]
+        payload: Vec<u8>,
+    },
+    /// OUT publishing its load, kept until expired. Not answered.
+    PublishLoad {
+        out_id: MatchOutId,
+        #[serde(with = "payload")]
+        load: Vec<u8>,
+    },
+    /// IN getting the load of an OUT, answered with `Load` or `OutGone`.
+    GetLoad { out_id: MatchOutId },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum P2pMatchResponse {
+    Authenticated,
+    Out {
+        id: MatchOutId,
+    },
+    OutGone,
+    In {
+        #[serde(with = "payload")]
+        announcement: Vec<u8>,
+    },
+    Matched {
+        #[serde(with = "payload")]
+        payload: Vec<u8>,
+    },
+    Load {
+        #[serde(with = "payload")]
+        load: Vec<u8>,
+    },
+}
+
+/// Payloads are opaque to the match server, encrypted with the key shared by
+/// IN and OUT.
+mod payload {
+    use base64::Engine as _;
+
+    pub fn serialize<S: serde::Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let data = <String as serde::Deserialize>::deserialize(deserializer)?;
+
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub async fn write_frame<T: serde::Serialize>(
+    stream: &mut (impl tokio::io::AsyncWrite + Unpin),
+    frame: &T,
+) -> anyhow::Result<()> {
+    let data = serde_json::to_vec(frame)?;
+
+    stream.write_u32(data.len().try_into()?).await?;
+    stream.write_all(&data).await?;
+
+    Ok(())
+}
+
+/// Reads a frame, `None` if the stream ended in between.
+pub async fn read_frame<T: serde::de::DeserializeOwned>(
+    stream: &mut (impl tokio::io::AsyncRead + Unpin),
+) -> anyhow::Result<Option<T>> {
+    let length = match stream.read_u32().await {
+        Ok(length) => length as usize,
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+
+    anyhow::ensure!(length <= FRAME_SIZE_MAX, "match frame of {length} bytes.");
+
+    let mut data = vec![0; length];
+
+    stream.read_exact(&mut data).await?;
+
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// MAC (HMAC-SHA256) of keying material exported from the TLS session with the
+/// access key of the match server, proving the key without sending it and
+/// binding the proof to the connection so that it cannot be replayed.
+pub fn sign_p2p_match_connection(
+    connection: &quinn::Connection,
+    access_key: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let context = get_authentication_context(connection)?;
+
+    Ok(
+        ring::hmac::sign(&get_authentication_key(access_key), &context)
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// Verifies a MAC from `sign_p2p_match_connection` against any of the access
+/// keys.
+pub fn verify_p2p_match_connection(
+    connection: &quinn::Connection,
+    access_keys: &[String],
+    mac: &[u8],
+) -> anyhow::Result<bool> {
+    let context = get_authentication_context(connection)?;
+
+    Ok(access_keys.iter().any(|access_key| {
+        ring::hmac::verify(&get_authentication_key(access_key), &context, mac).is_ok()
+    }))
+}
+
+fn get_authentication_context(
+    connection: &quinn::Connection,
+) -> anyhow::Result<[u8; AUTHENTICATION_CONTEXT_LENGTH]> {
+    let mut context = [0; AUTHENTICATION_CONTEXT_LENGTH];
+
+    connection
+        .export_keying_material(&mut context, AUTHENTICATION_LABEL, &[])
+        .map_err(|_| anyhow::anyhow!("failed to export keying material."))?;
+
+    Ok(context)
+}
+
+fn get_authentication_key(access_key: &str) -> ring::hmac::Key {
+    ring::hmac::Key::new(ring::hmac::HMAC_SHA256, access_key.as_bytes())
+}
+
+pub fn create_p2p_match_server_config(
+    cert_pem: &str,
+    key_pem: &str,
+) -> anyhow::Result<rustls::ServerConfig> {
+    let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid key."))?;
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)?;
+
+    server_config.alpn_protocols = vec![P2P_MATCH_ALPN.as_bytes().to_vec()];
+
+    Ok(server_config)
+}
+
+/// Trusts the certificate of the given SHA-256 fingerprint if any, or one
+/// issued by system root certificates.
+pub fn create_p2p_match_client_config(
+    fingerprint: Option<&str>,
+) -> anyhow::Result<rustls::ClientConfig> {
+    let mut client_config = match fingerprint {
+        Some(fingerprint) => rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FingerprintCertVerifier::new(
+                parse_fingerprint(fingerprint)?,
+            )))
+            .with_no_client_auth(),
+        None => create_rustls_client_config_with_native_roots()?,
+    };
+
+    client_config.alpn_protocols = vec![P2P_MATCH_ALPN.as_bytes().to_vec()];
+
+    Ok(client_config)
+}
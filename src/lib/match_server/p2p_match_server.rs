@@ -0,0 +1,471 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use itertools::Itertools as _;
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    route::{config::OutRuleConfig, rule::Label},
+    tunnel::{
+        quic::{quinn::create_client_endpoint, QuicCongestionController, QuicTransportConfig},
+        TunnelProtocol,
+    },
+    utils::net::get_any_address,
+};
+
+use super::{
+    match_cipher::MatchCipher,
+    match_server::{
+        create_match_out, negotiate_match_out, InMatchServer, MatchIn, MatchOut,
+        OutMatchServerTrait,
+    },
+    p2p_match_protocol::{
+        create_p2p_match_client_config, read_frame, sign_p2p_match_connection, write_frame,
+        P2pMatchRequest, P2pMatchResponse,
+    },
+    MatchInId, MatchOutId, MatchPair, OutIdentity, OutLoad, OUT_LOAD_MATCH_NAME,
+};
+
+/// Connection to a standalone match server, shared by all requests of IN or OUT
+/// and reestablished on demand.
+pub struct P2pMatchClient {
+    server_address: String,
+    server_name: String,
+    access_key: String,
+    client_config: Arc<rustls::ClientConfig>,
+    connection: tokio::sync::Mutex<Option<(quinn::Endpoint, quinn::Connection)>>,
+}
+
+impl P2pMatchClient {
+    /// Accepts `p2p://host:port`, with an optional `fingerprint` query parameter
+    /// pinning the certificate of the match server.
+    pub fn new(url: &str, access_key: String) -> anyhow::Result<Self> {
+        let url = url::Url::parse(url)?;
+
+        anyhow::ensure!(url.scheme() == "p2p", "unsupported match server url.");
+
+        anyhow::ensure!(
+            !access_key.is_empty(),
+            "match server access key must not be empty."
+        );
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("match server url without host."))?;
+        let port = url
+            .port()
+            .ok_or_else(|| anyhow::anyhow!("match server url without port."))?;
+
+        let fingerprint = url
+            .query_pairs()
+            .find(|(name, _)| name == "fingerprint")
+            .map(|(_, fingerprint)| fingerprint.into_owned());
+
+        Ok(Self {
+            server_address: format!("{host}:{port}"),
+            server_name: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            access_key,
+            client_config: Arc::new(create_p2p_match_client_config(fingerprint.as_deref())?),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub(crate) async fn open(&self) -> anyhow::Result<(quinn::SendStream, quinn::RecvStream)> {
+        let connection = self.get_connection().await?;
+
+        // Opening a stream waits for the match server to allow more, not holding
+        // the connection meanwhile.
+        match connection.open_bi().await {
+            Ok(streams) => Ok(streams),
+            Err(error) => {
+                log::warn!("match server connection lost: {error}");
+
+                Ok(self.get_connection().await?.open_bi().await?)
+            }
+        }
+    }
+
+    /// Gets the current connection, reconnecting if closed. Reconnecting holds
+    /// the connection for concurrent requests to wait for the same one.
+    async fn get_connection(&self) -> anyhow::Result<quinn::Connection> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some((_, existing_connection)) = connection.as_ref() {
+            if existing_connection.close_reason().is_none() {
+                return Ok(existing_connection.clone());
+            }
+        }
+
+        *connection = None;
+
+        let (endpoint, new_connection) = self.connect().await?;
+
+        *connection = Some((endpoint, new_connection.clone()));
+
+        Ok(new_connection)
+    }
+
+    async fn connect(&self) -> anyhow::Result<(quinn::Endpoint, quinn::Connection)> {
+        let address = tokio::net::lookup_host(&self.server_address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve {}.", self.server_address))?;
+
+        let endpoint = create_client_endpoint(
+            std::net::UdpSocket::bind(get_any_address(&address))?,
+            (*self.client_config).clone(),
+            &QuicTransportConfig::default(),
+            QuicCongestionController::Cubic,
+        )?;
+
+        let connection = endpoint.connect(address, &self.server_name)?.await?;
+
+        self.authenticate(&connection).await?;
+
+        log::info!("connected to match server {address}.");
+
+        Ok((endpoint, connection))
+    }
+
+    async fn authenticate(&self, connection: &quinn::Connection) -> anyhow::Result<()> {
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+
+        write_frame(
+            &mut send_stream,
+            &P2pMatchRequest::Authenticate {
+                mac: sign_p2p_match_connection(connection, &self.access_key)?,
+            },
+        )
+        .await?;
+
+        send_stream.finish()?;
+
+        match read_frame(&mut recv_stream).await {
+            Ok(Some(P2pMatchResponse::Authenticated)) => Ok(()),
+            _ => anyhow::bail!("match server refused access key."),
+        }
+    }
+}
+
+pub struct P2pInMatchServer {
+    id: MatchInId,
+    client: P2pMatchClient,
+    cipher: MatchCipher,
+    match_name_to_active_out_id_set_map:
+        tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<HashSet<MatchOutId>>>>>,
+}
+
+impl P2pInMatchServer {
+    pub fn new(client: P2pMatchClient, keys: Vec<String>) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: MatchInId::new(),
+            client,
+            cipher: MatchCipher::new(keys)?,
+            match_name_to_active_out_id_set_map: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn get_active_out_id_set(
+        &self,
+        match_name: &str,
+    ) -> Arc<tokio::sync::Mutex<HashSet<MatchOutId>>> {
+        self.match_name_to_active_out_id_set_map
+            .lock()
+            .await
+            .entry(match_name.to_owned())
+            .or_default()
+            .clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl InMatchServer for P2pInMatchServer {
+    async fn accept_out<TInData, TOutData>(&self) -> anyhow::Result<MatchOutId>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        log::info!("accepting {match_name} OUT...");
+
+        let active_out_id_set = self.get_active_out_id_set(match_name).await;
+
+        loop {
+            let excluded = active_out_id_set.lock().await.iter().copied().collect_vec();
+
+            let (mut send_stream, mut recv_stream) = self.client.open().await?;
+
+            write_frame(
+                &mut send_stream,
+                &P2pMatchRequest::AcceptOut {
+                    name: match_name.to_owned(),
+                    excluded,
+                },
+            )
+            .await?;
+
+            let Some(P2pMatchResponse::Out { id: out_id }) = read_frame(&mut recv_stream).await?
+            else {
+                anyhow::bail!("unexpected response to accepting {match_name} OUT.");
+            };
+
+            // Another accepting task might have got the same OUT in the meantime.
+            if active_out_id_set.lock().await.insert(out_id) {
+                log::debug!("accepting OUT {match_name} {out_id}...");
+
+                return Ok(out_id);
+            }
+        }
+    }
+
+    async fn match_out<TInData, TOutData>(
+        &self,
+        out_id: MatchOutId,
+        in_data: TInData,
+    ) -> anyhow::Result<Option<MatchOut<TOutData>>>
+    where
+        TInData: serde::Serialize + Send,
+        TOutData: serde::de::DeserializeOwned + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        let announcement = InAnnouncement {
+            id: self.id,
+            protocol: TunnelProtocol::current(),
+            data: serde_json::to_value(in_data)?,
+        };
+
+        let (mut send_stream, mut recv_stream) = self.client.open().await?;
+
+        log::debug!("announcing {match_name} IN {} to OUT {out_id}...", self.id);
+
+        write_frame(
+            &mut send_stream,
+            &P2pMatchRequest::MatchOut {
+                name: match_name.to_owned(),
+                out_id,
+                announcement: self.cipher.encrypt(
+                    match_name,
+                    out_id,
+                    serde_json::to_string(&announcement)?.as_bytes(),
+                ),
+            },
+        )
+        .await?;
+
+        let payload = match read_frame(&mut recv_stream).await? {
+            Some(P2pMatchResponse::Matched { payload }) => payload,
+            Some(P2pMatchResponse::OutGone) => {
+                self.get_active_out_id_set(match_name)
+                    .await
+                    .lock()
+                    .await
+                    .remove(&out_id);
+
+                log::info!("{match_name} OUT {out_id} no longer active.");
+
+                return Ok(None);
+            }
+            _ => anyhow::bail!("unexpected response to matching {match_name} OUT {out_id}."),
+        };
+
+        let match_out: MatchOut<serde_json::Value> =
+            serde_json::from_slice(&self.cipher.decrypt(match_name, out_id, &payload)?)?;
+
+        negotiate_match_out(match_name, match_out)
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        let (mut send_stream, mut recv_stream) = self.client.open().await?;
+
+        write_frame(&mut send_stream, &P2pMatchRequest::GetLoad { out_id }).await?;
+
+        send_stream.finish()?;
+
+        match read_frame(&mut recv_stream).await? {
+            Some(P2pMatchResponse::Load { load }) => Ok(Some(serde_json::from_slice(
+                &self
+                    .cipher
+                    .decrypt_stored(OUT_LOAD_MATCH_NAME, out_id, &load)?,
+            )?)),
+            Some(P2pMatchResponse::OutGone) => Ok(None),
+            _ => anyhow::bail!("unexpected response to getting load of OUT {out_id}."),
+        }
+    }
+}
+
+pub struct P2pOutMatchServer {
+    id: MatchOutId,
+    identity: OutIdentity,
+    labels: Vec<Label>,
+    bandwidth_limit: BandwidthLimit,
+    cipher: MatchCipher,
+    client: P2pMatchClient,
+}
+
+impl P2pOutMatchServer {
+    pub fn new(
+        client: P2pMatchClient,
+        keys: Vec<String>,
+        identity: OutIdentity,
+        labels: Vec<Label>,
+        bandwidth_limit: BandwidthLimit,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: identity.id(),
+            identity,
+            labels,
+            bandwidth_limit,
+            cipher: MatchCipher::new(keys)?,
+            client,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl OutMatchServerTrait for P2pOutMatchServer {
+    async fn match_in<TInData, TOutData>(
+        &self,
+        out_data: TOutData,
+        out_priority: Option<i64>,
+        out_routing_rules: &[OutRuleConfig],
+        out_routing_priority: i64,
+    ) -> anyhow::Result<MatchIn<TInData>>
+    where
+        TInData: serde::de::DeserializeOwned + Send,
+        TOutData: serde::Serialize + Send,
+        (TInData, TOutData): MatchPair<TInData, TOutData>,
+    {
+        let match_name = <(TInData, TOutData)>::get_match_name();
+
+        // Serialized upfront, as it is sent again to every incompatible IN.
+        let out_data = serde_json::to_value(out_data)?;
+
+        let (mut send_stream, mut recv_stream) = self.client.open().await?;
+
+        write_frame(
+            &mut send_stream,
+            &P2pMatchRequest::MatchIn {
+                name: match_name.to_owned(),
+                out_id: self.id,
+            },
+        )
+        .await?;
+
+        loop {
+            let announcement = match read_frame(&mut recv_stream).await? {
+                Some(P2pMatchResponse::In { announcement }) => announcement,
+                None => anyhow::bail!("match server ended IN subscription."),
+                _ => anyhow::bail!("unexpected response to matching {match_name} IN."),
+            };
+
+            let announcement = self
+                .cipher
+                .decrypt(match_name, self.id, &announcement)
+                .and_then(|announcement| {
+                    Ok(serde_json::from_slice::<InAnnouncement>(&announcement)?)
+                });
+
+            let InAnnouncement { id, protocol, data } = match announcement {
+                Ok(announcement) => announcement,
+                Err(error) => {
+                    log::warn!("invalid {match_name} IN announcement: {error}");
+
+                    // IN fails on the empty reply in turn.
+                    write_frame(
+                        &mut send_stream,
+                        &P2pMatchRequest::Reply {
+                            payload: Vec::new(),
+                        },
+                    )
+                    .await?;
+
+                    continue;
+                }
+            };
+
+            let negotiation = TunnelProtocol::current().negotiate(&protocol);
+
+            let in_data = match negotiation {
+                Ok(_) => Some(serde_json::from_value::<TInData>(data)?),
+                Err(_) => None,
+            };
+
+            let match_out = create_match_out(
+                &self.identity,
+                &self.labels,
+                out_priority,
+                out_routing_rules,
+                out_routing_priority,
+                self.bandwidth_limit,
+                &out_data,
+            );
+            let tunnel_id = match_out.tunnel_id;
+
+            write_frame(
+                &mut send_stream,
+                &P2pMatchRequest::Reply {
+                    payload: self.cipher.encrypt(
+                        match_name,
+                        self.id,
+                        serde_json::to_string(&match_out)?.as_bytes(),
+                    ),
+                },
+            )
+            .await?;
+
+            let Some(in_data) = in_data else {
+                log::warn!("refused IN {match_name} {id}: {}", negotiation.unwrap_err());
+
+                continue;
+            };
+
+            send_stream.finish()?;
+
+            log::info!("matched IN {match_name} {id} as tunnel {tunnel_id}.");
+
+            return Ok(MatchIn {
+                id,
+                tunnel_id,
+                protocol,
+                data: in_data,
+            });
+        }
+    }
+
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()> {
+        let (mut send_stream, _) = self.client.open().await?;
+
+        write_frame(
+            &mut send_stream,
+            &P2pMatchRequest::PublishLoad {
+                out_id: self.id,
+                load: self.cipher.encrypt(
+                    OUT_LOAD_MATCH_NAME,
+                    self.id,
+                    serde_json::to_string(&load)?.as_bytes(),
+                ),
+            },
+        )
+        .await?;
+
+        send_stream.finish()?;
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct InAnnouncement {
+    id: MatchInId,
+    protocol: TunnelProtocol,
+    data: serde_json::Value,
+}
@@ -1,48 +1,153 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
     time::Duration,
 };
 
-use aes_gcm::{aead::Aead, AeadCore, KeyInit as _};
-use futures::{future::ready, pin_mut, Stream, StreamExt as _};
-use itertools::Itertools;
-use redis::AsyncCommands;
-use sha2::Digest as _;
+use futures::{Stream, StreamExt as _};
+use redis::{
+    aio::ConnectionLike as _,
+    streams::{StreamId, StreamMaxlen, StreamReadOptions, StreamReadReply},
+    AsyncCommands,
+};
 
 use crate::{
+    bandwidth::BandwidthLimit,
     route::{config::OutRuleConfig, rule::Label},
-    tunnel::TunnelId,
+    tunnel::TunnelProtocol,
 };
 
 use super::{
-    match_server::{InMatchServer, MatchIn, MatchOut, OutMatchServerTrait},
-    MatchInId, MatchOutId, MatchPair,
+    match_cipher::MatchCipher,
+    match_server::{
+        create_match_out, negotiate_match_out, InMatchServer, MatchIn, MatchOut,
+        OutMatchServerTrait,
+    },
+    MatchInId, MatchOutId, MatchPair, OutIdentity, OutLoad, OUT_LOAD_EXPIRATION,
+    OUT_LOAD_MATCH_NAME,
 };
 
-const MATCH_TIMEOUT_SECONDS: i64 = 5;
-const MATCH_IN_DATA_EXPIRATION_IN_SECONDS: i64 = 30;
+/// OUT waiting for IN is registered for this long, refreshed every second.
+const MATCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Also the expiration of the lock OUT takes on IN data, so that IN is not
+/// matched again by a late announcement while its data exists.
+const MATCH_IN_DATA_EXPIRATION_IN_SECONDS: u64 = 30;
+
+/// Announcements (of IN to OUT, and of OUT to IN) are made right away, and
+/// repeated with exponential backoff in case they were missed (e.g. while
+/// reconnecting, or trimmed from the OUT stream).
+const ANNOUNCEMENT_INITIAL_INTERVAL: Duration = Duration::from_millis(500);
+const ANNOUNCEMENT_MAX_INTERVAL: Duration = Duration::from_secs(8);
+/// IN gives up on OUT not replying to its announcements within this long, as
+/// OUT might be stuck while still registered.
+const IN_ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// OUT announcements kept in the stream (approximately), enough for IN to
+/// catch up with those made while it was not reading.
+const OUT_STREAM_MAX_LENGTH: usize = 1024;
+/// A blocking read of the OUT stream ends after this long, for IN to notice a
+/// connection gone silently.
+const OUT_STREAM_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const OUT_STREAM_READ_COUNT: usize = 16;
 
 pub struct RedisInMatchServer {
     id: MatchInId,
-    redis: redis::Client,
-    cipher: Option<Arc<aes_gcm::Aes256Gcm>>,
-    match_name_to_active_out_id_set_map:
-        tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<HashSet<MatchOutId>>>>>,
+    redis: RedisMatchClient,
+    cipher: Arc<MatchCipher>,
+    match_name_to_accept_state_map: tokio::sync::Mutex<HashMap<String, Arc<AcceptState>>>,
 }
 
-impl RedisInMatchServer {
-    pub fn new(redis: redis::Client, key: Option<String>) -> Self {
+#[derive(Default)]
+struct AcceptState {
+    active_out_id_set: Mutex<HashSet<MatchOutId>>,
+    /// Reader of OUT announcements, kept across accepts so that none is missed
+    /// in between.
+    reader: tokio::sync::Mutex<OutStreamReader>,
+}
+
+/// Position of IN in the stream of OUT announcements, read with a connection
+/// of its own, as a blocking read holds up the commands sent after it on the
+/// same connection.
+struct OutStreamReader {
+    connection: Option<RedisConnection>,
+    last_entry_id: String,
+}
+
+impl Default for OutStreamReader {
+    /// Reads from the start of the stream, for OUT announced before IN.
+    fn default() -> Self {
         Self {
+            connection: None,
+            last_entry_id: "0-0".to_owned(),
+        }
+    }
+}
+
+impl RedisInMatchServer {
+    pub fn new(redis: RedisMatchClient, keys: Vec<String>) -> anyhow::Result<Self> {
+        Ok(Self {
             id: MatchInId::new(),
             redis,
-            cipher: create_cipher(key),
-            match_name_to_active_out_id_set_map: tokio::sync::Mutex::new(HashMap::new()),
-        }
+            cipher: Arc::new(MatchCipher::new(keys)?),
+            match_name_to_accept_state_map: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn is_out_registered(
+        &self,
+        connection: &mut RedisConnection,
+        out_key: &str,
+    ) -> anyhow::Result<bool> {
+        Ok(connection.exists(out_key).await?)
+    }
+
+    /// Forgets OUT no longer registered, for it to be accepted again once back.
+    async fn remove_inactive_out(&self, match_name: &str, out_id: MatchOutId) {
+        self.get_accept_state(match_name)
+            .await
+            .active_out_id_set
+            .lock()
+            .unwrap()
+            .remove(&out_id);
+
+        log::info!("{match_name} OUT {out_id} no longer active.");
+    }
+
+    async fn get_accept_state(&self, match_name: &str) -> Arc<AcceptState> {
+        self.match_name_to_accept_state_map
+            .lock()
+            .await
+            .entry(match_name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Verifies an OUT announcement comes from an OUT sharing the key.
+    fn verify_out_announcement(
+        &self,
+        match_name: &str,
+        entry: &StreamId,
+    ) -> anyhow::Result<MatchOutId> {
+        let out_id = entry
+            .get::<String>("out")
+            .ok_or_else(|| anyhow::anyhow!("OUT id missing."))?
+            .parse()?;
+
+        let announcement = entry
+            .get::<Vec<u8>>("announcement")
+            .ok_or_else(|| anyhow::anyhow!("announcement missing."))?;
+
+        self.cipher.decrypt(match_name, out_id, &announcement)?;
+
+        Ok(out_id)
     }
 }
 
-#[allow(dependency_on_unit_never_type_fallback)]
 #[async_trait::async_trait]
 impl InMatchServer for RedisInMatchServer {
     async fn accept_out<TInData, TOutData>(&self) -> anyhow::Result<MatchOutId>
@@ -52,72 +157,90 @@ impl InMatchServer for RedisInMatchServer {
         (TInData, TOutData): MatchPair<TInData, TOutData>,
     {
         let match_name = <(TInData, TOutData)>::get_match_name();
-        let out_pattern = <(TInData, TOutData)>::get_redis_out_pattern();
+        let stream_key = self.redis.get_name(&get_out_stream_key(match_name));
 
         log::info!("accepting {match_name} OUT...");
 
-        let (mut connection, message_stream) = get_connection_and_subscribe::<MatchOutId>(
-            &self.redis,
-            out_pattern,
-            self.cipher.clone(),
-        )
-        .await?;
+        let accept_state = self.get_accept_state(match_name).await;
 
-        let active_out_id_set = self
-            .match_name_to_active_out_id_set_map
-            .lock()
-            .await
-            .entry(match_name.to_owned())
-            .or_default()
-            .clone();
+        let mut reader = accept_state.reader.lock().await;
+        let reader = &mut *reader;
 
-        {
-            let mut active_out_id_set = active_out_id_set.lock().await;
-
-            let out_id = connection
-                .scan_match(out_pattern)
-                .await?
-                .filter_map(|out_key: String| {
-                    ready({
-                        <(TInData, TOutData)>::get_out_id_from_redis_out_key(&out_key)
-                            .inspect_err(|_| {
-                                log::warn!("failed to parse OUT id from key {out_key}.");
-                            })
-                            .ok()
-                            .filter(|&out_id| !active_out_id_set.contains(&out_id))
-                    })
-                })
-                .next()
-                .await;
-
-            if let Some(out_id) = out_id {
-                log::debug!("accepting OUT {match_name} {out_id} by key value...");
-
-                active_out_id_set.insert(out_id);
+        let mut connection = self.redis.get_connection().await?;
 
-                return Ok(out_id);
+        let options = StreamReadOptions::default()
+            .block(OUT_STREAM_BLOCK_TIMEOUT.as_millis() as usize)
+            .count(OUT_STREAM_READ_COUNT);
+
+        loop {
+            if reader
+                .connection
+                .as_ref()
+                .is_none_or(|connection| connection.is_broken())
+            {
+                reader.connection = Some(self.redis.connect().await?);
             }
-        }
 
-        pin_mut!(message_stream);
+            let reply: Option<StreamReadReply> = reader
+                .connection
+                .as_mut()
+                .unwrap()
+                .xread_options(&[&stream_key], &[&reader.last_entry_id], &options)
+                .await?;
 
-        while let Some(out_id) = message_stream.next().await {
-            let out_id = out_id?;
+            // Nothing announced before the read timed out.
+            let Some(reply) = reply else {
+                continue;
+            };
 
-            let mut active_out_id_set = active_out_id_set.lock().await;
+            for entry in reply.keys.into_iter().flat_map(|key| key.ids) {
+                reader.last_entry_id.clone_from(&entry.id);
 
-            if active_out_id_set.contains(&out_id) {
-                continue;
-            }
+                let out_id = match self.verify_out_announcement(match_name, &entry) {
+                    Ok(out_id) => out_id,
+                    Err(error) => {
+                        log::debug!("ignored {match_name} OUT announcement: {error}");
 
-            log::debug!("accepting OUT {match_name} {out_id} by subscription...");
+                        continue;
+                    }
+                };
 
-            active_out_id_set.insert(out_id);
+                if accept_state
+                    .active_out_id_set
+                    .lock()
+                    .unwrap()
+                    .contains(&out_id)
+                {
+                    continue;
+                }
 
-            return Ok(out_id);
-        }
+                // Announcements outlive the OUT making them.
+                if !self
+                    .is_out_registered(
+                        &mut connection,
+                        &self
+                            .redis
+                            .get_name(&<(TInData, TOutData)>::get_redis_out_key(&out_id)),
+                    )
+                    .await?
+                {
+                    continue;
+                }
 
-        anyhow::bail!("subscription to OUT ended.");
+                if !accept_state
+                    .active_out_id_set
+                    .lock()
+                    .unwrap()
+                    .insert(out_id)
+                {
+                    continue;
+                }
+
+                log::debug!("accepting OUT {match_name} {out_id}...");
+
+                return Ok(out_id);
+            }
+        }
     }
 
     async fn match_out<TInData, TOutData>(
@@ -130,71 +253,56 @@ impl InMatchServer for RedisInMatchServer {
         TOutData: serde::de::DeserializeOwned + Send,
         (TInData, TOutData): MatchPair<TInData, TOutData>,
     {
-        let out_key = <(TInData, TOutData)>::get_redis_out_key(&out_id);
-
         let match_name = <(TInData, TOutData)>::get_match_name();
-        let in_announcement_channel_name =
-            <(TInData, TOutData)>::get_redis_in_announcement_channel_name(&out_id);
+        let out_key = self
+            .redis
+            .get_name(&<(TInData, TOutData)>::get_redis_out_key(&out_id));
+        let in_announcement_channel_name = self
+            .redis
+            .get_name(&<(TInData, TOutData)>::get_redis_in_announcement_channel_name(&out_id));
 
-        let match_key = uuid::Uuid::new_v4().to_string();
+        let match_key = self.redis.get_name(&uuid::Uuid::new_v4().to_string());
 
-        let (mut connection, subscription_stream) = get_connection_and_subscribe::<
-            MatchOut<TOutData>,
-        >(
-            &self.redis, &match_key, self.cipher.clone()
-        )
-        .await?;
+        let mut subscription = self.redis.subscribe(&match_key).await?;
 
-        if !connection.exists(out_key).await? {
-            self.match_name_to_active_out_id_set_map
-                .lock()
-                .await
-                .get(match_name)
-                .unwrap()
-                .lock()
-                .await
-                .remove(&out_id);
+        let mut connection = self.redis.get_connection().await?;
 
-            log::info!("{match_name} OUT {out_id} no longer active.");
+        if !self.is_out_registered(&mut connection, &out_key).await? {
+            self.remove_inactive_out(match_name, out_id).await;
 
             return Ok(None);
         }
 
         let match_task = async {
-            pin_mut!(subscription_stream);
-
-            subscription_stream.next().await.unwrap_or_else(|| {
-                Err(anyhow::anyhow!(
-                    "subscription ended before match to OUT {out_id}."
-                ))
-            })
+            let message = subscription.next().await.ok_or_else(|| {
+                anyhow::anyhow!("subscription ended before match to OUT {out_id}.")
+            })?;
+
+            // Data is parsed after checking the protocol, as an incompatible OUT
+            // might send data in another format.
+            anyhow::Ok(serde_json::from_slice::<MatchOut<serde_json::Value>>(
+                &self
+                    .cipher
+                    .decrypt(match_name, out_id, message.get_payload_bytes())?,
+            )?)
         };
 
         let announce_task = {
             let cipher = self.cipher.clone();
+            let match_key = match_key.clone();
 
             async move {
                 let announcement = InAnnouncement {
                     id: self.id,
                     match_key: match_key.clone(),
+                    protocol: TunnelProtocol::current(),
                 };
 
-                connection
-                    .send_packed_command(
-                        redis::cmd("SET")
-                            .arg(&match_key)
-                            .arg(encrypt(
-                                &cipher,
-                                serde_json::to_string(&in_data)?.as_bytes(),
-                            ))
-                            .arg("NX")
-                            .arg("EX")
-                            .arg(MATCH_IN_DATA_EXPIRATION_IN_SECONDS),
-                    )
-                    .await?;
+                let in_data = serde_json::to_string(&in_data)?;
+                let announcement = serde_json::to_string(&announcement)?;
 
-                let encrypted_announcement =
-                    encrypt(&cipher, serde_json::to_string(&announcement)?.as_bytes());
+                let deadline = tokio::time::Instant::now() + IN_ANNOUNCEMENT_TIMEOUT;
+                let mut interval = ANNOUNCEMENT_INITIAL_INTERVAL;
 
                 loop {
                     log::debug!(
@@ -202,60 +310,161 @@ impl InMatchServer for RedisInMatchServer {
                         self.id
                     );
 
+                    // Data and announcement are encrypted again every time, for them
+                    // to stay fresh however long IN waits.
                     connection
-                        .publish(&in_announcement_channel_name, &encrypted_announcement)
+                        .set_ex::<_, _, ()>(
+                            &match_key,
+                            cipher.encrypt(match_name, out_id, in_data.as_bytes()),
+                            MATCH_IN_DATA_EXPIRATION_IN_SECONDS,
+                        )
                         .await?;
 
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-
-                    connection
-                        .expire(&match_key, MATCH_IN_DATA_EXPIRATION_IN_SECONDS)
+                    self.redis
+                        .publish(
+                            &mut connection,
+                            &in_announcement_channel_name,
+                            cipher.encrypt(match_name, out_id, announcement.as_bytes()),
+                        )
                         .await?;
-                }
 
-                #[allow(unreachable_code)]
-                anyhow::Ok(())
+                    tokio::time::sleep(interval).await;
+
+                    interval = (interval * 2).min(ANNOUNCEMENT_MAX_INTERVAL);
+
+                    // OUT might have stopped matching without replying.
+                    if tokio::time::Instant::now() >= deadline
+                        || !self.is_out_registered(&mut connection, &out_key).await?
+                    {
+                        return anyhow::Ok(());
+                    }
+                }
             }
         };
 
         let match_out = tokio::select! {
-            match_out = match_task => match_out?,
-            _ = announce_task => anyhow::bail!("failed to match IN."),
+            match_out = match_task => Some(match_out?),
+            result = announce_task => {
+                result?;
+
+                None
+            }
         };
 
-        log::info!(
-            "matched {match_name} OUT {} as tunnel {}.",
-            match_out.id,
-            match_out.tunnel_id
-        );
+        // Matched, so that announcements OUT has yet to take are ignored.
+        if let Err(error) = self
+            .redis
+            .get_connection()
+            .await?
+            .del::<_, ()>(&match_key)
+            .await
+        {
+            log::debug!("failed to delete match key {match_key}: {error}");
+        }
+
+        let Some(match_out) = match_out else {
+            self.remove_inactive_out(match_name, out_id).await;
+
+            return Ok(None);
+        };
 
-        Ok(Some(match_out))
+        negotiate_match_out(match_name, match_out)
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        let load: Option<Vec<u8>> = self
+            .redis
+            .get_connection()
+            .await?
+            .get(self.redis.get_name(&get_out_load_key(&out_id)))
+            .await?;
+
+        load.map(|load| {
+            Ok(serde_json::from_slice(&self.cipher.decrypt_stored(
+                OUT_LOAD_MATCH_NAME,
+                out_id,
+                &load,
+            )?)?)
+        })
+        .transpose()
     }
 }
 
 pub struct RedisOutMatchServer {
     id: MatchOutId,
+    identity: OutIdentity,
     labels: Vec<Label>,
-    cipher: Option<Arc<aes_gcm::Aes256Gcm>>,
-    redis: redis::Client,
+    bandwidth_limit: BandwidthLimit,
+    cipher: Arc<MatchCipher>,
+    redis: RedisMatchClient,
+    /// Subscriptions to IN announcements, kept across matches so that IN
+    /// announcing in between are matched next.
+    match_name_to_subscription_map:
+        tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<RedisSubscription>>>>>,
 }
 
 impl RedisOutMatchServer {
     pub async fn new(
-        redis: redis::Client,
-        key: Option<String>,
+        redis: RedisMatchClient,
+        keys: Vec<String>,
+        identity: OutIdentity,
         labels: Vec<Label>,
+        bandwidth_limit: BandwidthLimit,
     ) -> anyhow::Result<Self> {
         Ok(Self {
-            id: MatchOutId::new(),
+            id: identity.id(),
+            identity,
             labels,
-            cipher: create_cipher(key),
+            bandwidth_limit,
+            cipher: Arc::new(MatchCipher::new(keys)?),
             redis,
+            match_name_to_subscription_map: tokio::sync::Mutex::new(HashMap::new()),
         })
     }
+
+    /// Registers OUT as waiting for IN until `MATCH_TIMEOUT` from now.
+    async fn register(
+        &self,
+        connection: &mut RedisConnection,
+        out_key: &str,
+    ) -> anyhow::Result<()> {
+        connection
+            .pset_ex::<_, _, ()>(out_key, "", MATCH_TIMEOUT.as_millis() as u64)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Announces OUT to IN reading the stream, with the OUT id encrypted for IN
+    /// to verify the announcement.
+    async fn announce(
+        &self,
+        connection: &mut RedisConnection,
+        match_name: &str,
+        stream_key: &str,
+    ) -> anyhow::Result<()> {
+        let announcement = self.cipher.encrypt(
+            match_name,
+            self.id,
+            serde_json::to_string(&self.id)?.as_bytes(),
+        );
+
+        connection
+            .xadd_maxlen::<_, _, _, _, ()>(
+                stream_key,
+                StreamMaxlen::Approx(OUT_STREAM_MAX_LENGTH),
+                "*",
+                &[
+                    ("out", self.id.to_string().into_bytes()),
+                    ("announcement", announcement),
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
 }
 
-#[allow(dependency_on_unit_never_type_fallback)]
 #[async_trait::async_trait]
 impl OutMatchServerTrait for RedisOutMatchServer {
     async fn match_in<TInData, TOutData>(
@@ -270,217 +479,729 @@ impl OutMatchServerTrait for RedisOutMatchServer {
         TOutData: serde::Serialize + Send,
         (TInData, TOutData): MatchPair<TInData, TOutData>,
     {
-        let channel_name = <(TInData, TOutData)>::get_redis_in_announcement_channel_name(&self.id);
-        let out_key = <(TInData, TOutData)>::get_redis_out_key(&self.id);
+        let match_name = <(TInData, TOutData)>::get_match_name();
+        let out_key = self
+            .redis
+            .get_name(&<(TInData, TOutData)>::get_redis_out_key(&self.id));
+        let stream_key = self.redis.get_name(&get_out_stream_key(match_name));
 
-        let (connection, in_announcement_stream) = get_connection_and_subscribe::<InAnnouncement>(
-            &self.redis,
-            &channel_name,
-            self.cipher.clone(),
-        )
-        .await?;
+        // Serialized upfront, as it is published again to every incompatible IN.
+        let out_data = serde_json::to_value(out_data)?;
 
-        let connection = Arc::new(tokio::sync::Mutex::new(connection));
+        let subscription = self
+            .match_name_to_subscription_map
+            .lock()
+            .await
+            .entry(match_name.to_owned())
+            .or_default()
+            .clone();
 
-        let announce_task = {
-            let connection = connection.clone();
-            let cipher = self.cipher.clone();
+        // Matches of the same name take IN announcements one after another.
+        let mut subscription = subscription.lock().await;
+
+        if subscription.is_none() {
+            *subscription = Some(
+                self.redis
+                    .subscribe(&self.redis.get_name(
+                        &<(TInData, TOutData)>::get_redis_in_announcement_channel_name(&self.id),
+                    ))
+                    .await?,
+            );
+        }
 
-            async move {
-                {
-                    let mut connection = connection.lock().await;
+        let mut connection = self.redis.get_connection().await?;
 
-                    // Actually it's not necessary as the OUT id is already in key (and it's
-                    // neither used), but we are using the same subscribe utility that does
-                    // decryption anyway.
-                    let encrypted_out_id =
-                        encrypt(&cipher, serde_json::to_string(&self.id)?.as_bytes());
+        // Registered before announced, for IN to find OUT registered.
+        self.register(&mut connection, &out_key).await?;
+        self.announce(&mut connection, match_name, &stream_key)
+            .await?;
 
-                    connection
-                        .send_packed_command(
-                            redis::cmd("SET")
-                                .arg(&out_key)
-                                .arg(&encrypted_out_id)
-                                .arg("EX")
-                                .arg(MATCH_TIMEOUT_SECONDS),
-                        )
-                        .await?;
+        let mut register_interval = tokio::time::interval(Duration::from_secs(1));
+
+        register_interval.reset();
+
+        let mut announcement_interval = ANNOUNCEMENT_INITIAL_INTERVAL;
+        let mut next_announcement_at = tokio::time::Instant::now() + announcement_interval;
 
-                    connection.publish(&out_key, &encrypted_out_id).await?;
+        loop {
+            let message = tokio::select! {
+                message = subscription.as_mut().unwrap().next() => message,
+                _ = register_interval.tick() => {
+                    self.register(&mut connection, &out_key).await?;
+
+                    continue;
                 }
+                _ = tokio::time::sleep_until(next_announcement_at) => {
+                    self.announce(&mut connection, match_name, &stream_key).await?;
 
-                loop {
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    announcement_interval = (announcement_interval * 2).min(ANNOUNCEMENT_MAX_INTERVAL);
+                    next_announcement_at = tokio::time::Instant::now() + announcement_interval;
 
-                    let ret: i32 = connection
-                        .lock()
-                        .await
-                        .expire(&out_key, MATCH_TIMEOUT_SECONDS)
-                        .await?;
+                    continue;
+                }
+            };
+
+            let Some(message) = message else {
+                *subscription = None;
+
+                anyhow::bail!("IN announcement subscription ended.");
+            };
 
-                    if ret == 0 {
-                        anyhow::bail!("OUT {} expired.", self.id);
+            let in_announcement = self
+                .cipher
+                .decrypt(match_name, self.id, message.get_payload_bytes())
+                .and_then(|in_announcement| {
+                    Ok(serde_json::from_slice::<InAnnouncement>(&in_announcement)?)
+                });
+
+            let InAnnouncement {
+                id,
+                match_key,
+                protocol,
+            } = match in_announcement {
+                Ok(in_announcement) => in_announcement,
+                Err(error) => {
+                    log::warn!("invalid {match_name} IN announcement: {error}");
+
+                    continue;
+                }
+            };
+
+            let match_lock_key = format!("{match_key}:lock");
+
+            log::debug!("locking IN {match_lock_key}...");
+
+            let match_key_locking = connection
+                .send_packed_command(
+                    redis::cmd("SET")
+                        .arg(&match_lock_key)
+                        .arg("")
+                        .arg("NX")
+                        .arg("EX")
+                        .arg(MATCH_IN_DATA_EXPIRATION_IN_SECONDS),
+                )
+                .await?;
+
+            if !matches!(match_key_locking, redis::Value::Okay) {
+                log::debug!("missed IN {match_lock_key}...");
+
+                continue;
+            }
+
+            let negotiation = TunnelProtocol::current().negotiate(&protocol);
+
+            let in_data = match negotiation {
+                Ok(_) => {
+                    let in_data: Option<Vec<u8>> = connection.get(&match_key).await?;
+
+                    // IN announcing again and again, its data expires only once it is
+                    // gone.
+                    let Some(in_data) = in_data else {
+                        log::debug!("IN {match_key} gone.");
+
+                        continue;
+                    };
+
+                    let in_data = self
+                        .cipher
+                        .decrypt(match_name, self.id, &in_data)
+                        .and_then(|in_data| Ok(serde_json::from_slice::<TInData>(&in_data)?));
+
+                    match in_data {
+                        Ok(in_data) => Some(in_data),
+                        Err(error) => {
+                            log::warn!("invalid {match_name} IN {id} data: {error}");
+
+                            continue;
+                        }
                     }
                 }
+                Err(_) => None,
+            };
+
+            let match_out = create_match_out(
+                &self.identity,
+                &self.labels,
+                out_priority,
+                out_routing_rules,
+                out_routing_priority,
+                self.bandwidth_limit,
+                &out_data,
+            );
+            let tunnel_id = match_out.tunnel_id;
+
+            log::debug!("matching IN {match_lock_key}...");
+
+            self.redis
+                .publish(
+                    &mut connection,
+                    &match_key,
+                    self.cipher.encrypt(
+                        match_name,
+                        self.id,
+                        serde_json::to_string(&match_out)?.as_bytes(),
+                    ),
+                )
+                .await?;
+
+            let Some(in_data) = in_data else {
+                log::warn!("refused IN {match_name} {id}: {}", negotiation.unwrap_err());
+
+                continue;
+            };
+
+            log::info!("matched IN {match_name} {id} as tunnel {tunnel_id}.");
+
+            return Ok(MatchIn {
+                id,
+                tunnel_id,
+                protocol,
+                data: in_data,
+            });
+        }
+    }
+
+    async fn publish_load(&self, load: OutLoad) -> anyhow::Result<()> {
+        self.redis
+            .get_connection()
+            .await?
+            .send_packed_command(
+                redis::cmd("SET")
+                    .arg(self.redis.get_name(&get_out_load_key(&self.id)))
+                    .arg(self.cipher.encrypt(
+                        OUT_LOAD_MATCH_NAME,
+                        self.id,
+                        serde_json::to_string(&load)?.as_bytes(),
+                    ))
+                    .arg("EX")
+                    .arg(OUT_LOAD_EXPIRATION.as_secs()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct InAnnouncement {
+    id: MatchInId,
+    match_key: String,
+    #[serde(default = "TunnelProtocol::legacy")]
+    protocol: TunnelProtocol,
+}
+
+fn get_out_load_key(out_id: &MatchOutId) -> String {
+    format!("out-load:{out_id}")
+}
+
+/// Stream of OUT announcements, read by IN to accept OUT as they come.
+fn get_out_stream_key(match_name: &str) -> String {
+    format!("{match_name}:outs")
+}
+
+/// Redis deployment used as match server.
+pub enum RedisDeployment {
+    Standalone(redis::Client),
+    /// Master monitored by Sentinel, resolved again for every new connection so
+    /// that failovers are followed.
+    Sentinel(tokio::sync::Mutex<redis::sentinel::SentinelClient>),
+    /// Cluster, with keys spread across shards, and channels using sharded
+    /// pub/sub.
+    Cluster(redis::cluster::ClusterClient),
+}
+
+/// Client of the Redis match server, with keys and channels prefixed by an
+/// optional namespace, so that independent deployments can share a Redis.
+///
+/// Commands and subscriptions all go through one multiplexed connection,
+/// subscriptions being shared by name, except blocking reads of OUT
+/// announcements, each on a connection of its own.
+pub struct RedisMatchClient {
+    deployment: RedisDeployment,
+    namespace: Option<String>,
+    /// Created on first use and again once broken.
+    connection: tokio::sync::Mutex<Option<RedisConnection>>,
+}
+
+impl RedisMatchClient {
+    pub fn new(deployment: RedisDeployment, namespace: Option<String>) -> anyhow::Result<Self> {
+        if let Some(namespace) = &namespace {
+            anyhow::ensure!(
+                !namespace.is_empty(),
+                "invalid redis match server namespace {namespace:?}."
+            );
+        }
+
+        Ok(Self {
+            deployment,
+            namespace,
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Key or channel prefixed by the namespace.
+    fn get_name(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{namespace}:{name}"),
+            None => name.to_owned(),
+        }
+    }
+
+    async fn get_connection(&self) -> anyhow::Result<RedisConnection> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(connection) = connection
+            .as_ref()
+            .filter(|connection| !connection.is_broken())
+        {
+            return Ok(connection.clone());
+        }
+
+        let new_connection = self.connect().await?;
+
+        *connection = Some(new_connection.clone());
+
+        Ok(new_connection)
+    }
+
+    async fn connect(&self) -> anyhow::Result<RedisConnection> {
+        let (push_sender, push_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let inner = match &self.deployment {
+            RedisDeployment::Standalone(client) => RedisConnectionInner::Manager(
+                client
+                    .get_connection_manager_with_config(
+                        redis::aio::ConnectionManagerConfig::default()
+                            .set_push_sender(push_sender)
+                            .set_automatic_resubscription(),
+                    )
+                    .await?,
+            ),
+            RedisDeployment::Sentinel(sentinel) => {
+                let client = sentinel.lock().await.async_get_client().await?;
+
+                RedisConnectionInner::Multiplexed(
+                    client
+                        .get_multiplexed_async_connection_with_config(
+                            &redis::AsyncConnectionConfig::new().set_push_sender(push_sender),
+                        )
+                        .await?,
+                )
             }
+            RedisDeployment::Cluster(client) => RedisConnectionInner::Cluster(
+                client
+                    .get_async_connection_with_config(
+                        redis::cluster::ClusterConfig::new().set_push_sender(push_sender),
+                    )
+                    .await?,
+            ),
         };
 
-        let match_task = async {
-            pin_mut!(in_announcement_stream);
+        let connection = RedisConnection {
+            inner,
+            broken: Arc::new(AtomicBool::new(false)),
+            subscribers: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        };
 
-            let cipher = self.cipher.clone();
+        tokio::spawn(dispatch_messages(
+            push_receiver,
+            connection.subscribers.clone(),
+            connection.broken.clone(),
+            !matches!(self.deployment, RedisDeployment::Sentinel(_)),
+        ));
 
-            while let Some(in_announcement) = in_announcement_stream.next().await {
-                let InAnnouncement { id, match_key } = in_announcement?;
+        Ok(connection)
+    }
 
-                let match_lock_key = format!("{match_key}:lock");
+    /// Subscribes to a channel until the subscription is dropped.
+    ///
+    /// Subscriptions to a Sentinel master are not resubscribed on disconnection,
+    /// but end instead, for the caller to subscribe again to the current master.
+    async fn subscribe(&self, name: &str) -> anyhow::Result<RedisSubscription> {
+        let mut connection = self.get_connection().await?;
 
-                log::debug!("locking IN {match_lock_key}...");
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
 
-                let mut connection = connection.lock().await;
+        let subscribers = connection.subscribers.clone();
 
-                let match_key_locking = connection
-                    .send_packed_command(
-                        redis::cmd("SET")
-                            .arg(&match_lock_key)
-                            .arg("")
-                            .arg("NX")
-                            .arg("EX")
-                            .arg(MATCH_TIMEOUT_SECONDS),
-                    )
-                    .await?;
+        let mut subscribers = subscribers.lock().await;
 
-                if !matches!(match_key_locking, redis::Value::Okay) {
-                    log::debug!("missed IN {match_lock_key}...");
+        match subscribers.get_mut(name) {
+            Some(senders) => senders.push(sender),
+            None => {
+                connection.subscribe(name).await?;
 
-                    continue;
-                }
+                subscribers.insert(name.to_owned(), vec![sender]);
+            }
+        }
 
-                let in_data: Vec<u8> = connection.get(&match_key).await?;
-
-                let in_data: TInData = serde_json::from_slice(&decrypt(&cipher, &in_data)?)?;
-
-                let tunnel_id = TunnelId::new();
-
-                log::debug!("matching IN {match_lock_key}...");
-
-                connection
-                    .publish(
-                        match_key,
-                        encrypt(
-                            &cipher,
-                            serde_json::to_string(&MatchOut {
-                                id: self.id,
-                                tunnel_id,
-                                tunnel_labels: self.labels.clone(),
-                                tunnel_priority: out_priority,
-                                routing_rules: out_routing_rules.to_vec(),
-                                routing_priority: out_routing_priority,
-                                data: out_data,
-                            })?
-                            .as_bytes(),
-                        ),
-                    )
-                    .await?;
+        Ok(RedisSubscription {
+            name: name.to_owned(),
+            connection,
+            receiver,
+        })
+    }
 
-                log::info!(
-                    "matched IN {} {id} as tunnel {tunnel_id}.",
-                    <(TInData, TOutData)>::get_match_name(),
-                );
+    /// Publishes to a channel, sharded in a cluster.
+    async fn publish(
+        &self,
+        connection: &mut RedisConnection,
+        channel_name: &str,
+        message: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        connection
+            .send_packed_command(
+                redis::cmd(self.get_publish_command())
+                    .arg(channel_name)
+                    .arg(message),
+            )
+            .await?;
+
+        Ok(())
+    }
 
-                return Ok(MatchIn {
-                    id,
-                    tunnel_id,
-                    data: in_data,
-                });
+    fn get_publish_command(&self) -> &'static str {
+        match self.deployment {
+            RedisDeployment::Cluster(_) => "SPUBLISH",
+            _ => "PUBLISH",
+        }
+    }
+}
+
+/// Delivers messages pushed on a connection to the subscribers of their
+/// channel.
+async fn dispatch_messages(
+    mut push_receiver: tokio::sync::mpsc::UnboundedReceiver<redis::PushInfo>,
+    subscribers: Arc<tokio::sync::Mutex<SubscriberMap>>,
+    broken: Arc<AtomicBool>,
+    resubscribing: bool,
+) {
+    while let Some(push) = push_receiver.recv().await {
+        if push.kind == redis::PushKind::Disconnection {
+            if !resubscribing {
+                broken.store(true, Ordering::Relaxed);
+
+                // Ends all subscriptions.
+                subscribers.lock().await.clear();
             }
 
-            anyhow::bail!("IN announcement subscription ended.");
+            continue;
+        }
+
+        let Some(message) = redis::Msg::from_push_info(push) else {
+            continue;
         };
 
-        tokio::select! {
-            match_in = match_task => match_in,
-            result = announce_task => result,
+        if let Some(senders) = subscribers.lock().await.get(message.get_channel_name()) {
+            for sender in senders {
+                let _ = sender.send(message.clone());
+            }
         }
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct InAnnouncement {
-    id: MatchInId,
-    match_key: String,
+type SubscriberMap = HashMap<String, Vec<tokio::sync::mpsc::UnboundedSender<redis::Msg>>>;
+
+struct RedisSubscription {
+    name: String,
+    connection: RedisConnection,
+    receiver: tokio::sync::mpsc::UnboundedReceiver<redis::Msg>,
 }
 
-async fn get_connection_and_subscribe<T: serde::de::DeserializeOwned>(
-    redis: &redis::Client,
-    channel_name: &str,
-    cipher: Option<Arc<aes_gcm::Aes256Gcm>>,
-) -> anyhow::Result<(
-    redis::aio::ConnectionManager,
-    impl Stream<Item = anyhow::Result<T>>,
-)> {
-    let (push_sender, mut push_receiver) = tokio::sync::mpsc::unbounded_channel();
-
-    let mut connection = redis
-        .get_connection_manager_with_config(
-            redis::aio::ConnectionManagerConfig::default()
-                .set_push_sender(push_sender)
-                .set_automatic_resubscription(),
-        )
-        .await?;
+impl Stream for RedisSubscription {
+    type Item = redis::Msg;
 
-    if channel_name.contains("*") {
-        connection.psubscribe(channel_name).await?;
-    } else {
-        connection.subscribe(channel_name).await?;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
     }
+}
 
-    let subscription_stream = async_stream::try_stream!({
-        while let Some(push) = push_receiver.recv().await {
-            let Some(message) = redis::Msg::from_push_info(push) else {
-                continue;
+impl Drop for RedisSubscription {
+    fn drop(&mut self) {
+        self.receiver.close();
+
+        let name = std::mem::take(&mut self.name);
+        let mut connection = self.connection.clone();
+
+        // Unsubscribes once the last subscriber of the name is gone, serialized
+        // with subscribing by the lock.
+        tokio::spawn(async move {
+            let subscribers = connection.subscribers.clone();
+
+            let mut subscribers = subscribers.lock().await;
+
+            let Some(senders) = subscribers.get_mut(&name) else {
+                return;
             };
 
-            let value: T =
-                serde_json::from_slice(decrypt(&cipher, message.get_payload_bytes())?.as_slice())?;
+            senders.retain(|sender| !sender.is_closed());
 
-            yield value;
-        }
-    });
+            if senders.is_empty() {
+                subscribers.remove(&name);
+
+                if let Err(error) = connection.unsubscribe(&name).await {
+                    log::debug!("failed to unsubscribe {name}: {error}");
+                }
+            }
+        });
+    }
+}
 
-    Ok((connection, subscription_stream))
+#[derive(Clone)]
+struct RedisConnection {
+    inner: RedisConnectionInner,
+    /// Set once a command failed as the node is gone or no longer master, for
+    /// a connection to the current master to replace it.
+    broken: Arc<AtomicBool>,
+    subscribers: Arc<tokio::sync::Mutex<SubscriberMap>>,
 }
 
-fn create_cipher(key: Option<String>) -> Option<Arc<aes_gcm::Aes256Gcm>> {
-    key.map(|key| {
-        let key = sha2::Sha256::digest(key.as_bytes());
-        let key = aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&key);
-        Arc::new(aes_gcm::Aes256Gcm::new(key))
-    })
+#[derive(Clone)]
+enum RedisConnectionInner {
+    Manager(redis::aio::ConnectionManager),
+    Multiplexed(redis::aio::MultiplexedConnection),
+    Cluster(redis::cluster_async::ClusterConnection),
 }
 
-fn encrypt(cipher: &Option<Arc<aes_gcm::Aes256Gcm>>, data: &[u8]) -> Vec<u8> {
-    if let Some(cipher) = cipher {
-        let nonce = aes_gcm::Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
+impl RedisConnection {
+    async fn send_packed_command(&mut self, cmd: &redis::Cmd) -> redis::RedisResult<redis::Value> {
+        self.req_packed_command(cmd).await
+    }
+
+    /// Connection managers and cluster connections reconnect by themselves,
+    /// so only plain connections (to a Sentinel master) break.
+    fn is_broken(&self) -> bool {
+        matches!(self.inner, RedisConnectionInner::Multiplexed(_))
+            && self.broken.load(Ordering::Relaxed)
+    }
+
+    fn check_result<T>(&self, result: &redis::RedisResult<T>) {
+        if let Err(error) = result {
+            if error.is_unrecoverable_error()
+                || matches!(
+                    error.kind(),
+                    redis::ErrorKind::ReadOnly | redis::ErrorKind::MasterDown
+                )
+            {
+                self.broken.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Subscribes to a channel, sharded in a cluster.
+    async fn subscribe(&mut self, name: &str) -> redis::RedisResult<()> {
+        match &mut self.inner {
+            RedisConnectionInner::Manager(connection) => connection.subscribe(name).await,
+            RedisConnectionInner::Multiplexed(connection) => connection.subscribe(name).await,
+            RedisConnectionInner::Cluster(connection) => connection.ssubscribe(name).await,
+        }
+    }
 
-        let data = cipher.encrypt(&nonce, data).unwrap();
+    async fn unsubscribe(&mut self, name: &str) -> redis::RedisResult<()> {
+        match &mut self.inner {
+            RedisConnectionInner::Manager(connection) => connection.unsubscribe(name).await,
+            RedisConnectionInner::Multiplexed(connection) => connection.unsubscribe(name).await,
+            RedisConnectionInner::Cluster(connection) => connection.sunsubscribe(name).await,
+        }
+    }
+}
+
+impl redis::aio::ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        Box::pin(async move {
+            let result = match &mut self.inner {
+                RedisConnectionInner::Manager(connection) => {
+                    connection.req_packed_command(cmd).await
+                }
+                RedisConnectionInner::Multiplexed(connection) => {
+                    connection.req_packed_command(cmd).await
+                }
+                RedisConnectionInner::Cluster(connection) => {
+                    connection.req_packed_command(cmd).await
+                }
+            };
+
+            self.check_result(&result);
+
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async move {
+            let result = match &mut self.inner {
+                RedisConnectionInner::Manager(connection) => {
+                    connection.req_packed_commands(cmd, offset, count).await
+                }
+                RedisConnectionInner::Multiplexed(connection) => {
+                    connection.req_packed_commands(cmd, offset, count).await
+                }
+                RedisConnectionInner::Cluster(connection) => {
+                    connection.req_packed_commands(cmd, offset, count).await
+                }
+            };
+
+            self.check_result(&result);
+
+            result
+        })
+    }
 
-        nonce.iter().copied().chain(data).collect_vec()
-    } else {
-        data.to_vec()
+    fn get_db(&self) -> i64 {
+        match &self.inner {
+            RedisConnectionInner::Manager(connection) => connection.get_db(),
+            RedisConnectionInner::Multiplexed(connection) => connection.get_db(),
+            RedisConnectionInner::Cluster(connection) => connection.get_db(),
+        }
     }
 }
 
-fn decrypt(cipher: &Option<Arc<aes_gcm::Aes256Gcm>>, data: &[u8]) -> anyhow::Result<Vec<u8>> {
-    if let Some(cipher) = cipher {
-        let (nonce, data) = data.split_at(12);
+#[cfg(test)]
+mod tests {
+    use crate::match_server::testing::{TestInData, TestOutData};
+
+    use super::*;
+
+    fn new_client(namespace: Option<&str>) -> anyhow::Result<RedisMatchClient> {
+        RedisMatchClient::new(
+            RedisDeployment::Standalone(redis::Client::open("redis://redis/")?),
+            namespace.map(str::to_owned),
+        )
+    }
+
+    /// Matches IN and OUT twice through clients of a namespace of their own, so
+    /// that OUT is accepted once and matched again.
+    async fn match_in_and_out(
+        new_deployment: impl Fn() -> anyhow::Result<RedisDeployment>,
+    ) -> anyhow::Result<()> {
+        let namespace = format!("test-{}", uuid::Uuid::new_v4());
+
+        let in_match_server = RedisInMatchServer::new(
+            RedisMatchClient::new(new_deployment()?, Some(namespace.clone()))?,
+            vec!["key".to_owned()],
+        )?;
+
+        let out_match_server = RedisOutMatchServer::new(
+            RedisMatchClient::new(new_deployment()?, Some(namespace))?,
+            vec!["key".to_owned()],
+            OutIdentity::load_or_generate(None)?,
+            Vec::new(),
+            BandwidthLimit::default(),
+        )
+        .await?;
+
+        let out_task = async {
+            for _ in 0..2 {
+                let match_in = out_match_server
+                    .match_in::<TestInData, TestOutData>(
+                        TestOutData {
+                            name: "out".to_owned(),
+                        },
+                        None,
+                        &[],
+                        0,
+                    )
+                    .await?;
+
+                assert_eq!(match_in.data.name, "in");
+            }
+
+            anyhow::Ok(())
+        };
 
-        let nonce = aes_gcm::Nonce::from_slice(nonce);
+        let in_task = async {
+            let out_id = in_match_server
+                .accept_out::<TestInData, TestOutData>()
+                .await?;
+
+            for _ in 0..2 {
+                let match_out = in_match_server
+                    .match_out::<TestInData, TestOutData>(
+                        out_id,
+                        TestInData {
+                            name: "in".to_owned(),
+                        },
+                    )
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("OUT {out_id} gone."))?;
 
-        cipher
-            .decrypt(nonce, data)
-            .map_err(|error| anyhow::anyhow!("redis match decryption failed, wrong key? {error}"))
-    } else {
-        Ok(data.to_vec())
+                assert_eq!(match_out.data.name, "out");
+            }
+
+            anyhow::Ok(())
+        };
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::try_join!(out_task, in_task)
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_names_with_namespace() -> anyhow::Result<()> {
+        assert_eq!(new_client(None)?.get_name("quic:outs"), "quic:outs");
+        assert_eq!(
+            new_client(Some("fleet"))?.get_name("quic:outs"),
+            "fleet:quic:outs"
+        );
+
+        assert!(new_client(Some("")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn publishes_sharded_in_cluster() -> anyhow::Result<()> {
+        assert_eq!(new_client(None)?.get_publish_command(), "PUBLISH");
+        assert_eq!(
+            RedisMatchClient::new(
+                RedisDeployment::Cluster(redis::cluster::ClusterClient::new(vec![
+                    "redis://node-1/"
+                ])?),
+                None,
+            )?
+            .get_publish_command(),
+            "SPUBLISH"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis-server at REDIS_URL"]
+    async fn matches_on_standalone() -> anyhow::Result<()> {
+        let url = std::env::var("REDIS_URL")?;
+
+        match_in_and_out(|| {
+            Ok(RedisDeployment::Standalone(redis::Client::open(format!(
+                "{url}?protocol=resp3"
+            ))?))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a redis cluster at REDIS_CLUSTER_URLS (comma separated)"]
+    async fn matches_on_cluster() -> anyhow::Result<()> {
+        let urls = std::env::var("REDIS_CLUSTER_URLS")?;
+
+        match_in_and_out(|| {
+            Ok(RedisDeployment::Cluster(
+                redis::cluster::ClusterClientBuilder::new(urls.split(','))
+                    .use_protocol(redis::ProtocolVersion::RESP3)
+                    .build()?,
+            ))
+        })
+        .await
     }
 }
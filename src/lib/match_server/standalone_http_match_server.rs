@@ -0,0 +1,674 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Full, Limited};
+use itertools::Itertools as _;
+
+use crate::match_server::MatchOutId;
+
+/// Longest a long poll waits, and an OUT or its load is kept without refresh.
+const WAIT_MAX: Duration = Duration::from_secs(60);
+const TTL_MAX: Duration = Duration::from_secs(60);
+
+/// Match results not taken by IN in time (e.g. IN gave up) are dropped.
+const MATCH_RESULT_EXPIRATION: Duration = Duration::from_secs(60);
+
+const EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const BODY_SIZE_MAX: usize = 1024 * 1024;
+
+/// Serves the HTTP rendezvous API of `HttpMatchClient` over plain HTTP, to be
+/// put behind a reverse proxy terminating TLS. Requests authenticate with any
+/// of the access keys as a bearer token.
+pub async fn serve_http(
+    listener: tokio::net::TcpListener,
+    access_keys: Arc<Vec<String>>,
+) -> anyhow::Result<()> {
+    let state = Arc::new(HttpMatchState::new());
+
+    tokio::spawn({
+        let state = state.clone();
+
+        async move {
+            loop {
+                tokio::time::sleep(EXPIRATION_CHECK_INTERVAL).await;
+
+                state.remove_expired();
+            }
+        }
+    });
+
+    loop {
+        let (stream, remote_address) = listener.accept().await?;
+
+        tokio::spawn({
+            let state = state.clone();
+            let access_keys = access_keys.clone();
+
+            async move {
+                let service = hyper::service::service_fn(move |request| {
+                    let state = state.clone();
+                    let access_keys = access_keys.clone();
+
+                    async move {
+                        Ok::<_, Infallible>(handle_request(&state, &access_keys, request).await)
+                    }
+                });
+
+                if let Err(error) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                    .await
+                {
+                    log::debug!("HTTP connection from {remote_address} errored: {error}");
+                }
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    state: &HttpMatchState,
+    access_keys: &[String],
+    request: http::Request<hyper::body::Incoming>,
+) -> http::Response<Full<Bytes>> {
+    if !is_authorized(&request, access_keys) {
+        return respond(http::StatusCode::UNAUTHORIZED, None);
+    }
+
+    match route(state, request).await {
+        Ok(response) => response,
+        Err(error) => {
+            log::debug!("bad HTTP match request: {error}");
+
+            respond(http::StatusCode::BAD_REQUEST, None)
+        }
+    }
+}
+
+async fn route(
+    state: &HttpMatchState,
+    request: http::Request<hyper::body::Incoming>,
+) -> anyhow::Result<http::Response<Full<Bytes>>> {
+    let method = request.method().clone();
+
+    let path = request.uri().path().to_owned();
+    let query = url::form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect_vec();
+
+    let segments = path.trim_matches('/').split('/').collect_vec();
+
+    let wait = get_seconds(&query, "wait")?.map_or(Duration::ZERO, |wait| wait.min(WAIT_MAX));
+    let ttl = get_seconds(&query, "ttl")?.map(|ttl| ttl.min(TTL_MAX));
+
+    let response = match (method, segments.as_slice()) {
+        (http::Method::PUT, ["out-loads", out_id]) => {
+            let out_id = out_id.parse()?;
+            let ttl = ttl.ok_or_else(|| anyhow::anyhow!("ttl is required."))?;
+            let load = read_body(request).await?;
+
+            state.update(|entries| {
+                entries
+                    .out_loads
+                    .insert(out_id, (load, Instant::now() + ttl));
+            });
+
+            respond(http::StatusCode::NO_CONTENT, None)
+        }
+        (http::Method::GET, ["out-loads", out_id]) => {
+            let out_id: MatchOutId = out_id.parse()?;
+
+            let load = state
+                .entries
+                .lock()
+                .unwrap()
+                .out_loads
+                .get(&out_id)
+                .filter(|(_, expires_at)| *expires_at > Instant::now())
+                .map(|(load, _)| load.clone());
+
+            match load {
+                Some(load) => respond(http::StatusCode::OK, Some(load)),
+                None => respond(http::StatusCode::NOT_FOUND, None),
+            }
+        }
+        (http::Method::GET, [name, "outs"]) => {
+            let excluded: Vec<MatchOutId> = query
+                .iter()
+                .filter(|(key, _)| key == "exclude")
+                .map(|(_, out_id)| out_id.parse())
+                .try_collect()?;
+
+            let out_ids = state
+                .wait_for(wait, |entries| {
+                    let now = Instant::now();
+
+                    let out_ids = entries
+                        .outs
+                        .iter()
+                        .filter(|((out_name, out_id), expires_at)| {
+                            out_name == name && **expires_at > now && !excluded.contains(out_id)
+                        })
+                        .map(|((_, out_id), _)| *out_id)
+                        .collect_vec();
+
+                    (!out_ids.is_empty()).then_some(out_ids)
+                })
+                .await;
+
+            match out_ids {
+                Some(out_ids) => respond(
+                    http::StatusCode::OK,
+                    Some(serde_json::to_vec(&out_ids)?.into()),
+                ),
+                None => respond(http::StatusCode::NO_CONTENT, None),
+            }
+        }
+        (http::Method::PUT, [name, "outs", out_id]) => {
+            let out_id = out_id.parse()?;
+            let ttl = ttl.ok_or_else(|| anyhow::anyhow!("ttl is required."))?;
+
+            state.update(|entries| {
+                entries
+                    .outs
+                    .insert((name.to_string(), out_id), Instant::now() + ttl);
+            });
+
+            respond(http::StatusCode::NO_CONTENT, None)
+        }
+        (http::Method::POST, [name, "outs", out_id, "ins", match_key]) => {
+            let out_id = out_id.parse()?;
+            let announcement = read_body(request).await?;
+
+            let announced = state.update(|entries| {
+                let key = (name.to_string(), out_id);
+
+                if !entries.is_out_active(&key) {
+                    return false;
+                }
+
+                entries.ins.entry(key).or_default().push_back(announcement);
+
+                entries.matches.insert(
+                    (name.to_string(), match_key.to_string()),
+                    PendingMatch {
+                        out_id,
+                        result: None,
+                        expires_at: Instant::now() + MATCH_RESULT_EXPIRATION,
+                    },
+                );
+
+                true
+            });
+
+            if announced {
+                respond(http::StatusCode::NO_CONTENT, None)
+            } else {
+                respond(http::StatusCode::NOT_FOUND, None)
+            }
+        }
+        (http::Method::GET, [name, "outs", out_id, "ins"]) => {
+            let key = (name.to_string(), out_id.parse()?);
+
+            let announcement = state
+                .wait_for(wait, |entries| {
+                    entries
+                        .ins
+                        .get_mut(&key)
+                        .and_then(|announcements| announcements.pop_front())
+                })
+                .await;
+
+            match announcement {
+                Some(announcement) => respond(http::StatusCode::OK, Some(announcement)),
+                None => respond(http::StatusCode::NO_CONTENT, None),
+            }
+        }
+        (http::Method::PUT, [name, "matches", match_key]) => {
+            let result = read_body(request).await?;
+
+            let stored = state.update(|entries| {
+                match entries
+                    .matches
+                    .get_mut(&(name.to_string(), match_key.to_string()))
+                {
+                    Some(pending_match) => {
+                        pending_match.result = Some(result);
+
+                        true
+                    }
+                    None => false,
+                }
+            });
+
+            if stored {
+                respond(http::StatusCode::NO_CONTENT, None)
+            } else {
+                respond(http::StatusCode::NOT_FOUND, None)
+            }
+        }
+        (http::Method::GET, [name, "matches", match_key]) => {
+            let key = (name.to_string(), match_key.to_string());
+
+            // `Some(None)` if the OUT expired before storing the result.
+            let result = state
+                .wait_for(wait, |entries| {
+                    let pending_match = entries.matches.get(&key)?;
+
+                    if pending_match.result.is_none()
+                        && entries.is_out_active(&(key.0.clone(), pending_match.out_id))
+                    {
+                        return None;
+                    }
+
+                    Some(entries.matches.remove(&key).unwrap().result)
+                })
+                .await;
+
+            match result {
+                Some(Some(result)) => respond(http::StatusCode::OK, Some(result)),
+                Some(None) => respond(http::StatusCode::NOT_FOUND, None),
+                None => {
+                    if state.entries.lock().unwrap().matches.contains_key(&key) {
+                        respond(http::StatusCode::NO_CONTENT, None)
+                    } else {
+                        respond(http::StatusCode::NOT_FOUND, None)
+                    }
+                }
+            }
+        }
+        (http::Method::DELETE, [name, "matches", match_key]) => {
+            // Withdrawn by IN giving up, the result is handed over if OUT posted
+            // it already.
+            let pending_match = state.update(|entries| {
+                entries
+                    .matches
+                    .remove(&(name.to_string(), match_key.to_string()))
+            });
+
+            match pending_match.and_then(|pending_match| pending_match.result) {
+                Some(result) => respond(http::StatusCode::OK, Some(result)),
+                None => respond(http::StatusCode::NO_CONTENT, None),
+            }
+        }
+        _ => respond(http::StatusCode::NOT_FOUND, None),
+    };
+
+    Ok(response)
+}
+
+fn is_authorized(request: &http::Request<hyper::body::Incoming>, access_keys: &[String]) -> bool {
+    let Some(token) = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    // Compared through MACs, in constant time.
+    let key = ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &ring::rand::SystemRandom::new())
+        .unwrap();
+    let tag = ring::hmac::sign(&key, token.as_bytes());
+
+    access_keys
+        .iter()
+        .any(|access_key| ring::hmac::verify(&key, access_key.as_bytes(), tag.as_ref()).is_ok())
+}
+
+fn get_seconds(query: &[(String, String)], name: &str) -> anyhow::Result<Option<Duration>> {
+    query
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, seconds)| Ok(Duration::from_secs(seconds.parse()?)))
+        .transpose()
+}
+
+async fn read_body(request: http::Request<hyper::body::Incoming>) -> anyhow::Result<Bytes> {
+    Ok(Limited::new(request.into_body(), BODY_SIZE_MAX)
+        .collect()
+        .await
+        .map_err(|error| anyhow::anyhow!(error))?
+        .to_bytes())
+}
+
+fn respond(status: http::StatusCode, body: Option<Bytes>) -> http::Response<Full<Bytes>> {
+    let mut response = http::Response::new(Full::new(body.unwrap_or_default()));
+
+    *response.status_mut() = status;
+
+    response
+}
+
+struct HttpMatchState {
+    entries: Mutex<HttpMatchEntries>,
+    /// Notified on every update, waking long polls to check again.
+    updated: tokio::sync::Notify,
+}
+
+impl HttpMatchState {
+    fn new() -> Self {
+        Self {
+            entries: Mutex::new(HttpMatchEntries::default()),
+            updated: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn update<T>(&self, update: impl FnOnce(&mut HttpMatchEntries) -> T) -> T {
+        let value = update(&mut self.entries.lock().unwrap());
+
+        self.updated.notify_waiters();
+
+        value
+    }
+
+    /// Takes a value once available, `None` if not within `wait`.
+    async fn wait_for<T>(
+        &self,
+        wait: Duration,
+        mut take: impl FnMut(&mut HttpMatchEntries) -> Option<T>,
+    ) -> Option<T> {
+        let deadline = tokio::time::Instant::now() + wait;
+
+        loop {
+            let updated = self.updated.notified();
+
+            tokio::pin!(updated);
+
+            // Registered before checking, not to miss an update in between.
+            updated.as_mut().enable();
+
+            if let Some(value) = take(&mut self.entries.lock().unwrap()) {
+                return Some(value);
+            }
+
+            tokio::time::timeout_at(deadline, updated).await.ok()?;
+        }
+    }
+
+    fn remove_expired(&self) {
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let out_count = entries.outs.len();
+
+        entries.outs.retain(|(name, out_id), expires_at| {
+            let expired = *expires_at <= now;
+
+            if expired {
+                log::info!("{name} OUT {out_id} expired.");
+            }
+
+            !expired
+        });
+
+        // Waiters on the match results of expired OUT are answered with `404`.
+        let expired = entries.outs.len() < out_count;
+
+        let HttpMatchEntries {
+            outs,
+            ins,
+            matches,
+            out_loads,
+        } = &mut *entries;
+
+        ins.retain(|key, _| outs.contains_key(key));
+        matches.retain(|_, pending_match| pending_match.expires_at > now);
+        out_loads.retain(|_, (_, expires_at)| *expires_at > now);
+
+        drop(entries);
+
+        if expired {
+            self.updated.notify_waiters();
+        }
+    }
+}
+
+#[derive(Default)]
+struct HttpMatchEntries {
+    /// OUT waiting for IN, with the time it expires at.
+    outs: HashMap<(String, MatchOutId), Instant>,
+    /// IN announcements queued for OUT.
+    ins: HashMap<(String, MatchOutId), VecDeque<Bytes>>,
+    matches: HashMap<(String, String), PendingMatch>,
+    out_loads: HashMap<MatchOutId, (Bytes, Instant)>,
+}
+
+impl HttpMatchEntries {
+    fn is_out_active(&self, key: &(String, MatchOutId)) -> bool {
+        self.outs
+            .get(key)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+}
+
+/// IN waiting for the result of matching an OUT.
+struct PendingMatch {
+    out_id: MatchOutId,
+    result: Option<Bytes>,
+    expires_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{
+        bandwidth::BandwidthLimit,
+        match_server::{
+            http_match_server::{HttpInMatchServer, HttpMatchClient, HttpOutMatchServer},
+            testing::{TestInData, TestOutData},
+            InMatchServer as _, OutIdentity, OutLoad, OutMatchServerTrait as _,
+        },
+    };
+
+    use super::*;
+
+    const ACCESS_KEY: &str = "access key";
+    const KEY: &str = "key";
+
+    #[tokio::test]
+    async fn matches_in_and_out() -> anyhow::Result<()> {
+        let address = spawn_http_match_server().await?;
+
+        let out_match_server = HttpOutMatchServer::new(
+            new_client(address, Some(ACCESS_KEY))?,
+            vec![KEY.to_owned()],
+            OutIdentity::load_or_generate(None)?,
+            Vec::new(),
+            BandwidthLimit::default(),
+        )?;
+
+        let in_match_server =
+            HttpInMatchServer::new(new_client(address, Some(ACCESS_KEY))?, vec![KEY.to_owned()])?;
+
+        let out_task = async {
+            out_match_server
+                .match_in::<TestInData, TestOutData>(
+                    TestOutData {
+                        name: "out".to_owned(),
+                    },
+                    None,
+                    &[],
+                    0,
+                )
+                .await
+        };
+
+        let in_task = async {
+            let out_id = in_match_server
+                .accept_out::<TestInData, TestOutData>()
+                .await?;
+
+            in_match_server
+                .match_out::<TestInData, TestOutData>(
+                    out_id,
+                    TestInData {
+                        name: "in".to_owned(),
+                    },
+                )
+                .await
+        };
+
+        let (match_in, match_out) = tokio::try_join!(out_task, in_task)?;
+
+        let match_out = match_out.ok_or_else(|| anyhow::anyhow!("OUT gone."))?;
+
+        assert_eq!(match_in.data.name, "in");
+        assert_eq!(match_out.data.name, "out");
+        assert!(match_in.tunnel_id == match_out.tunnel_id);
+
+        let load = OutLoad {
+            tunnels: 1,
+            streams: 2,
+            ..OutLoad::default()
+        };
+
+        out_match_server.publish_load(load.clone()).await?;
+
+        assert_eq!(
+            in_match_server.get_out_load(match_out.id).await?,
+            Some(load)
+        );
+        assert_eq!(in_match_server.get_out_load(MatchOutId::new()).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_out_not_replying() -> anyhow::Result<()> {
+        let address = spawn_http_match_server().await?;
+
+        let out_id = MatchOutId::new();
+
+        // Registered, but never taking IN announcements.
+        reqwest::Client::new()
+            .put(format!("http://{address}/test/outs/{out_id}?ttl=60"))
+            .bearer_auth(ACCESS_KEY)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let in_match_server =
+            HttpInMatchServer::new(new_client(address, Some(ACCESS_KEY))?, vec![KEY.to_owned()])?;
+
+        let match_out = tokio::time::timeout(
+            Duration::from_secs(10),
+            in_match_server.match_out::<TestInData, TestOutData>(
+                out_id,
+                TestInData {
+                    name: "in".to_owned(),
+                },
+            ),
+        )
+        .await??;
+
+        assert!(match_out.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdraws_abandoned_match() -> anyhow::Result<()> {
+        let address = spawn_http_match_server().await?;
+        let client = reqwest::Client::new();
+        let out_id = MatchOutId::new();
+
+        let request = |method: reqwest::Method, path: &str| {
+            client
+                .request(method, format!("http://{address}/test/{path}"))
+                .bearer_auth(ACCESS_KEY)
+        };
+
+        request(reqwest::Method::PUT, &format!("outs/{out_id}?ttl=60"))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        for match_key in ["abandoned", "replied"] {
+            request(
+                reqwest::Method::POST,
+                &format!("outs/{out_id}/ins/{match_key}"),
+            )
+            .body("announcement")
+            .send()
+            .await?
+            .error_for_status()?;
+        }
+
+        let withdrawn = request(reqwest::Method::DELETE, "matches/abandoned")
+            .send()
+            .await?;
+
+        assert_eq!(withdrawn.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // OUT replying late learns the match was withdrawn.
+        let late_reply = request(reqwest::Method::PUT, "matches/abandoned")
+            .body("result")
+            .send()
+            .await?;
+
+        assert_eq!(late_reply.status(), reqwest::StatusCode::NOT_FOUND);
+
+        request(reqwest::Method::PUT, "matches/replied")
+            .body("result")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // OUT replying just before the withdrawal still gets its reply taken.
+        let withdrawn = request(reqwest::Method::DELETE, "matches/replied")
+            .send()
+            .await?;
+
+        assert_eq!(withdrawn.status(), reqwest::StatusCode::OK);
+        assert_eq!(withdrawn.bytes().await?, "result");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_missing_access_key() -> anyhow::Result<()> {
+        let address = spawn_http_match_server().await?;
+
+        for access_key in [None, Some("wrong access key")] {
+            let in_match_server =
+                HttpInMatchServer::new(new_client(address, access_key)?, vec![KEY.to_owned()])?;
+
+            let error = in_match_server
+                .get_out_load(MatchOutId::new())
+                .await
+                .unwrap_err();
+
+            assert_eq!(
+                error.downcast_ref::<reqwest::Error>().unwrap().status(),
+                Some(reqwest::StatusCode::UNAUTHORIZED)
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn spawn_http_match_server() -> anyhow::Result<SocketAddr> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        tokio::spawn(serve_http(listener, Arc::new(vec![ACCESS_KEY.to_owned()])));
+
+        Ok(address)
+    }
+
+    fn new_client(
+        address: SocketAddr,
+        access_key: Option<&str>,
+    ) -> anyhow::Result<HttpMatchClient> {
+        HttpMatchClient::new(&format!("http://{address}/"), access_key)
+    }
+}
@@ -0,0 +1,660 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use quinn::crypto::rustls::QuicServerConfig;
+
+use crate::{
+    match_server::{
+        p2p_match_protocol::{
+            create_p2p_match_server_config, read_frame, verify_p2p_match_connection, write_frame,
+            P2pMatchRequest, P2pMatchResponse,
+        },
+        standalone_http_match_server::serve_http,
+        MatchOutId, OUT_LOAD_EXPIRATION,
+    },
+    tunnel::{
+        common::get_cert_fingerprint,
+        quic::{quinn::create_server_endpoint, QuicCongestionController, QuicTransportConfig},
+        TunnelTlsConfig,
+    },
+};
+
+/// OUT stays announced for a while after its last stream waiting for IN ends,
+/// as it opens the next one only after setting up the tunnel just matched.
+const OUT_EXPIRATION: Duration = Duration::from_secs(5);
+const OUT_EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time for a connection to authenticate before it is closed.
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+const UNAUTHENTICATED_ERROR_CODE: u32 = 1;
+
+pub struct Options {
+    pub listen_address: SocketAddr,
+    /// Address to serve the HTTP rendezvous API on, see `serve_http`.
+    pub http_listen_address: Option<SocketAddr>,
+    pub tls: TunnelTlsConfig,
+    /// Keys IN and OUT authenticate with, any of them accepted for rotation.
+    pub access_keys: Vec<String>,
+}
+
+pub async fn up(
+    Options {
+        listen_address,
+        http_listen_address,
+        tls,
+        access_keys,
+    }: Options,
+) -> anyhow::Result<()> {
+    log::info!("starting match server...");
+
+    anyhow::ensure!(
+        !access_keys.is_empty() && access_keys.iter().all(|key| !key.is_empty()),
+        "match server access key is required."
+    );
+
+    let (cert, key) = tls.load_or_generate_cert()?;
+
+    log::info!("certificate fingerprint {}.", get_cert_fingerprint(&cert)?);
+
+    let endpoint = create_endpoint(listen_address, &cert, &key)?;
+
+    log::info!("match server listening on {listen_address}...");
+
+    let Some(http_listen_address) = http_listen_address else {
+        serve(endpoint, access_keys).await;
+
+        return Ok(());
+    };
+
+    let listener = tokio::net::TcpListener::bind(http_listen_address).await?;
+
+    log::info!("HTTP match server listening on {http_listen_address}...");
+
+    tokio::select! {
+        _ = serve(endpoint, access_keys.clone()) => Ok(()),
+        result = serve_http(listener, Arc::new(access_keys)) => result,
+    }
+}
+
+fn create_endpoint(
+    listen_address: SocketAddr,
+    cert: &str,
+    key: &str,
+) -> anyhow::Result<quinn::Endpoint> {
+    create_server_endpoint(
+        std::net::UdpSocket::bind(listen_address)?,
+        Arc::new(QuicServerConfig::try_from(create_p2p_match_server_config(
+            cert, key,
+        )?)?),
+        &QuicTransportConfig::default(),
+        QuicCongestionController::Cubic,
+    )
+}
+
+async fn serve(endpoint: quinn::Endpoint, access_keys: Vec<String>) {
+    let state = Arc::new(MatchState::new());
+    let access_keys = Arc::new(access_keys);
+
+    tokio::spawn({
+        let state = state.clone();
+
+        async move {
+            loop {
+                tokio::time::sleep(OUT_EXPIRATION_CHECK_INTERVAL).await;
+
+                state.remove_expired_outs();
+            }
+        }
+    });
+
+    while let Some(incoming) = endpoint.accept().await {
+        tokio::spawn(handle_connection(
+            state.clone(),
+            access_keys.clone(),
+            incoming,
+        ));
+    }
+}
+
+async fn handle_connection(
+    state: Arc<MatchState>,
+    access_keys: Arc<Vec<String>>,
+    incoming: quinn::Incoming,
+) {
+    let remote_address = incoming.remote_address();
+
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(error) => {
+            log::warn!("failed to accept connection from {remote_address}: {error}");
+
+            return;
+        }
+    };
+
+    let authentication = tokio::time::timeout(
+        AUTHENTICATION_TIMEOUT,
+        authenticate(&connection, &access_keys),
+    )
+    .await
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out.")));
+
+    if let Err(error) = authentication {
+        log::warn!("connection from {remote_address} not authenticated: {error}");
+
+        connection.close(UNAUTHENTICATED_ERROR_CODE.into(), b"unauthenticated");
+
+        return;
+    }
+
+    log::info!("accepted connection from {remote_address}.");
+
+    loop {
+        let (send_stream, recv_stream) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(error) => {
+                log::info!("connection from {remote_address} closed: {error}");
+
+                return;
+            }
+        };
+
+        tokio::spawn({
+            let state = state.clone();
+
+            async move {
+                if let Err(error) = handle_stream(&state, send_stream, recv_stream).await {
+                    log::warn!("request from {remote_address} errored: {error}");
+                }
+            }
+        });
+    }
+}
+
+/// Expects the first stream of the connection to authenticate it, before any
+/// other request is handled.
+async fn authenticate(
+    connection: &quinn::Connection,
+    access_keys: &[String],
+) -> anyhow::Result<()> {
+    let (mut send_stream, mut recv_stream) = connection.accept_bi().await?;
+
+    let Some(P2pMatchRequest::Authenticate { mac }) = read_frame(&mut recv_stream).await? else {
+        anyhow::bail!("request before authentication.");
+    };
+
+    anyhow::ensure!(
+        verify_p2p_match_connection(connection, access_keys, &mac)?,
+        "wrong access key."
+    );
+
+    write_frame(&mut send_stream, &P2pMatchResponse::Authenticated).await?;
+
+    send_stream.finish()?;
+
+    Ok(())
+}
+
+async fn handle_stream(
+    state: &MatchState,
+    mut send_stream: quinn::SendStream,
+    mut recv_stream: quinn::RecvStream,
+) -> anyhow::Result<()> {
+    let Some(request) = read_frame::<P2pMatchRequest>(&mut recv_stream).await? else {
+        return Ok(());
+    };
+
+    match request {
+        P2pMatchRequest::AcceptOut { name, excluded } => {
+            let out_id = tokio::select! {
+                out_id = state.accept_out(&name, &excluded) => out_id?,
+                _ = cancelled(&mut recv_stream) => return Ok(()),
+            };
+
+            write_frame(&mut send_stream, &P2pMatchResponse::Out { id: out_id }).await?;
+        }
+        P2pMatchRequest::MatchOut {
+            name,
+            out_id,
+            announcement,
+        } => {
+            let out = state.outs.lock().unwrap().get(&(name, out_id)).cloned();
+
+            let response = match out {
+                Some(out) => {
+                    let (reply_sender, reply_receiver) = tokio::sync::oneshot::channel();
+
+                    out.in_sender
+                        .send(PendingIn {
+                            announcement,
+                            reply_sender,
+                        })
+                        .ok();
+
+                    // Not holding the OUT, whose pending IN are dropped once expired.
+                    drop(out);
+
+                    tokio::select! {
+                        reply = reply_receiver => match reply {
+                            Ok(payload) => P2pMatchResponse::Matched { payload },
+                            Err(_) => P2pMatchResponse::OutGone,
+                        },
+                        _ = cancelled(&mut recv_stream) => return Ok(()),
+                    }
+                }
+                None => P2pMatchResponse::OutGone,
+            };
+
+            write_frame(&mut send_stream, &response).await?;
+        }
+        P2pMatchRequest::MatchIn { name, out_id } => {
+            let registration = state.register_out(name, out_id);
+
+            loop {
+                let pending_in = tokio::select! {
+                    biased;
+                    _ = cancelled(&mut recv_stream) => return Ok(()),
+                    pending_in = async {
+                        registration.out.in_receiver.lock().await.recv().await
+                    } => pending_in,
+                };
+
+                let Some(pending_in) = pending_in else {
+                    return Ok(());
+                };
+
+                // IN gave up waiting.
+                if pending_in.reply_sender.is_closed() {
+                    continue;
+                }
+
+                write_frame(
+                    &mut send_stream,
+                    &P2pMatchResponse::In {
+                        announcement: pending_in.announcement.clone(),
+                    },
+                )
+                .await?;
+
+                match read_frame(&mut recv_stream).await? {
+                    Some(P2pMatchRequest::Reply { payload }) => {
+                        pending_in.reply_sender.send(payload).ok();
+                    }
+                    // OUT ended the stream before the IN arrived, left for its
+                    // next stream.
+                    None => {
+                        registration.out.in_sender.send(pending_in).ok();
+
+                        return Ok(());
+                    }
+                    Some(_) => anyhow::bail!("OUT {out_id} did not reply to IN."),
+                }
+            }
+        }
+        P2pMatchRequest::PublishLoad { out_id, load } => {
+            state
+                .out_loads
+                .lock()
+                .unwrap()
+                .insert(out_id, (load, Instant::now()));
+        }
+        P2pMatchRequest::GetLoad { out_id } => {
+            let load = state
+                .out_loads
+                .lock()
+                .unwrap()
+                .get(&out_id)
+                .map(|(load, _)| load.clone());
+
+            let response = match load {
+                Some(load) => P2pMatchResponse::Load { load },
+                None => P2pMatchResponse::OutGone,
+            };
+
+            write_frame(&mut send_stream, &response).await?;
+        }
+        P2pMatchRequest::Reply { .. } => anyhow::bail!("unexpected reply."),
+        P2pMatchRequest::Authenticate { .. } => anyhow::bail!("already authenticated."),
+    }
+
+    send_stream.finish()?;
+
+    Ok(())
+}
+
+/// Resolves once the peer ends (or resets) its side of the stream, which it
+/// keeps open while waiting for the response.
+async fn cancelled(recv_stream: &mut quinn::RecvStream) {
+    let _ = recv_stream.read(&mut [0; 1]).await;
+}
+
+struct MatchState {
+    outs: Mutex<HashMap<(String, MatchOutId), Arc<MatchOutEntry>>>,
+    /// Latest load published by OUT, with the time it was published.
+    out_loads: Mutex<HashMap<MatchOutId, (Vec<u8>, Instant)>>,
+    out_announcement_sender: tokio::sync::broadcast::Sender<(String, MatchOutId)>,
+}
+
+impl MatchState {
+    fn new() -> Self {
+        let (out_announcement_sender, _) = tokio::sync::broadcast::channel(256);
+
+        Self {
+            outs: Mutex::new(HashMap::new()),
+            out_loads: Mutex::new(HashMap::new()),
+            out_announcement_sender,
+        }
+    }
+
+    async fn accept_out(&self, name: &str, excluded: &[MatchOutId]) -> anyhow::Result<MatchOutId> {
+        let mut out_announcement_receiver = self.out_announcement_sender.subscribe();
+
+        loop {
+            let out_id = self
+                .outs
+                .lock()
+                .unwrap()
+                .keys()
+                .find(|(out_name, out_id)| out_name == name && !excluded.contains(out_id))
+                .map(|&(_, out_id)| out_id);
+
+            if let Some(out_id) = out_id {
+                return Ok(out_id);
+            }
+
+            loop {
+                match out_announcement_receiver.recv().await {
+                    Ok((out_name, out_id)) => {
+                        if out_name == name && !excluded.contains(&out_id) {
+                            return Ok(out_id);
+                        }
+                    }
+                    // Missed announcements, check the OUT available again.
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        anyhow::bail!("OUT announcement ended.")
+                    }
+                }
+            }
+        }
+    }
+
+    fn register_out(&self, name: String, out_id: MatchOutId) -> MatchOutRegistration {
+        let out = self
+            .outs
+            .lock()
+            .unwrap()
+            .entry((name.clone(), out_id))
+            .or_insert_with(|| {
+                log::info!("{name} OUT {out_id} announced.");
+
+                Arc::new(MatchOutEntry::new())
+            })
+            .clone();
+
+        out.presence.lock().unwrap().streams += 1;
+
+        self.out_announcement_sender.send((name, out_id)).ok();
+
+        MatchOutRegistration { out }
+    }
+
+    fn remove_expired_outs(&self) {
+        self.outs.lock().unwrap().retain(|(name, out_id), out| {
+            let presence = out.presence.lock().unwrap();
+
+            let expired = presence.streams == 0 && presence.released_at.elapsed() >= OUT_EXPIRATION;
+
+            if expired {
+                log::info!("{name} OUT {out_id} expired.");
+            }
+
+            !expired
+        });
+
+        self.out_loads
+            .lock()
+            .unwrap()
+            .retain(|_, (_, published_at)| published_at.elapsed() < OUT_LOAD_EXPIRATION);
+    }
+}
+
+struct MatchOutEntry {
+    in_sender: tokio::sync::mpsc::UnboundedSender<PendingIn>,
+    in_receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<PendingIn>>,
+    presence: Mutex<MatchOutPresence>,
+}
+
+impl MatchOutEntry {
+    fn new() -> Self {
+        let (in_sender, in_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        Self {
+            in_sender,
+            in_receiver: tokio::sync::Mutex::new(in_receiver),
+            presence: Mutex::new(MatchOutPresence {
+                streams: 0,
+                released_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+struct MatchOutPresence {
+    /// Streams of the OUT waiting for IN.
+    streams: usize,
+    released_at: Instant,
+}
+
+struct MatchOutRegistration {
+    out: Arc<MatchOutEntry>,
+}
+
+impl Drop for MatchOutRegistration {
+    fn drop(&mut self) {
+        let mut presence = self.out.presence.lock().unwrap();
+
+        presence.streams -= 1;
+        presence.released_at = Instant::now();
+    }
+}
+
+struct PendingIn {
+    announcement: Vec<u8>,
+    reply_sender: tokio::sync::oneshot::Sender<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bandwidth::BandwidthLimit,
+        match_server::{
+            p2p_match_protocol::create_p2p_match_client_config,
+            p2p_match_server::{P2pInMatchServer, P2pMatchClient, P2pOutMatchServer},
+            testing::{TestInData, TestOutData},
+            InMatchServer as _, OutIdentity, OutLoad, OutMatchServerTrait as _,
+        },
+        tunnel::quic::quinn::create_client_endpoint,
+    };
+
+    use super::*;
+
+    const ACCESS_KEY: &str = "access key";
+    const KEY: &str = "key";
+
+    #[tokio::test]
+    async fn matches_authenticated_in_and_out() -> anyhow::Result<()> {
+        let (address, fingerprint) = spawn_match_server().await?;
+
+        let out_match_server = P2pOutMatchServer::new(
+            new_client(address, &fingerprint, ACCESS_KEY)?,
+            vec![KEY.to_owned()],
+            OutIdentity::load_or_generate(None)?,
+            Vec::new(),
+            BandwidthLimit::default(),
+        )?;
+
+        let in_match_server = P2pInMatchServer::new(
+            new_client(address, &fingerprint, ACCESS_KEY)?,
+            vec![KEY.to_owned()],
+        )?;
+
+        let out_task = async {
+            out_match_server
+                .match_in::<TestInData, TestOutData>(
+                    TestOutData {
+                        name: "out".to_owned(),
+                    },
+                    None,
+                    &[],
+                    0,
+                )
+                .await
+        };
+
+        let in_task = async {
+            let out_id = in_match_server
+                .accept_out::<TestInData, TestOutData>()
+                .await?;
+
+            in_match_server
+                .match_out::<TestInData, TestOutData>(
+                    out_id,
+                    TestInData {
+                        name: "in".to_owned(),
+                    },
+                )
+                .await
+        };
+
+        let (match_in, match_out) = tokio::try_join!(out_task, in_task)?;
+
+        let match_out = match_out.ok_or_else(|| anyhow::anyhow!("OUT gone."))?;
+
+        assert_eq!(match_in.data.name, "in");
+        assert_eq!(match_out.data.name, "out");
+        assert!(match_in.tunnel_id == match_out.tunnel_id);
+
+        let load = OutLoad {
+            tunnels: 1,
+            streams: 2,
+            ..OutLoad::default()
+        };
+
+        out_match_server.publish_load(load.clone()).await?;
+
+        // Not answered, the load is only stored once the stream is read.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            in_match_server.get_out_load(match_out.id).await?,
+            Some(load)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_access_key() -> anyhow::Result<()> {
+        let (address, fingerprint) = spawn_match_server().await?;
+
+        let in_match_server = P2pInMatchServer::new(
+            new_client(address, &fingerprint, "wrong access key")?,
+            vec![KEY.to_owned()],
+        )?;
+
+        let error = in_match_server
+            .get_out_load(MatchOutId::new())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "match server refused access key.");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_before_authentication() -> anyhow::Result<()> {
+        let (address, fingerprint) = spawn_match_server().await?;
+
+        let endpoint = create_client_endpoint(
+            std::net::UdpSocket::bind("127.0.0.1:0")?,
+            create_p2p_match_client_config(Some(&fingerprint))?,
+            &QuicTransportConfig::default(),
+            QuicCongestionController::Cubic,
+        )?;
+
+        let connection = endpoint.connect(address, "localhost")?.await?;
+
+        let out_id = MatchOutId::new();
+
+        let (mut send_stream, _) = connection.open_bi().await?;
+
+        write_frame(
+            &mut send_stream,
+            &P2pMatchRequest::PublishLoad {
+                out_id,
+                load: b"load".to_vec(),
+            },
+        )
+        .await?;
+
+        send_stream.finish()?;
+
+        assert!(matches!(
+            connection.closed().await,
+            quinn::ConnectionError::ApplicationClosed(close)
+                if close.error_code == UNAUTHENTICATED_ERROR_CODE.into()
+        ));
+
+        // The load was not stored.
+        let client = new_client(address, &fingerprint, ACCESS_KEY)?;
+
+        let (mut send_stream, mut recv_stream) = client.open().await?;
+
+        write_frame(&mut send_stream, &P2pMatchRequest::GetLoad { out_id }).await?;
+
+        send_stream.finish()?;
+
+        assert!(matches!(
+            read_frame(&mut recv_stream).await?,
+            Some(P2pMatchResponse::OutGone)
+        ));
+
+        Ok(())
+    }
+
+    async fn spawn_match_server() -> anyhow::Result<(SocketAddr, String)> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let (cert, key) = TunnelTlsConfig {
+            server_name: "localhost".to_owned(),
+            alpn_protocols: Vec::new(),
+            identity_paths: None,
+        }
+        .load_or_generate_cert()?;
+
+        let endpoint = create_endpoint("127.0.0.1:0".parse()?, &cert, &key)?;
+        let address = endpoint.local_addr()?;
+
+        tokio::spawn(serve(endpoint, vec![ACCESS_KEY.to_owned()]));
+
+        Ok((address, get_cert_fingerprint(&cert)?))
+    }
+
+    fn new_client(
+        address: SocketAddr,
+        fingerprint: &str,
+        access_key: &str,
+    ) -> anyhow::Result<P2pMatchClient> {
+        P2pMatchClient::new(
+            &format!("p2p://{address}?fingerprint={fingerprint}"),
+            access_key.to_owned(),
+        )
+    }
+}
@@ -0,0 +1,25 @@
+use crate::match_server::{MatchOutId, MatchPair};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TestInData {
+    pub name: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct TestOutData {
+    pub name: String,
+}
+
+impl MatchPair<TestInData, TestOutData> for (TestInData, TestOutData) {
+    fn get_match_name() -> &'static str {
+        "test"
+    }
+
+    fn get_redis_out_key(out_id: &MatchOutId) -> String {
+        format!("test:out:{out_id}")
+    }
+
+    fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
+        format!("test:in:out:{out_id}")
+    }
+}
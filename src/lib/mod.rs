@@ -1,3 +1,4 @@
+pub mod bandwidth;
 pub mod common;
 pub mod config;
 pub mod r#in;
@@ -1,8 +1,11 @@
 use std::net::{IpAddr, SocketAddr};
 
-use crate::utils::net::socket::set_keepalive_options;
+use crate::utils::{
+    io::{AsyncWriteReset, TcpWriteHalf},
+    net::{get_any_address, socket::set_keepalive_options},
+};
 
-use super::output::Output;
+use super::output::{Output, OutputUdpSocket};
 
 pub enum LocalIpOrInterface {
     Ip(IpAddr),
@@ -47,7 +50,7 @@ impl Output for LocalOutput {
         address: SocketAddr,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )> {
         let socket = match address {
             SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
@@ -72,6 +75,27 @@ impl Output for LocalOutput {
 
         let (read_stream, write_stream) = stream.into_split();
 
-        Ok((Box::new(read_stream), Box::new(write_stream)))
+        Ok((
+            Box::new(read_stream),
+            Box::new(TcpWriteHalf::new(write_stream)),
+        ))
+    }
+
+    async fn bind_udp(&self, address: SocketAddr) -> anyhow::Result<Box<dyn OutputUdpSocket>> {
+        let socket = match &self.ip_or_interface {
+            Some(LocalIpOrInterface::Ip(ip)) => {
+                tokio::net::UdpSocket::bind(SocketAddr::new(*ip, 0)).await?
+            }
+            Some(LocalIpOrInterface::Interface(interface)) => {
+                let socket = tokio::net::UdpSocket::bind(get_any_address(&address)).await?;
+
+                socket.bind_device(Some(interface.as_bytes()))?;
+
+                socket
+            }
+            None => tokio::net::UdpSocket::bind(get_any_address(&address)).await?,
+        };
+
+        Ok(Box::new(socket))
     }
 }
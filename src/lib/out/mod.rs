@@ -1,6 +1,8 @@
 pub mod local_output;
 #[allow(clippy::module_inception)]
 mod out;
+mod out_load_tracker;
+mod out_udp_relay;
 pub mod output;
 pub mod socks5_output;
 
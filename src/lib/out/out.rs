@@ -1,15 +1,19 @@
 use std::{
     collections::HashMap,
     net::{SocketAddr, ToSocketAddrs as _},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use futures::future::join_all;
 use itertools::Itertools as _;
 
 use crate::{
+    bandwidth::{BandwidthConfig, BandwidthLimiters, BandwidthManager},
     common::get_destination_string,
     config::MatchServerConfig,
+    match_server::{OutIdentity, OutLoad, OutMatchServerTrait as _, OUT_LOAD_INTERVAL},
     out::local_output::LocalOutput,
     route::{
         config::{OutOutputConfig, OutRuleConfig},
@@ -17,24 +21,55 @@ use crate::{
     },
     tunnel::{
         http2::{
-            Http2OutTunnelConfig, Http2OutTunnelProvider, PlugHttp2OutTunnelConfig,
-            PlugHttp2OutTunnelProvider,
+            Http2OutTunnelConfig, Http2OutTunnelProvider, Http2TransportConfig,
+            PlugHttp2OutTunnelConfig, PlugHttp2OutTunnelProvider,
         },
-        quic::{QuicOutTunnelConfig, QuicOutTunnelProvider},
-        OutTunnel, OutTunnelProvider,
+        quic::{
+            QuicOutTunnelConfig, QuicOutTunnelProvider, QuicTransportConfig,
+            StaticQuicOutTunnelConfig, StaticQuicOutTunnelProvider,
+        },
+        websocket::{WebSocketOutTunnelConfig, WebSocketOutTunnelProvider},
+        OutTunnel, OutTunnelProvider, OutTunnelStream, TunnelConnectError, TunnelConnectErrorKind,
+        TunnelTlsConfig,
+    },
+    utils::{
+        io::{copy_bidirectional, StreamTimeoutConfig},
+        port_mapping::{PortMappingClient, PortMappingConfig},
     },
-    utils::io::copy_bidirectional,
 };
 
-use super::output::{AnyOutput, Output as _};
+use super::{
+    out_load_tracker::OutLoadTracker,
+    out_udp_relay::relay_udp_flows,
+    output::{AnyOutput, Output as _},
+};
+
+const OUTPUT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Options {
     pub labels: Vec<Label>,
     pub stun_server_addresses: Vec<String>,
     pub match_server_config: MatchServerConfig,
+    /// OUT id and identity key file, generated if missing and reused afterwards
+    /// to keep a stable identity across restarts.
+    pub identity_path: Option<PathBuf>,
     pub http2_priority: Option<i64>,
+    pub http2_tls: TunnelTlsConfig,
+    pub http2_transport: Http2TransportConfig,
     pub plug_http2_priority: Option<i64>,
+    pub plug_http2_transport: Http2TransportConfig,
     pub quic_priority: Option<i64>,
+    pub quic_tls: TunnelTlsConfig,
+    pub quic_transport: QuicTransportConfig,
+    /// Identity presented to static IN pinning certificates.
+    pub static_tls: TunnelTlsConfig,
+    pub websocket_url: Option<String>,
+    pub websocket_listen_address: SocketAddr,
+    pub websocket_sni: Option<String>,
+    pub websocket_priority: Option<i64>,
+    pub port_mapping: Option<PortMappingConfig>,
+    pub bandwidth: BandwidthConfig,
+    pub stream_timeout: StreamTimeoutConfig,
     pub routing_rules: Vec<OutRuleConfig>,
     pub routing_priority: i64,
     pub output_configs: Vec<OutOutputConfig>,
@@ -45,9 +80,23 @@ pub async fn up(
         labels,
         stun_server_addresses,
         match_server_config,
+        identity_path,
         http2_priority,
+        http2_tls,
+        http2_transport,
         plug_http2_priority,
+        plug_http2_transport,
         quic_priority,
+        quic_tls,
+        quic_transport,
+        static_tls,
+        websocket_url,
+        websocket_listen_address,
+        websocket_sni,
+        websocket_priority,
+        port_mapping,
+        bandwidth,
+        stream_timeout,
         routing_rules,
         routing_priority,
         output_configs,
@@ -55,41 +104,118 @@ pub async fn up(
 ) -> anyhow::Result<()> {
     log::info!("starting OUT...");
 
-    let match_server = Arc::new(match_server_config.new_out_match_server(labels).await?);
+    let load_tracker = Arc::new(OutLoadTracker::new(bandwidth.limit));
 
-    let stun_server_addresses = stun_server_addresses
-        .iter()
-        .flat_map(|address| address.to_socket_addrs().unwrap_or_default())
-        .collect_vec();
-
-    let tunnel_providers: Vec<Box<dyn OutTunnelProvider>> = vec![
-        Box::new(Http2OutTunnelProvider::new(
-            match_server.clone(),
-            Http2OutTunnelConfig {
-                stun_server_addresses: stun_server_addresses.clone(),
-                priority: http2_priority,
-                routing_priority,
-                routing_rules: routing_rules.clone(),
-            },
-        )),
-        Box::new(PlugHttp2OutTunnelProvider::new(
-            match_server.clone(),
-            PlugHttp2OutTunnelConfig {
-                priority: plug_http2_priority,
-                routing_priority,
-                routing_rules: routing_rules.clone(),
-            },
-        )),
-        Box::new(QuicOutTunnelProvider::new(
-            match_server.clone(),
-            QuicOutTunnelConfig {
-                stun_server_addresses,
+    let tunnel_providers = if let MatchServerConfig::Static(static_config) = match_server_config {
+        vec![Box::new(StaticQuicOutTunnelProvider::new(
+            StaticQuicOutTunnelConfig {
+                listen_address: static_config
+                    .listen
+                    .ok_or_else(|| anyhow::anyhow!("static OUT without listen address."))?,
+                key: static_config.key,
+                in_fingerprints: static_config.ins,
+                tls: static_tls,
+                transport: quic_transport,
+                labels,
+                bandwidth_limit: bandwidth.limit,
                 priority: quic_priority,
-                routing_priority,
                 routing_rules,
+                routing_priority,
             },
-        )),
-    ];
+        )?) as Box<dyn OutTunnelProvider>]
+    } else {
+        let identity = OutIdentity::load_or_generate(identity_path.as_deref())?;
+
+        log::info!("OUT id {}, identity key {}.", identity.id(), identity.key());
+
+        let instance = identity.sign_instance();
+
+        let match_server = Arc::new(
+            match_server_config
+                .new_out_match_server(identity, labels, bandwidth.limit)
+                .await?,
+        );
+
+        tokio::spawn({
+            let match_server = match_server.clone();
+            let load_tracker = load_tracker.clone();
+
+            async move {
+                loop {
+                    let load = OutLoad {
+                        instance: Some(instance.clone()),
+                        ..load_tracker.sample()
+                    };
+
+                    if let Err(error) = match_server.publish_load(load).await {
+                        log::warn!("failed to publish OUT load: {error}");
+                    }
+
+                    tokio::time::sleep(OUT_LOAD_INTERVAL).await;
+                }
+            }
+        });
+
+        let stun_server_addresses = stun_server_addresses
+            .iter()
+            .flat_map(|address| address.to_socket_addrs().unwrap_or_default())
+            .collect_vec();
+
+        let mut tunnel_providers: Vec<Box<dyn OutTunnelProvider>> = vec![
+            Box::new(Http2OutTunnelProvider::new(
+                match_server.clone(),
+                Http2OutTunnelConfig {
+                    stun_server_addresses: stun_server_addresses.clone(),
+                    tls: http2_tls,
+                    transport: http2_transport,
+                    priority: http2_priority,
+                    routing_priority,
+                    routing_rules: routing_rules.clone(),
+                },
+            )?),
+            Box::new(PlugHttp2OutTunnelProvider::new(
+                match_server.clone(),
+                PlugHttp2OutTunnelConfig {
+                    stun_server_addresses: stun_server_addresses.clone(),
+                    transport: plug_http2_transport,
+                    priority: plug_http2_priority,
+                    routing_priority,
+                    routing_rules: routing_rules.clone(),
+                },
+            )?),
+            Box::new(QuicOutTunnelProvider::new(
+                match_server.clone(),
+                QuicOutTunnelConfig {
+                    stun_server_addresses,
+                    tls: quic_tls,
+                    transport: quic_transport,
+                    port_mapping: port_mapping
+                        .map(|config| Arc::new(PortMappingClient::new(config))),
+                    priority: quic_priority,
+                    routing_priority,
+                    routing_rules: routing_rules.clone(),
+                },
+            )?),
+        ];
+
+        if let Some(websocket_url) = websocket_url {
+            tunnel_providers.push(Box::new(WebSocketOutTunnelProvider::new(
+                match_server.clone(),
+                WebSocketOutTunnelConfig {
+                    listen_address: websocket_listen_address,
+                    url: websocket_url,
+                    sni: websocket_sni,
+                    priority: websocket_priority,
+                    routing_priority,
+                    routing_rules,
+                },
+            )?));
+        }
+
+        tunnel_providers
+    };
+
+    let bandwidth_manager = Arc::new(BandwidthManager::new(bandwidth));
 
     let output_map = Arc::new(
         output_configs
@@ -105,6 +231,8 @@ pub async fn up(
         .map(|tunnel_provider| {
             let output_map = output_map.clone();
             let direct_output = direct_output.clone();
+            let bandwidth_manager = bandwidth_manager.clone();
+            let load_tracker = load_tracker.clone();
 
             async move {
                 loop {
@@ -114,6 +242,9 @@ pub async fn up(
                                 tunnel,
                                 output_map.clone(),
                                 direct_output.clone(),
+                                bandwidth_manager.clone(),
+                                load_tracker.clone(),
+                                stream_timeout,
                             ));
 
                             // tokio::task::spawn_blocking(|| {
@@ -143,13 +274,27 @@ async fn handle_tunnel(
     tunnel: Box<dyn OutTunnel>,
     output_map: Arc<HashMap<String, Arc<AnyOutput>>>,
     direct_output: Arc<AnyOutput>,
+    bandwidth_manager: Arc<BandwidthManager>,
+    load_tracker: Arc<OutLoadTracker>,
+    stream_timeout: StreamTimeoutConfig,
 ) {
+    let _load_guard = load_tracker.track_tunnel();
+
+    // Datagrams go through the outputs selected by tag as streams do.
+    let udp_relay_handle = tunnel.udp_relay().map(|relay| {
+        tokio::spawn(relay_udp_flows(
+            relay,
+            tunnel.name(),
+            output_map.clone(),
+            direct_output.clone(),
+            bandwidth_manager.clone(),
+            load_tracker.clone(),
+        ))
+    });
+
     loop {
         match tunnel.accept().await {
-            Ok((
-                (destination_address, destination_name, tag),
-                (tunnel_read_stream, tunnel_write_stream),
-            )) => {
+            Ok(((destination_address, destination_name, tag), tunnel_stream)) => {
                 log::info!(
                     "accepted connection{tagged} to {destination}.",
                     tagged = tag
@@ -158,6 +303,8 @@ async fn handle_tunnel(
                     destination = get_destination_string(destination_address, &destination_name),
                 );
 
+                let limiters = bandwidth_manager.get_limiters(tunnel.name(), tag.as_deref(), None);
+
                 let output = tag
                     .and_then(|tag| output_map.get(&tag))
                     .unwrap_or(&direct_output);
@@ -166,8 +313,10 @@ async fn handle_tunnel(
                     destination_address,
                     destination_name,
                     output.clone(),
-                    tunnel_read_stream,
-                    tunnel_write_stream,
+                    tunnel_stream,
+                    limiters,
+                    load_tracker.clone(),
+                    stream_timeout,
                 ));
             }
             Err(error) => {
@@ -181,32 +330,90 @@ async fn handle_tunnel(
             }
         }
     }
+
+    if let Some(udp_relay_handle) = udp_relay_handle {
+        udp_relay_handle.abort();
+    }
 }
 
 async fn handle_tcp_stream(
     destination_address: SocketAddr,
     destination_name: Option<String>,
     output: Arc<AnyOutput>,
-    tunnel_read_stream: Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-    tunnel_write_stream: Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
-) -> anyhow::Result<()> {
-    let address = if let Some(destination_name) = &destination_name {
-        tokio::net::lookup_host(format!("{destination_name}:{}", destination_address.port()))
-            .await?
-            .next()
-            .unwrap_or(destination_address)
-    } else {
-        destination_address
+    tunnel_stream: Box<dyn OutTunnelStream>,
+    limiters: BandwidthLimiters,
+    load_tracker: Arc<OutLoadTracker>,
+    stream_timeout: StreamTimeoutConfig,
+) {
+    let _load_guard = load_tracker.track_stream();
+
+    let destination_string = get_destination_string(destination_address, &destination_name);
+
+    let connect_result = tokio::time::timeout(OUTPUT_CONNECT_TIMEOUT, async {
+        let address = if let Some(destination_name) = &destination_name {
+            tokio::net::lookup_host(format!("{destination_name}:{}", destination_address.port()))
+                .await
+                .map_err(|error| {
+                    TunnelConnectError::new(TunnelConnectErrorKind::DnsFailure, error.to_string())
+                })?
+                .next()
+                .unwrap_or(destination_address)
+        } else {
+            destination_address
+        };
+
+        let streams = output
+            .connect(address)
+            .await
+            .map_err(|error| TunnelConnectError::from_error(&error))?;
+
+        Ok::<_, TunnelConnectError>((address, streams))
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(TunnelConnectError::new(
+            TunnelConnectErrorKind::TimedOut,
+            format!("no connection within {OUTPUT_CONNECT_TIMEOUT:?}."),
+        ))
+    });
+
+    let (address, (read_stream, write_stream)) = match connect_result {
+        Ok(connected) => connected,
+        Err(error) => {
+            log::warn!("connection to {destination_string} failed: {error}");
+
+            if let Err(error) = tunnel_stream.reject(error).await {
+                log::warn!("failed to reject connection to {destination_string}: {error}");
+            }
+
+            return;
+        }
     };
 
-    let (read_stream, write_stream) = output.connect(address).await?;
+    let result = async {
+        let (tunnel_read_stream, tunnel_write_stream) = tunnel_stream.accept().await?;
 
-    copy_bidirectional(
-        &get_destination_string(address, &destination_name),
-        (tunnel_read_stream, write_stream, false),
-        (read_stream, tunnel_write_stream),
-    )
-    .await?;
+        copy_bidirectional(
+            &get_destination_string(address, &destination_name),
+            (
+                load_tracker.counting_read(tunnel_read_stream, true),
+                write_stream,
+                false,
+            ),
+            (
+                load_tracker.counting_read(read_stream, false),
+                tunnel_write_stream,
+            ),
+            &limiters,
+            &stream_timeout,
+        )
+        .await?;
 
-    Ok(())
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(error) = result {
+        log::warn!("connection to {destination_string} errored: {error}");
+    }
 }
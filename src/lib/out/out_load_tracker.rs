@@ -0,0 +1,200 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    match_server::{OutLoad, Throughput},
+};
+
+/// Counts tunnels, streams and bytes relayed by OUT, sampled periodically into
+/// the load published through the match server.
+pub struct OutLoadTracker {
+    tunnels: AtomicUsize,
+    streams: AtomicUsize,
+    upload_bytes: AtomicU64,
+    download_bytes: AtomicU64,
+    capacity: BandwidthLimit,
+    /// Time and byte counts of the previous sample.
+    sampled: Mutex<(Instant, u64, u64)>,
+}
+
+impl OutLoadTracker {
+    pub fn new(capacity: BandwidthLimit) -> Self {
+        Self {
+            tunnels: AtomicUsize::new(0),
+            streams: AtomicUsize::new(0),
+            upload_bytes: AtomicU64::new(0),
+            download_bytes: AtomicU64::new(0),
+            capacity,
+            sampled: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+
+    /// Counts a tunnel until the guard is dropped.
+    pub fn track_tunnel(self: &Arc<Self>) -> OutLoadGuard {
+        self.tunnels.fetch_add(1, Ordering::Relaxed);
+
+        OutLoadGuard {
+            tracker: self.clone(),
+            tunnel: true,
+        }
+    }
+
+    /// Counts a stream until the guard is dropped.
+    pub fn track_stream(self: &Arc<Self>) -> OutLoadGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+
+        OutLoadGuard {
+            tracker: self.clone(),
+            tunnel: false,
+        }
+    }
+
+    /// Counts bytes read from IN (upload) or from the destination (download).
+    pub fn counting_read<T>(self: &Arc<Self>, inner: T, upload: bool) -> CountingRead<T> {
+        CountingRead {
+            inner,
+            tracker: self.clone(),
+            upload,
+        }
+    }
+
+    /// Counts bytes relayed from IN (upload) or from the destination (download)
+    /// other than by reading streams, e.g. UDP datagrams.
+    pub fn count(&self, bytes: usize, upload: bool) {
+        let counter = if upload {
+            &self.upload_bytes
+        } else {
+            &self.download_bytes
+        };
+
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Load with the throughput since the previous sample.
+    pub fn sample(&self) -> OutLoad {
+        let upload_bytes = self.upload_bytes.load(Ordering::Relaxed);
+        let download_bytes = self.download_bytes.load(Ordering::Relaxed);
+
+        let (sampled_at, sampled_upload_bytes, sampled_download_bytes) = std::mem::replace(
+            &mut *self.sampled.lock().unwrap(),
+            (Instant::now(), upload_bytes, download_bytes),
+        );
+
+        let elapsed = sampled_at.elapsed().as_secs_f64().max(1.0);
+
+        OutLoad {
+            tunnels: self.tunnels.load(Ordering::Relaxed),
+            streams: self.streams.load(Ordering::Relaxed),
+            throughput: Throughput {
+                upload: ((upload_bytes - sampled_upload_bytes) as f64 / elapsed) as u64,
+                download: ((download_bytes - sampled_download_bytes) as f64 / elapsed) as u64,
+            },
+            capacity: self.capacity,
+            instance: None,
+        }
+    }
+}
+
+pub struct OutLoadGuard {
+    tracker: Arc<OutLoadTracker>,
+    tunnel: bool,
+}
+
+impl Drop for OutLoadGuard {
+    fn drop(&mut self) {
+        if self.tunnel {
+            self.tracker.tunnels.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.tracker.streams.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub struct CountingRead<T> {
+    inner: T,
+    tracker: Arc<OutLoadTracker>,
+    upload: bool,
+}
+
+impl<T: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for CountingRead<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        let bytes = buf.filled().len() - filled;
+
+        if bytes > 0 {
+            self.tracker.count(bytes, self.upload);
+        }
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn samples_tracked_load() -> anyhow::Result<()> {
+        let capacity = BandwidthLimit {
+            upload: Some(1000),
+            download: None,
+        };
+
+        let tracker = Arc::new(OutLoadTracker::new(capacity));
+
+        let tunnel_guard = tracker.track_tunnel();
+        let stream_guards = [tracker.track_stream(), tracker.track_stream()];
+
+        let mut buffer = Vec::new();
+
+        tracker
+            .counting_read(&b"upload"[..], true)
+            .read_to_end(&mut buffer)
+            .await?;
+
+        tracker.count(10, false);
+
+        let load = tracker.sample();
+
+        assert_eq!(load.tunnels, 1);
+        assert_eq!(load.streams, 2);
+        assert_eq!(load.capacity, capacity);
+        // Over at least a second.
+        assert_eq!(
+            load.throughput,
+            Throughput {
+                upload: 6,
+                download: 10
+            }
+        );
+
+        drop(tunnel_guard);
+        drop(stream_guards);
+
+        let load = tracker.sample();
+
+        assert_eq!(load.tunnels, 0);
+        assert_eq!(load.streams, 0);
+        // Only bytes since the previous sample count.
+        assert_eq!(load.throughput, Throughput::default());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    bandwidth::{BandwidthLimiters, BandwidthManager},
+    tunnel::{OutUdpRelay, UdpFlowId, UDP_FLOW_EXPIRATION},
+};
+
+use super::{
+    out_load_tracker::OutLoadTracker,
+    output::{AnyOutput, Output as _, OutputUdpSocket},
+};
+
+const OUTPUT_BIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+type OutUdpSocketMap = HashMap<(UdpFlowId, bool), (Arc<dyn OutputUdpSocket>, BandwidthLimiters)>;
+
+/// Sends datagrams of each flow relayed by the tunnel from a socket (one per flow
+/// and address family) of the output of the flow tag, and relays the responses
+/// back to IN. Sockets idle for `UDP_FLOW_EXPIRATION` are closed. Datagrams
+/// beyond the bandwidth limits of the tunnel type and flow tag are dropped, the
+/// relayed ones count in the load of OUT.
+pub async fn relay_udp_flows(
+    relay: Arc<dyn OutUdpRelay>,
+    tunnel_name: &'static str,
+    output_map: Arc<HashMap<String, Arc<AnyOutput>>>,
+    direct_output: Arc<AnyOutput>,
+    bandwidth_manager: Arc<BandwidthManager>,
+    load_tracker: Arc<OutLoadTracker>,
+) {
+    let socket_map = Arc::new(tokio::sync::Mutex::new(OutUdpSocketMap::new()));
+
+    // Response tasks are aborted with the tunnel.
+    let mut response_tasks = tokio::task::JoinSet::new();
+
+    // Packets are handled in order.
+    while let Ok((flow_id, tag, address, data)) = relay.receive().await {
+        let result = async {
+            let key = (flow_id, address.is_ipv4());
+
+            let socket = socket_map.lock().await.get(&key).cloned();
+
+            let (socket, limiters) = match socket {
+                Some(socket) => socket,
+                None => {
+                    let output = tag
+                        .as_ref()
+                        .and_then(|tag| output_map.get(tag))
+                        .unwrap_or(&direct_output);
+
+                    let socket: Arc<dyn OutputUdpSocket> =
+                        tokio::time::timeout(OUTPUT_BIND_TIMEOUT, output.bind_udp(address))
+                            .await
+                            .map_err(|_| anyhow::anyhow!("output bind timed out."))??
+                            .into();
+
+                    let limiters =
+                        bandwidth_manager.get_limiters(tunnel_name, tag.as_deref(), None);
+
+                    socket_map
+                        .lock()
+                        .await
+                        .insert(key, (socket.clone(), limiters.clone()));
+
+                    response_tasks.spawn(relay_responses(
+                        flow_id,
+                        key,
+                        socket.clone(),
+                        limiters.clone(),
+                        relay.clone(),
+                        socket_map.clone(),
+                        load_tracker.clone(),
+                    ));
+
+                    (socket, limiters)
+                }
+            };
+
+            if !limiters.try_consume_upload(data.len()) {
+                log::debug!("UDP packet to {address} dropped beyond bandwidth limit.");
+
+                return Ok(());
+            }
+
+            load_tracker.count(data.len(), true);
+
+            socket.send_to(&data, address).await
+        }
+        .await;
+
+        if let Err(error) = result {
+            log::warn!("error relaying UDP packet to {address}: {error}");
+        }
+
+        // Reaps finished response tasks.
+        while response_tasks.try_join_next().is_some() {}
+    }
+}
+
+async fn relay_responses(
+    flow_id: UdpFlowId,
+    key: (UdpFlowId, bool),
+    socket: Arc<dyn OutputUdpSocket>,
+    limiters: BandwidthLimiters,
+    relay: Arc<dyn OutUdpRelay>,
+    socket_map: Arc<tokio::sync::Mutex<OutUdpSocketMap>>,
+    load_tracker: Arc<OutLoadTracker>,
+) {
+    let mut buffer = vec![0u8; u16::MAX as usize];
+
+    while let Ok(Ok((length, address))) =
+        tokio::time::timeout(UDP_FLOW_EXPIRATION, socket.recv_from(&mut buffer)).await
+    {
+        if !limiters.try_consume_download(length) {
+            log::debug!("UDP response from {address} dropped beyond bandwidth limit.");
+
+            continue;
+        }
+
+        load_tracker.count(length, false);
+
+        if let Err(error) = relay.send(flow_id, address, &buffer[..length]).await {
+            log::warn!("error relaying UDP response: {error}");
+            break;
+        }
+    }
+
+    socket_map.lock().await.remove(&key);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use crate::{
+        bandwidth::{BandwidthConfig, BandwidthLimit},
+        out::{local_output::LocalOutput, socks5_output::Socks5Output},
+    };
+
+    use super::*;
+
+    type Datagram = (UdpFlowId, Option<String>, SocketAddr, bytes::Bytes);
+
+    struct FakeRelay {
+        receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Datagram>>,
+        response_sender: tokio::sync::mpsc::UnboundedSender<(UdpFlowId, SocketAddr, Vec<u8>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl OutUdpRelay for FakeRelay {
+        async fn receive(
+            &self,
+        ) -> anyhow::Result<(UdpFlowId, Option<String>, SocketAddr, bytes::Bytes)> {
+            self.receiver
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("closed."))
+        }
+
+        async fn send(
+            &self,
+            flow_id: UdpFlowId,
+            address: SocketAddr,
+            data: &[u8],
+        ) -> anyhow::Result<()> {
+            self.response_sender
+                .send((flow_id, address, data.to_vec()))?;
+
+            Ok(())
+        }
+    }
+
+    async fn spawn_udp_echo_server() -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+
+            while let Ok((length, source)) = socket.recv_from(&mut buffer).await {
+                socket.send_to(&buffer[..length], source).await.ok();
+            }
+        });
+
+        address
+    }
+
+    /// SOCKS5 proxy accepting a UDP association, and echoing datagrams sent
+    /// through it as if from the destination.
+    async fn spawn_socks5_udp_echo_proxy() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 3, 0, 1]);
+
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = socket.local_addr().unwrap().port().to_be_bytes();
+
+            // Unspecified relay address, for the proxy address to be used.
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]])
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 1024];
+
+            while let Ok((length, source)) = socket.recv_from(&mut buffer).await {
+                socket.send_to(&buffer[..length], source).await.ok();
+            }
+
+            drop(stream);
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn relays_flows_through_outputs_by_tag() {
+        let echo_address = spawn_udp_echo_server().await;
+        let proxy_address = spawn_socks5_udp_echo_proxy().await;
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (response_sender, mut response_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let relay = Arc::new(FakeRelay {
+            receiver: tokio::sync::Mutex::new(receiver),
+            response_sender,
+        });
+
+        let output_map = Arc::new(HashMap::from([(
+            "socks5".to_owned(),
+            Arc::new(AnyOutput::Socks5(Socks5Output::new(proxy_address))),
+        )]));
+
+        let direct_output = Arc::new(AnyOutput::Local(LocalOutput::default()));
+
+        let load_tracker = Arc::new(OutLoadTracker::new(BandwidthLimit::default()));
+
+        let handle = tokio::spawn(relay_udp_flows(
+            relay,
+            "quic",
+            output_map,
+            direct_output,
+            Arc::new(BandwidthManager::new(BandwidthConfig::default())),
+            load_tracker.clone(),
+        ));
+
+        // Unknown tags fall back to the direct output.
+        for (flow_id, tag) in [(1, None), (2, Some("unknown"))] {
+            sender
+                .send((
+                    flow_id,
+                    tag.map(str::to_owned),
+                    echo_address,
+                    bytes::Bytes::from_static(b"direct"),
+                ))
+                .unwrap();
+
+            assert_eq!(
+                response_receiver.recv().await.unwrap(),
+                (flow_id, echo_address, b"direct".to_vec())
+            );
+        }
+
+        // The proxy echoes instead of the destination, which is not listening.
+        let destination_address = "127.0.0.1:9".parse().unwrap();
+
+        sender
+            .send((
+                3,
+                Some("socks5".to_owned()),
+                destination_address,
+                bytes::Bytes::from_static(b"proxied"),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            response_receiver.recv().await.unwrap(),
+            (3, destination_address, b"proxied".to_vec())
+        );
+
+        // Relayed datagrams count in the load both ways.
+        let throughput = load_tracker.sample().throughput;
+
+        assert_eq!((throughput.upload, throughput.download), (19, 19));
+
+        // Relaying ends with the tunnel.
+        drop(sender);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_datagrams_beyond_tag_limits() {
+        let echo_address = spawn_udp_echo_server().await;
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (response_sender, mut response_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let relay = Arc::new(FakeRelay {
+            receiver: tokio::sync::Mutex::new(receiver),
+            response_sender,
+        });
+
+        // Allows bursts of 32 bytes upstream and 8 downstream for the tag.
+        let bandwidth_manager = Arc::new(BandwidthManager::new(BandwidthConfig {
+            tags: HashMap::from([(
+                "limited".to_owned(),
+                BandwidthLimit {
+                    upload: Some(32),
+                    download: Some(8),
+                },
+            )]),
+            ..Default::default()
+        }));
+
+        tokio::spawn(relay_udp_flows(
+            relay,
+            "quic",
+            Arc::new(HashMap::new()),
+            Arc::new(AnyOutput::Local(LocalOutput::default())),
+            bandwidth_manager,
+            Arc::new(OutLoadTracker::new(BandwidthLimit::default())),
+        ));
+
+        let send = |flow_id, tag: Option<&str>, data: &'static [u8]| {
+            sender
+                .send((
+                    flow_id,
+                    tag.map(str::to_owned),
+                    echo_address,
+                    bytes::Bytes::from_static(data),
+                ))
+                .unwrap();
+        };
+
+        // Beyond the upload limit.
+        send(1, Some("limited"), b"a datagram beyond the upload limit");
+        // Within the upload limit but beyond the download limit.
+        send(1, Some("limited"), b"download limit");
+        send(1, Some("limited"), b"within");
+        send(2, None, b"unlimited");
+
+        let mut responses = Vec::new();
+
+        for _ in 0..2 {
+            responses.push(
+                tokio::time::timeout(Duration::from_secs(5), response_receiver.recv())
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        responses.sort();
+
+        assert_eq!(
+            responses,
+            [
+                (1, echo_address, b"within".to_vec()),
+                (2, echo_address, b"unlimited".to_vec()),
+            ]
+        );
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), response_receiver.recv())
+                .await
+                .is_err()
+        );
+    }
+}
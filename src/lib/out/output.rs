@@ -1,5 +1,7 @@
 use std::net::SocketAddr;
 
+use crate::utils::io::AsyncWriteReset;
+
 use super::{local_output::LocalOutput, socks5_output::Socks5Output};
 
 #[async_trait::async_trait]
@@ -9,8 +11,32 @@ pub trait Output {
         address: SocketAddr,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )>;
+
+    /// Binds a socket for datagrams of a UDP flow, `address` is the first
+    /// destination of the flow.
+    async fn bind_udp(&self, address: SocketAddr) -> anyhow::Result<Box<dyn OutputUdpSocket>>;
+}
+
+#[async_trait::async_trait]
+pub trait OutputUdpSocket: Send + Sync {
+    async fn send_to(&self, data: &[u8], address: SocketAddr) -> anyhow::Result<()>;
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)>;
+}
+
+#[async_trait::async_trait]
+impl OutputUdpSocket for tokio::net::UdpSocket {
+    async fn send_to(&self, data: &[u8], address: SocketAddr) -> anyhow::Result<()> {
+        tokio::net::UdpSocket::send_to(self, data, address).await?;
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        Ok(tokio::net::UdpSocket::recv_from(self, buffer).await?)
+    }
 }
 
 #[derive(derive_more::From)]
@@ -26,11 +52,18 @@ impl Output for AnyOutput {
         address: SocketAddr,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )> {
         match self {
             AnyOutput::Local(output) => output.connect(address).await,
             AnyOutput::Socks5(output) => output.connect(address).await,
         }
     }
+
+    async fn bind_udp(&self, address: SocketAddr) -> anyhow::Result<Box<dyn OutputUdpSocket>> {
+        match self {
+            AnyOutput::Local(output) => output.bind_udp(address).await,
+            AnyOutput::Socks5(output) => output.bind_udp(address).await,
+        }
+    }
 }
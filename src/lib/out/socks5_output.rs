@@ -1,6 +1,22 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use super::output::Output;
+use bytes::{Buf as _, BufMut as _};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::utils::{
+    io::{AsyncWriteReset, TcpWriteHalf},
+    net::get_any_address,
+};
+
+use super::output::{Output, OutputUdpSocket};
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTHENTICATION: u8 = 0;
+const SOCKS5_UDP_ASSOCIATE: u8 = 3;
+const SOCKS5_SUCCEEDED: u8 = 0;
+const SOCKS5_ATYP_IPV4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN_NAME: u8 = 3;
+const SOCKS5_ATYP_IPV6: u8 = 4;
 
 pub struct Socks5Output {
     proxy_address: SocketAddr,
@@ -19,14 +35,183 @@ impl Output for Socks5Output {
         address: SocketAddr,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )> {
         let stream = tokio_socks::tcp::Socks5Stream::connect(self.proxy_address, address).await?;
 
         stream.set_nodelay(true)?;
 
-        let (read_stream, write_stream) = tokio::io::split(stream);
+        let (read_stream, write_stream) = stream.into_inner().into_split();
+
+        Ok((
+            Box::new(read_stream),
+            Box::new(TcpWriteHalf::new(write_stream)),
+        ))
+    }
+
+    async fn bind_udp(&self, _address: SocketAddr) -> anyhow::Result<Box<dyn OutputUdpSocket>> {
+        Ok(Box::new(
+            Socks5UdpSocket::associate(self.proxy_address).await?,
+        ))
+    }
+}
+
+/// UDP socket relaying datagrams through a SOCKS5 UDP ASSOCIATE (RFC 1928), the
+/// association lasts as long as the control connection.
+struct Socks5UdpSocket {
+    _control_stream: tokio::net::TcpStream,
+    socket: tokio::net::UdpSocket,
+    relay_address: SocketAddr,
+}
+
+impl Socks5UdpSocket {
+    async fn associate(proxy_address: SocketAddr) -> anyhow::Result<Self> {
+        let mut control_stream = tokio::net::TcpStream::connect(proxy_address).await?;
+
+        control_stream
+            .write_all(&[SOCKS5_VERSION, 1, SOCKS5_NO_AUTHENTICATION])
+            .await?;
+
+        let mut method_reply = [0; 2];
+
+        control_stream.read_exact(&mut method_reply).await?;
+
+        anyhow::ensure!(
+            method_reply == [SOCKS5_VERSION, SOCKS5_NO_AUTHENTICATION],
+            "SOCKS5 proxy requires authentication."
+        );
+
+        let socket = tokio::net::UdpSocket::bind(get_any_address(&proxy_address)).await?;
+
+        // The address datagrams are sent from, as far as known before NAT.
+        let mut request = vec![SOCKS5_VERSION, SOCKS5_UDP_ASSOCIATE, 0];
+
+        put_socks5_address(&mut request, socket.local_addr()?);
+
+        control_stream.write_all(&request).await?;
+
+        let mut reply_head = [0; 4];
+
+        control_stream.read_exact(&mut reply_head).await?;
+
+        anyhow::ensure!(
+            reply_head[1] == SOCKS5_SUCCEEDED,
+            "SOCKS5 UDP ASSOCIATE failed with reply {}.",
+            reply_head[1]
+        );
+
+        let relay_address = match reply_head[3] {
+            SOCKS5_ATYP_IPV4 => {
+                let mut buffer = [0; 6];
+
+                control_stream.read_exact(&mut buffer).await?;
+
+                get_socks5_address(SOCKS5_ATYP_IPV4, &mut &buffer[..])?
+            }
+            SOCKS5_ATYP_IPV6 => {
+                let mut buffer = [0; 18];
+
+                control_stream.read_exact(&mut buffer).await?;
+
+                get_socks5_address(SOCKS5_ATYP_IPV6, &mut &buffer[..])?
+            }
+            atyp => anyhow::bail!("unsupported SOCKS5 relay address type {atyp}."),
+        };
+
+        // An unspecified relay address stands for the proxy itself.
+        let relay_address = if relay_address.ip().is_unspecified() {
+            SocketAddr::new(proxy_address.ip(), relay_address.port())
+        } else {
+            relay_address
+        };
+
+        Ok(Self {
+            _control_stream: control_stream,
+            socket,
+            relay_address,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl OutputUdpSocket for Socks5UdpSocket {
+    async fn send_to(&self, data: &[u8], address: SocketAddr) -> anyhow::Result<()> {
+        let mut packet = Vec::with_capacity(3 + 1 + 16 + 2 + data.len());
+
+        // Reserved and fragment number, fragmentation is not supported.
+        packet.put_slice(&[0, 0, 0]);
+
+        put_socks5_address(&mut packet, address);
+
+        packet.put_slice(data);
+
+        self.socket.send_to(&packet, self.relay_address).await?;
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> anyhow::Result<(usize, SocketAddr)> {
+        let mut packet = vec![0; buffer.len() + 3 + 1 + 16 + 2];
+
+        loop {
+            let (length, relay_address) = self.socket.recv_from(&mut packet).await?;
+
+            if relay_address != self.relay_address {
+                continue;
+            }
+
+            let mut packet = &packet[..length];
+
+            // Fragments are dropped.
+            if packet.remaining() < 4 || packet[2] != 0 {
+                continue;
+            }
+
+            let atyp = packet[3];
+
+            packet.advance(4);
+
+            let Ok(address) = get_socks5_address(atyp, &mut packet) else {
+                continue;
+            };
+
+            let length = packet.len().min(buffer.len());
+
+            buffer[..length].copy_from_slice(&packet[..length]);
+
+            return Ok((length, address));
+        }
+    }
+}
+
+fn put_socks5_address(buffer: &mut Vec<u8>, address: SocketAddr) {
+    match address {
+        SocketAddr::V4(address) => {
+            buffer.put_u8(SOCKS5_ATYP_IPV4);
+            buffer.put_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => {
+            buffer.put_u8(SOCKS5_ATYP_IPV6);
+            buffer.put_slice(&address.ip().octets());
+        }
+    }
+
+    buffer.put_u16(address.port());
+}
 
-        Ok((Box::new(read_stream), Box::new(write_stream)))
+fn get_socks5_address(atyp: u8, buffer: &mut &[u8]) -> anyhow::Result<SocketAddr> {
+    match atyp {
+        SOCKS5_ATYP_IPV4 if buffer.remaining() >= 6 => Ok(SocketAddr::V4(SocketAddrV4::new(
+            buffer.get_u32().into(),
+            buffer.get_u16(),
+        ))),
+        SOCKS5_ATYP_IPV6 if buffer.remaining() >= 18 => Ok(SocketAddr::V6(SocketAddrV6::new(
+            buffer.get_u128().into(),
+            buffer.get_u16(),
+            0,
+            0,
+        ))),
+        SOCKS5_ATYP_DOMAIN_NAME => anyhow::bail!("SOCKS5 domain name addresses not supported."),
+        _ => anyhow::bail!("invalid SOCKS5 address."),
     }
 }
@@ -31,6 +31,17 @@ pub enum OutRuleConfig {
 }
 
 impl OutRuleConfig {
+    /// Priority of the rule, `None` for rules without one (fallback).
+    pub fn priority_mut(&mut self) -> Option<&mut Option<i64>> {
+        match self {
+            OutRuleConfig::GeoIp(config) => Some(&mut config.priority),
+            OutRuleConfig::Address(config) => Some(&mut config.priority),
+            OutRuleConfig::Domain(config) => Some(&mut config.priority),
+            OutRuleConfig::DomainPattern(config) => Some(&mut config.priority),
+            OutRuleConfig::Fallback(_) => None,
+        }
+    }
+
     pub fn into_rule(self, out_id: MatchOutId, priority_default: i64) -> DynRuleBox {
         match self {
             OutRuleConfig::GeoIp(config) => Box::new(GeoIpRule {
@@ -2,13 +2,30 @@ use std::{
     fmt,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 
-use crate::{match_server::MatchOutId, route::rule::Label, tunnel::common::get_tunnel_string};
+use crate::{
+    match_server::MatchOutId, route::rule::Label, tunnel::common::get_tunnel_string,
+    utils::io::AsyncWriteReset,
+};
+
+use super::{
+    InTunnel, InTunnelLike, InUdpRelay, NegotiatedTunnelProtocol, OutTunnel, OutTunnelStream,
+    OutUdpRelay, TunnelCapability, TunnelConnectError, TunnelConnectErrorKind, TunnelId,
+};
+
+const STATUS_CONNECTED: u8 = 0;
+
+/// Delay before retrying to open a stream for the pool after a failure.
+const STREAM_POOL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
-use super::{InTunnel, InTunnelLike, OutTunnel, TunnelId};
+const HEAD_IPV6_FLAG: u8 = 0b_1000_0000;
+/// Lower bits of the first head byte carry the protocol version, always 0 from
+/// legacy IN.
+const HEAD_VERSION_MASK: u8 = 0b_0111_1111;
 
 #[async_trait::async_trait]
 pub trait ByteStreamInTunnelConnection: Send + Sync {
@@ -16,12 +33,82 @@ pub trait ByteStreamInTunnelConnection: Send + Sync {
         &self,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )>;
 
     async fn closed(&self);
 
     fn is_closed(&self) -> bool;
+
+    /// Whether data written can no longer be replayed by a third party, false
+    /// while a connection resumed with early data is not confirmed.
+    fn is_handshake_confirmed(&self) -> bool {
+        true
+    }
+
+    async fn handshake_confirmed(&self) {}
+
+    fn udp_relay(&self) -> Option<Arc<dyn InUdpRelay>> {
+        None
+    }
+}
+
+type ByteStreamPair = (
+    Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+    Box<dyn AsyncWriteReset>,
+);
+
+/// Streams opened ahead of connections, so that connecting only costs the head
+/// frame. Streams are not seen by OUT until the head is written, so idle pooled
+/// streams cost nothing but their share of the stream limit.
+struct ByteStreamPool {
+    receiver: Mutex<tokio::sync::mpsc::Receiver<ByteStreamPair>>,
+    size: usize,
+}
+
+impl ByteStreamPool {
+    fn new<TConnection>(connection: Arc<TConnection>, size: usize) -> Self
+    where
+        TConnection: ByteStreamInTunnelConnection + 'static,
+    {
+        let (sender, receiver) = tokio::sync::mpsc::channel(size);
+
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    stream = connection.open() => stream,
+                    _ = sender.closed() => break,
+                };
+
+                match stream {
+                    Ok(stream) => {
+                        if sender.send(stream).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        if connection.is_closed() {
+                            break;
+                        }
+
+                        log::debug!("failed to open pooled stream: {error}");
+
+                        tokio::time::sleep(STREAM_POOL_RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        });
+
+        ByteStreamPool {
+            receiver: Mutex::new(receiver),
+            size,
+        }
+    }
+
+    /// Takes a pooled stream, which is only known to be usable once written to.
+    fn take(&self) -> Option<ByteStreamPair> {
+        self.receiver.lock().unwrap().try_recv().ok()
+    }
 }
 
 pub struct ByteStreamInTunnel<TConnection> {
@@ -30,7 +117,9 @@ pub struct ByteStreamInTunnel<TConnection> {
     out_id: MatchOutId,
     labels: Vec<Label>,
     priority: i64,
+    protocol: NegotiatedTunnelProtocol,
     connection: Arc<TConnection>,
+    stream_pool: Option<ByteStreamPool>,
     active_permit: Arc<Mutex<Option<tokio::sync::OwnedSemaphorePermit>>>,
 }
 
@@ -44,9 +133,15 @@ where
         out_id: MatchOutId,
         labels: Vec<Label>,
         priority: i64,
+        protocol: NegotiatedTunnelProtocol,
         connection: TConnection,
+        stream_pool_size: usize,
     ) -> Self {
         let connection = Arc::new(connection);
+
+        let stream_pool = (stream_pool_size > 0)
+            .then(|| ByteStreamPool::new(connection.clone(), stream_pool_size));
+
         let active_permit = Arc::new(Mutex::new(None));
 
         tokio::spawn({
@@ -65,7 +160,9 @@ where
             out_id,
             labels,
             priority,
+            protocol,
             connection,
+            stream_pool,
             active_permit,
         }
     }
@@ -86,6 +183,10 @@ impl<TConnection> InTunnelLike for ByteStreamInTunnel<TConnection>
 where
     TConnection: ByteStreamInTunnelConnection,
 {
+    fn name(&self) -> &'static str {
+        self.r#type
+    }
+
     async fn connect(
         &self,
         destination_address: SocketAddr,
@@ -94,21 +195,26 @@ where
         sniff_buffer: Option<Vec<u8>>,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
         tokio::sync::oneshot::Sender<()>,
     )> {
-        let (read_stream, mut write_stream) = self.connection.open().await?;
+        // Early data can be replayed, so it only carries the head, as connecting
+        // to the destination again is harmless. Data of the client waits for the
+        // handshake to be confirmed.
+        let early = !self.connection.is_handshake_confirmed();
 
         let head = {
             let mut head = Vec::<u8>::new();
 
+            let version = self.protocol.version as u8 & HEAD_VERSION_MASK;
+
             match destination_address {
                 SocketAddr::V4(address) => {
-                    head.push(0b_0000_0000);
+                    head.push(version);
                     head.extend_from_slice(&address.ip().octets());
                 }
                 SocketAddr::V6(address) => {
-                    head.push(0b_1000_0000);
+                    head.push(HEAD_IPV6_FLAG | version);
                     head.extend_from_slice(&address.ip().octets());
                 }
             }
@@ -131,19 +237,86 @@ where
                 head.extend_from_slice(tag.as_bytes());
             }
 
+            // Carried in the same frame as the head, saving OUT a read before
+            // it can forward anything to the destination.
+            if !early {
+                if let Some(sniff_buffer) = &sniff_buffer {
+                    head.extend_from_slice(sniff_buffer);
+                }
+            }
+
             head
         };
 
-        write_stream.write_all(&head).await?;
+        // Stale pooled streams are skipped, up to a pool worth of them before a new
+        // stream is opened.
+        let mut pooled_attempts = self.stream_pool.as_ref().map_or(0, |pool| pool.size);
+
+        let (mut read_stream, mut write_stream) = loop {
+            let pooled_stream = match &self.stream_pool {
+                Some(stream_pool) if pooled_attempts > 0 => {
+                    pooled_attempts -= 1;
+                    stream_pool.take()
+                }
+                _ => None,
+            };
+
+            let (read_stream, mut write_stream, pooled) = match pooled_stream {
+                Some((read_stream, write_stream)) => (read_stream, write_stream, true),
+                None => {
+                    let (read_stream, write_stream) = self.connection.open().await?;
 
-        if let Some(sniff_buffer) = sniff_buffer {
-            write_stream.write_all(&sniff_buffer).await?;
+                    (read_stream, write_stream, false)
+                }
+            };
+
+            match write_stream.write_all(&head).await {
+                Ok(()) => break (read_stream, write_stream),
+                // A pooled stream may have gone stale, e.g. opened in rejected
+                // early data.
+                Err(error) if pooled => {
+                    log::debug!("pooled stream of tunnel {self} unusable: {error}");
+                }
+                Err(error) => return Err(error.into()),
+            }
+        };
+
+        if early {
+            self.connection.handshake_confirmed().await;
+
+            if let Some(sniff_buffer) = &sniff_buffer {
+                write_stream.write_all(sniff_buffer).await?;
+            }
+        }
+
+        let status = if self.protocol.supports(TunnelCapability::ConnectStatus) {
+            read_stream.read_u8().await?
+        } else {
+            STATUS_CONNECTED
+        };
+
+        if status != STATUS_CONNECTED {
+            let message_length = read_stream.read_u8().await? as usize;
+
+            let mut message = vec![0; message_length];
+
+            read_stream.read_exact(&mut message).await?;
+
+            return Err(TunnelConnectError::new(
+                TunnelConnectErrorKind::from_code(status),
+                String::from_utf8_lossy(&message),
+            )
+            .into());
         }
 
         let (stream_closed_sender, _) = tokio::sync::oneshot::channel();
 
         Ok((read_stream, write_stream, stream_closed_sender))
     }
+
+    fn udp_relay(&self) -> Option<Arc<dyn InUdpRelay>> {
+        self.connection.udp_relay()
+    }
 }
 
 #[async_trait::async_trait]
@@ -175,6 +348,10 @@ where
         self.active_permit.lock().unwrap().is_some()
     }
 
+    fn deactivate(&self) {
+        self.active_permit.lock().unwrap().take();
+    }
+
     async fn closed(&self) {
         self.connection.closed().await;
     }
@@ -190,23 +367,34 @@ pub trait ByteStreamOutTunnelConnection: Send + Sync {
         &self,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )>;
 
     fn is_closed(&self) -> bool;
+
+    fn udp_relay(&self) -> Option<Arc<dyn OutUdpRelay>> {
+        None
+    }
 }
 
 pub struct ByteStreamOutTunnel<TConnection> {
     r#type: &'static str,
     id: TunnelId,
+    protocol: NegotiatedTunnelProtocol,
     connection: TConnection,
 }
 
 impl<TConnection> ByteStreamOutTunnel<TConnection> {
-    pub fn new(r#type: &'static str, id: TunnelId, connection: TConnection) -> Self {
+    pub fn new(
+        r#type: &'static str,
+        id: TunnelId,
+        protocol: NegotiatedTunnelProtocol,
+        connection: TConnection,
+    ) -> Self {
         ByteStreamOutTunnel {
             r#type,
             id,
+            protocol,
             connection,
         }
     }
@@ -231,21 +419,47 @@ where
         self.id
     }
 
+    fn name(&self) -> &'static str {
+        self.r#type
+    }
+
     async fn accept(
         &self,
     ) -> anyhow::Result<(
         (SocketAddr, Option<String>, Option<String>),
-        (
-            Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-            Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
-        ),
+        Box<dyn OutTunnelStream>,
     )> {
         let (mut read_stream, write_stream) = self.connection.accept().await?;
 
+        let connect_status = self.protocol.supports(TunnelCapability::ConnectStatus);
+
         let destination_tuple = {
             let option_byte = read_stream.read_u8().await?;
 
-            let destination_address = match option_byte & 0b_1000_0000 {
+            let version = option_byte & HEAD_VERSION_MASK;
+
+            if version != 0 && u16::from(version) != self.protocol.version {
+                let error = TunnelConnectError::new(
+                    TunnelConnectErrorKind::Other,
+                    format!(
+                        "stream of protocol version {version}, tunnel negotiated {}.",
+                        self.protocol.version
+                    ),
+                );
+
+                Box::new(ByteStreamOutTunnelStream {
+                    read_stream,
+                    write_stream,
+                    connect_status,
+                })
+                .reject(error.clone())
+                .await
+                .ok();
+
+                return Err(error.into());
+            }
+
+            let destination_address = match option_byte & HEAD_IPV6_FLAG {
                 0 => SocketAddr::V4(SocketAddrV4::new(
                     read_stream.read_u32().await?.into(),
                     read_stream.read_u16().await?,
@@ -283,10 +497,257 @@ where
             (destination_address, destination_name, tag)
         };
 
-        Ok((destination_tuple, (read_stream, write_stream)))
+        Ok((
+            destination_tuple,
+            Box::new(ByteStreamOutTunnelStream {
+                read_stream,
+                write_stream,
+                connect_status,
+            }),
+        ))
     }
 
     fn is_closed(&self) -> bool {
         self.connection.is_closed()
     }
+
+    fn udp_relay(&self) -> Option<Arc<dyn OutUdpRelay>> {
+        self.connection.udp_relay()
+    }
+}
+
+struct ByteStreamOutTunnelStream {
+    read_stream: Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+    write_stream: Box<dyn AsyncWriteReset>,
+    /// Whether IN expects the status of connecting to the destination.
+    connect_status: bool,
+}
+
+#[async_trait::async_trait]
+impl OutTunnelStream for ByteStreamOutTunnelStream {
+    async fn accept(
+        mut self: Box<Self>,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+    )> {
+        if self.connect_status {
+            self.write_stream.write_u8(STATUS_CONNECTED).await?;
+        }
+
+        Ok((self.read_stream, self.write_stream))
+    }
+
+    async fn reject(mut self: Box<Self>, error: TunnelConnectError) -> anyhow::Result<()> {
+        if self.connect_status {
+            let message = error.message.as_bytes();
+            let message = &message[..message.len().min(u8::MAX as usize)];
+
+            let mut status = vec![error.kind.as_code(), message.len() as u8];
+
+            status.extend_from_slice(message);
+
+            self.write_stream.write_all(&status).await?;
+        }
+
+        self.write_stream.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, pin::Pin, task::Poll, time::Duration};
+
+    use super::*;
+
+    struct FakeWriteHalf<TWrite>(TWrite);
+
+    impl<TWrite: tokio::io::AsyncWrite + Send + Unpin> tokio::io::AsyncWrite for FakeWriteHalf<TWrite> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            context: &mut std::task::Context<'_>,
+            buffer: &[u8],
+        ) -> Poll<Result<usize, tokio::io::Error>> {
+            Pin::new(&mut self.0).poll_write(context, buffer)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            context: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), tokio::io::Error>> {
+            Pin::new(&mut self.0).poll_flush(context)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            context: &mut std::task::Context<'_>,
+        ) -> Poll<Result<(), tokio::io::Error>> {
+            Pin::new(&mut self.0).poll_shutdown(context)
+        }
+    }
+
+    impl<TWrite: tokio::io::AsyncWrite + Send + Unpin> AsyncWriteReset for FakeWriteHalf<TWrite> {
+        fn reset(&mut self) {}
+    }
+
+    /// Connection handing out prepared streams.
+    struct FakeConnection {
+        streams: Mutex<VecDeque<ByteStreamPair>>,
+        handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+    }
+
+    #[async_trait::async_trait]
+    impl ByteStreamInTunnelConnection for FakeConnection {
+        async fn open(&self) -> anyhow::Result<ByteStreamPair> {
+            self.streams
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| anyhow::anyhow!("no stream left."))
+        }
+
+        async fn closed(&self) {
+            std::future::pending().await
+        }
+
+        fn is_closed(&self) -> bool {
+            false
+        }
+
+        fn is_handshake_confirmed(&self) -> bool {
+            *self.handshake_confirmed.borrow()
+        }
+
+        async fn handshake_confirmed(&self) {
+            let _ = self
+                .handshake_confirmed
+                .clone()
+                .wait_for(|confirmed| *confirmed)
+                .await;
+        }
+    }
+
+    /// Stream of which OUT reads through the returned peer.
+    fn create_stream() -> (ByteStreamPair, tokio::io::DuplexStream) {
+        let (stream, peer) = tokio::io::duplex(1024);
+
+        let (read_stream, write_stream) = tokio::io::split(stream);
+
+        (
+            (Box::new(read_stream), Box::new(FakeWriteHalf(write_stream))),
+            peer,
+        )
+    }
+
+    fn create_tunnel(
+        streams: Vec<ByteStreamPair>,
+        handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+        stream_pool_size: usize,
+    ) -> Arc<ByteStreamInTunnel<FakeConnection>> {
+        Arc::new(ByteStreamInTunnel::new(
+            "fake",
+            TunnelId::new(),
+            MatchOutId::new(),
+            Vec::new(),
+            0,
+            NegotiatedTunnelProtocol {
+                version: 3,
+                capabilities: Vec::new(),
+            },
+            FakeConnection {
+                streams: Mutex::new(streams.into()),
+                handshake_confirmed,
+            },
+            stream_pool_size,
+        ))
+    }
+
+    const DESTINATION_ADDRESS: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 80));
+
+    /// Head of a connection to `DESTINATION_ADDRESS` without name or tag.
+    const HEAD: [u8; 9] = [3, 127, 0, 0, 1, 0, 80, 0, 0];
+
+    #[tokio::test]
+    async fn withholds_sniffed_data_until_handshake_confirmed() {
+        let (stream, mut peer) = create_stream();
+        let (handshake_confirmed_sender, handshake_confirmed) = tokio::sync::watch::channel(false);
+
+        let tunnel = create_tunnel(vec![stream], handshake_confirmed, 0);
+
+        let connect = tokio::spawn({
+            let tunnel = tunnel.clone();
+
+            async move {
+                tunnel
+                    .connect(DESTINATION_ADDRESS, None, None, Some(b"GET /".to_vec()))
+                    .await
+            }
+        });
+
+        let mut head = [0; HEAD.len()];
+        peer.read_exact(&mut head).await.unwrap();
+        assert_eq!(head, HEAD);
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), peer.read_u8())
+                .await
+                .is_err()
+        );
+
+        handshake_confirmed_sender.send_replace(true);
+
+        let mut sniffed = [0; 5];
+        peer.read_exact(&mut sniffed).await.unwrap();
+        assert_eq!(&sniffed, b"GET /");
+
+        connect.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sends_sniffed_data_with_head_once_confirmed() {
+        let (stream, mut peer) = create_stream();
+
+        let tunnel = create_tunnel(vec![stream], tokio::sync::watch::channel(true).1, 0);
+
+        tunnel
+            .connect(DESTINATION_ADDRESS, None, None, Some(b"GET /".to_vec()))
+            .await
+            .unwrap();
+
+        let mut head = [0; HEAD.len() + 5];
+        peer.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[..HEAD.len()], HEAD);
+        assert_eq!(&head[HEAD.len()..], b"GET /");
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_pooled_streams() {
+        // Peers of the first pooled streams are gone.
+        let (stale_streams, stale_peers): (Vec<_>, Vec<_>) =
+            (0..2).map(|_| create_stream()).unzip();
+        drop(stale_peers);
+
+        let (stream, mut peer) = create_stream();
+
+        let tunnel = create_tunnel(
+            stale_streams.into_iter().chain([stream]).collect(),
+            tokio::sync::watch::channel(true).1,
+            3,
+        );
+
+        // Lets the pool take the streams.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        tunnel
+            .connect(DESTINATION_ADDRESS, None, None, None)
+            .await
+            .unwrap();
+
+        let mut head = [0; HEAD.len()];
+        peer.read_exact(&mut head).await.unwrap();
+        assert_eq!(head, HEAD);
+    }
 }
@@ -2,10 +2,20 @@ use std::sync::Arc;
 
 use itertools::Itertools;
 use rustls::pki_types::pem::PemObject as _;
+use sha2::Digest as _;
 
 use crate::route::rule::Label;
 
-use super::TunnelId;
+use super::{tls_name_default, TunnelId};
+
+/// PKCS#8 v1 prefix of an Ed25519 private key, followed by the 32 bytes seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+const TLS_IDENTITY_KDF_SALT: &[u8] = b"plug2proxy static tls identity";
+const TLS_IDENTITY_KDF_ITERATIONS: std::num::NonZeroU32 =
+    std::num::NonZeroU32::new(600_000).unwrap();
 
 pub fn get_tunnel_string(r#type: &'static str, id: TunnelId, labels: &[Label]) -> String {
     let id_short = &id.0.as_bytes()[..4]
@@ -44,36 +54,380 @@ pub fn create_rustls_client_config(
     Ok(client_config)
 }
 
-pub fn create_rustls_server_config_and_cert(
-    cert_names: impl Into<Vec<String>>,
-) -> (rustls::ServerConfig, String, String) {
-    let cert = rcgen::generate_simple_self_signed(cert_names).unwrap();
+pub fn create_rustls_client_config_with_native_roots() -> anyhow::Result<rustls::ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+
+    let native_certs = rustls_native_certs::load_native_certs();
+
+    for error in native_certs.errors {
+        log::warn!("error loading native certs: {error}");
+    }
+
+    root_store.add_parsable_certificates(native_certs.certs);
+
+    if root_store.is_empty() {
+        anyhow::bail!("no native root certs available.");
+    }
+
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
 
-    let cert_chain = vec![cert.cert.der().clone()];
-    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    Ok(client_config)
+}
+
+pub fn create_rustls_server_config(
+    cert_pem: &str,
+    key_pem: &str,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ServerConfig> {
+    let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid key."))?;
 
     let root_store = {
         let mut root_store = rustls::RootCertStore::empty();
 
-        let cert = rustls::pki_types::CertificateDer::from_slice(cert.cert.der());
-
-        root_store.add(cert).unwrap();
+        root_store.add(cert.clone())?;
 
         root_store
     };
 
-    let client_cert_verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(root_store))
-        .build()
-        .unwrap();
+    let client_cert_verifier =
+        rustls::server::WebPkiClientVerifier::builder(Arc::new(root_store)).build()?;
 
-    let server_config =
+    let mut server_config =
         rustls::ServerConfig::builder_with_protocol_versions(rustls::DEFAULT_VERSIONS)
             .with_client_cert_verifier(client_cert_verifier)
-            .with_single_cert(cert_chain, key.into())
-            .unwrap();
+            .with_single_cert(vec![cert], key)?;
+
+    server_config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(server_config)
+}
+
+pub fn create_rustls_client_config_with_alpn(
+    cert_pem: &str,
+    key_pem: &str,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ClientConfig> {
+    let mut client_config = create_rustls_client_config(cert_pem, key_pem)?;
+
+    client_config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(client_config)
+}
+
+/// Presents the given cert to the server and trusts the server certificate of
+/// the SHA-256 fingerprint.
+pub fn create_rustls_client_config_with_fingerprint(
+    cert_pem: &str,
+    key_pem: &str,
+    fingerprint: Vec<u8>,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ClientConfig> {
+    let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid key."))?;
 
-    let key = cert.key_pair.serialize_pem();
-    let cert = cert.cert.pem();
+    let mut client_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(FingerprintCertVerifier::new(fingerprint)))
+        .with_client_auth_cert(vec![cert], key)?;
 
-    (server_config, cert, key)
+    client_config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(client_config)
+}
+
+/// Requires client certificates of the SHA-256 fingerprints.
+pub fn create_rustls_server_config_with_client_fingerprints(
+    cert_pem: &str,
+    key_pem: &str,
+    client_fingerprints: Vec<Vec<u8>>,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ServerConfig> {
+    create_rustls_server_config_with_client_cert_verifier(
+        cert_pem,
+        key_pem,
+        FingerprintClientCertVerifier::new(client_fingerprints),
+        alpn_protocols,
+    )
+}
+
+/// Requires a client certificate of any fingerprint, for servers shared by peers
+/// not known at handshake. The fingerprint is then checked against the matched
+/// peer with `get_peer_fingerprint`.
+pub fn create_rustls_server_config_with_any_client_cert(
+    cert_pem: &str,
+    key_pem: &str,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ServerConfig> {
+    create_rustls_server_config_with_client_cert_verifier(
+        cert_pem,
+        key_pem,
+        FingerprintClientCertVerifier::any(),
+        alpn_protocols,
+    )
+}
+
+fn create_rustls_server_config_with_client_cert_verifier(
+    cert_pem: &str,
+    key_pem: &str,
+    client_cert_verifier: FingerprintClientCertVerifier,
+    alpn_protocols: &[String],
+) -> anyhow::Result<rustls::ServerConfig> {
+    let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+    let key = rustls::pki_types::PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid key."))?;
+
+    let mut server_config =
+        rustls::ServerConfig::builder_with_protocol_versions(rustls::DEFAULT_VERSIONS)
+            .with_client_cert_verifier(Arc::new(client_cert_verifier))
+            .with_single_cert(vec![cert], key)?;
+
+    server_config.alpn_protocols = alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+
+    Ok(server_config)
+}
+
+/// SHA-256 of the end entity certificate presented by the peer.
+pub fn get_peer_fingerprint(
+    peer_certificates: Option<&[rustls::pki_types::CertificateDer<'_>]>,
+) -> Option<Vec<u8>> {
+    peer_certificates
+        .and_then(|certs| certs.first())
+        .map(|cert| sha2::Sha256::digest(cert.as_ref()).to_vec())
+}
+
+/// SHA-256 of the DER certificate in colon separated hex.
+pub fn get_cert_fingerprint(cert_pem: &str) -> anyhow::Result<String> {
+    let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+
+    Ok(sha2::Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .join(":"))
+}
+
+pub fn parse_fingerprint(fingerprint: &str) -> anyhow::Result<Vec<u8>> {
+    let hex = fingerprint.replace(':', "");
+
+    anyhow::ensure!(
+        hex.len() == 64 && hex.is_ascii(),
+        "invalid fingerprint {fingerprint}."
+    );
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| Ok(u8::from_str_radix(&hex[index..index + 2], 16)?))
+        .collect()
+}
+
+/// Generates a throwaway cert and key for the connecting end of a tunnel, which
+/// publishes only the fingerprint for the other end to pin.
+pub fn generate_tls_identity() -> anyhow::Result<(String, String)> {
+    let cert = rcgen::generate_simple_self_signed([tls_name_default()])?;
+
+    Ok((cert.cert.pem(), cert.key_pair.serialize_pem()))
+}
+
+/// Derives the cert and key shared by both ends from a pre-shared key. Ed25519
+/// signatures and the defaults of rcgen are deterministic, so is the cert.
+///
+/// The cert being public, the key is stretched with PBKDF2 to slow down
+/// guessing it from the cert, the salt separating this use from others.
+pub fn derive_tls_identity(key: &str) -> anyhow::Result<(String, String)> {
+    let mut seed = [0; 32];
+
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        TLS_IDENTITY_KDF_ITERATIONS,
+        TLS_IDENTITY_KDF_SALT,
+        key.as_bytes(),
+        &mut seed,
+    );
+
+    let der = ED25519_PKCS8_PREFIX
+        .iter()
+        .copied()
+        .chain(seed)
+        .collect_vec();
+
+    let key_pair = rcgen::KeyPair::try_from(der.as_slice())?;
+
+    let cert = rcgen::CertificateParams::new(vec![tls_name_default()])?.self_signed(&key_pair)?;
+
+    Ok((cert.pem(), key_pair.serialize_pem()))
+}
+
+/// Trusts the server certificate of the given SHA-256 fingerprint.
+#[derive(Debug)]
+pub struct FingerprintCertVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl FingerprintCertVerifier {
+    pub fn new(fingerprint: Vec<u8>) -> Self {
+        Self {
+            fingerprint,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for FingerprintCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if sha2::Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Trusts client certificates of the given SHA-256 fingerprints.
+#[derive(Debug)]
+pub struct FingerprintClientCertVerifier {
+    /// Any certificate is trusted if `None`, still requiring the client to prove
+    /// possession of its key.
+    fingerprints: Option<Vec<Vec<u8>>>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl FingerprintClientCertVerifier {
+    pub fn new(fingerprints: Vec<Vec<u8>>) -> Self {
+        Self {
+            fingerprints: Some(fingerprints),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    pub fn any() -> Self {
+        Self {
+            fingerprints: None,
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl rustls::server::danger::ClientCertVerifier for FingerprintClientCertVerifier {
+    fn root_hint_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::server::danger::ClientCertVerified, rustls::Error> {
+        let fingerprint = sha2::Sha256::digest(end_entity.as_ref());
+
+        if self.fingerprints.as_ref().is_none_or(|fingerprints| {
+            fingerprints
+                .iter()
+                .any(|allowed| allowed.as_slice() == fingerprint.as_slice())
+        }) {
+            Ok(rustls::server::danger::ClientCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
 }
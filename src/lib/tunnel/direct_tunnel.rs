@@ -2,7 +2,10 @@ use std::{fmt, net::SocketAddr};
 
 use tokio::io::AsyncWriteExt;
 
-use crate::utils::net::socket::set_keepalive_options;
+use crate::utils::{
+    io::{AsyncWriteReset, TcpWriteHalf},
+    net::socket::set_keepalive_options,
+};
 
 use super::InTunnelLike;
 
@@ -14,21 +17,14 @@ impl DirectInTunnel {
     pub fn new(traffic_mark: u32) -> Self {
         Self { traffic_mark }
     }
-}
 
-#[async_trait::async_trait]
-impl InTunnelLike for DirectInTunnel {
-    async fn connect(
+    /// Connects to the destination, returning the plain TCP stream for relaying
+    /// with zero-copy.
+    pub async fn connect_tcp(
         &self,
         destination_address: SocketAddr,
-        _destination_name: Option<String>,
-        _tag: Option<String>,
         sniff_buffer: Option<Vec<u8>>,
-    ) -> anyhow::Result<(
-        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
-        tokio::sync::oneshot::Sender<()>,
-    )> {
+    ) -> anyhow::Result<tokio::net::TcpStream> {
         let socket = match destination_address {
             SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
             SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
@@ -46,13 +42,36 @@ impl InTunnelLike for DirectInTunnel {
             stream.write_all(&sniff_buffer).await?;
         }
 
+        Ok(stream)
+    }
+}
+
+#[async_trait::async_trait]
+impl InTunnelLike for DirectInTunnel {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    async fn connect(
+        &self,
+        destination_address: SocketAddr,
+        _destination_name: Option<String>,
+        _tag: Option<String>,
+        sniff_buffer: Option<Vec<u8>>,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+        tokio::sync::oneshot::Sender<()>,
+    )> {
+        let stream = self.connect_tcp(destination_address, sniff_buffer).await?;
+
         let (read_stream, write_stream) = stream.into_split();
 
         let (stream_closed_sender, _) = tokio::sync::oneshot::channel();
 
         Ok((
             Box::new(read_stream),
-            Box::new(write_stream),
+            Box::new(TcpWriteHalf::new(write_stream)),
             stream_closed_sender,
         ))
     }
@@ -1,12 +1,31 @@
 use std::{
     cmp::min,
     pin::Pin,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
     task::{ready, Context, Poll},
 };
 
 use bytes::Buf;
 use futures::FutureExt;
 
+use crate::utils::io::AsyncWriteReset;
+
+/// Bytes of DATA frames through the streams of a connection.
+#[derive(Default)]
+pub struct H2Traffic {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+}
+
+impl H2Traffic {
+    pub fn total(&self) -> u64 {
+        self.received.load(atomic::Ordering::Relaxed) + self.sent.load(atomic::Ordering::Relaxed)
+    }
+}
+
 #[derive(derive_more::From)]
 pub enum ResponseFutureOrRecvStream {
     ResponseFuture(h2::client::ResponseFuture),
@@ -16,13 +35,18 @@ pub enum ResponseFutureOrRecvStream {
 pub struct H2RecvStreamAsyncRead {
     inner: ResponseFutureOrRecvStream,
     pending: bytes::BytesMut,
+    traffic: Option<Arc<H2Traffic>>,
 }
 
 impl H2RecvStreamAsyncRead {
-    pub fn new(inner: impl Into<ResponseFutureOrRecvStream>) -> Self {
+    pub fn new(
+        inner: impl Into<ResponseFutureOrRecvStream>,
+        traffic: Option<Arc<H2Traffic>>,
+    ) -> Self {
         Self {
             inner: inner.into(),
             pending: bytes::BytesMut::new(),
+            traffic,
         }
     }
 }
@@ -65,6 +89,12 @@ impl tokio::io::AsyncRead for H2RecvStreamAsyncRead {
                     if let Some(data) = ready!(recv_stream.poll_data(context)) {
                         let data = data.map_err(h2_error_to_io_error)?;
 
+                        if let Some(traffic) = &this.traffic {
+                            traffic
+                                .received
+                                .fetch_add(data.len() as u64, atomic::Ordering::Relaxed);
+                        }
+
                         if data.len() < buffer.remaining() {
                             buffer.put_slice(&data);
                         } else {
@@ -92,11 +122,15 @@ impl tokio::io::AsyncRead for H2RecvStreamAsyncRead {
 
 pub struct H2SendStreamAsyncWrite {
     send_stream: h2::SendStream<bytes::Bytes>,
+    traffic: Option<Arc<H2Traffic>>,
 }
 
 impl H2SendStreamAsyncWrite {
-    pub fn new(send_stream: h2::SendStream<bytes::Bytes>) -> Self {
-        Self { send_stream }
+    pub fn new(send_stream: h2::SendStream<bytes::Bytes>, traffic: Option<Arc<H2Traffic>>) -> Self {
+        Self {
+            send_stream,
+            traffic,
+        }
     }
 }
 
@@ -133,7 +167,15 @@ impl tokio::io::AsyncWrite for H2SendStreamAsyncWrite {
             .send_stream
             .send_data(buffer[..length].to_vec().into(), false)
         {
-            Ok(_) => Poll::Ready(Ok(length)),
+            Ok(_) => {
+                if let Some(traffic) = &this.traffic {
+                    traffic
+                        .sent
+                        .fetch_add(length as u64, atomic::Ordering::Relaxed);
+                }
+
+                Poll::Ready(Ok(length))
+            }
             Err(error) => Poll::Ready(Err(h2_error_to_io_error(error))),
         }
     }
@@ -158,10 +200,16 @@ impl tokio::io::AsyncWrite for H2SendStreamAsyncWrite {
     }
 }
 
+impl AsyncWriteReset for H2SendStreamAsyncWrite {
+    fn reset(&mut self) {
+        self.send_stream.send_reset(h2::Reason::CANCEL);
+    }
+}
+
 fn h2_error_to_io_error(error: h2::Error) -> std::io::Error {
     if error.is_io() {
         error.into_io().unwrap()
     } else {
-        std::io::Error::new(std::io::ErrorKind::Other, error.to_string())
+        std::io::Error::other(error.to_string())
     }
 }
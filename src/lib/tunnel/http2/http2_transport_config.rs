@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Largest flow control window allowed by HTTP/2 (2^31-1).
+pub const HTTP2_MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Http2FlowControl {
+    /// Follows the receive buffer size of the TCP socket, which the kernel tunes
+    /// with the congestion window.
+    Socket,
+    /// Grows windows with the bandwidth-delay product estimated from PING round
+    /// trips.
+    Adaptive,
+}
+
+#[derive(Clone)]
+pub struct Http2TransportConfig {
+    pub flow_control: Http2FlowControl,
+    /// Initial windows, and the lower bound of the connection window in socket
+    /// mode.
+    pub min_window_size: u32,
+    /// Upper bound of windows in adaptive mode.
+    pub max_window_size: u32,
+    /// Interval of checking the socket receive buffer in socket mode, or sending
+    /// PING in adaptive mode.
+    pub window_size_check_interval: Duration,
+    /// Streams opened before a tunnel is rotated, a workaround of suspected
+    /// memory growth of long standing h2 connections.
+    pub max_lifetime_streams: Option<usize>,
+    /// Age at which a tunnel is rotated.
+    pub max_lifetime: Option<Duration>,
+    /// Bytes transferred (both directions) before a tunnel is rotated.
+    pub max_lifetime_bytes: Option<u64>,
+}
+
+impl Default for Http2TransportConfig {
+    fn default() -> Self {
+        Self {
+            flow_control: Http2FlowControl::Socket,
+            min_window_size: 4 * 1024 * 1024,
+            max_window_size: 64 * 1024 * 1024,
+            window_size_check_interval: Duration::from_millis(200),
+            max_lifetime_streams: Some(4096),
+            max_lifetime: None,
+            max_lifetime_bytes: None,
+        }
+    }
+}
@@ -6,8 +6,8 @@ use std::{
         atomic::{self, AtomicBool, AtomicUsize},
         Arc, Mutex,
     },
-    task::Poll,
-    time::Duration,
+    task::{Context, Poll},
+    time::Instant,
 };
 
 use futures::FutureExt;
@@ -18,12 +18,19 @@ use crate::{
     route::rule::Label,
     tunnel::{
         common::get_tunnel_string,
-        http2::compat::{H2RecvStreamAsyncRead, H2SendStreamAsyncWrite},
-        InTunnel, InTunnelLike, OutTunnel, TunnelId,
+        http2::{
+            compat::{H2RecvStreamAsyncRead, H2SendStreamAsyncWrite, H2Traffic},
+            Http2FlowControl, Http2TransportConfig, HTTP2_MAX_WINDOW_SIZE,
+        },
+        InTunnel, InTunnelLike, NegotiatedTunnelProtocol, OutTunnel, OutTunnelStream,
+        TunnelConnectError, TunnelConnectErrorKind, TunnelId,
     },
+    utils::io::AsyncWriteReset,
 };
 
-const WINDOW_SIZE_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+const VERSION_HEADER: &str = "X-Version";
+const ERROR_KIND_HEADER: &str = "X-Error-Kind";
+const ERROR_HEADER: &str = "X-Error";
 
 type Http2ServerConnection<TTlsStream> = h2::server::Connection<TTlsStream, bytes::Bytes>;
 
@@ -35,10 +42,13 @@ pub struct Http2InTunnel {
     out_id: MatchOutId,
     labels: Vec<Label>,
     priority: i64,
+    protocol: NegotiatedTunnelProtocol,
     request_sender: Arc<Mutex<Option<h2::client::SendRequest<bytes::Bytes>>>>,
     active_permit: Arc<Mutex<Option<tokio::sync::OwnedSemaphorePermit>>>,
     lifetime_streams: AtomicUsize,
+    max_lifetime_streams: Option<usize>,
     active_streams: Arc<AtomicUsize>,
+    traffic: Arc<H2Traffic>,
     closed_notify: Arc<tokio::sync::Notify>,
     closed: Arc<AtomicBool>,
     handle: tokio::task::JoinHandle<()>,
@@ -51,43 +61,84 @@ impl Http2InTunnel {
         out_id: MatchOutId,
         labels: Vec<Label>,
         priority: i64,
+        protocol: NegotiatedTunnelProtocol,
         request_sender: h2::client::SendRequest<bytes::Bytes>,
         mut connection: Http2ClientConnection<TTlsStream>,
         fd: i32,
+        transport: &Http2TransportConfig,
     ) -> Self
     where
         TTlsStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     {
+        let request_sender = Arc::new(Mutex::new(Some(request_sender)));
         let active_permit = Arc::new(Mutex::new(None));
+        let active_streams = Arc::new(AtomicUsize::new(0));
+        let traffic = Arc::new(H2Traffic::default());
 
         let closed_notify = Arc::new(tokio::sync::Notify::new());
         let closed = Arc::new(AtomicBool::new(false));
 
         let handle = tokio::spawn({
+            let request_sender = request_sender.clone();
             let active_permit = active_permit.clone();
+            let active_streams = active_streams.clone();
+            let traffic = traffic.clone();
 
             let closed_notify = closed_notify.clone();
             let closed = closed.clone();
 
-            async move {
-                let mut window_size_setter = WindowSizeSetter::new(fd);
+            let tunnel_string = get_tunnel_string(tunnel_type, id, &labels);
+
+            let mut flow_controller =
+                Http2FlowController::new(transport, fd, traffic.clone(), connection.ping_pong());
+
+            let mut interval = tokio::time::interval(transport.window_size_check_interval);
 
-                let mut interval = tokio::time::interval(WINDOW_SIZE_CHECK_INTERVAL);
+            let created_at = Instant::now();
+            let max_lifetime = transport.max_lifetime;
+            let max_lifetime_bytes = transport.max_lifetime_bytes;
 
+            async move {
                 futures::future::poll_fn(|context| {
                     match connection.poll_unpin(context) {
                         std::task::Poll::Ready(_) => return Poll::Ready(()),
                         std::task::Poll::Pending => {}
                     }
 
-                    match interval.poll_tick(context) {
-                        Poll::Ready(_) => {
-                            window_size_setter
-                                .set_window_size(H2ConnectionMutRef::Client(&mut connection));
-
-                            context.waker().wake_by_ref();
+                    let ticked = interval.poll_tick(context).is_ready();
+
+                    flow_controller.poll(
+                        context,
+                        ticked,
+                        H2ConnectionMutRef::Client(&mut connection),
+                    );
+
+                    if ticked {
+                        let rotation_reason = if max_lifetime
+                            .is_some_and(|max_lifetime| created_at.elapsed() >= max_lifetime)
+                        {
+                            Some("lifetime limit")
+                        } else if max_lifetime_bytes
+                            .is_some_and(|max_lifetime_bytes| traffic.total() >= max_lifetime_bytes)
+                        {
+                            Some("lifetime bytes limit")
+                        } else {
+                            None
+                        };
+
+                        if let Some(rotation_reason) = rotation_reason {
+                            if active_permit.lock().unwrap().take().is_some() {
+                                log::info!("tunnel {tunnel_string} reached {rotation_reason}.");
+
+                                if active_streams.load(atomic::Ordering::Relaxed) == 0 {
+                                    log::info!("closing inactive tunnel {tunnel_string}.");
+
+                                    request_sender.lock().unwrap().take();
+                                }
+                            }
                         }
-                        Poll::Pending => {}
+
+                        context.waker().wake_by_ref();
                     }
 
                     Poll::Pending
@@ -107,10 +158,13 @@ impl Http2InTunnel {
             out_id,
             labels,
             priority,
-            request_sender: Arc::new(Mutex::new(Some(request_sender))),
+            protocol,
+            request_sender,
             active_permit,
             lifetime_streams: AtomicUsize::new(0),
-            active_streams: Arc::new(AtomicUsize::new(0)),
+            max_lifetime_streams: transport.max_lifetime_streams,
+            active_streams,
+            traffic,
             closed_notify,
             closed,
             handle,
@@ -134,10 +188,12 @@ impl fmt::Display for Http2InTunnel {
     }
 }
 
-const LIFETIME_STREAMS_LIMIT: usize = 4096;
-
 #[async_trait::async_trait]
 impl InTunnelLike for Http2InTunnel {
+    fn name(&self) -> &'static str {
+        self.tunnel_type
+    }
+
     async fn connect(
         &self,
         destination_address: SocketAddr,
@@ -146,7 +202,7 @@ impl InTunnelLike for Http2InTunnel {
         sniff_buffer: Option<Vec<u8>>,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
         tokio::sync::oneshot::Sender<()>,
     )> {
         let lifetime_streams = self
@@ -155,7 +211,7 @@ impl InTunnelLike for Http2InTunnel {
             + 1;
 
         // h2 might has some memory leak issue for long standing connections.
-        if lifetime_streams == LIFETIME_STREAMS_LIMIT {
+        if Some(lifetime_streams) == self.max_lifetime_streams {
             log::info!("tunnel {self} reached lifetime connection limit.");
 
             self.active_permit.lock().unwrap().take();
@@ -166,6 +222,7 @@ impl InTunnelLike for Http2InTunnel {
 
             http_request = http_request
                 .method(http::Method::POST)
+                .header(VERSION_HEADER, self.protocol.version)
                 .header("X-Address", destination_address.to_string());
 
             if let Some(destination_name) = destination_name {
@@ -187,13 +244,42 @@ impl InTunnelLike for Http2InTunnel {
             .ok_or_else(|| anyhow::anyhow!("{} connection no longer available.", self.tunnel_type))?
             .send_request(http_request, false)?;
 
-        let read_stream = H2RecvStreamAsyncRead::new(response);
-        let mut write_stream = H2SendStreamAsyncWrite::new(write_stream);
+        let mut write_stream =
+            H2SendStreamAsyncWrite::new(write_stream, Some(self.traffic.clone()));
 
         if let Some(sniff_buffer) = sniff_buffer {
             write_stream.write_all(&sniff_buffer).await?;
         }
 
+        let response = response.await?;
+
+        if !response.status().is_success() {
+            let headers = response.headers();
+
+            // Kinds unknown to this side (e.g. from a newer OUT) are taken as
+            // `Other`.
+            let kind = headers
+                .get(ERROR_KIND_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| {
+                    value
+                        .parse()
+                        .inspect_err(|error| log::debug!("{error}"))
+                        .ok()
+                })
+                .unwrap_or(TunnelConnectErrorKind::Other);
+
+            let message = headers
+                .get(ERROR_HEADER)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .unwrap_or_else(|| response.status().to_string());
+
+            return Err(TunnelConnectError::new(kind, message).into());
+        }
+
+        let read_stream =
+            H2RecvStreamAsyncRead::new(response.into_body(), Some(self.traffic.clone()));
+
         let active_streams = self.active_streams.clone();
 
         active_streams.fetch_add(1, atomic::Ordering::Relaxed);
@@ -256,6 +342,16 @@ impl InTunnel for Http2InTunnel {
         self.active_permit.lock().unwrap().is_some()
     }
 
+    fn deactivate(&self) {
+        if self.active_permit.lock().unwrap().take().is_some()
+            && self.active_streams.load(atomic::Ordering::Relaxed) == 0
+        {
+            log::info!("closing inactive tunnel {self}.");
+
+            self.request_sender.lock().unwrap().take();
+        }
+    }
+
     async fn closed(&self) {
         self.closed_notify.notified().await;
     }
@@ -268,27 +364,49 @@ impl InTunnel for Http2InTunnel {
 pub struct Http2OutTunnel<TTlsStream> {
     tunnel_type: &'static str,
     id: TunnelId,
-    connection: Arc<tokio::sync::Mutex<Http2ServerConnection<TTlsStream>>>,
+    protocol: NegotiatedTunnelProtocol,
+    connection: tokio::sync::Mutex<Http2OutTunnelConnection<TTlsStream>>,
+    traffic: Arc<H2Traffic>,
     closed: AtomicBool,
-    fd: i32,
+}
+
+/// Connection polled by `accept`, with the flow control state kept across calls.
+struct Http2OutTunnelConnection<TTlsStream> {
+    connection: Http2ServerConnection<TTlsStream>,
+    flow_controller: Http2FlowController,
+    interval: tokio::time::Interval,
 }
 
 impl<TTlsStream> Http2OutTunnel<TTlsStream>
 where
     TTlsStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
 {
+    /// The connection is expected to be built with the initial windows of
+    /// `transport`, which are then adjusted as configured.
     pub fn new(
         tunnel_type: &'static str,
         id: TunnelId,
-        connection: Http2ServerConnection<TTlsStream>,
+        protocol: NegotiatedTunnelProtocol,
+        mut connection: Http2ServerConnection<TTlsStream>,
         fd: i32,
+        transport: &Http2TransportConfig,
     ) -> Self {
+        let traffic = Arc::new(H2Traffic::default());
+
+        let flow_controller =
+            Http2FlowController::new(transport, fd, traffic.clone(), connection.ping_pong());
+
         Http2OutTunnel {
             tunnel_type,
             id,
-            connection: Arc::new(tokio::sync::Mutex::new(connection)),
+            protocol,
+            connection: tokio::sync::Mutex::new(Http2OutTunnelConnection {
+                connection,
+                flow_controller,
+                interval: tokio::time::interval(transport.window_size_check_interval),
+            }),
+            traffic,
             closed: AtomicBool::new(false),
-            fd,
         }
     }
 }
@@ -315,23 +433,56 @@ where
         self.id
     }
 
+    fn name(&self) -> &'static str {
+        self.tunnel_type
+    }
+
     async fn accept(
         &self,
     ) -> anyhow::Result<(
         (SocketAddr, Option<String>, Option<String>),
-        (
-            Box<dyn AsyncRead + Send + Unpin>,
-            Box<dyn AsyncWrite + Send + Unpin>,
-        ),
+        Box<dyn OutTunnelStream>,
     )> {
         let mut connection = self.connection.lock().await;
-        let mut window_size_setter = WindowSizeSetter::new(self.fd);
 
-        let mut interval = tokio::time::interval(WINDOW_SIZE_CHECK_INTERVAL);
+        let Http2OutTunnelConnection {
+            connection,
+            flow_controller,
+            interval,
+        } = &mut *connection;
 
         let result = futures::future::poll_fn(|context| {
             match connection.poll_accept(context) {
                 Poll::Ready(Some(Ok((request, mut response_sender)))) => {
+                    let version = request
+                        .headers()
+                        .get(VERSION_HEADER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<u16>().ok());
+
+                    // Legacy IN sends no version.
+                    if let Some(version) =
+                        version.filter(|&version| version != self.protocol.version)
+                    {
+                        let error = TunnelConnectError::new(
+                            TunnelConnectErrorKind::Other,
+                            format!(
+                                "stream of protocol version {version}, tunnel negotiated {}.",
+                                self.protocol.version
+                            ),
+                        );
+
+                        log::warn!("tunnel {self} rejected stream: {error}");
+
+                        response_sender
+                            .send_response(create_error_response(&error), true)
+                            .ok();
+
+                        context.waker().wake_by_ref();
+
+                        return Poll::Pending;
+                    }
+
                     let destination_tuple = {
                         let headers = request.headers();
 
@@ -352,19 +503,13 @@ where
                         )
                     };
 
-                    let response = http::Response::builder().body(()).unwrap();
-
-                    let send_stream = response_sender.send_response(response, false)?;
-
-                    let read_stream = H2RecvStreamAsyncRead::new(request.into_body());
-                    let write_stream = H2SendStreamAsyncWrite::new(send_stream);
-
                     return Poll::Ready(Ok((
                         destination_tuple,
-                        (
-                            Box::new(read_stream) as Box<dyn AsyncRead + Send + Unpin>,
-                            Box::new(write_stream) as Box<dyn AsyncWrite + Send + Unpin>,
-                        ),
+                        Box::new(Http2OutTunnelStream {
+                            recv_stream: request.into_body(),
+                            response_sender,
+                            traffic: self.traffic.clone(),
+                        }) as Box<dyn OutTunnelStream>,
                     )));
                 }
                 Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error.into())),
@@ -377,12 +522,9 @@ where
                 Poll::Pending => {}
             }
 
-            match interval.poll_tick(context) {
-                Poll::Ready(_) => {
-                    window_size_setter.set_window_size(H2ConnectionMutRef::Server(&mut connection));
-                }
-                Poll::Pending => {}
-            }
+            let ticked = interval.poll_tick(context).is_ready();
+
+            flow_controller.poll(context, ticked, H2ConnectionMutRef::Server(connection));
 
             Poll::Pending
         })
@@ -400,6 +542,50 @@ where
     }
 }
 
+struct Http2OutTunnelStream {
+    recv_stream: h2::RecvStream,
+    response_sender: h2::server::SendResponse<bytes::Bytes>,
+    traffic: Arc<H2Traffic>,
+}
+
+#[async_trait::async_trait]
+impl OutTunnelStream for Http2OutTunnelStream {
+    async fn accept(
+        mut self: Box<Self>,
+    ) -> anyhow::Result<(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWriteReset>)> {
+        let response = http::Response::builder().body(()).unwrap();
+
+        let send_stream = self.response_sender.send_response(response, false)?;
+
+        Ok((
+            Box::new(H2RecvStreamAsyncRead::new(
+                self.recv_stream,
+                Some(self.traffic.clone()),
+            )),
+            Box::new(H2SendStreamAsyncWrite::new(send_stream, Some(self.traffic))),
+        ))
+    }
+
+    async fn reject(mut self: Box<Self>, error: TunnelConnectError) -> anyhow::Result<()> {
+        self.response_sender
+            .send_response(create_error_response(&error), true)?;
+
+        Ok(())
+    }
+}
+
+fn create_error_response(error: &TunnelConnectError) -> http::Response<()> {
+    let mut response = http::Response::builder()
+        .status(http::StatusCode::BAD_GATEWAY)
+        .header(ERROR_KIND_HEADER, error.kind.as_str());
+
+    if let Ok(message) = http::HeaderValue::from_str(&error.message) {
+        response = response.header(ERROR_HEADER, message);
+    }
+
+    response.body(()).unwrap()
+}
+
 struct AnyAsFd {
     raw_fd: i32,
 }
@@ -410,17 +596,58 @@ impl AsFd for AnyAsFd {
     }
 }
 
-const MIN_WINDOW_SIZE: u32 = 4 * 1024 * 1024; // 4MB
+enum Http2FlowController {
+    Socket(WindowSizeSetter),
+    Adaptive(BdpWindowSizeSetter),
+}
+
+impl Http2FlowController {
+    fn new(
+        transport: &Http2TransportConfig,
+        fd: i32,
+        traffic: Arc<H2Traffic>,
+        ping_pong: Option<h2::PingPong>,
+    ) -> Self {
+        match (transport.flow_control, ping_pong) {
+            (Http2FlowControl::Adaptive, Some(ping_pong)) => {
+                Self::Adaptive(BdpWindowSizeSetter::new(transport, traffic, ping_pong))
+            }
+            _ => Self::Socket(WindowSizeSetter::new(fd, transport.min_window_size)),
+        }
+    }
+
+    fn poll<TTlsStream>(
+        &mut self,
+        context: &mut Context,
+        ticked: bool,
+        connection: H2ConnectionMutRef<TTlsStream>,
+    ) where
+        TTlsStream: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Self::Socket(window_size_setter) => {
+                if ticked {
+                    window_size_setter.set_window_size(connection);
+                }
+            }
+            Self::Adaptive(window_size_setter) => {
+                window_size_setter.poll(context, ticked, connection);
+            }
+        }
+    }
+}
 
 struct WindowSizeSetter {
     fd: AnyAsFd,
+    min_window_size: u32,
     recorded_receive_buffer_size: u32,
 }
 
 impl WindowSizeSetter {
-    fn new(fd: i32) -> Self {
+    fn new(fd: i32, min_window_size: u32) -> Self {
         WindowSizeSetter {
             fd: AnyAsFd { raw_fd: fd },
+            min_window_size,
             recorded_receive_buffer_size: 0,
         }
     }
@@ -440,9 +667,125 @@ impl WindowSizeSetter {
         self.recorded_receive_buffer_size = receive_buffer_size;
 
         let stream_window_size = receive_buffer_size;
-        let connection_window_size = receive_buffer_size.max(MIN_WINDOW_SIZE) * 4;
+        let connection_window_size = receive_buffer_size
+            .max(self.min_window_size)
+            .saturating_mul(4);
+
+        connection.set_window_sizes(stream_window_size, connection_window_size);
+    }
+}
+
+/// Estimates the bandwidth-delay product as the bytes received during a PING
+/// round trip, and doubles it for windows once it reaches 2/3 of the current
+/// window (the way hyper does).
+struct BdpWindowSizeSetter {
+    traffic: Arc<H2Traffic>,
+    ping_pong: Option<h2::PingPong>,
+    /// Time and received bytes at sending the PING in flight.
+    ping: Option<(Instant, u64)>,
+    last_received: u64,
+    window_size: u32,
+    max_window_size: u32,
+}
+
+impl BdpWindowSizeSetter {
+    fn new(
+        transport: &Http2TransportConfig,
+        traffic: Arc<H2Traffic>,
+        ping_pong: h2::PingPong,
+    ) -> Self {
+        Self {
+            traffic,
+            ping_pong: Some(ping_pong),
+            ping: None,
+            last_received: 0,
+            window_size: transport.min_window_size,
+            max_window_size: transport.max_window_size.max(transport.min_window_size),
+        }
+    }
+
+    fn poll<TTlsStream>(
+        &mut self,
+        context: &mut Context,
+        ticked: bool,
+        connection: H2ConnectionMutRef<TTlsStream>,
+    ) where
+        TTlsStream: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(ping_pong) = &mut self.ping_pong else {
+            return;
+        };
 
-        match connection {
+        let received = self.traffic.received.load(atomic::Ordering::Relaxed);
+
+        match self.ping {
+            Some((sent_at, received_at_ping)) => match ping_pong.poll_pong(context) {
+                Poll::Ready(Ok(_)) => {
+                    self.ping = None;
+
+                    let bdp = received - received_at_ping;
+
+                    let Some(window_size) =
+                        get_bdp_window_size(self.window_size, self.max_window_size, bdp)
+                    else {
+                        return;
+                    };
+
+                    self.window_size = window_size;
+
+                    log::debug!(
+                        "http2 window size set to {} (BDP {bdp}, RTT {:?}).",
+                        self.window_size,
+                        sent_at.elapsed()
+                    );
+
+                    connection.set_window_sizes(self.window_size, self.window_size);
+                }
+                Poll::Ready(Err(error)) => {
+                    log::warn!("http2 PING failed, adaptive flow control disabled: {error}");
+
+                    self.ping_pong = None;
+                }
+                Poll::Pending => {}
+            },
+            None => {
+                // Only measures while receiving.
+                if ticked && received != self.last_received {
+                    self.last_received = received;
+
+                    if ping_pong.send_ping(h2::Ping::opaque()).is_ok() {
+                        self.ping = Some((Instant::now(), received));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Window size for the estimated BDP, `None` to keep the current one.
+fn get_bdp_window_size(window_size: u32, max_window_size: u32, bdp: u64) -> Option<u32> {
+    if bdp * 3 < u64::from(window_size) * 2 || window_size == max_window_size {
+        return None;
+    }
+
+    Some((bdp * 2).min(u64::from(max_window_size)) as u32)
+}
+
+enum H2ConnectionMutRef<'a, TTlsStream> {
+    Server(&'a mut Http2ServerConnection<TTlsStream>),
+    Client(&'a mut Http2ClientConnection<TTlsStream>),
+}
+
+impl<TTlsStream> H2ConnectionMutRef<'_, TTlsStream>
+where
+    TTlsStream: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Window sizes are clamped as h2 panics on sizes beyond the HTTP/2 limit.
+    fn set_window_sizes(self, stream_window_size: u32, connection_window_size: u32) {
+        let stream_window_size = stream_window_size.min(HTTP2_MAX_WINDOW_SIZE);
+        let connection_window_size = connection_window_size.min(HTTP2_MAX_WINDOW_SIZE);
+
+        match self {
             H2ConnectionMutRef::Server(connection) => {
                 connection.set_initial_window_size(stream_window_size).ok();
                 connection.set_target_window_size(connection_window_size);
@@ -455,7 +798,88 @@ impl WindowSizeSetter {
     }
 }
 
-enum H2ConnectionMutRef<'a, TTlsStream> {
-    Server(&'a mut Http2ServerConnection<TTlsStream>),
-    Client(&'a mut Http2ClientConnection<TTlsStream>),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_window_with_bdp() {
+        const MIB: u32 = 1024 * 1024;
+
+        for (window_size, bdp, expected) in [
+            // Below 2/3 of the window.
+            (4 * MIB, 2 * u64::from(MIB), None),
+            (4 * MIB, 3 * u64::from(MIB), Some(6 * MIB)),
+            (6 * MIB, 5 * u64::from(MIB), Some(10 * MIB)),
+            // Capped.
+            (32 * MIB, 40 * u64::from(MIB), Some(64 * MIB)),
+            (64 * MIB, 100 * u64::from(MIB), None),
+        ] {
+            assert_eq!(
+                get_bdp_window_size(window_size, 64 * MIB, bdp),
+                expected,
+                "window size {window_size}, BDP {bdp}"
+            );
+        }
+    }
+
+    async fn create_out_tunnel(
+        flow_control: Http2FlowControl,
+    ) -> Http2OutTunnel<tokio::net::TcpStream> {
+        use std::os::fd::AsRawFd as _;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (client_stream, server_stream) =
+            tokio::join!(tokio::net::TcpStream::connect(address), listener.accept());
+
+        let (server_stream, _) = server_stream.unwrap();
+        let fd = server_stream.as_raw_fd();
+
+        let transport = Http2TransportConfig {
+            flow_control,
+            ..Default::default()
+        };
+
+        let (server, client) = tokio::join!(
+            h2::server::Builder::new()
+                .initial_window_size(transport.min_window_size)
+                .handshake::<_, bytes::Bytes>(server_stream),
+            h2::client::handshake(client_stream.unwrap()),
+        );
+
+        let (_, client_connection) = client.unwrap();
+
+        tokio::spawn(client_connection);
+
+        Http2OutTunnel::new(
+            "http2",
+            TunnelId::new(),
+            NegotiatedTunnelProtocol {
+                version: 3,
+                capabilities: Vec::new(),
+            },
+            server.unwrap(),
+            fd,
+            &transport,
+        )
+    }
+
+    #[tokio::test]
+    async fn out_tunnel_follows_flow_control() {
+        let tunnel = create_out_tunnel(Http2FlowControl::Adaptive).await;
+
+        assert!(matches!(
+            tunnel.connection.lock().await.flow_controller,
+            Http2FlowController::Adaptive(_)
+        ));
+
+        let tunnel = create_out_tunnel(Http2FlowControl::Socket).await;
+
+        assert!(matches!(
+            tunnel.connection.lock().await.flow_controller,
+            Http2FlowController::Socket(_)
+        ));
+    }
 }
@@ -1,24 +1,27 @@
 use std::{
     net::SocketAddr,
     os::fd::{AsFd as _, AsRawFd as _},
-    str::FromStr,
     sync::Arc,
     time::Duration,
 };
 
-use itertools::Itertools;
-
 use crate::{
+    bandwidth::BandwidthLimit,
     match_server::{
-        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchPair,
-        OutMatchServer, OutMatchServerTrait as _,
+        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchOutIdentity,
+        MatchPair, OutLoad, OutMatchServer, OutMatchServerTrait as _,
     },
     route::config::OutRuleConfig,
     tunnel::{
-        common::{create_rustls_client_config, create_rustls_server_config_and_cert},
-        http2::{Http2InTunnel, Http2OutTunnel},
+        common::{
+            create_rustls_client_config_with_fingerprint,
+            create_rustls_server_config_with_client_fingerprints, generate_tls_identity,
+            get_cert_fingerprint, parse_fingerprint,
+        },
+        http2::{Http2InTunnel, Http2OutTunnel, Http2TransportConfig},
+        tls_name_default,
         tunnel_provider::{InTunnelProvider, OutTunnelProvider},
-        InTunnel, OutTunnel,
+        InTunnel, OutTunnel, TunnelProtocol, TunnelTlsConfig,
     },
     utils::{
         net::{bind_tcp_listener_reuseaddr, socket::set_keepalive_options},
@@ -28,17 +31,19 @@ use crate::{
 
 const TUNNEL_NAME: &str = "http2";
 
-const TLS_NAME: &str = "localhost";
-
 pub struct Http2InTunnelConfig {
     pub connections: usize,
     pub priority: Option<i64>,
     pub priority_default: i64,
     pub traffic_mark: u32,
+    pub transport: Http2TransportConfig,
 }
 
 pub struct Http2InTunnelProvider {
     match_server: Arc<AnyInMatchServer>,
+    /// Client cert presented to OUT, which pins it by fingerprint.
+    cert: String,
+    key: String,
     config: Http2InTunnelConfig,
 }
 
@@ -47,8 +52,12 @@ impl Http2InTunnelProvider {
         match_server: Arc<AnyInMatchServer>,
         config: Http2InTunnelConfig,
     ) -> anyhow::Result<Self> {
+        let (cert, key) = generate_tls_identity()?;
+
         Ok(Self {
             match_server,
+            cert,
+            key,
             config,
         })
     }
@@ -67,10 +76,21 @@ impl InTunnelProvider for Http2InTunnelProvider {
             .map(|out_id| (out_id, self.config.connections))
     }
 
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        self.match_server.get_out_load(out_id).await
+    }
+
     async fn accept(
         &self,
         out_id: MatchOutId,
-    ) -> anyhow::Result<Option<(Box<dyn InTunnel>, (Vec<OutRuleConfig>, i64))>> {
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    > {
         let Some(MatchOut {
             id,
             tunnel_id,
@@ -78,12 +98,31 @@ impl InTunnelProvider for Http2InTunnelProvider {
             tunnel_priority,
             routing_priority,
             routing_rules,
-            data: Http2OutData { address, cert, key },
-        }) = self.match_server.match_out(out_id, Http2InData {}).await?
+            bandwidth_limit,
+            protocol,
+            identity,
+            data:
+                Http2OutData {
+                    address,
+                    fingerprint,
+                    tls_name,
+                    alpn,
+                },
+        }) = self
+            .match_server
+            .match_out(
+                out_id,
+                Http2InData {
+                    fingerprint: get_cert_fingerprint(&self.cert)?,
+                },
+            )
+            .await?
         else {
             return Ok(None);
         };
 
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
         let socket = match address {
             SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
             SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
@@ -105,23 +144,22 @@ impl InTunnelProvider for Http2InTunnelProvider {
 
         log::info!("http2 tunnel {tunnel_id} underlying TCP connected.");
 
-        let client_config = {
-            let mut client_config = create_rustls_client_config(&cert, &key)?;
-
-            client_config.alpn_protocols = vec![b"h2".to_vec()];
-
-            Arc::new(client_config)
-        };
+        let client_config = Arc::new(create_rustls_client_config_with_fingerprint(
+            &self.cert,
+            &self.key,
+            parse_fingerprint(&fingerprint)?,
+            &alpn,
+        )?);
 
         let tls_connector = tokio_rustls::TlsConnector::from(client_config);
 
-        let stream = tls_connector.connect(TLS_NAME.try_into()?, stream).await?;
+        let stream = tls_connector.connect(tls_name.try_into()?, stream).await?;
 
         log::debug!("http2 tunnel {tunnel_id} underlying TLS connection established.");
 
         let (request_sender, h2_connection) = h2::client::Builder::new()
-            .initial_connection_window_size(4 * 1024 * 1024)
-            .initial_window_size(4 * 1024 * 1024)
+            .initial_connection_window_size(self.config.transport.min_window_size)
+            .initial_window_size(self.config.transport.min_window_size)
             .handshake(stream)
             .await?;
 
@@ -136,19 +174,28 @@ impl InTunnelProvider for Http2InTunnelProvider {
             id,
             tunnel_labels,
             priority,
+            protocol,
             request_sender,
             h2_connection,
             fd,
+            &self.config.transport,
         );
 
         log::info!("tunnel {tunnel} established.");
 
-        Ok(Some((Box::new(tunnel), (routing_rules, routing_priority))))
+        Ok(Some((
+            Box::new(tunnel),
+            (routing_rules, routing_priority),
+            bandwidth_limit,
+            identity,
+        )))
     }
 }
 
 pub struct Http2OutTunnelConfig {
     pub stun_server_addresses: Vec<SocketAddr>,
+    pub tls: TunnelTlsConfig,
+    pub transport: Http2TransportConfig,
     pub priority: Option<i64>,
     pub routing_rules: Vec<OutRuleConfig>,
     pub routing_priority: i64,
@@ -156,24 +203,24 @@ pub struct Http2OutTunnelConfig {
 
 pub struct Http2OutTunnelProvider {
     match_server: Arc<OutMatchServer>,
-    tls_server_config: Arc<tokio_rustls::rustls::ServerConfig>,
     cert: String,
     key: String,
     config: Http2OutTunnelConfig,
 }
 
 impl Http2OutTunnelProvider {
-    pub fn new(match_server: Arc<OutMatchServer>, config: Http2OutTunnelConfig) -> Self {
-        let (server_config, cert, key) =
-            create_rustls_server_config_and_cert([TLS_NAME.to_owned()]);
+    pub fn new(
+        match_server: Arc<OutMatchServer>,
+        config: Http2OutTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let (cert, key) = config.tls.load_or_generate_cert()?;
 
-        Self {
+        Ok(Self {
             match_server,
-            tls_server_config: Arc::new(server_config),
             cert,
             key,
             config,
-        }
+        })
     }
 }
 
@@ -189,14 +236,18 @@ impl OutTunnelProvider for Http2OutTunnelProvider {
         let MatchIn {
             id: _,
             tunnel_id,
-            data: Http2InData {},
+            protocol,
+            data: Http2InData {
+                fingerprint: in_fingerprint,
+            },
         } = self
             .match_server
             .match_in(
                 Http2OutData {
                     address: external_address,
-                    cert: self.cert.clone(),
-                    key: self.key.clone(),
+                    fingerprint: get_cert_fingerprint(&self.cert)?,
+                    tls_name: self.config.tls.server_name.clone(),
+                    alpn: self.config.tls.alpn_protocols.clone(),
                 },
                 self.config.priority,
                 &self.config.routing_rules,
@@ -204,6 +255,8 @@ impl OutTunnelProvider for Http2OutTunnelProvider {
             )
             .await?;
 
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
         let (stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await??;
 
         stream.set_nodelay(true)?;
@@ -212,17 +265,32 @@ impl OutTunnelProvider for Http2OutTunnelProvider {
 
         let fd = stream.as_fd().as_raw_fd();
 
-        let tls_acceptor = tokio_rustls::TlsAcceptor::from(self.tls_server_config.clone());
+        // The listener is of this tunnel only, so is the client cert trusted.
+        let tls_server_config = create_rustls_server_config_with_client_fingerprints(
+            &self.cert,
+            &self.key,
+            vec![parse_fingerprint(&in_fingerprint)?],
+            &self.config.tls.alpn_protocols,
+        )?;
+
+        let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_server_config));
 
         let stream = tls_acceptor.accept(stream).await?;
 
         let connection = h2::server::Builder::new()
-            .initial_connection_window_size(4 * 1024 * 1024)
-            .initial_window_size(4 * 1024 * 1024)
+            .initial_connection_window_size(self.config.transport.min_window_size)
+            .initial_window_size(self.config.transport.min_window_size)
             .handshake(stream)
             .await?;
 
-        let tunnel = Http2OutTunnel::new(TUNNEL_NAME, tunnel_id, connection, fd);
+        let tunnel = Http2OutTunnel::new(
+            TUNNEL_NAME,
+            tunnel_id,
+            protocol,
+            connection,
+            fd,
+            &self.config.transport,
+        );
 
         log::info!("tunnel {tunnel} established.");
 
@@ -231,13 +299,24 @@ impl OutTunnelProvider for Http2OutTunnelProvider {
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct Http2InData {}
+struct Http2InData {
+    /// Fingerprint of the client cert IN presents.
+    pub fingerprint: String,
+}
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct Http2OutData {
     pub address: SocketAddr,
-    pub cert: String,
-    pub key: String,
+    /// Fingerprint of the cert OUT presents, the key never leaves OUT.
+    pub fingerprint: String,
+    #[serde(default = "tls_name_default")]
+    pub tls_name: String,
+    #[serde(default = "alpn_default")]
+    pub alpn: Vec<String>,
+}
+
+fn alpn_default() -> Vec<String> {
+    vec!["h2".to_owned()]
 }
 
 impl MatchPair<Http2InData, Http2OutData> for (Http2InData, Http2OutData) {
@@ -245,23 +324,10 @@ impl MatchPair<Http2InData, Http2OutData> for (Http2InData, Http2OutData) {
         "http2"
     }
 
-    fn get_redis_out_pattern() -> &'static str {
-        "http2:out:*"
-    }
-
     fn get_redis_out_key(out_id: &MatchOutId) -> String {
         format!("http2:out:{}", out_id)
     }
 
-    fn get_out_id_from_redis_out_key(out_key: &str) -> anyhow::Result<MatchOutId> {
-        out_key
-            .split(":")
-            .collect_vec()
-            .last()
-            .map(|out_id| MatchOutId::from_str(out_id))
-            .unwrap()
-    }
-
     fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
         format!("http2:in:out:{}", out_id)
     }
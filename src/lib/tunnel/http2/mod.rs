@@ -1,8 +1,10 @@
 mod compat;
+mod http2_transport_config;
 mod http2_tunnel;
 mod http2_tunnel_provider;
 mod plug_http2_tunnel_provider;
 
+pub use http2_transport_config::*;
 pub use http2_tunnel::*;
 pub use http2_tunnel_provider::*;
 pub use plug_http2_tunnel_provider::*;
@@ -1,60 +1,87 @@
 use std::{
-    net::SocketAddr,
-    os::fd::{AsFd as _, AsRawFd as _},
-    str::FromStr,
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::fd::AsRawFd as _,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+    time::Duration,
 };
 
-use itertools::Itertools;
 use lits::duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
+    bandwidth::BandwidthLimit,
     match_server::{
-        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchPair,
-        OutMatchServer, OutMatchServerTrait as _,
+        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchOutIdentity,
+        MatchPair, OutLoad, OutMatchServer, OutMatchServerTrait as _,
     },
     route::config::OutRuleConfig,
     tunnel::{
-        common::{create_rustls_client_config, create_rustls_server_config_and_cert},
-        http2::{Http2InTunnel, Http2OutTunnel},
+        common::{
+            create_rustls_client_config_with_fingerprint,
+            create_rustls_server_config_with_any_client_cert, generate_tls_identity,
+            get_cert_fingerprint, get_peer_fingerprint, parse_fingerprint,
+        },
+        http2::{Http2InTunnel, Http2OutTunnel, Http2TransportConfig},
+        tls_name_default,
         tunnel_provider::{InTunnelProvider, OutTunnelProvider},
-        InTunnel, OutTunnel, TunnelId,
+        InTunnel, OutTunnel, TunnelId, TunnelProtocol, TunnelTlsConfig,
     },
     utils::{
-        net::{bind_tcp_listener_reuseaddr, socket::set_keepalive_options},
+        nat::{NatBehavior, NatBehaviorDetector, NatMapping},
+        net::{
+            bind_tcp_listener_reuseport, bind_tcp_socket_reuseport, socket::set_keepalive_options,
+        },
+        port_mapping::{PortMapper, PortMappingClient, PortMappingProtocol},
         stun::probe_external_ip,
     },
 };
 
 const TUNNEL_NAME: &str = "plug-http2";
 
-const TLS_NAME: &str = "localhost";
+/// Time IN waits for OUT to connect after the match, also the time IN punches for.
+const PENDING_STREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// OUT retries connecting within the time IN waits, as the first attempts may be
+/// dropped by the NAT of IN until it punches.
+const CONNECT_ATTEMPT_COUNT: u32 = 3;
+const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+type PendingStream = Arc<
+    tokio::sync::Mutex<
+        Option<(
+            TunnelId,
+            tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+        )>,
+    >,
+>;
 
 pub struct PlugHttp2InTunnelConfig {
     pub listen_address: SocketAddr,
     pub external_port: Option<u16>,
+    /// Maps the listen port on the gateway unless `external_port` is set.
+    pub port_mapping: Option<Arc<PortMappingClient>>,
+    pub tls: TunnelTlsConfig,
     pub connections: usize,
     pub priority: Option<i64>,
     pub priority_default: i64,
     pub stun_server_addresses: Vec<SocketAddr>,
     pub traffic_mark: u32,
+    pub transport: Http2TransportConfig,
 }
 
 pub struct PlugHttp2InTunnelProvider {
     match_server: Arc<AnyInMatchServer>,
     config: PlugHttp2InTunnelConfig,
     external_port: u16,
-    pending_stream: Arc<
-        tokio::sync::Mutex<
-            Option<(
-                TunnelId,
-                tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
-            )>,
-        >,
-    >,
-    cert: String,
-    key: String,
+    port_mapper: Option<PortMapper>,
+    nat_detector: NatBehaviorDetector,
+    nat_warned: AtomicBool,
+    pending_stream: PendingStream,
+    tls_server_config: Arc<rustls::ServerConfig>,
+    fingerprint: String,
     handle: tokio::task::JoinHandle<()>,
 }
 
@@ -63,21 +90,38 @@ impl PlugHttp2InTunnelProvider {
         match_server: Arc<AnyInMatchServer>,
         config: PlugHttp2InTunnelConfig,
     ) -> anyhow::Result<Self> {
-        let (tls_server_config, cert, key) =
-            create_rustls_server_config_and_cert([TLS_NAME.to_owned()]);
+        let (cert, key) = config.tls.load_or_generate_cert()?;
 
-        let tls_server_config = Arc::new(tls_server_config);
+        // OUT connecting is not known until the tunnel id is read, its cert is
+        // checked against the matched one when the stream is taken.
+        let tls_server_config = Arc::new(create_rustls_server_config_with_any_client_cert(
+            &cert,
+            &key,
+            &config.tls.alpn_protocols,
+        )?);
 
         let external_port = config
             .external_port
             .unwrap_or_else(|| config.listen_address.port());
 
-        let listener = bind_tcp_listener_reuseaddr(config.listen_address)?;
+        let port_mapper = match (&config.port_mapping, config.external_port) {
+            (Some(port_mapping_client), None) => Some(PortMapper::new(
+                port_mapping_client.clone(),
+                PortMappingProtocol::Tcp,
+                config.listen_address.port(),
+            )),
+            _ => None,
+        };
+
+        // Shares the port with punching sockets.
+        let listener = bind_tcp_listener_reuseport(config.listen_address)?;
 
         let pending_stream = Arc::new(tokio::sync::Mutex::new(None));
 
         let handle = tokio::spawn({
             let pending_stream = pending_stream.clone();
+            let tls_server_config = tls_server_config.clone();
+            let traffic_mark = config.traffic_mark;
 
             async move {
                 loop {
@@ -90,67 +134,114 @@ impl PlugHttp2InTunnelProvider {
                         }
                     };
 
-                    nix::sys::socket::setsockopt(
-                        &stream,
-                        nix::sys::socket::sockopt::Mark,
-                        &config.traffic_mark,
+                    Self::handle_incoming_stream(
+                        stream,
+                        &tls_server_config,
+                        &pending_stream,
+                        traffic_mark,
                     )
-                    .unwrap();
-
-                    stream.set_nodelay(true).unwrap();
-
-                    set_keepalive_options(&stream, 5, 5, 3).unwrap();
-
-                    let tls_acceptor = tokio_rustls::TlsAcceptor::from(tls_server_config.clone());
-
-                    let mut stream = match tls_acceptor.accept(stream).await {
-                        Ok(stream) => stream,
-                        Err(error) => {
-                            log::error!("error accepting plug-http2 TLS stream: {:?}", error);
-                            continue;
-                        }
-                    };
-
-                    let mut tunnel_id_buffer = [0; 16];
-
-                    let tunnel_id = match tokio::time::timeout(
-                        duration!("1s"),
-                        stream.read_exact(&mut tunnel_id_buffer),
-                    )
-                    .await
-                    {
-                        Ok(read_result) => match read_result {
-                            Ok(_) => TunnelId::from(tunnel_id_buffer),
-                            Err(error) => {
-                                log::error!("error reading plug-http2 tunnel id: {:?}", error);
-                                continue;
-                            }
-                        },
-                        Err(_) => {
-                            log::error!("read timeout for plug-http2 tunnel id");
-                            continue;
-                        }
-                    };
-
-                    pending_stream.lock().await.replace((tunnel_id, stream));
+                    .await;
                 }
             }
         });
 
         Ok(Self {
             match_server,
+            nat_detector: NatBehaviorDetector::new(config.stun_server_addresses.clone()),
+            nat_warned: AtomicBool::new(false),
             config,
             external_port,
+            port_mapper,
             pending_stream,
-            cert,
-            key,
+            tls_server_config,
+            fingerprint: get_cert_fingerprint(&cert)?,
             handle,
         })
     }
 
+    /// Accepts TLS on a stream from OUT, either accepted by the listener or
+    /// punched, and keeps it pending with the tunnel id OUT sends first.
+    async fn handle_incoming_stream(
+        stream: tokio::net::TcpStream,
+        tls_server_config: &Arc<rustls::ServerConfig>,
+        pending_stream: &PendingStream,
+        traffic_mark: u32,
+    ) {
+        let result = async {
+            nix::sys::socket::setsockopt(&stream, nix::sys::socket::sockopt::Mark, &traffic_mark)?;
+
+            stream.set_nodelay(true)?;
+
+            set_keepalive_options(&stream, 5, 5, 3)?;
+
+            let tls_acceptor = tokio_rustls::TlsAcceptor::from(tls_server_config.clone());
+
+            let mut stream = tls_acceptor.accept(stream).await?;
+
+            let mut tunnel_id_buffer = [0; 16];
+
+            tokio::time::timeout(duration!("1s"), stream.read_exact(&mut tunnel_id_buffer))
+                .await
+                .map_err(|_| anyhow::anyhow!("read timeout for tunnel id."))??;
+
+            anyhow::Ok((TunnelId::from(tunnel_id_buffer), stream))
+        }
+        .await;
+
+        match result {
+            Ok(pending) => {
+                pending_stream.lock().await.replace(pending);
+            }
+            Err(error) => log::error!("error accepting plug-http2 stream: {error}"),
+        }
+    }
+
+    /// Connects out of the listen port to the address OUT connects from, which
+    /// opens the NAT of IN for OUT to connect in, or establishes the connection
+    /// itself if both meet (TCP simultaneous open).
+    fn punch(&self, out_address: SocketAddr) {
+        if out_address.is_ipv4() != self.config.listen_address.is_ipv4() {
+            return;
+        }
+
+        let listen_address = self.config.listen_address;
+        let tls_server_config = self.tls_server_config.clone();
+        let pending_stream = self.pending_stream.clone();
+        let traffic_mark = self.config.traffic_mark;
+
+        tokio::spawn(async move {
+            let result = async {
+                let socket = bind_tcp_socket_reuseport(listen_address)?;
+
+                Ok::<_, anyhow::Error>(
+                    tokio::time::timeout(PENDING_STREAM_TIMEOUT, socket.connect(out_address))
+                        .await??,
+                )
+            }
+            .await;
+
+            match result {
+                Ok(stream) => {
+                    log::debug!("plug-http2 punched through to {out_address}.");
+
+                    Self::handle_incoming_stream(
+                        stream,
+                        &tls_server_config,
+                        &pending_stream,
+                        traffic_mark,
+                    )
+                    .await;
+                }
+                // Expected if OUT connected through the listener instead.
+                Err(error) => log::debug!("plug-http2 punch to {out_address} ended: {error}"),
+            }
+        });
+    }
+
     async fn wait_for_pending_stream(
         &self,
         tunnel_id: TunnelId,
+        out_fingerprint: &[u8],
     ) -> tokio_rustls::server::TlsStream<tokio::net::TcpStream> {
         loop {
             let Some((pending_tunnel_id, stream)) = self.pending_stream.lock().await.take() else {
@@ -158,9 +249,17 @@ impl PlugHttp2InTunnelProvider {
                 continue;
             };
 
-            if pending_tunnel_id == tunnel_id {
+            if pending_tunnel_id != tunnel_id {
+                continue;
+            }
+
+            if get_peer_fingerprint(stream.get_ref().1.peer_certificates()).as_deref()
+                == Some(out_fingerprint)
+            {
                 return stream;
             }
+
+            log::warn!("plug-http2 tunnel {tunnel_id} connected with unexpected cert.");
         }
     }
 }
@@ -184,11 +283,52 @@ impl InTunnelProvider for PlugHttp2InTunnelProvider {
             .map(|out_id| (out_id, self.config.connections))
     }
 
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        self.match_server.get_out_load(out_id).await
+    }
+
     async fn accept(
         &self,
         out_id: MatchOutId,
-    ) -> anyhow::Result<Option<(Box<dyn InTunnel>, (Vec<OutRuleConfig>, i64))>> {
-        let ip = probe_external_ip(&self.config.stun_server_addresses).await?;
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    > {
+        let nat = self.nat_detector.get().await;
+
+        let mapped_address = match &self.port_mapper {
+            Some(port_mapper) => port_mapper.external_address().await,
+            None => None,
+        };
+
+        // Without a forwarded port, IN punches its NAT towards the address OUT
+        // connects from.
+        let punching = nat.is_some_and(|nat| nat.mapping != NatMapping::NoNat)
+            && mapped_address.is_none()
+            && self.config.external_port.is_none();
+
+        if let Some(nat) = nat {
+            if punching
+                && nat.is_symmetric()
+                && !self.nat_warned.swap(true, atomic::Ordering::Relaxed)
+            {
+                log::warn!(
+                    "plug-http2 listening behind symmetric NAT ({nat}), OUT may fail to connect unless the port is forwarded and external port configured accordingly."
+                );
+            }
+        }
+
+        let address = match mapped_address {
+            Some(mapped_address) => mapped_address,
+            None => SocketAddr::new(
+                probe_external_ip(&self.config.stun_server_addresses).await?,
+                self.external_port,
+            ),
+        };
 
         let Some(MatchOut {
             id,
@@ -197,15 +337,24 @@ impl InTunnelProvider for PlugHttp2InTunnelProvider {
             tunnel_priority,
             routing_priority,
             routing_rules,
-            data: PlugHttp2OutData {},
+            bandwidth_limit,
+            protocol,
+            identity,
+            data:
+                PlugHttp2OutData {
+                    fingerprint: out_fingerprint,
+                    address: out_address,
+                },
         }) = self
             .match_server
             .match_out(
                 out_id,
                 PlugHttp2InData {
-                    address: SocketAddr::new(ip, self.external_port),
-                    cert: self.cert.clone(),
-                    key: self.key.clone(),
+                    address,
+                    fingerprint: self.fingerprint.clone(),
+                    tls_name: self.config.tls.server_name.clone(),
+                    alpn: self.config.tls.alpn_protocols.clone(),
+                    nat,
                 },
             )
             .await?
@@ -213,14 +362,28 @@ impl InTunnelProvider for PlugHttp2InTunnelProvider {
             return Ok(None);
         };
 
-        let stream =
-            tokio::time::timeout(duration!("3s"), self.wait_for_pending_stream(tunnel_id)).await?;
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let out_fingerprint = parse_fingerprint(&out_fingerprint)?;
+
+        if punching {
+            match out_address {
+                Some(out_address) => self.punch(out_address),
+                None => log::debug!("plug-http2 OUT {id} published no address to punch towards."),
+            }
+        }
+
+        let stream = tokio::time::timeout(
+            PENDING_STREAM_TIMEOUT,
+            self.wait_for_pending_stream(tunnel_id, &out_fingerprint),
+        )
+        .await?;
 
         let fd = stream.as_raw_fd();
 
         let (request_sender, h2_connection) = h2::client::Builder::new()
-            .initial_connection_window_size(4 * 1024 * 1024)
-            .initial_window_size(4 * 1024 * 1024)
+            .initial_connection_window_size(self.config.transport.min_window_size)
+            .initial_window_size(self.config.transport.min_window_size)
             .handshake(stream)
             .await?;
 
@@ -235,18 +398,28 @@ impl InTunnelProvider for PlugHttp2InTunnelProvider {
             id,
             tunnel_labels,
             priority,
+            protocol,
             request_sender,
             h2_connection,
             fd,
+            &self.config.transport,
         );
 
         log::info!("tunnel {tunnel} established.");
 
-        Ok(Some((Box::new(tunnel), (routing_rules, routing_priority))))
+        Ok(Some((
+            Box::new(tunnel),
+            (routing_rules, routing_priority),
+            bandwidth_limit,
+            identity,
+        )))
     }
 }
 
 pub struct PlugHttp2OutTunnelConfig {
+    /// Probes the external IP OUT connects from, published for IN to punch.
+    pub stun_server_addresses: Vec<SocketAddr>,
+    pub transport: Http2TransportConfig,
     pub priority: Option<i64>,
     pub routing_rules: Vec<OutRuleConfig>,
     pub routing_priority: i64,
@@ -254,14 +427,77 @@ pub struct PlugHttp2OutTunnelConfig {
 
 pub struct PlugHttp2OutTunnelProvider {
     match_server: Arc<OutMatchServer>,
+    /// Client cert presented to IN, which pins it by fingerprint.
+    cert: String,
+    key: String,
     config: PlugHttp2OutTunnelConfig,
 }
 
 impl PlugHttp2OutTunnelProvider {
-    pub fn new(match_server: Arc<OutMatchServer>, config: PlugHttp2OutTunnelConfig) -> Self {
-        Self {
+    pub fn new(
+        match_server: Arc<OutMatchServer>,
+        config: PlugHttp2OutTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let (cert, key) = generate_tls_identity()?;
+
+        Ok(Self {
             match_server,
+            cert,
+            key,
             config,
+        })
+    }
+}
+
+impl PlugHttp2OutTunnelProvider {
+    /// Connects out of `local_port` if bound, retrying as IN may only have
+    /// punched its NAT after the first attempts.
+    async fn connect(
+        &self,
+        address: SocketAddr,
+        local_port: Option<u16>,
+    ) -> anyhow::Result<tokio::net::TcpStream> {
+        let local_address = match (address, local_port) {
+            (SocketAddr::V4(_), Some(local_port)) => Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                local_port,
+            )),
+            _ => None,
+        };
+
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let socket = match local_address {
+                Some(local_address) => bind_tcp_socket_reuseport(local_address)?,
+                None => match address {
+                    SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+                },
+            };
+
+            socket.set_nodelay(true)?;
+
+            set_keepalive_options(&socket, 5, 5, 3)?;
+
+            let error = match tokio::time::timeout(CONNECT_ATTEMPT_TIMEOUT, socket.connect(address))
+                .await
+            {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(error)) => anyhow::Error::from(error),
+                Err(_) => anyhow::anyhow!("connect timeout."),
+            };
+
+            if local_address.is_none() || attempt >= CONNECT_ATTEMPT_COUNT {
+                return Err(error);
+            }
+
+            log::debug!("plug-http2 connect attempt {attempt} to {address} failed: {error}");
+
+            // Refused attempts return early, wait for IN to punch.
+            tokio::time::sleep(CONNECT_ATTEMPT_TIMEOUT).await;
         }
     }
 }
@@ -269,54 +505,87 @@ impl PlugHttp2OutTunnelProvider {
 #[async_trait::async_trait]
 impl OutTunnelProvider for PlugHttp2OutTunnelProvider {
     async fn accept(&self) -> anyhow::Result<Box<dyn OutTunnel>> {
+        // Reserves the local port to connect out of, published with the external
+        // IP (assuming the NAT preserves the port) for IN to punch towards.
+        let local_socket =
+            bind_tcp_socket_reuseport(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))?;
+
+        let local_port = local_socket.local_addr()?.port();
+
+        let out_address = match probe_external_ip(&self.config.stun_server_addresses).await {
+            Ok(ip) => Some(SocketAddr::new(ip, local_port)),
+            Err(error) => {
+                log::debug!("plug-http2 failed to probe external IP: {error}");
+                None
+            }
+        };
+
         let MatchIn {
             id: _,
             tunnel_id,
-            data: PlugHttp2InData { address, cert, key },
+            protocol,
+            data:
+                PlugHttp2InData {
+                    address,
+                    fingerprint,
+                    tls_name,
+                    alpn,
+                    nat,
+                },
         } = self
             .match_server
             .match_in(
-                PlugHttp2OutData {},
+                PlugHttp2OutData {
+                    fingerprint: get_cert_fingerprint(&self.cert)?,
+                    address: out_address,
+                },
                 self.config.priority,
                 &self.config.routing_rules,
                 self.config.routing_priority,
             )
             .await?;
 
-        let socket = match address {
-            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
-            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
-        }?;
-
-        socket.set_nodelay(true)?;
-
-        set_keepalive_options(&socket, 5, 5, 3)?;
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
 
-        let fd = socket.as_fd().as_raw_fd();
+        drop(local_socket);
 
-        let stream = socket.connect(address).await?;
-
-        let client_config = {
-            let mut client_config = create_rustls_client_config(&cert, &key)?;
+        let stream = self
+            .connect(address, out_address.map(|address| address.port()))
+            .await
+            .map_err(|error| match nat {
+                Some(nat) => anyhow::anyhow!("error connecting to {address} (IN {nat}): {error}"),
+                None => error,
+            })?;
 
-            client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let fd = stream.as_raw_fd();
 
-            Arc::new(client_config)
-        };
+        let client_config = Arc::new(create_rustls_client_config_with_fingerprint(
+            &self.cert,
+            &self.key,
+            parse_fingerprint(&fingerprint)?,
+            &alpn,
+        )?);
 
         let tls_connector = tokio_rustls::TlsConnector::from(client_config);
 
-        let mut stream = tls_connector.connect(TLS_NAME.try_into()?, stream).await?;
+        let mut stream = tls_connector.connect(tls_name.try_into()?, stream).await?;
 
         stream.write_all(tunnel_id.as_bytes()).await?;
 
         let connection = h2::server::Builder::new()
-            .initial_connection_window_size(4 * 1024 * 1024)
-            .initial_window_size(4 * 1024 * 1024)
+            .initial_connection_window_size(self.config.transport.min_window_size)
+            .initial_window_size(self.config.transport.min_window_size)
             .handshake(stream)
             .await?;
 
-        let tunnel = Http2OutTunnel::new(TUNNEL_NAME, tunnel_id, connection, fd);
+        let tunnel = Http2OutTunnel::new(
+            TUNNEL_NAME,
+            tunnel_id,
+            protocol,
+            connection,
+            fd,
+            &self.config.transport,
+        );
 
         log::info!("tunnel {tunnel} established.");
 
@@ -327,35 +596,38 @@ impl OutTunnelProvider for PlugHttp2OutTunnelProvider {
 #[derive(serde::Serialize, serde::Deserialize)]
 struct PlugHttp2InData {
     pub address: SocketAddr,
-    pub cert: String,
-    pub key: String,
+    /// Fingerprint of the cert IN presents, the key never leaves IN.
+    pub fingerprint: String,
+    #[serde(default = "tls_name_default")]
+    pub tls_name: String,
+    #[serde(default = "alpn_default")]
+    pub alpn: Vec<String>,
+    #[serde(default)]
+    pub nat: Option<NatBehavior>,
+}
+
+fn alpn_default() -> Vec<String> {
+    vec!["h2".to_owned()]
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
-struct PlugHttp2OutData {}
+struct PlugHttp2OutData {
+    /// Fingerprint of the client cert OUT presents.
+    pub fingerprint: String,
+    /// External address OUT connects from, for IN to punch its NAT.
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+}
 
 impl MatchPair<PlugHttp2InData, PlugHttp2OutData> for (PlugHttp2InData, PlugHttp2OutData) {
     fn get_match_name() -> &'static str {
         "plug-http2"
     }
 
-    fn get_redis_out_pattern() -> &'static str {
-        "plug-http2:out:*"
-    }
-
     fn get_redis_out_key(out_id: &MatchOutId) -> String {
         format!("plug-http2:out:{}", out_id)
     }
 
-    fn get_out_id_from_redis_out_key(out_key: &str) -> anyhow::Result<MatchOutId> {
-        out_key
-            .split(":")
-            .collect_vec()
-            .last()
-            .map(|out_id| MatchOutId::from_str(out_id))
-            .unwrap()
-    }
-
     fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
         format!("plug-http2:in:out:{}", out_id)
     }
@@ -1,13 +1,22 @@
 mod byte_stream_tunnel;
-mod common;
+pub(crate) mod common;
 pub mod direct_tunnel;
 pub mod http2;
 pub mod quic;
 #[allow(clippy::module_inception)]
 mod tunnel;
+mod tunnel_connect_error;
+mod tunnel_protocol;
 mod tunnel_provider;
+mod tunnel_tls_config;
 mod tunnels;
+mod udp_relay;
+pub mod websocket;
 
 pub use tunnel::*;
+pub use tunnel_connect_error::*;
+pub use tunnel_protocol::*;
 pub use tunnel_provider::*;
+pub use tunnel_tls_config::*;
 pub use tunnels::*;
+pub use udp_relay::*;
@@ -1,17 +1,53 @@
-use std::{net::SocketAddr, str::FromStr as _};
+use std::net::SocketAddr;
 
-use itertools::Itertools as _;
+use crate::{
+    match_server::{MatchOutId, MatchPair},
+    tunnel::tls_name_default,
+    utils::nat::NatBehavior,
+};
 
-use crate::match_server::{MatchOutId, MatchPair};
+use super::QuicCongestionController;
 
 #[derive(serde::Serialize, serde::Deserialize)]
-pub struct QuicInData {}
+pub struct QuicInData {
+    /// Congestion controller explicitly configured by IN.
+    #[serde(default)]
+    pub congestion_controller: Option<QuicCongestionController>,
+    /// Addresses (probed and predicted) IN's socket is mapped to, for OUT to
+    /// punch its NAT.
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    #[serde(default)]
+    pub nat: Option<NatBehavior>,
+    /// Fingerprint of the client cert IN presents.
+    pub fingerprint: String,
+}
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct QuicOutData {
     pub address: SocketAddr,
-    pub cert: String,
-    pub key: String,
+    /// Fingerprint of the cert OUT presents, the key never leaves OUT.
+    pub fingerprint: String,
+    #[serde(default = "tls_name_default")]
+    pub tls_name: String,
+    #[serde(default = "alpn_default")]
+    pub alpn: Vec<String>,
+    /// Congestion controller explicitly configured by OUT.
+    #[serde(default)]
+    pub congestion_controller: Option<QuicCongestionController>,
+    /// Whether OUT relays UDP packets over the connection.
+    #[serde(default)]
+    pub udp: bool,
+    /// Candidate addresses including `address`, more than one if OUT is behind a
+    /// symmetric NAT.
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    #[serde(default)]
+    pub nat: Option<NatBehavior>,
+}
+
+fn alpn_default() -> Vec<String> {
+    vec!["h3".to_owned()]
 }
 
 impl MatchPair<QuicInData, QuicOutData> for (QuicInData, QuicOutData) {
@@ -19,23 +55,10 @@ impl MatchPair<QuicInData, QuicOutData> for (QuicInData, QuicOutData) {
         "quic"
     }
 
-    fn get_redis_out_pattern() -> &'static str {
-        "quic:out:*"
-    }
-
     fn get_redis_out_key(out_id: &MatchOutId) -> String {
         format!("quic:out:{}", out_id)
     }
 
-    fn get_out_id_from_redis_out_key(out_key: &str) -> anyhow::Result<MatchOutId> {
-        out_key
-            .split(":")
-            .collect_vec()
-            .last()
-            .map(|out_id| MatchOutId::from_str(out_id))
-            .unwrap()
-    }
-
     fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
         format!("quic:in:out:{}", out_id)
     }
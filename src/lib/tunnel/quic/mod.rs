@@ -1,7 +1,13 @@
 mod match_pair;
+mod quic_migration;
+mod quic_transport_config;
 mod quic_tunnel;
 mod quic_tunnel_provider;
-mod quinn;
+mod quic_udp_relay;
+pub(crate) mod quinn;
+mod static_quic_tunnel_provider;
 
+pub use quic_transport_config::*;
 pub use quic_tunnel::*;
 pub use quic_tunnel_provider::*;
+pub use static_quic_tunnel_provider::*;
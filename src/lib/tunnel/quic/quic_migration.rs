@@ -0,0 +1,316 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::utils::{net::get_any_address, stun::ExternalIpWatcher};
+
+const EXTERNAL_IP_PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+const MIGRATION_FAILED_ERROR_CODE: u32 = 1;
+const EXTERNAL_IP_CHANGED_ERROR_CODE: u32 = 2;
+
+type ConnectionList<T> = Arc<Mutex<Vec<T>>>;
+
+/// Migrates IN (client) connections to new sockets once the external IP changes.
+/// Connections that fail to migrate or show no sign of life within
+/// `validation_timeout` are closed, so that they are rematched before the idle
+/// timeout.
+pub struct QuicInMigrator {
+    connections: ConnectionList<(quinn::Endpoint, quinn::Connection)>,
+    _watcher: ExternalIpWatcher,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl QuicInMigrator {
+    pub fn new(stun_server_addresses: Vec<SocketAddr>, validation_timeout: Duration) -> Self {
+        let watcher = ExternalIpWatcher::new(stun_server_addresses, EXTERNAL_IP_PROBE_INTERVAL);
+
+        let connections = ConnectionList::default();
+
+        let handle = tokio::spawn({
+            let mut change_receiver = watcher.subscribe();
+            let connections = connections.clone();
+
+            async move {
+                loop {
+                    let (previous_ip, ip) = match change_receiver.recv().await {
+                        Ok(change) => change,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    };
+
+                    let connections = retain_open(&connections, |(_, connection)| connection);
+
+                    for (endpoint, connection) in connections {
+                        tokio::spawn(migrate(
+                            endpoint,
+                            connection,
+                            (previous_ip, ip),
+                            validation_timeout,
+                        ));
+                    }
+                }
+            }
+        });
+
+        Self {
+            connections,
+            _watcher: watcher,
+            handle,
+        }
+    }
+
+    pub fn register(&self, endpoint: quinn::Endpoint, connection: quinn::Connection) {
+        retain_open(&self.connections, |(_, connection)| connection);
+
+        self.connections
+            .lock()
+            .unwrap()
+            .push((endpoint, connection));
+    }
+}
+
+impl Drop for QuicInMigrator {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn migrate(
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    (previous_ip, ip): (IpAddr, IpAddr),
+    validation_timeout: Duration,
+) {
+    let remote_address = connection.remote_address();
+
+    log::info!("migrating QUIC connection to {remote_address} from {previous_ip} to {ip}...");
+
+    let received_datagrams = connection.stats().udp_rx.datagrams;
+
+    let result = std::net::UdpSocket::bind(get_any_address(&remote_address))
+        .and_then(|socket| endpoint.rebind(socket));
+
+    if let Err(error) = result {
+        log::warn!("failed to rebind QUIC connection to {remote_address}: {error}");
+
+        connection.close(MIGRATION_FAILED_ERROR_CODE.into(), b"migration failed");
+
+        return;
+    }
+
+    tokio::time::sleep(validation_timeout).await;
+
+    if connection.close_reason().is_some() {
+        return;
+    }
+
+    if connection.stats().udp_rx.datagrams == received_datagrams {
+        log::warn!(
+            "QUIC connection to {remote_address} silent after migration, closing to rematch..."
+        );
+
+        connection.close(MIGRATION_FAILED_ERROR_CODE.into(), b"migration failed");
+    } else {
+        log::info!("QUIC connection to {remote_address} migrated.");
+    }
+}
+
+/// QUIC servers cannot migrate, so OUT closes its connections once the external
+/// IP changes for IN to rematch with the new address right away.
+pub struct QuicOutMigrator {
+    connections: ConnectionList<quinn::Connection>,
+    watcher: ExternalIpWatcher,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl QuicOutMigrator {
+    pub fn new(stun_server_addresses: Vec<SocketAddr>) -> Self {
+        let watcher = ExternalIpWatcher::new(stun_server_addresses, EXTERNAL_IP_PROBE_INTERVAL);
+
+        let connections = ConnectionList::default();
+
+        let handle = tokio::spawn({
+            let mut change_receiver = watcher.subscribe();
+            let connections = connections.clone();
+
+            async move {
+                while !matches!(
+                    change_receiver.recv().await,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed)
+                ) {
+                    for connection in retain_open(&connections, |connection| connection) {
+                        log::info!(
+                            "closing QUIC connection from {} to rematch...",
+                            connection.remote_address()
+                        );
+
+                        connection.close(
+                            EXTERNAL_IP_CHANGED_ERROR_CODE.into(),
+                            b"external IP changed",
+                        );
+                    }
+                }
+            }
+        });
+
+        Self {
+            connections,
+            watcher,
+            handle,
+        }
+    }
+
+    pub fn register(&self, connection: quinn::Connection) {
+        retain_open(&self.connections, |connection| connection);
+
+        self.connections.lock().unwrap().push(connection);
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<(IpAddr, IpAddr)> {
+        self.watcher.subscribe()
+    }
+}
+
+impl Drop for QuicOutMigrator {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Drops closed connections and returns the open ones.
+fn retain_open<T: Clone>(
+    connections: &ConnectionList<T>,
+    get_connection: fn(&T) -> &quinn::Connection,
+) -> Vec<T> {
+    let mut connections = connections.lock().unwrap();
+
+    connections.retain(|connection| get_connection(connection).close_reason().is_none());
+
+    connections.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::quinn::tests::connect, super::QuicCongestionController, *};
+
+    const VALIDATION_TIMEOUT: Duration = Duration::from_millis(300);
+
+    fn get_ip_change() -> (IpAddr, IpAddr) {
+        ([192, 0, 2, 1].into(), [192, 0, 2, 2].into())
+    }
+
+    async fn echo(connection: &quinn::Connection) -> anyhow::Result<()> {
+        let (mut send_stream, mut receive_stream) = connection.open_bi().await?;
+
+        send_stream.write_all(b"ping").await?;
+        send_stream.finish()?;
+
+        assert_eq!(receive_stream.read_to_end(16).await?, b"pong");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migrates_to_new_socket() -> anyhow::Result<()> {
+        let ((_server_endpoint, server_connection), (client_endpoint, client_connection)) =
+            connect(
+                QuicCongestionController::Cubic,
+                QuicCongestionController::Cubic,
+            )
+            .await?;
+
+        let echo_handle = tokio::spawn(async move {
+            while let Ok((mut send_stream, mut receive_stream)) =
+                server_connection.accept_bi().await
+            {
+                receive_stream.read_to_end(16).await?;
+                send_stream.write_all(b"pong").await?;
+                send_stream.finish()?;
+            }
+
+            anyhow::Ok(())
+        });
+
+        let previous_address = client_endpoint.local_addr()?;
+
+        migrate(
+            client_endpoint.clone(),
+            client_connection.clone(),
+            get_ip_change(),
+            VALIDATION_TIMEOUT,
+        )
+        .await;
+
+        assert_ne!(
+            client_endpoint.local_addr()?.port(),
+            previous_address.port()
+        );
+        assert!(client_connection.close_reason().is_none());
+
+        echo(&client_connection).await?;
+
+        echo_handle.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closes_silent_connection_after_migration() -> anyhow::Result<()> {
+        let ((server_endpoint, _server_connection), (client_endpoint, client_connection)) =
+            connect(
+                QuicCongestionController::Cubic,
+                QuicCongestionController::Cubic,
+            )
+            .await?;
+
+        // Packets to the previous server address are no longer received. Those
+        // the server sends from its new address on rebinding are let through
+        // before migrating, later ones go to the previous client socket.
+        server_endpoint.rebind(std::net::UdpSocket::bind("127.0.0.1:0")?)?;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        migrate(
+            client_endpoint,
+            client_connection.clone(),
+            get_ip_change(),
+            VALIDATION_TIMEOUT,
+        )
+        .await;
+
+        assert!(matches!(
+            client_connection.close_reason(),
+            Some(quinn::ConnectionError::LocallyClosed)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_closed_connections() -> anyhow::Result<()> {
+        let ((_server_endpoint, server_connection), _client) = connect(
+            QuicCongestionController::Cubic,
+            QuicCongestionController::Cubic,
+        )
+        .await?;
+
+        let connections = ConnectionList::default();
+
+        connections.lock().unwrap().push(server_connection.clone());
+
+        assert_eq!(retain_open(&connections, |connection| connection).len(), 1);
+
+        server_connection.close(
+            EXTERNAL_IP_CHANGED_ERROR_CODE.into(),
+            b"external IP changed",
+        );
+
+        assert!(retain_open(&connections, |connection| connection).is_empty());
+        assert!(connections.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+}
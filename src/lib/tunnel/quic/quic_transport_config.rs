@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum QuicCongestionController {
+    #[display("NewReno")]
+    NewReno,
+    #[display("Cubic")]
+    Cubic,
+    #[display("BBR")]
+    Bbr,
+}
+
+impl QuicCongestionController {
+    /// Resolves the controller used by both ends, preferring OUT's explicit choice
+    /// over IN's and falling back to quinn's default.
+    pub fn negotiate(out: Option<Self>, r#in: Option<Self>) -> Self {
+        out.or(r#in).unwrap_or(Self::Cubic)
+    }
+}
+
+#[derive(Clone)]
+pub struct QuicTransportConfig {
+    /// Preferred congestion controller, negotiated through the match server.
+    /// Static peering has no match data to negotiate with, each end then sends
+    /// with its own.
+    pub congestion_controller: Option<QuicCongestionController>,
+    pub max_concurrent_bidi_streams: u32,
+    pub keep_alive_interval: Duration,
+    /// The effective idle timeout is the smaller one of both ends.
+    pub max_idle_timeout: Duration,
+    pub stream_receive_window: Option<u64>,
+    pub receive_window: Option<u64>,
+    pub send_window: Option<u64>,
+    /// Enables early data on resumed connections. OUT accepts it only if
+    /// enabled on its side as well.
+    pub zero_rtt: bool,
+}
+
+impl Default for QuicTransportConfig {
+    fn default() -> Self {
+        Self {
+            congestion_controller: None,
+            max_concurrent_bidi_streams: 1024,
+            keep_alive_interval: Duration::from_secs(5),
+            max_idle_timeout: Duration::from_secs(30),
+            stream_receive_window: None,
+            receive_window: None,
+            send_window: None,
+            zero_rtt: false,
+        }
+    }
+}
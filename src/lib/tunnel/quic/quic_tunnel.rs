@@ -1,16 +1,48 @@
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::sync::Arc;
 
-use crate::tunnel::byte_stream_tunnel::{
-    ByteStreamInTunnelConnection, ByteStreamOutTunnelConnection,
+use tokio::io::AsyncRead;
+
+use crate::{
+    tunnel::{
+        byte_stream_tunnel::{ByteStreamInTunnelConnection, ByteStreamOutTunnelConnection},
+        InUdpRelay, OutUdpRelay,
+    },
+    utils::{io::AsyncWriteReset, port_mapping::PortMapper},
 };
 
+use super::quic_udp_relay::{QuicInUdpRelay, QuicOutUdpRelay};
+
 pub struct QuicInTunnelConnection {
     connection: quinn::Connection,
+    handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+    udp_relay: Option<Arc<QuicInUdpRelay>>,
+    _port_mapper: Option<PortMapper>,
 }
 
 impl QuicInTunnelConnection {
-    pub fn new(connection: quinn::Connection) -> Self {
-        QuicInTunnelConnection { connection }
+    /// `udp` tells whether OUT relays UDP packets of this connection,
+    /// `handshake_confirmed` turns true once a connection resumed with 0-RTT
+    /// completes its handshake, and `port_mapper` keeps the socket port mapped as
+    /// long as the connection.
+    pub fn new(
+        connection: quinn::Connection,
+        udp: bool,
+        handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+        port_mapper: Option<PortMapper>,
+    ) -> Self {
+        let udp_relay = udp.then(|| {
+            Arc::new(QuicInUdpRelay::new(
+                connection.clone(),
+                handshake_confirmed.clone(),
+            ))
+        });
+
+        QuicInTunnelConnection {
+            connection,
+            handshake_confirmed,
+            udp_relay,
+            _port_mapper: port_mapper,
+        }
     }
 }
 
@@ -20,7 +52,7 @@ impl ByteStreamInTunnelConnection for QuicInTunnelConnection {
         &self,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
     )> {
         let (send_stream, recv_stream) = self.connection.open_bi().await?;
 
@@ -34,15 +66,42 @@ impl ByteStreamInTunnelConnection for QuicInTunnelConnection {
     fn is_closed(&self) -> bool {
         self.connection.close_reason().is_some()
     }
+
+    fn is_handshake_confirmed(&self) -> bool {
+        *self.handshake_confirmed.borrow()
+    }
+
+    async fn handshake_confirmed(&self) {
+        // Fails only if the sender is dropped unconfirmed with the connection.
+        let _ = self
+            .handshake_confirmed
+            .clone()
+            .wait_for(|confirmed| *confirmed)
+            .await;
+    }
+
+    fn udp_relay(&self) -> Option<Arc<dyn InUdpRelay>> {
+        self.udp_relay
+            .clone()
+            .map(|udp_relay| udp_relay as Arc<dyn InUdpRelay>)
+    }
 }
 
 pub struct QuicOutTunnelConnection {
     connection: quinn::Connection,
+    udp_relay: Arc<QuicOutUdpRelay>,
+    _port_mapper: Option<PortMapper>,
 }
 
 impl QuicOutTunnelConnection {
-    pub fn new(connection: quinn::Connection) -> Self {
-        QuicOutTunnelConnection { connection }
+    pub fn new(connection: quinn::Connection, port_mapper: Option<PortMapper>) -> Self {
+        let udp_relay = Arc::new(QuicOutUdpRelay::new(connection.clone()));
+
+        QuicOutTunnelConnection {
+            connection,
+            udp_relay,
+            _port_mapper: port_mapper,
+        }
     }
 }
 
@@ -50,10 +109,7 @@ impl QuicOutTunnelConnection {
 impl ByteStreamOutTunnelConnection for QuicOutTunnelConnection {
     async fn accept(
         &self,
-    ) -> anyhow::Result<(
-        Box<dyn AsyncRead + Send + Unpin>,
-        Box<dyn AsyncWrite + Send + Unpin>,
-    )> {
+    ) -> anyhow::Result<(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWriteReset>)> {
         let (write_stream, read_stream) = {
             loop {
                 match self.connection.accept_bi().await {
@@ -78,4 +134,16 @@ impl ByteStreamOutTunnelConnection for QuicOutTunnelConnection {
     fn is_closed(&self) -> bool {
         self.connection.close_reason().is_some()
     }
+
+    fn udp_relay(&self) -> Option<Arc<dyn OutUdpRelay>> {
+        Some(self.udp_relay.clone())
+    }
+}
+
+/// The receiving half stops sending on drop if not read to the end, so only the
+/// sending half needs an explicit reset.
+impl AsyncWriteReset for quinn::SendStream {
+    fn reset(&mut self) {
+        let _ = quinn::SendStream::reset(self, 0u32.into());
+    }
 }
@@ -1,50 +1,144 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 use quinn::crypto::rustls::QuicServerConfig;
 
 use crate::{
+    bandwidth::BandwidthLimit,
     match_server::{
-        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, OutMatchServer,
-        OutMatchServerTrait as _,
+        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchOutIdentity,
+        OutLoad, OutMatchServer, OutMatchServerTrait as _,
     },
     route::config::OutRuleConfig,
     tunnel::{
         byte_stream_tunnel::{ByteStreamInTunnel, ByteStreamOutTunnel},
-        common::{create_rustls_client_config, create_rustls_server_config_and_cert},
+        common::{
+            create_rustls_client_config_with_fingerprint,
+            create_rustls_server_config_with_any_client_cert, generate_tls_identity,
+            get_cert_fingerprint, get_peer_fingerprint, parse_fingerprint,
+        },
         tunnel_provider::{InTunnelProvider, OutTunnelProvider},
-        InTunnel, OutTunnel,
+        InTunnel, OutTunnel, TunnelCapability, TunnelProtocol, TunnelTlsConfig,
+    },
+    utils::{
+        nat::{
+            create_socket_and_predict_mapped_addresses, punch, NatBehaviorDetector,
+            MAPPED_ADDRESS_COUNT_MAX,
+        },
+        net::get_any_address,
+        port_mapping::{PortMapper, PortMappingClient, PortMappingProtocol},
     },
-    utils::{net::get_any_address, stun::probe_external_ip},
 };
 
 use super::{
     match_pair::{QuicInData, QuicOutData},
+    quic_migration::{QuicInMigrator, QuicOutMigrator},
     quinn::{create_client_endpoint, create_server_endpoint},
-    QuicInTunnelConnection, QuicOutTunnelConnection,
+    QuicCongestionController, QuicInTunnelConnection, QuicOutTunnelConnection, QuicTransportConfig,
 };
 
 const TUNNEL_NAME: &str = "quic";
 
-const TLS_NAME: &str = "localhost";
+/// Candidate addresses of OUT IN connects to at the same time, unless OUT is
+/// behind a symmetric NAT, for which all predicted addresses (up to
+/// `MAPPED_ADDRESS_COUNT_MAX` with birthday-style random ports) are tried.
+const CONNECT_CANDIDATE_COUNT_MAX: usize = 32;
+
+/// OUT fingerprints IN keeps client configs (and thus session tickets) for.
+const CLIENT_CONFIG_CACHE_SIZE_MAX: usize = 64;
 
 pub struct QuicInTunnelConfig {
     pub priority: Option<i64>,
     pub priority_default: i64,
+    pub stream_pool_size: usize,
     pub stun_server_addresses: Vec<SocketAddr>,
+    pub transport: QuicTransportConfig,
+    pub port_mapping: Option<Arc<PortMappingClient>>,
     pub traffic_mark: u32,
 }
 
 pub struct QuicInTunnelProvider {
     match_server: Arc<AnyInMatchServer>,
+    migrator: QuicInMigrator,
+    nat_detector: NatBehaviorDetector,
+    /// Client cert presented to OUT, which pins it by fingerprint.
+    cert: String,
+    key: String,
+    /// Client configs by OUT fingerprint, shared by tunnels to the same OUT so
+    /// that reconnecting resumes the TLS session.
+    client_configs: Mutex<HashMap<String, rustls::ClientConfig>>,
     config: QuicInTunnelConfig,
 }
 
 impl QuicInTunnelProvider {
-    pub fn new(match_server: Arc<AnyInMatchServer>, config: QuicInTunnelConfig) -> Self {
-        Self {
+    pub fn new(
+        match_server: Arc<AnyInMatchServer>,
+        config: QuicInTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        // Keep-alive packets should be acknowledged within two intervals if the
+        // migrated path works.
+        let migrator = QuicInMigrator::new(
+            config.stun_server_addresses.clone(),
+            config.transport.keep_alive_interval * 2,
+        );
+
+        let (cert, key) = generate_tls_identity()?;
+
+        Ok(Self {
             match_server,
+            migrator,
+            nat_detector: NatBehaviorDetector::new(config.stun_server_addresses.clone()),
+            cert,
+            key,
+            client_configs: Mutex::new(HashMap::new()),
             config,
+        })
+    }
+
+    fn create_client_config(
+        &self,
+        fingerprint: &str,
+        alpn: &[String],
+    ) -> anyhow::Result<rustls::ClientConfig> {
+        create_rustls_client_config_with_fingerprint(
+            &self.cert,
+            &self.key,
+            parse_fingerprint(fingerprint)?,
+            alpn,
+        )
+    }
+
+    fn get_client_config(
+        &self,
+        fingerprint: &str,
+        alpn: &[String],
+    ) -> anyhow::Result<rustls::ClientConfig> {
+        let mut client_configs = self.client_configs.lock().unwrap();
+
+        if let Some(client_config) = client_configs.get(fingerprint) {
+            if client_config
+                .alpn_protocols
+                .iter()
+                .eq(alpn.iter().map(|protocol| protocol.as_bytes()))
+            {
+                return Ok(client_config.clone());
+            }
+        }
+
+        let mut client_config = self.create_client_config(fingerprint, alpn)?;
+
+        client_config.enable_early_data = true;
+
+        if client_configs.len() >= CLIENT_CONFIG_CACHE_SIZE_MAX {
+            client_configs.clear();
         }
+
+        client_configs.insert(fingerprint.to_owned(), client_config.clone());
+
+        Ok(client_config)
     }
 }
 
@@ -61,10 +155,48 @@ impl InTunnelProvider for QuicInTunnelProvider {
             .map(|out_id| (out_id, 1))
     }
 
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        self.match_server.get_out_load(out_id).await
+    }
+
     async fn accept(
         &self,
         out_id: MatchOutId,
-    ) -> anyhow::Result<Option<(Box<dyn InTunnel>, (Vec<OutRuleConfig>, i64))>> {
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    > {
+        let nat = self.nat_detector.get().await;
+
+        // The socket is bound before matching, so that OUT could punch the
+        // addresses it is mapped to.
+        let (socket, mut mapped_addresses) =
+            match create_socket_and_predict_mapped_addresses(&self.config.stun_server_addresses)
+                .await
+            {
+                Ok((socket, mapped_addresses)) => (Some(socket), mapped_addresses),
+                Err(error) => {
+                    log::warn!("failed to probe mapped addresses: {error}");
+                    (None, Vec::new())
+                }
+            };
+
+        let mut port_mapper = match &socket {
+            Some(socket) => {
+                map_socket_port(
+                    self.config.port_mapping.as_ref(),
+                    socket,
+                    &mut mapped_addresses,
+                )
+                .await?
+            }
+            None => None,
+        };
+
         let Some(MatchOut {
             id,
             tunnel_id,
@@ -72,19 +204,141 @@ impl InTunnelProvider for QuicInTunnelProvider {
             tunnel_priority,
             routing_priority,
             routing_rules,
-            data: QuicOutData { address, cert, key },
-        }) = self.match_server.match_out(out_id, QuicInData {}).await?
+            bandwidth_limit,
+            protocol,
+            identity,
+            data:
+                QuicOutData {
+                    address,
+                    fingerprint,
+                    tls_name,
+                    alpn,
+                    congestion_controller: out_congestion_controller,
+                    udp,
+                    addresses,
+                    nat: out_nat,
+                },
+        }) = self
+            .match_server
+            .match_out(
+                out_id,
+                QuicInData {
+                    congestion_controller: self.config.transport.congestion_controller,
+                    addresses: mapped_addresses,
+                    nat,
+                    fingerprint: get_cert_fingerprint(&self.cert)?,
+                },
+            )
+            .await?
         else {
             return Ok(None);
         };
 
-        let socket = std::net::UdpSocket::bind(get_any_address(&address))?;
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let congestion_controller = QuicCongestionController::negotiate(
+            out_congestion_controller,
+            self.config.transport.congestion_controller,
+        );
+
+        let socket = match socket {
+            Some(socket) if socket.local_addr()?.is_ipv4() == address.is_ipv4() => {
+                socket.into_std()?
+            }
+            _ => {
+                port_mapper = None;
+
+                std::net::UdpSocket::bind(get_any_address(&address))?
+            }
+        };
+
+        // OUT not known to be behind a symmetric NAT is reachable at its probed
+        // addresses, the rest are predictions that only matter otherwise.
+        let candidate_count_max = if out_nat.is_some_and(|nat| !nat.is_symmetric()) {
+            CONNECT_CANDIDATE_COUNT_MAX
+        } else {
+            MAPPED_ADDRESS_COUNT_MAX
+        };
+
+        let candidate_addresses = if addresses.is_empty() {
+            vec![address]
+        } else {
+            addresses
+                .into_iter()
+                .filter(|candidate| candidate.is_ipv4() == address.is_ipv4())
+                .take(candidate_count_max)
+                .collect()
+        };
 
-        let client_config = create_rustls_client_config(&cert, &key)?;
+        let client_config = if self.config.transport.zero_rtt {
+            self.get_client_config(&fingerprint, &alpn)?
+        } else {
+            self.create_client_config(&fingerprint, &alpn)?
+        };
+
+        let endpoint = create_client_endpoint(
+            socket,
+            client_config,
+            &self.config.transport,
+            congestion_controller,
+        )?;
+
+        let (handshake_confirmed_sender, handshake_confirmed) = tokio::sync::watch::channel(true);
+
+        let connection = match &candidate_addresses[..] {
+            // Early data is only sent to a single candidate, as the connection
+            // is usable before the path is known to work.
+            &[address] if self.config.transport.zero_rtt => {
+                match endpoint.connect(address, &tls_name)?.into_0rtt() {
+                    Ok((connection, accepted)) => {
+                        handshake_confirmed_sender.send_replace(false);
+
+                        tokio::spawn({
+                            let connection = connection.clone();
+
+                            async move {
+                                let accepted = accepted.await;
+
+                                log::debug!(
+                                    "0-RTT to {} {}.",
+                                    connection.remote_address(),
+                                    if accepted { "accepted" } else { "rejected" },
+                                );
+
+                                handshake_confirmed_sender.send_replace(true);
+                            }
+                        });
+
+                        connection
+                    }
+                    Err(connecting) => connecting.await?,
+                }
+            }
+            _ => {
+                // Connecting to all candidates also punches IN's NAT for them,
+                // the first one established wins.
+                let connectings = candidate_addresses
+                    .into_iter()
+                    .map(|address| endpoint.connect(address, &tls_name))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                futures::future::select_ok(connectings)
+                    .await
+                    .map_err(|error| match out_nat {
+                        Some(out_nat) => {
+                            anyhow::anyhow!(
+                                "error connecting to {address} (OUT {out_nat}): {error}"
+                            )
+                        }
+                        None => error.into(),
+                    })?
+                    .0
+            }
+        };
 
-        let endpoint = create_client_endpoint(socket, client_config)?;
+        self.migrator.register(endpoint, connection.clone());
 
-        let connection = endpoint.connect(address, TLS_NAME)?.await?;
+        let udp = udp && protocol.supports(TunnelCapability::UdpRelay);
 
         let tunnel = ByteStreamInTunnel::new(
             TUNNEL_NAME,
@@ -94,85 +348,200 @@ impl InTunnelProvider for QuicInTunnelProvider {
             self.config
                 .priority
                 .unwrap_or(tunnel_priority.unwrap_or(self.config.priority_default)),
-            QuicInTunnelConnection::new(connection),
+            protocol,
+            QuicInTunnelConnection::new(connection, udp, handshake_confirmed, port_mapper),
+            self.config.stream_pool_size,
         );
 
         log::info!("tunnel {tunnel} established.");
 
-        return Ok(Some((Box::new(tunnel), (routing_rules, routing_priority))));
+        return Ok(Some((
+            Box::new(tunnel),
+            (routing_rules, routing_priority),
+            bandwidth_limit,
+            identity,
+        )));
     }
 }
 
 pub struct QuicOutTunnelConfig {
     pub priority: Option<i64>,
     pub stun_server_addresses: Vec<SocketAddr>,
+    pub tls: TunnelTlsConfig,
+    pub transport: QuicTransportConfig,
+    pub port_mapping: Option<Arc<PortMappingClient>>,
     pub routing_rules: Vec<OutRuleConfig>,
     pub routing_priority: i64,
 }
 
 pub struct QuicOutTunnelProvider {
     match_server: Arc<OutMatchServer>,
+    migrator: QuicOutMigrator,
+    nat_detector: NatBehaviorDetector,
     server_config: Arc<QuicServerConfig>,
-    cert: String,
-    key: String,
+    fingerprint: String,
     config: QuicOutTunnelConfig,
 }
 
 impl QuicOutTunnelProvider {
-    pub fn new(match_server: Arc<OutMatchServer>, config: QuicOutTunnelConfig) -> Self {
-        let (server_config, cert, key) =
-            create_rustls_server_config_and_cert([TLS_NAME.to_owned()]);
+    pub fn new(
+        match_server: Arc<OutMatchServer>,
+        config: QuicOutTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let (cert, key) = config.tls.load_or_generate_cert()?;
+
+        // The config is shared by tunnels for sessions to be resumed, the client
+        // cert is checked against the matched IN once connected.
+        let mut server_config = create_rustls_server_config_with_any_client_cert(
+            &cert,
+            &key,
+            &config.tls.alpn_protocols,
+        )?;
+
+        if config.transport.zero_rtt {
+            // Sessions are kept in memory and each can be resumed once, which
+            // rejects replayed early data within the lifetime of OUT. IN only
+            // sends heads of streams as early data anyway.
+            server_config.max_early_data_size = u32::MAX;
+        }
 
-        Self {
+        Ok(Self {
             match_server,
-            server_config: Arc::new(QuicServerConfig::try_from(server_config).unwrap()),
-            cert,
-            key,
+            migrator: QuicOutMigrator::new(config.stun_server_addresses.clone()),
+            nat_detector: NatBehaviorDetector::new(config.stun_server_addresses.clone()),
+            server_config: Arc::new(QuicServerConfig::try_from(server_config)?),
+            fingerprint: get_cert_fingerprint(&cert)?,
             config,
-        }
+        })
     }
 }
 
 #[async_trait::async_trait]
 impl OutTunnelProvider for QuicOutTunnelProvider {
     async fn accept(&self) -> anyhow::Result<Box<dyn OutTunnel>> {
-        let ip = probe_external_ip(&self.config.stun_server_addresses).await?;
+        let mut external_ip_change_receiver = self.migrator.subscribe();
+
+        let nat = self.nat_detector.get().await;
 
-        let socket = tokio::net::UdpSocket::bind("0:0").await?;
+        let (socket, mut mapped_addresses) =
+            create_socket_and_predict_mapped_addresses(&self.config.stun_server_addresses).await?;
 
-        let external_address = SocketAddr::new(ip, socket.local_addr()?.port());
+        let port_mapper = map_socket_port(
+            self.config.port_mapping.as_ref(),
+            &socket,
+            &mut mapped_addresses,
+        )
+        .await?;
 
         let MatchIn {
             id: _,
             tunnel_id,
-            data: QuicInData {},
-        } = self
-            .match_server
-            .match_in(
+            protocol,
+            data:
+                QuicInData {
+                    congestion_controller: in_congestion_controller,
+                    addresses: in_addresses,
+                    nat: in_nat,
+                    fingerprint: in_fingerprint,
+                },
+        } = tokio::select! {
+            match_in = self.match_server.match_in(
                 QuicOutData {
-                    address: external_address,
-                    cert: self.cert.clone(),
-                    key: self.key.clone(),
+                    address: mapped_addresses[0],
+                    fingerprint: self.fingerprint.clone(),
+                    tls_name: self.config.tls.server_name.clone(),
+                    alpn: self.config.tls.alpn_protocols.clone(),
+                    congestion_controller: self.config.transport.congestion_controller,
+                    udp: true,
+                    addresses: mapped_addresses.clone(),
+                    nat,
                 },
                 self.config.priority,
                 &self.config.routing_rules,
                 self.config.routing_priority,
-            )
-            .await?;
+            ) => match_in?,
+            // The advertised address is no longer reachable.
+            _ = external_ip_change_receiver.recv() => anyhow::bail!("external IP changed."),
+        };
+
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let congestion_controller = QuicCongestionController::negotiate(
+            self.config.transport.congestion_controller,
+            in_congestion_controller,
+        );
 
-        let endpoint = create_server_endpoint(socket.into_std()?, self.server_config.clone())?;
+        let socket = socket.into_std()?;
+
+        let punch_handle = tokio::spawn({
+            let socket = tokio::net::UdpSocket::from_std(socket.try_clone()?)?;
+
+            async move { punch(&socket, &in_addresses).await }
+        });
+
+        let endpoint = create_server_endpoint(
+            socket,
+            self.server_config.clone(),
+            &self.config.transport,
+            congestion_controller,
+        )?;
 
         let incoming = endpoint
             .accept()
             .await
             .ok_or_else(|| anyhow::anyhow!("incoming not available"))?;
 
-        let connection = incoming.accept()?.await?;
+        let map_accept_error = |error: quinn::ConnectionError| match in_nat {
+            Some(in_nat) => {
+                anyhow::anyhow!("error accepting tunnel {tunnel_id} (IN {in_nat}): {error}")
+            }
+            None => error.into(),
+        };
+
+        let connecting = incoming.accept()?;
+
+        let connection = if self.config.transport.zero_rtt {
+            // Always succeeds for incoming connections.
+            let Ok((connection, handshake_completed)) = connecting.into_0rtt() else {
+                anyhow::bail!("tunnel {tunnel_id} can not be accepted with 0-RTT.");
+            };
+
+            // A resumed session carries the cert of IN, so that the early data (only
+            // heads of streams) is served before the handshake completes. Otherwise
+            // the cert is only known then.
+            if connection.peer_identity().is_none() && !handshake_completed.await {
+                return Err(map_accept_error(connection.closed().await));
+            }
+
+            connection
+        } else {
+            connecting.await.map_err(map_accept_error)?
+        };
+
+        punch_handle.abort();
+
+        let peer_fingerprint = connection
+            .peer_identity()
+            .and_then(|identity| {
+                identity
+                    .downcast::<Vec<rustls::pki_types::CertificateDer<'static>>>()
+                    .ok()
+            })
+            .and_then(|certs| get_peer_fingerprint(Some(&certs)));
+
+        if peer_fingerprint != Some(parse_fingerprint(&in_fingerprint)?) {
+            connection.close(0u32.into(), b"");
+
+            anyhow::bail!("tunnel {tunnel_id} connected with unexpected cert.");
+        }
+
+        self.migrator.register(connection.clone());
 
         let tunnel = ByteStreamOutTunnel::new(
             TUNNEL_NAME,
             tunnel_id,
-            QuicOutTunnelConnection::new(connection),
+            protocol,
+            QuicOutTunnelConnection::new(connection, port_mapper),
         );
 
         log::info!("tunnel {tunnel} established.");
@@ -180,3 +549,28 @@ impl OutTunnelProvider for QuicOutTunnelProvider {
         return Ok(Box::new(tunnel));
     }
 }
+
+/// Maps the socket port on the gateway if enabled, the mapped address is put
+/// before the ones probed through STUN.
+async fn map_socket_port(
+    port_mapping_client: Option<&Arc<PortMappingClient>>,
+    socket: &tokio::net::UdpSocket,
+    mapped_addresses: &mut Vec<SocketAddr>,
+) -> anyhow::Result<Option<PortMapper>> {
+    let Some(port_mapping_client) = port_mapping_client else {
+        return Ok(None);
+    };
+
+    let port_mapper = PortMapper::new(
+        port_mapping_client.clone(),
+        PortMappingProtocol::Udp,
+        socket.local_addr()?.port(),
+    );
+
+    if let Some(external_address) = port_mapper.external_address().await {
+        mapped_addresses.retain(|&address| address != external_address);
+        mapped_addresses.insert(0, external_address);
+    }
+
+    Ok(Some(port_mapper))
+}
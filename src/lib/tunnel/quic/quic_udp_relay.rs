@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{self, AtomicU32},
+        Arc, Mutex,
+    },
+};
+
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::tunnel::{
+    decode_udp_packet, encode_udp_packet, InUdpRelay, OutUdpRelay, UdpFlowId, UdpFlowResponseSender,
+};
+
+type QuicInUdpFlowMap = HashMap<UdpFlowId, (Option<String>, UdpFlowResponseSender)>;
+
+/// Packets go through QUIC datagrams (RFC 9221) to avoid head-of-line blocking,
+/// and fall back to a unidirectional stream framed by u16 length if the peer does
+/// not support datagrams or a packet exceeds the datagram size limit.
+struct QuicUdpPacketChannel {
+    connection: quinn::Connection,
+    uni_stream: tokio::sync::Mutex<Option<quinn::SendStream>>,
+}
+
+impl QuicUdpPacketChannel {
+    fn new(connection: quinn::Connection) -> Self {
+        Self {
+            connection,
+            uni_stream: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn send(&self, packet: Vec<u8>) -> anyhow::Result<()> {
+        let packet = bytes::Bytes::from(packet);
+
+        if self
+            .connection
+            .max_datagram_size()
+            .is_some_and(|max_datagram_size| packet.len() <= max_datagram_size)
+        {
+            match self.connection.send_datagram(packet.clone()) {
+                Ok(()) => return Ok(()),
+                Err(quinn::SendDatagramError::ConnectionLost(error)) => return Err(error.into()),
+                Err(_) => {}
+            }
+        }
+
+        let mut uni_stream = self.uni_stream.lock().await;
+
+        let stream = match uni_stream.as_mut() {
+            Some(stream) => stream,
+            None => uni_stream.insert(self.connection.open_uni().await?),
+        };
+
+        let result = async {
+            stream.write_u16(packet.len().try_into()?).await?;
+            stream.write_all(&packet).await?;
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            uni_stream.take();
+        }
+
+        result
+    }
+
+    /// Receives packets from both datagrams and unidirectional streams until the
+    /// connection closes.
+    async fn receive<THandler>(&self, handler: THandler)
+    where
+        THandler: Fn(bytes::Bytes) + Clone + Send + 'static,
+    {
+        let datagram_task = {
+            let connection = &self.connection;
+            let handler = handler.clone();
+
+            async move {
+                while let Ok(packet) = connection.read_datagram().await {
+                    handler(packet);
+                }
+            }
+        };
+
+        let uni_stream_task = async {
+            while let Ok(mut stream) = self.connection.accept_uni().await {
+                tokio::spawn({
+                    let handler = handler.clone();
+
+                    async move {
+                        while let Ok(length) = stream.read_u16().await {
+                            let mut packet = vec![0; length as usize];
+
+                            if stream.read_exact(&mut packet).await.is_err() {
+                                break;
+                            }
+
+                            handler(packet.into());
+                        }
+                    }
+                });
+            }
+        };
+
+        tokio::join!(datagram_task, uni_stream_task);
+    }
+}
+
+pub struct QuicInUdpRelay {
+    channel: Arc<QuicUdpPacketChannel>,
+    handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+    next_flow_id: AtomicU32,
+    flow_map: Arc<Mutex<QuicInUdpFlowMap>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl QuicInUdpRelay {
+    /// Datagrams are held until `handshake_confirmed` turns true, not to be sent
+    /// as replayable early data.
+    pub fn new(
+        connection: quinn::Connection,
+        handshake_confirmed: tokio::sync::watch::Receiver<bool>,
+    ) -> Self {
+        let channel = Arc::new(QuicUdpPacketChannel::new(connection));
+
+        let flow_map = Arc::new(Mutex::new(QuicInUdpFlowMap::new()));
+
+        let handle = tokio::spawn({
+            let channel = channel.clone();
+            let flow_map = flow_map.clone();
+
+            async move {
+                channel
+                    .receive(move |packet| match decode_udp_packet(packet) {
+                        Ok((flow_id, address, _, data)) => {
+                            if let Some((_, response_sender)) =
+                                flow_map.lock().unwrap().get(&flow_id)
+                            {
+                                response_sender.send((data, address)).ok();
+                            }
+                        }
+                        Err(error) => log::warn!("error decoding UDP packet: {error}"),
+                    })
+                    .await;
+            }
+        });
+
+        Self {
+            channel,
+            handshake_confirmed,
+            next_flow_id: AtomicU32::new(0),
+            flow_map,
+            handle,
+        }
+    }
+}
+
+impl Drop for QuicInUdpRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[async_trait::async_trait]
+impl InUdpRelay for QuicInUdpRelay {
+    fn open_flow(&self, tag: Option<String>, response_sender: UdpFlowResponseSender) -> UdpFlowId {
+        let flow_id = self.next_flow_id.fetch_add(1, atomic::Ordering::Relaxed);
+
+        self.flow_map
+            .lock()
+            .unwrap()
+            .insert(flow_id, (tag, response_sender));
+
+        flow_id
+    }
+
+    fn close_flow(&self, flow_id: UdpFlowId) {
+        self.flow_map.lock().unwrap().remove(&flow_id);
+    }
+
+    async fn send(
+        &self,
+        flow_id: UdpFlowId,
+        address: SocketAddr,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let tag = match self.flow_map.lock().unwrap().get(&flow_id) {
+            Some((tag, _)) => tag.clone(),
+            None => anyhow::bail!("UDP flow {flow_id} closed."),
+        };
+
+        if !*self.handshake_confirmed.borrow() {
+            self.handshake_confirmed
+                .clone()
+                .wait_for(|confirmed| *confirmed)
+                .await?;
+        }
+
+        self.channel
+            .send(encode_udp_packet(flow_id, address, tag.as_deref(), data))
+            .await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.channel.connection.close_reason().is_some()
+    }
+}
+
+/// Relays datagrams of flows to OUT, which sends them through its outputs.
+pub struct QuicOutUdpRelay {
+    channel: Arc<QuicUdpPacketChannel>,
+    packet_receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<bytes::Bytes>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl QuicOutUdpRelay {
+    pub fn new(connection: quinn::Connection) -> Self {
+        let channel = Arc::new(QuicUdpPacketChannel::new(connection));
+
+        // Packets are handled in order by the receiving end.
+        let (packet_sender, packet_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let handle = tokio::spawn({
+            let channel = channel.clone();
+
+            async move {
+                channel
+                    .receive(move |packet| {
+                        packet_sender.send(packet).ok();
+                    })
+                    .await;
+            }
+        });
+
+        Self {
+            channel,
+            packet_receiver: tokio::sync::Mutex::new(packet_receiver),
+            handle,
+        }
+    }
+}
+
+impl Drop for QuicOutUdpRelay {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[async_trait::async_trait]
+impl OutUdpRelay for QuicOutUdpRelay {
+    async fn receive(
+        &self,
+    ) -> anyhow::Result<(UdpFlowId, Option<String>, SocketAddr, bytes::Bytes)> {
+        let mut packet_receiver = self.packet_receiver.lock().await;
+
+        loop {
+            let packet = packet_receiver
+                .recv()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("quic connection closed."))?;
+
+            match decode_udp_packet(packet) {
+                Ok((flow_id, address, tag, data)) => return Ok((flow_id, tag, address, data)),
+                Err(error) => log::warn!("error decoding UDP packet: {error}"),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        flow_id: UdpFlowId,
+        address: SocketAddr,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        self.channel
+            .send(encode_udp_packet(flow_id, address, None, data))
+            .await
+    }
+}
@@ -1,19 +1,27 @@
-use std::{net::UdpSocket, sync::Arc, time::Duration};
+use std::{net::UdpSocket, sync::Arc};
 
 use quinn::{
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     crypto::rustls::{QuicClientConfig, QuicServerConfig},
     ClientConfig, Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime,
     TransportConfig, VarInt,
 };
 
+use super::{QuicCongestionController, QuicTransportConfig};
+
 pub fn create_server_endpoint(
     socket: UdpSocket,
     server_config: Arc<QuicServerConfig>,
+    transport_config: &QuicTransportConfig,
+    congestion_controller: QuicCongestionController,
 ) -> anyhow::Result<Endpoint> {
     let server_config = {
         let mut server_config = ServerConfig::with_crypto(server_config);
 
-        server_config.transport_config(Arc::new(create_transport_config()));
+        server_config.transport_config(Arc::new(create_transport_config(
+            transport_config,
+            congestion_controller,
+        )?));
 
         server_config
     };
@@ -29,13 +37,18 @@ pub fn create_server_endpoint(
 pub fn create_client_endpoint(
     socket: UdpSocket,
     client_config: rustls::ClientConfig,
+    transport_config: &QuicTransportConfig,
+    congestion_controller: QuicCongestionController,
 ) -> anyhow::Result<Endpoint> {
     let client_config = {
         let client_config = Arc::new(QuicClientConfig::try_from(client_config).unwrap());
 
         let mut client_config = ClientConfig::new(client_config);
 
-        client_config.transport_config(Arc::new(create_transport_config()));
+        client_config.transport_config(Arc::new(create_transport_config(
+            transport_config,
+            congestion_controller,
+        )?));
 
         client_config
     };
@@ -52,15 +65,135 @@ pub fn create_client_endpoint(
     Ok(endpoint)
 }
 
-fn create_transport_config() -> TransportConfig {
+fn create_transport_config(
+    config: &QuicTransportConfig,
+    congestion_controller: QuicCongestionController,
+) -> anyhow::Result<TransportConfig> {
     let mut transport_config = TransportConfig::default();
 
     transport_config
-        .max_concurrent_bidi_streams(VarInt::from_u32(1024))
-        .keep_alive_interval(Some(Duration::from_secs(5)))
-        .max_idle_timeout(Some(
-            IdleTimeout::try_from(Duration::from_secs(30)).unwrap(),
-        ));
+        .max_concurrent_bidi_streams(VarInt::from_u32(config.max_concurrent_bidi_streams))
+        .keep_alive_interval(Some(config.keep_alive_interval))
+        .max_idle_timeout(Some(IdleTimeout::try_from(config.max_idle_timeout)?));
 
-    transport_config
+    if let Some(stream_receive_window) = config.stream_receive_window {
+        transport_config.stream_receive_window(VarInt::from_u64(stream_receive_window)?);
+    }
+
+    if let Some(receive_window) = config.receive_window {
+        transport_config.receive_window(VarInt::from_u64(receive_window)?);
+    }
+
+    if let Some(send_window) = config.send_window {
+        transport_config.send_window(send_window);
+    }
+
+    match congestion_controller {
+        QuicCongestionController::NewReno => {
+            transport_config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+        }
+        QuicCongestionController::Cubic => {
+            transport_config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+        }
+        QuicCongestionController::Bbr => {
+            transport_config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+        }
+    };
+
+    Ok(transport_config)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use quinn::congestion::{Bbr, NewReno};
+
+    use crate::tunnel::{
+        common::{create_rustls_client_config_with_alpn, create_rustls_server_config},
+        tls_name_default, TunnelTlsConfig,
+    };
+
+    use super::*;
+
+    /// Connects a client endpoint to a server endpoint on localhost, returning
+    /// both endpoints and connections.
+    pub(in crate::tunnel::quic) async fn connect(
+        server_congestion_controller: QuicCongestionController,
+        client_congestion_controller: QuicCongestionController,
+    ) -> anyhow::Result<((Endpoint, quinn::Connection), (Endpoint, quinn::Connection))> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let alpn_protocols = vec!["h3".to_owned()];
+
+        let (cert, key) = TunnelTlsConfig {
+            server_name: tls_name_default(),
+            alpn_protocols: alpn_protocols.clone(),
+            identity_paths: None,
+        }
+        .load_or_generate_cert()?;
+
+        let server_endpoint = create_server_endpoint(
+            UdpSocket::bind("127.0.0.1:0")?,
+            Arc::new(QuicServerConfig::try_from(create_rustls_server_config(
+                &cert,
+                &key,
+                &alpn_protocols,
+            )?)?),
+            &QuicTransportConfig::default(),
+            server_congestion_controller,
+        )?;
+
+        let client_endpoint = create_client_endpoint(
+            UdpSocket::bind("127.0.0.1:0")?,
+            create_rustls_client_config_with_alpn(&cert, &key, &alpn_protocols)?,
+            &QuicTransportConfig::default(),
+            client_congestion_controller,
+        )?;
+
+        let (server_connection, client_connection) = tokio::try_join!(
+            async {
+                let incoming = server_endpoint
+                    .accept()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("endpoint closed."))?;
+
+                anyhow::Ok(incoming.await?)
+            },
+            async {
+                anyhow::Ok(
+                    client_endpoint
+                        .connect(server_endpoint.local_addr()?, &tls_name_default())?
+                        .await?,
+                )
+            },
+        )?;
+
+        Ok((
+            (server_endpoint, server_connection),
+            (client_endpoint, client_connection),
+        ))
+    }
+
+    #[tokio::test]
+    async fn installs_congestion_controller() -> anyhow::Result<()> {
+        let ((_server_endpoint, server_connection), (_client_endpoint, client_connection)) =
+            connect(
+                QuicCongestionController::Bbr,
+                QuicCongestionController::NewReno,
+            )
+            .await?;
+
+        // Each end sends with its own controller.
+        assert!(server_connection
+            .congestion_state()
+            .into_any()
+            .downcast::<Bbr>()
+            .is_ok());
+        assert!(client_connection
+            .congestion_state()
+            .into_any()
+            .downcast::<NewReno>()
+            .is_ok());
+
+        Ok(())
+    }
 }
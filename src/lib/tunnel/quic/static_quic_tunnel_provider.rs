@@ -0,0 +1,573 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use quinn::crypto::rustls::QuicServerConfig;
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    config::StaticOutConfig,
+    match_server::{
+        p2p_match_protocol::{read_frame, write_frame},
+        MatchIn, MatchInId, MatchOut, MatchOutId, MatchOutIdentity, OutLoad,
+    },
+    route::{config::OutRuleConfig, rule::Label},
+    tunnel::{
+        byte_stream_tunnel::{ByteStreamInTunnel, ByteStreamOutTunnel},
+        common::{
+            create_rustls_client_config_with_alpn, create_rustls_client_config_with_fingerprint,
+            create_rustls_server_config, create_rustls_server_config_with_client_fingerprints,
+            derive_tls_identity, get_cert_fingerprint, parse_fingerprint,
+        },
+        tls_name_default,
+        tunnel_provider::{InTunnelProvider, OutTunnelProvider},
+        InTunnel, OutTunnel, TunnelCapability, TunnelId, TunnelProtocol, TunnelTlsConfig,
+    },
+    utils::net::get_any_address,
+};
+
+use super::{
+    quinn::{create_client_endpoint, create_server_endpoint},
+    QuicCongestionController, QuicInTunnelConnection, QuicOutTunnelConnection, QuicTransportConfig,
+};
+
+const TUNNEL_NAME: &str = "quic";
+
+const STATIC_ALPN: &str = "h3";
+
+/// Time for IN to send and OUT to answer the match payloads exchanged in place
+/// of the match server, on the first stream of a connection. On OUT it also
+/// bounds the QUIC handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct StaticQuicInTunnelConfig {
+    pub priority: Option<i64>,
+    pub priority_default: i64,
+    pub stream_pool_size: usize,
+    pub transport: QuicTransportConfig,
+    /// Identity presented to OUT without a shared key.
+    pub tls: TunnelTlsConfig,
+}
+
+/// Connects to OUT listed in the config, each accepted once as if announced by
+/// a match server.
+pub struct StaticQuicInTunnelProvider {
+    id: MatchInId,
+    outs: Vec<(MatchOutId, StaticOut)>,
+    next_out_index: AtomicUsize,
+    config: StaticQuicInTunnelConfig,
+}
+
+struct StaticOut {
+    address: String,
+    connections: usize,
+    client_config: rustls::ClientConfig,
+}
+
+impl StaticQuicInTunnelProvider {
+    pub fn new(
+        out_configs: Vec<StaticOutConfig>,
+        config: StaticQuicInTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let alpn = [STATIC_ALPN.to_owned()];
+
+        let mut identity = None;
+
+        let outs = out_configs
+            .into_iter()
+            .map(|out_config| {
+                let client_config = match (&out_config.key, &out_config.fingerprint) {
+                    (Some(key), None) => {
+                        let (cert, key) = derive_tls_identity(key)?;
+
+                        create_rustls_client_config_with_alpn(&cert, &key, &alpn)?
+                    }
+                    (None, Some(fingerprint)) => {
+                        if identity.is_none() {
+                            let (cert, key) = config.tls.load_or_generate_cert()?;
+
+                            log::info!(
+                                "static IN certificate fingerprint {}.",
+                                get_cert_fingerprint(&cert)?
+                            );
+
+                            identity = Some((cert, key));
+                        }
+
+                        let (cert, key) = identity.as_ref().unwrap();
+
+                        create_rustls_client_config_with_fingerprint(
+                            cert,
+                            key,
+                            parse_fingerprint(fingerprint)?,
+                            &alpn,
+                        )?
+                    }
+                    _ => anyhow::bail!(
+                        "static OUT {} needs either key or fingerprint.",
+                        out_config.address
+                    ),
+                };
+
+                Ok((
+                    MatchOutId::new(),
+                    StaticOut {
+                        address: out_config.address,
+                        connections: out_config.connections.unwrap_or(1),
+                        client_config,
+                    },
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            id: MatchInId::new(),
+            outs,
+            next_out_index: AtomicUsize::new(0),
+            config,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl InTunnelProvider for StaticQuicInTunnelProvider {
+    fn name(&self) -> &'static str {
+        TUNNEL_NAME
+    }
+
+    async fn accept_out(&self) -> anyhow::Result<(MatchOutId, usize)> {
+        let index = self.next_out_index.fetch_add(1, Ordering::Relaxed);
+
+        match self.outs.get(index) {
+            Some((out_id, out)) => {
+                log::info!("accepting static OUT {}...", out.address);
+
+                Ok((*out_id, out.connections))
+            }
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// OUT load is published only through match servers.
+    async fn get_out_load(&self, _out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        Ok(None)
+    }
+
+    async fn accept(
+        &self,
+        out_id: MatchOutId,
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    > {
+        let Some((_, out)) = self.outs.iter().find(|(id, _)| *id == out_id) else {
+            return Ok(None);
+        };
+
+        let address = tokio::net::lookup_host(&out.address)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve {}.", out.address))?;
+
+        let endpoint = create_client_endpoint(
+            std::net::UdpSocket::bind(get_any_address(&address))?,
+            out.client_config.clone(),
+            &self.config.transport,
+            // Congestion control only applies to the sending side, so the ends
+            // do not need to agree on it.
+            QuicCongestionController::negotiate(None, self.config.transport.congestion_controller),
+        )?;
+
+        let connection = endpoint.connect(address, &tls_name_default())?.await?;
+
+        let tunnel_id = TunnelId::new();
+
+        let MatchOut {
+            id: _,
+            tunnel_id: _,
+            tunnel_labels,
+            tunnel_priority,
+            routing_priority,
+            routing_rules,
+            bandwidth_limit,
+            protocol,
+            identity: _,
+            data: (),
+        } = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+
+            write_frame(
+                &mut send_stream,
+                &MatchIn {
+                    id: self.id,
+                    tunnel_id,
+                    protocol: TunnelProtocol::current(),
+                    data: (),
+                },
+            )
+            .await?;
+
+            send_stream.finish()?;
+
+            read_frame::<MatchOut<()>>(&mut recv_stream)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("static OUT {} ended handshake.", out.address))
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("static OUT {} handshake timed out.", out.address))??;
+
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let udp = protocol.supports(TunnelCapability::UdpRelay);
+
+        let tunnel = ByteStreamInTunnel::new(
+            TUNNEL_NAME,
+            tunnel_id,
+            out_id,
+            tunnel_labels,
+            self.config
+                .priority
+                .unwrap_or(tunnel_priority.unwrap_or(self.config.priority_default)),
+            protocol,
+            QuicInTunnelConnection::new(connection, udp, tokio::sync::watch::channel(true).1, None),
+            self.config.stream_pool_size,
+        );
+
+        log::info!("tunnel {tunnel} established.");
+
+        Ok(Some((
+            Box::new(tunnel),
+            (routing_rules, routing_priority),
+            bandwidth_limit,
+            // Authenticated by the pinned certificate instead.
+            None,
+        )))
+    }
+}
+
+pub struct StaticQuicOutTunnelConfig {
+    pub listen_address: SocketAddr,
+    /// Key shared with IN, otherwise IN is identified by `in_fingerprints`.
+    pub key: Option<String>,
+    pub in_fingerprints: Vec<String>,
+    /// Identity presented to IN without a shared key.
+    pub tls: TunnelTlsConfig,
+    pub transport: QuicTransportConfig,
+    pub labels: Vec<Label>,
+    pub bandwidth_limit: BandwidthLimit,
+    pub priority: Option<i64>,
+    pub routing_rules: Vec<OutRuleConfig>,
+    pub routing_priority: i64,
+}
+
+/// Accepts connections of IN listing this OUT, each as a tunnel. Connections
+/// are handshaked concurrently, not to be held up by a slow IN.
+pub struct StaticQuicOutTunnelProvider {
+    tunnel_receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Box<dyn OutTunnel>>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl StaticQuicOutTunnelProvider {
+    pub fn new(config: StaticQuicOutTunnelConfig) -> anyhow::Result<Self> {
+        let alpn = [STATIC_ALPN.to_owned()];
+
+        let server_config = match &config.key {
+            Some(key) => {
+                anyhow::ensure!(
+                    config.in_fingerprints.is_empty(),
+                    "static OUT takes either key or IN fingerprints."
+                );
+
+                let (cert, key) = derive_tls_identity(key)?;
+
+                create_rustls_server_config(&cert, &key, &alpn)?
+            }
+            None => {
+                anyhow::ensure!(
+                    !config.in_fingerprints.is_empty(),
+                    "static OUT needs either key or IN fingerprints."
+                );
+
+                let (cert, key) = config.tls.load_or_generate_cert()?;
+
+                log::info!(
+                    "static OUT certificate fingerprint {}.",
+                    get_cert_fingerprint(&cert)?
+                );
+
+                create_rustls_server_config_with_client_fingerprints(
+                    &cert,
+                    &key,
+                    config
+                        .in_fingerprints
+                        .iter()
+                        .map(|fingerprint| parse_fingerprint(fingerprint))
+                        .collect::<anyhow::Result<_>>()?,
+                    &alpn,
+                )?
+            }
+        };
+
+        let endpoint = create_server_endpoint(
+            std::net::UdpSocket::bind(config.listen_address)?,
+            Arc::new(QuicServerConfig::try_from(server_config)?),
+            &config.transport,
+            // Applies to data sent by OUT only, see the IN side.
+            QuicCongestionController::negotiate(config.transport.congestion_controller, None),
+        )?;
+
+        log::info!("static OUT listening on {}...", config.listen_address);
+
+        let (tunnel_sender, tunnel_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let handle = tokio::spawn({
+            let id = MatchOutId::new();
+            let config = Arc::new(config);
+
+            async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    tokio::spawn({
+                        let config = config.clone();
+                        let tunnel_sender = tunnel_sender.clone();
+
+                        async move {
+                            let remote_address = incoming.remote_address();
+
+                            match tokio::time::timeout(
+                                HANDSHAKE_TIMEOUT,
+                                accept_tunnel(id, &config, incoming),
+                            )
+                            .await
+                            {
+                                Ok(Ok(tunnel)) => {
+                                    tunnel_sender.send(tunnel).ok();
+                                }
+                                Ok(Err(error)) => {
+                                    log::warn!("error accepting IN from {remote_address}: {error}");
+                                }
+                                Err(_) => {
+                                    log::warn!("IN from {remote_address} handshake timed out.");
+                                }
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(Self {
+            tunnel_receiver: tokio::sync::Mutex::new(tunnel_receiver),
+            handle,
+        })
+    }
+}
+
+impl Drop for StaticQuicOutTunnelProvider {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[async_trait::async_trait]
+impl OutTunnelProvider for StaticQuicOutTunnelProvider {
+    async fn accept(&self) -> anyhow::Result<Box<dyn OutTunnel>> {
+        self.tunnel_receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("incoming not available"))
+    }
+}
+
+/// Connects IN and exchanges the match payloads with it on the first stream.
+async fn accept_tunnel(
+    id: MatchOutId,
+    config: &StaticQuicOutTunnelConfig,
+    incoming: quinn::Incoming,
+) -> anyhow::Result<Box<dyn OutTunnel>> {
+    let remote_address = incoming.remote_address();
+
+    let connection = incoming.accept()?.await?;
+
+    let (mut send_stream, mut recv_stream) = connection.accept_bi().await?;
+
+    let MatchIn {
+        id: in_id,
+        tunnel_id,
+        protocol,
+        data: (),
+    } = read_frame::<MatchIn<()>>(&mut recv_stream)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("IN from {remote_address} ended handshake."))?;
+
+    let negotiation = TunnelProtocol::current().negotiate(&protocol);
+
+    // Replies to incompatible IN as well, for it to report the refusal.
+    write_frame(
+        &mut send_stream,
+        &MatchOut {
+            id,
+            tunnel_id,
+            tunnel_labels: config.labels.clone(),
+            tunnel_priority: config.priority,
+            routing_priority: config.routing_priority,
+            routing_rules: config.routing_rules.clone(),
+            bandwidth_limit: config.bandwidth_limit,
+            protocol: TunnelProtocol::current(),
+            identity: None,
+            data: (),
+        },
+    )
+    .await?;
+
+    send_stream.finish()?;
+
+    let tunnel = ByteStreamOutTunnel::new(
+        TUNNEL_NAME,
+        tunnel_id,
+        negotiation?,
+        QuicOutTunnelConnection::new(connection, None),
+    );
+
+    log::info!("tunnel {tunnel} established with IN {in_id} from {remote_address}.");
+
+    Ok(Box::new(tunnel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key";
+
+    #[tokio::test]
+    async fn connects_with_shared_key() -> anyhow::Result<()> {
+        let (address, out_provider) = new_out_provider()?;
+        let in_provider = new_in_provider(address, KEY)?;
+
+        let (out_id, connections) = in_provider.accept_out().await?;
+
+        assert_eq!(connections, 2);
+
+        let (in_tunnel, out_tunnel) = tokio::try_join!(
+            async {
+                in_provider
+                    .accept(out_id)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("OUT not found."))
+            },
+            out_provider.accept(),
+        )?;
+
+        let (in_tunnel, (routing_rules, routing_priority), ..) = in_tunnel;
+
+        assert!(in_tunnel.id() == out_tunnel.id());
+        assert_eq!(in_tunnel.labels(), [Label::Custom("test".to_owned())]);
+        assert!(routing_rules.is_empty());
+        assert_eq!(routing_priority, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_key() -> anyhow::Result<()> {
+        let (address, _out_provider) = new_out_provider()?;
+        let in_provider = new_in_provider(address, "wrong key")?;
+
+        let (out_id, _) = in_provider.accept_out().await?;
+
+        assert!(in_provider.accept(out_id).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accepts_in_while_another_stalls() -> anyhow::Result<()> {
+        let (address, out_provider) = new_out_provider()?;
+
+        // Connected, but never sending its match payload.
+        let (cert, key) = derive_tls_identity(KEY)?;
+
+        let stalled_endpoint = create_client_endpoint(
+            std::net::UdpSocket::bind("127.0.0.1:0")?,
+            create_rustls_client_config_with_alpn(&cert, &key, &[STATIC_ALPN.to_owned()])?,
+            &QuicTransportConfig::default(),
+            QuicCongestionController::Cubic,
+        )?;
+
+        let _stalled_connection = stalled_endpoint
+            .connect(address, &tls_name_default())?
+            .await?;
+
+        let in_provider = new_in_provider(address, KEY)?;
+
+        let (out_id, _) = in_provider.accept_out().await?;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::try_join!(in_provider.accept(out_id), out_provider.accept())
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    fn new_out_provider() -> anyhow::Result<(SocketAddr, StaticQuicOutTunnelProvider)> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let address = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+
+        let provider = StaticQuicOutTunnelProvider::new(StaticQuicOutTunnelConfig {
+            listen_address: address,
+            key: Some(KEY.to_owned()),
+            in_fingerprints: Vec::new(),
+            tls: new_tls_config(),
+            transport: QuicTransportConfig::default(),
+            labels: vec![Label::Custom("test".to_owned())],
+            bandwidth_limit: BandwidthLimit::default(),
+            priority: None,
+            routing_rules: Vec::new(),
+            routing_priority: 1,
+        })?;
+
+        Ok((address, provider))
+    }
+
+    fn new_in_provider(
+        address: SocketAddr,
+        key: &str,
+    ) -> anyhow::Result<StaticQuicInTunnelProvider> {
+        StaticQuicInTunnelProvider::new(
+            vec![StaticOutConfig {
+                address: address.to_string(),
+                key: Some(key.to_owned()),
+                fingerprint: None,
+                connections: Some(2),
+            }],
+            StaticQuicInTunnelConfig {
+                priority: None,
+                priority_default: 0,
+                stream_pool_size: 0,
+                transport: QuicTransportConfig::default(),
+                tls: new_tls_config(),
+            },
+        )
+    }
+
+    fn new_tls_config() -> TunnelTlsConfig {
+        TunnelTlsConfig {
+            server_name: tls_name_default(),
+            alpn_protocols: vec![STATIC_ALPN.to_owned()],
+            identity_paths: None,
+        }
+    }
+}
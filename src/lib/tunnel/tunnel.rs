@@ -1,9 +1,14 @@
-use std::{fmt, net::SocketAddr};
+use std::{fmt, net::SocketAddr, sync::Arc};
 
-use crate::{match_server::MatchOutId, route::rule::Label};
+use crate::{match_server::MatchOutId, route::rule::Label, utils::io::AsyncWriteReset};
+
+use super::{InUdpRelay, OutUdpRelay, TunnelConnectError};
 
 #[async_trait::async_trait]
 pub trait InTunnelLike: fmt::Display + Send + Sync {
+    /// Tunnel type, e.g. "quic", or "direct".
+    fn name(&self) -> &'static str;
+
     async fn connect(
         &self,
         destination_address: SocketAddr,
@@ -12,9 +17,14 @@ pub trait InTunnelLike: fmt::Display + Send + Sync {
         sniff_buffer: Option<Vec<u8>>,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
         tokio::sync::oneshot::Sender<()>,
     )>;
+
+    /// UDP relay of the tunnel, `None` if it cannot carry datagrams.
+    fn udp_relay(&self) -> Option<Arc<dyn InUdpRelay>> {
+        None
+    }
 }
 
 #[async_trait::async_trait]
@@ -31,6 +41,10 @@ pub trait InTunnel: InTunnelLike {
 
     fn is_active(&self) -> bool;
 
+    /// Stops the tunnel from being selected and releases its permit, e.g. when
+    /// replaced by tunnels to the restarted OUT. It is closed by then or soon.
+    fn deactivate(&self);
+
     async fn closed(&self);
 
     fn is_closed(&self) -> bool;
@@ -40,17 +54,36 @@ pub trait InTunnel: InTunnelLike {
 pub trait OutTunnel: fmt::Display + Send {
     fn id(&self) -> TunnelId;
 
+    /// Tunnel type, e.g. "quic".
+    fn name(&self) -> &'static str;
+
     async fn accept(
         &self,
     ) -> anyhow::Result<(
         (SocketAddr, Option<String>, Option<String>),
-        (
-            Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-            Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
-        ),
+        Box<dyn OutTunnelStream>,
     )>;
 
     fn is_closed(&self) -> bool;
+
+    /// UDP relay of the tunnel, `None` if it cannot carry datagrams.
+    fn udp_relay(&self) -> Option<Arc<dyn OutUdpRelay>> {
+        None
+    }
+}
+
+/// An accepted tunnel stream waiting for OUT to report whether the destination is
+/// connected, IN does not start relaying data until then.
+#[async_trait::async_trait]
+pub trait OutTunnelStream: Send {
+    async fn accept(
+        self: Box<Self>,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+    )>;
+
+    async fn reject(self: Box<Self>, error: TunnelConnectError) -> anyhow::Result<()>;
 }
 
 #[derive(
@@ -0,0 +1,130 @@
+use std::str::FromStr;
+
+/// Reason reported by OUT when it fails to connect to the destination of a tunnel
+/// stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, derive_more::Display)]
+pub enum TunnelConnectErrorKind {
+    #[display("refused")]
+    Refused,
+    #[display("timed out")]
+    TimedOut,
+    #[display("DNS failure")]
+    DnsFailure,
+    #[display("unreachable")]
+    Unreachable,
+    #[display("error")]
+    Other,
+}
+
+impl TunnelConnectErrorKind {
+    const ALL: [Self; 5] = [
+        Self::Refused,
+        Self::TimedOut,
+        Self::DnsFailure,
+        Self::Unreachable,
+        Self::Other,
+    ];
+
+    pub fn as_code(&self) -> u8 {
+        match self {
+            Self::Refused => 1,
+            Self::TimedOut => 2,
+            Self::DnsFailure => 3,
+            Self::Unreachable => 4,
+            Self::Other => u8::MAX,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Self::Refused,
+            2 => Self::TimedOut,
+            3 => Self::DnsFailure,
+            4 => Self::Unreachable,
+            _ => Self::Other,
+        }
+    }
+
+    /// Code of the kind, as sent in the HTTP/2 error header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Refused => "refused",
+            Self::TimedOut => "timed_out",
+            Self::DnsFailure => "dns_failure",
+            Self::Unreachable => "unreachable",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl FromStr for TunnelConnectErrorKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown connect error kind {s}."))
+    }
+}
+
+#[derive(Clone, Debug, derive_more::Display)]
+#[display("OUT connect {kind}: {message}")]
+pub struct TunnelConnectError {
+    pub kind: TunnelConnectErrorKind,
+    pub message: String,
+}
+
+impl TunnelConnectError {
+    pub fn new(kind: TunnelConnectErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<Self>() {
+            return error.clone();
+        }
+
+        let kind = match error.downcast_ref::<std::io::Error>() {
+            Some(error) => match error.kind() {
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset => {
+                    TunnelConnectErrorKind::Refused
+                }
+                std::io::ErrorKind::TimedOut => TunnelConnectErrorKind::TimedOut,
+                std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => {
+                    TunnelConnectErrorKind::Unreachable
+                }
+                _ => TunnelConnectErrorKind::Other,
+            },
+            None => TunnelConnectErrorKind::Other,
+        };
+
+        Self::new(kind, error.to_string())
+    }
+}
+
+impl std::error::Error for TunnelConnectError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_connect_error_kind() {
+        for kind in TunnelConnectErrorKind::ALL {
+            assert_eq!(
+                kind.as_str().parse::<TunnelConnectErrorKind>().unwrap(),
+                kind
+            );
+        }
+
+        assert!("timed out".parse::<TunnelConnectErrorKind>().is_err());
+        assert!("".parse::<TunnelConnectErrorKind>().is_err());
+
+        assert_eq!(TunnelConnectErrorKind::TimedOut.to_string(), "timed out");
+        assert_eq!(TunnelConnectErrorKind::TimedOut.as_str(), "timed_out");
+    }
+}
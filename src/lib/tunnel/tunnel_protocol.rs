@@ -0,0 +1,199 @@
+use itertools::Itertools as _;
+
+/// Version of the protocol between IN and OUT, bumped on changes of match data
+/// or tunnel framing.
+pub const TUNNEL_PROTOCOL_VERSION: u16 = 3;
+
+/// Oldest version this build still speaks.
+pub const TUNNEL_PROTOCOL_VERSION_MIN: u16 = 3;
+
+/// Optional features, used only if both ends support them.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TunnelCapability {
+    /// Relaying UDP over tunnels supporting datagrams.
+    #[display("udp-relay")]
+    UdpRelay,
+    /// OUT reporting whether the destination is connected before relaying data.
+    #[display("connect-status")]
+    ConnectStatus,
+    /// Capability of a newer build.
+    #[serde(other)]
+    #[display("unknown")]
+    Unknown,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TunnelProtocol {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Vec<TunnelCapability>,
+}
+
+impl TunnelProtocol {
+    pub fn current() -> Self {
+        Self {
+            version: TUNNEL_PROTOCOL_VERSION,
+            min_version: TUNNEL_PROTOCOL_VERSION_MIN,
+            capabilities: vec![TunnelCapability::UdpRelay, TunnelCapability::ConnectStatus],
+        }
+    }
+
+    /// Protocol of peers built before the version exchange, which advertised no
+    /// capabilities. Refused since the minimum version is above 1.
+    pub fn legacy() -> Self {
+        Self {
+            version: 1,
+            min_version: 1,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Negotiates the protocol with the peer, the highest version both ends speak
+    /// and capabilities both ends support.
+    pub fn negotiate(&self, peer: &TunnelProtocol) -> anyhow::Result<NegotiatedTunnelProtocol> {
+        anyhow::ensure!(
+            peer.version >= self.min_version && self.version >= peer.min_version,
+            "incompatible protocol version {} (supports {}-{}), this end supports {}-{}.",
+            peer.version,
+            peer.min_version,
+            peer.version,
+            self.min_version,
+            self.version,
+        );
+
+        Ok(NegotiatedTunnelProtocol {
+            version: self.version.min(peer.version),
+            capabilities: self
+                .capabilities
+                .iter()
+                .copied()
+                .filter(|capability| {
+                    *capability != TunnelCapability::Unknown
+                        && peer.capabilities.contains(capability)
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NegotiatedTunnelProtocol {
+    pub version: u16,
+    pub capabilities: Vec<TunnelCapability>,
+}
+
+impl NegotiatedTunnelProtocol {
+    pub fn supports(&self, capability: TunnelCapability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+impl std::fmt::Display for NegotiatedTunnelProtocol {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            formatter,
+            "v{} [{}]",
+            self.version,
+            self.capabilities.iter().join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol(
+        min_version: u16,
+        version: u16,
+        capabilities: &[TunnelCapability],
+    ) -> TunnelProtocol {
+        TunnelProtocol {
+            version,
+            min_version,
+            capabilities: capabilities.to_vec(),
+        }
+    }
+
+    #[test]
+    fn negotiates_highest_common_version() {
+        for (this, peer, expected) in [
+            ((3, 3), (3, 3), Some(3)),
+            ((3, 5), (2, 4), Some(4)),
+            ((2, 4), (3, 5), Some(4)),
+            ((3, 5), (5, 6), Some(5)),
+            ((3, 4), (5, 6), None),
+            ((5, 6), (3, 4), None),
+        ] {
+            let negotiation =
+                protocol(this.0, this.1, &[]).negotiate(&protocol(peer.0, peer.1, &[]));
+
+            assert_eq!(
+                negotiation.ok().map(|protocol| protocol.version),
+                expected,
+                "{this:?} with {peer:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn negotiates_common_capabilities() {
+        let this = protocol(
+            3,
+            3,
+            &[TunnelCapability::UdpRelay, TunnelCapability::ConnectStatus],
+        );
+
+        let peer = protocol(
+            3,
+            3,
+            &[TunnelCapability::ConnectStatus, TunnelCapability::Unknown],
+        );
+
+        let negotiated = this.negotiate(&peer).unwrap();
+
+        assert_eq!(negotiated.capabilities, [TunnelCapability::ConnectStatus]);
+        assert!(!negotiated.supports(TunnelCapability::UdpRelay));
+        assert_eq!(
+            peer.negotiate(&this).unwrap().capabilities,
+            [TunnelCapability::ConnectStatus]
+        );
+    }
+
+    #[test]
+    fn parses_unknown_capabilities() {
+        let peer: TunnelProtocol = serde_json::from_value(serde_json::json!({
+            "version": 4,
+            "min_version": 3,
+            "capabilities": ["udp-relay", "compression"],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            TunnelProtocol::current()
+                .negotiate(&peer)
+                .unwrap()
+                .capabilities,
+            [TunnelCapability::UdpRelay]
+        );
+    }
+
+    #[test]
+    fn refuses_legacy_peers() {
+        let legacy = TunnelProtocol::legacy();
+
+        assert!(legacy.capabilities.is_empty());
+        assert!(TunnelProtocol::current().negotiate(&legacy).is_err());
+
+        // A build still speaking v1 would fall back to no capabilities.
+        let negotiated = protocol(1, 3, &[TunnelCapability::UdpRelay])
+            .negotiate(&legacy)
+            .unwrap();
+
+        assert_eq!(negotiated.version, 1);
+        assert!(negotiated.capabilities.is_empty());
+    }
+}
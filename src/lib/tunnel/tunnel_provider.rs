@@ -1,4 +1,8 @@
-use crate::{match_server::MatchOutId, route::config::OutRuleConfig};
+use crate::{
+    bandwidth::BandwidthLimit,
+    match_server::{MatchOutId, MatchOutIdentity, OutLoad},
+    route::config::OutRuleConfig,
+};
 
 use super::{InTunnel, OutTunnel};
 
@@ -8,10 +12,20 @@ pub trait InTunnelProvider: Sync {
 
     async fn accept_out(&self) -> anyhow::Result<(MatchOutId, usize)>;
 
+    /// Latest load published by OUT, `None` if unknown.
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>>;
+
     async fn accept(
         &self,
         out_id: MatchOutId,
-    ) -> anyhow::Result<Option<(Box<dyn InTunnel>, (Vec<OutRuleConfig>, i64))>>;
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    >;
 }
 
 #[async_trait::async_trait]
@@ -0,0 +1,186 @@
+use std::{fs, io::Write as _, os::unix::fs::OpenOptionsExt as _, path::PathBuf};
+
+use rustls::pki_types::pem::PemObject as _;
+
+/// TLS settings of the side owning the certificate of a tunnel transport. The
+/// server name and ALPN protocols are advertised to the peer through the match
+/// server.
+#[derive(Clone)]
+pub struct TunnelTlsConfig {
+    pub server_name: String,
+    pub alpn_protocols: Vec<String>,
+    pub identity_paths: Option<TunnelTlsIdentityPaths>,
+}
+
+/// Cert and key PEM files of a tunnel transport.
+#[derive(Clone)]
+pub enum TunnelTlsIdentityPaths {
+    /// Files in the data dir, generated if missing or no longer valid for the
+    /// server name, and reused afterwards to keep a stable identity across
+    /// restarts.
+    Generated(PathBuf, PathBuf),
+    /// Files configured by the user, only ever read.
+    Configured(PathBuf, PathBuf),
+}
+
+impl TunnelTlsConfig {
+    pub fn load_or_generate_cert(&self) -> anyhow::Result<(String, String)> {
+        match &self.identity_paths {
+            Some(TunnelTlsIdentityPaths::Configured(cert_path, key_path)) => {
+                log::info!("loading TLS identity from {}...", cert_path.display());
+
+                let cert = fs::read_to_string(cert_path)?;
+
+                if !self.is_cert_valid_for_server_name(&cert)? {
+                    anyhow::bail!(
+                        "TLS identity {} not valid for {}.",
+                        cert_path.display(),
+                        self.server_name
+                    );
+                }
+
+                return Ok((cert, fs::read_to_string(key_path)?));
+            }
+            Some(TunnelTlsIdentityPaths::Generated(cert_path, key_path))
+                if cert_path.exists() && key_path.exists() =>
+            {
+                log::info!("loading TLS identity from {}...", cert_path.display());
+
+                let cert = fs::read_to_string(cert_path)?;
+
+                // The cert is regenerated if the server name has been changed
+                // since it was saved.
+                if self.is_cert_valid_for_server_name(&cert)? {
+                    return Ok((cert, fs::read_to_string(key_path)?));
+                }
+
+                log::warn!(
+                    "TLS identity {} not valid for {}, regenerating...",
+                    cert_path.display(),
+                    self.server_name
+                );
+            }
+            _ => {}
+        }
+
+        let cert = rcgen::generate_simple_self_signed([self.server_name.clone()])?;
+
+        let key = cert.key_pair.serialize_pem();
+        let cert = cert.cert.pem();
+
+        if let Some(TunnelTlsIdentityPaths::Generated(cert_path, key_path)) = &self.identity_paths {
+            log::info!("saving TLS identity to {}...", cert_path.display());
+
+            for path in [cert_path, key_path] {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+
+            fs::write(cert_path, &cert)?;
+
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(key_path)?
+                .write_all(key.as_bytes())?;
+        }
+
+        Ok((cert, key))
+    }
+
+    fn is_cert_valid_for_server_name(&self, cert_pem: &str) -> anyhow::Result<bool> {
+        let cert = rustls::pki_types::CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|_| anyhow::anyhow!("invalid cert."))?;
+
+        let cert = rustls::server::ParsedCertificate::try_from(&cert)?;
+
+        let server_name = rustls::pki_types::ServerName::try_from(self.server_name.as_str())?;
+
+        Ok(rustls::client::verify_server_name(&cert, &server_name).is_ok())
+    }
+}
+
+pub fn tls_name_default() -> String {
+    "localhost".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_tls_config(identity_paths: TunnelTlsIdentityPaths) -> TunnelTlsConfig {
+        TunnelTlsConfig {
+            server_name: "example.com".to_owned(),
+            alpn_protocols: Vec::new(),
+            identity_paths: Some(identity_paths),
+        }
+    }
+
+    fn new_identity_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("plug2proxy-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn keeps_configured_identity() {
+        let dir = new_identity_dir();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+
+        // Valid for "localhost" only.
+        let (cert, key) = TunnelTlsConfig {
+            server_name: tls_name_default(),
+            alpn_protocols: Vec::new(),
+            identity_paths: Some(TunnelTlsIdentityPaths::Generated(
+                cert_path.clone(),
+                key_path.clone(),
+            )),
+        }
+        .load_or_generate_cert()
+        .unwrap();
+
+        let tls_config = new_tls_config(TunnelTlsIdentityPaths::Configured(
+            cert_path.clone(),
+            key_path.clone(),
+        ));
+
+        assert!(tls_config.load_or_generate_cert().is_err());
+
+        assert_eq!(fs::read_to_string(&cert_path).unwrap(), cert);
+        assert_eq!(fs::read_to_string(&key_path).unwrap(), key);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn regenerates_generated_identity() {
+        let dir = new_identity_dir();
+        let identity_paths =
+            TunnelTlsIdentityPaths::Generated(dir.join("cert.pem"), dir.join("key.pem"));
+
+        let (cert, _) = TunnelTlsConfig {
+            server_name: tls_name_default(),
+            alpn_protocols: Vec::new(),
+            identity_paths: Some(identity_paths.clone()),
+        }
+        .load_or_generate_cert()
+        .unwrap();
+
+        let tls_config = new_tls_config(identity_paths);
+
+        let (regenerated_cert, _) = tls_config.load_or_generate_cert().unwrap();
+
+        assert!(regenerated_cert != cert);
+        assert!(tls_config
+            .is_cert_valid_for_server_name(&regenerated_cert)
+            .unwrap());
+        assert_eq!(
+            tls_config.load_or_generate_cert().unwrap().0,
+            regenerated_cert
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,24 +1,33 @@
 use std::{fmt, net::SocketAddr, sync::Arc};
 
-use super::{InTunnel, InTunnelLike};
+use crate::utils::io::AsyncWriteReset;
+
+use super::{direct_tunnel::DirectInTunnel, InTunnel, InTunnelLike, InUdpRelay};
 
 #[derive(derive_more::From)]
 pub enum AnyInTunnelLikeArc {
     InTunnel(Arc<Box<dyn InTunnel>>),
-    InTunnelLike(Arc<Box<dyn InTunnelLike>>),
+    Direct(Arc<DirectInTunnel>),
 }
 
 impl fmt::Display for AnyInTunnelLikeArc {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AnyInTunnelLikeArc::InTunnel(tunnel) => tunnel.fmt(formatter),
-            AnyInTunnelLikeArc::InTunnelLike(tunnel) => tunnel.fmt(formatter),
+            AnyInTunnelLikeArc::Direct(tunnel) => tunnel.fmt(formatter),
         }
     }
 }
 
 #[async_trait::async_trait]
 impl InTunnelLike for AnyInTunnelLikeArc {
+    fn name(&self) -> &'static str {
+        match self {
+            AnyInTunnelLikeArc::InTunnel(tunnel) => tunnel.name(),
+            AnyInTunnelLikeArc::Direct(tunnel) => tunnel.name(),
+        }
+    }
+
     async fn connect(
         &self,
         destination_address: SocketAddr,
@@ -27,7 +36,7 @@ impl InTunnelLike for AnyInTunnelLikeArc {
         sniff_buffer: Option<Vec<u8>>,
     ) -> anyhow::Result<(
         Box<dyn tokio::io::AsyncRead + Send + Unpin>,
-        Box<dyn tokio::io::AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
         tokio::sync::oneshot::Sender<()>,
     )> {
         match self {
@@ -36,11 +45,18 @@ impl InTunnelLike for AnyInTunnelLikeArc {
                     .connect(destination_address, destination_name, tag, sniff_buffer)
                     .await
             }
-            AnyInTunnelLikeArc::InTunnelLike(tunnel) => {
+            AnyInTunnelLikeArc::Direct(tunnel) => {
                 tunnel
                     .connect(destination_address, destination_name, tag, sniff_buffer)
                     .await
             }
         }
     }
+
+    fn udp_relay(&self) -> Option<Arc<dyn InUdpRelay>> {
+        match self {
+            AnyInTunnelLikeArc::InTunnel(tunnel) => tunnel.udp_relay(),
+            AnyInTunnelLikeArc::Direct(tunnel) => tunnel.udp_relay(),
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use std::{
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::{Buf as _, BufMut as _};
+
+use super::TunnelId;
+
+/// Identifies a UDP association relayed over a tunnel, assigned by IN.
+pub type UdpFlowId = u32;
+
+/// Flows without datagrams in either direction for this long are closed, on both
+/// ends.
+pub const UDP_FLOW_EXPIRATION: Duration = Duration::from_secs(60);
+
+pub type UdpFlowResponseSender = tokio::sync::mpsc::UnboundedSender<(bytes::Bytes, SocketAddr)>;
+
+/// Tunnel relaying datagrams and the tag of the matched rule, which selects the
+/// output on OUT as for streams.
+pub type UdpRelayRoute = (TunnelId, Arc<dyn InUdpRelay>, Option<String>);
+
+/// UDP relay provided by tunnels that are able to carry datagrams, OUT sends them
+/// through the output of the flow tag, from a UDP socket per flow.
+#[async_trait::async_trait]
+pub trait InUdpRelay: Send + Sync {
+    /// Opens a flow, datagrams OUT receives for it are delivered to
+    /// `response_sender` with their remote addresses.
+    fn open_flow(&self, tag: Option<String>, response_sender: UdpFlowResponseSender) -> UdpFlowId;
+
+    fn close_flow(&self, flow_id: UdpFlowId);
+
+    async fn send(
+        &self,
+        flow_id: UdpFlowId,
+        address: SocketAddr,
+        data: &[u8],
+    ) -> anyhow::Result<()>;
+
+    fn is_closed(&self) -> bool;
+}
+
+/// OUT end of a tunnel relaying datagrams, flows are handled by OUT the same way
+/// for all tunnels.
+#[async_trait::async_trait]
+pub trait OutUdpRelay: Send + Sync {
+    /// Receives the next datagram from IN, with its flow, flow tag and
+    /// destination address. Fails once the tunnel is closed.
+    async fn receive(
+        &self,
+    ) -> anyhow::Result<(UdpFlowId, Option<String>, SocketAddr, bytes::Bytes)>;
+
+    /// Sends a response datagram of the flow from `address` back to IN.
+    async fn send(
+        &self,
+        flow_id: UdpFlowId,
+        address: SocketAddr,
+        data: &[u8],
+    ) -> anyhow::Result<()>;
+}
+
+/// Packet layout: flow ID (u32), address and tag (same as in a tunnel stream
+/// head) and payload. Responses from OUT carry no tag.
+pub fn encode_udp_packet(
+    flow_id: UdpFlowId,
+    address: SocketAddr,
+    tag: Option<&str>,
+    data: &[u8],
+) -> Vec<u8> {
+    let tag = tag.unwrap_or_default().as_bytes();
+
+    let mut packet = Vec::with_capacity(4 + 1 + 16 + 2 + 1 + tag.len() + data.len());
+
+    packet.put_u32(flow_id);
+
+    match address {
+        SocketAddr::V4(address) => {
+            packet.put_u8(0b_0000_0000);
+            packet.put_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => {
+            packet.put_u8(0b_1000_0000);
+            packet.put_slice(&address.ip().octets());
+        }
+    }
+
+    packet.put_u16(address.port());
+    packet.put_u8(tag.len().try_into().unwrap_or(u8::MAX));
+    packet.put_slice(&tag[..tag.len().min(u8::MAX as usize)]);
+    packet.put_slice(data);
+
+    packet
+}
+
+pub fn decode_udp_packet(
+    mut packet: bytes::Bytes,
+) -> anyhow::Result<(UdpFlowId, SocketAddr, Option<String>, bytes::Bytes)> {
+    anyhow::ensure!(packet.remaining() >= 5, "truncated UDP packet.");
+
+    let flow_id = packet.get_u32();
+
+    let address = match packet.get_u8() & 0b_1000_0000 {
+        0 => {
+            anyhow::ensure!(packet.remaining() >= 6, "truncated UDP packet.");
+
+            SocketAddr::V4(SocketAddrV4::new(packet.get_u32().into(), packet.get_u16()))
+        }
+        _ => {
+            anyhow::ensure!(packet.remaining() >= 18, "truncated UDP packet.");
+
+            SocketAddr::V6(SocketAddrV6::new(
+                packet.get_u128().into(),
+                packet.get_u16(),
+                0,
+                0,
+            ))
+        }
+    };
+
+    anyhow::ensure!(packet.remaining() >= 1, "truncated UDP packet.");
+
+    let tag_length = packet.get_u8() as usize;
+
+    anyhow::ensure!(packet.remaining() >= tag_length, "truncated UDP packet.");
+
+    let tag = match tag_length {
+        0 => None,
+        _ => Some(String::from_utf8(packet.split_to(tag_length).to_vec())?),
+    };
+
+    Ok((flow_id, address, tag, packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_encoded_packets() {
+        for (address, tag) in [
+            ("1.2.3.4:53".parse().unwrap(), None),
+            ("[2001:db8::1]:443".parse().unwrap(), Some("proxy")),
+        ] {
+            let packet = encode_udp_packet(7, address, tag, b"data");
+
+            let (flow_id, decoded_address, decoded_tag, data) =
+                decode_udp_packet(packet.into()).unwrap();
+
+            assert_eq!(flow_id, 7);
+            assert_eq!(decoded_address, address);
+            assert_eq!(decoded_tag.as_deref(), tag);
+            assert_eq!(&data[..], b"data");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let packet = encode_udp_packet(1, "1.2.3.4:53".parse().unwrap(), Some("proxy"), b"");
+
+        for length in [0, 4, 10, 12] {
+            assert!(decode_udp_packet(bytes::Bytes::copy_from_slice(&packet[..length])).is_err());
+        }
+    }
+}
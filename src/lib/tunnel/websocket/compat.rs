@@ -0,0 +1,108 @@
+use std::{
+    cmp::min,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Buf;
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// Byte stream over binary WebSocket messages.
+pub struct WebSocketByteStream<TStream> {
+    inner: WebSocketStream<TStream>,
+    pending: bytes::Bytes,
+}
+
+impl<TStream> WebSocketByteStream<TStream> {
+    pub fn new(inner: WebSocketStream<TStream>) -> Self {
+        Self {
+            inner,
+            pending: bytes::Bytes::new(),
+        }
+    }
+}
+
+impl<TStream> AsyncRead for WebSocketByteStream<TStream>
+where
+    TStream: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.pending.is_empty() {
+                let length = min(this.pending.len(), buffer.remaining());
+
+                buffer.put_slice(&this.pending[..length]);
+
+                this.pending.advance(length);
+
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_next(context)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.pending = data.into();
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(error)) => {
+                    return Poll::Ready(Err(web_socket_error_to_io_error(error)));
+                }
+            }
+        }
+    }
+}
+
+impl<TStream> AsyncWrite for WebSocketByteStream<TStream>
+where
+    TStream: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if buffer.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let this = self.get_mut();
+
+        ready!(Pin::new(&mut this.inner).poll_ready(context))
+            .map_err(web_socket_error_to_io_error)?;
+
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(buffer.to_vec()))
+            .map_err(web_socket_error_to_io_error)?;
+
+        Poll::Ready(Ok(buffer.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(context)
+            .map_err(web_socket_error_to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(context)
+            .map_err(web_socket_error_to_io_error)
+    }
+}
+
+fn web_socket_error_to_io_error(error: tokio_tungstenite::tungstenite::Error) -> std::io::Error {
+    match error {
+        tokio_tungstenite::tungstenite::Error::Io(error) => error,
+        error => std::io::Error::other(error.to_string()),
+    }
+}
@@ -0,0 +1,28 @@
+use crate::match_server::{MatchOutId, MatchPair};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct WebSocketInData {
+    /// Random key (base64) IN authenticates its WebSocket connection with, so
+    /// that only the matched IN can claim the tunnel on OUT.
+    pub key: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct WebSocketOutData {
+    pub url: String,
+    pub sni: Option<String>,
+}
+
+impl MatchPair<WebSocketInData, WebSocketOutData> for (WebSocketInData, WebSocketOutData) {
+    fn get_match_name() -> &'static str {
+        "websocket"
+    }
+
+    fn get_redis_out_key(out_id: &MatchOutId) -> String {
+        format!("websocket:out:{}", out_id)
+    }
+
+    fn get_redis_in_announcement_channel_name(out_id: &MatchOutId) -> String {
+        format!("websocket:in:out:{}", out_id)
+    }
+}
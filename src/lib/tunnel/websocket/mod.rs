@@ -0,0 +1,8 @@
+mod compat;
+mod match_pair;
+mod websocket_tunnel;
+mod websocket_tunnel_provider;
+mod yamux;
+
+pub use websocket_tunnel::*;
+pub use websocket_tunnel_provider::*;
@@ -0,0 +1,61 @@
+use tokio::io::AsyncRead;
+
+use crate::{
+    tunnel::byte_stream_tunnel::{ByteStreamInTunnelConnection, ByteStreamOutTunnelConnection},
+    utils::io::AsyncWriteReset,
+};
+
+use super::yamux::YamuxConnection;
+
+pub struct WebSocketInTunnelConnection {
+    connection: YamuxConnection,
+}
+
+impl WebSocketInTunnelConnection {
+    pub fn new(connection: YamuxConnection) -> Self {
+        WebSocketInTunnelConnection { connection }
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteStreamInTunnelConnection for WebSocketInTunnelConnection {
+    async fn open(
+        &self,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+    )> {
+        self.connection.open().await
+    }
+
+    async fn closed(&self) {
+        self.connection.closed().await;
+    }
+
+    fn is_closed(&self) -> bool {
+        self.connection.is_closed()
+    }
+}
+
+pub struct WebSocketOutTunnelConnection {
+    connection: YamuxConnection,
+}
+
+impl WebSocketOutTunnelConnection {
+    pub fn new(connection: YamuxConnection) -> Self {
+        WebSocketOutTunnelConnection { connection }
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteStreamOutTunnelConnection for WebSocketOutTunnelConnection {
+    async fn accept(
+        &self,
+    ) -> anyhow::Result<(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWriteReset>)> {
+        self.connection.accept().await
+    }
+
+    fn is_closed(&self) -> bool {
+        self.connection.is_closed()
+    }
+}
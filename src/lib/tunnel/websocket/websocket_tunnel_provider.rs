@@ -0,0 +1,516 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
+
+use base64::Engine as _;
+use lits::duration;
+use ring::rand::SecureRandom as _;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest as _,
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+};
+
+use crate::{
+    bandwidth::BandwidthLimit,
+    match_server::{
+        AnyInMatchServer, InMatchServer as _, MatchIn, MatchOut, MatchOutId, MatchOutIdentity,
+        OutLoad, OutMatchServer, OutMatchServerTrait as _,
+    },
+    r#in::dns_resolver::convert_to_socket_addresses,
+    route::config::OutRuleConfig,
+    tunnel::{
+        byte_stream_tunnel::{ByteStreamInTunnel, ByteStreamOutTunnel},
+        common::create_rustls_client_config_with_native_roots,
+        tunnel_provider::{InTunnelProvider, OutTunnelProvider},
+        InTunnel, OutTunnel, TunnelId, TunnelProtocol,
+    },
+    utils::net::{bind_tcp_listener_reuseaddr, socket::set_keepalive_options},
+};
+
+use super::{
+    compat::WebSocketByteStream,
+    match_pair::{WebSocketInData, WebSocketOutData},
+    yamux::YamuxConnection,
+    WebSocketInTunnelConnection, WebSocketOutTunnelConnection,
+};
+
+const TUNNEL_NAME: &str = "websocket";
+
+const TUNNEL_ID_HEADER: &str = "X-Tunnel-Id";
+const TUNNEL_AUTH_HEADER: &str = "X-Tunnel-Auth";
+
+const TUNNEL_AUTH_CONTEXT: &str = "plug2proxy websocket tunnel";
+
+pub struct WebSocketInTunnelConfig {
+    pub connections: usize,
+    pub priority: Option<i64>,
+    pub priority_default: i64,
+    pub stream_pool_size: usize,
+    pub dns_resolver: Arc<hickory_resolver::TokioAsyncResolver>,
+    pub traffic_mark: u32,
+}
+
+pub struct WebSocketInTunnelProvider {
+    match_server: Arc<AnyInMatchServer>,
+    tls_client_config: Arc<rustls::ClientConfig>,
+    config: WebSocketInTunnelConfig,
+}
+
+impl WebSocketInTunnelProvider {
+    pub fn new(
+        match_server: Arc<AnyInMatchServer>,
+        config: WebSocketInTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let tls_client_config = {
+            let mut client_config = create_rustls_client_config_with_native_roots()?;
+
+            client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+            Arc::new(client_config)
+        };
+
+        Ok(Self {
+            match_server,
+            tls_client_config,
+            config,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl InTunnelProvider for WebSocketInTunnelProvider {
+    fn name(&self) -> &'static str {
+        TUNNEL_NAME
+    }
+
+    async fn accept_out(&self) -> anyhow::Result<(MatchOutId, usize)> {
+        self.match_server
+            .accept_out::<WebSocketInData, WebSocketOutData>()
+            .await
+            .map(|out_id| (out_id, self.config.connections))
+    }
+
+    async fn get_out_load(&self, out_id: MatchOutId) -> anyhow::Result<Option<OutLoad>> {
+        self.match_server.get_out_load(out_id).await
+    }
+
+    async fn accept(
+        &self,
+        out_id: MatchOutId,
+    ) -> anyhow::Result<
+        Option<(
+            Box<dyn InTunnel>,
+            (Vec<OutRuleConfig>, i64),
+            BandwidthLimit,
+            Option<MatchOutIdentity>,
+        )>,
+    > {
+        let key = {
+            let mut key = [0; 32];
+
+            ring::rand::SystemRandom::new()
+                .fill(&mut key)
+                .map_err(|_| anyhow::anyhow!("failed to generate websocket tunnel key."))?;
+
+            key
+        };
+
+        let Some(MatchOut {
+            id,
+            tunnel_id,
+            tunnel_labels,
+            tunnel_priority,
+            routing_priority,
+            routing_rules,
+            bandwidth_limit,
+            protocol,
+            identity,
+            data: WebSocketOutData { url, sni },
+        }) = self
+            .match_server
+            .match_out(
+                out_id,
+                WebSocketInData {
+                    key: base64::engine::general_purpose::STANDARD.encode(key),
+                },
+            )
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let url = url::Url::parse(&url)?;
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("missing host in websocket url {url}."))?;
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow::anyhow!("missing port in websocket url {url}."))?;
+
+        let address =
+            convert_to_socket_addresses(format!("{host}:{port}"), &self.config.dns_resolver, None)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("failed to resolve websocket host {host}."))?;
+
+        let socket = match address {
+            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
+            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
+        }?;
+
+        nix::sys::socket::setsockopt(
+            &socket,
+            nix::sys::socket::sockopt::Mark,
+            &self.config.traffic_mark,
+        )?;
+
+        socket.set_nodelay(true)?;
+
+        set_keepalive_options(&socket, 5, 5, 3)?;
+
+        let stream = socket.connect(address).await?;
+
+        log::info!("websocket tunnel {tunnel_id} underlying TCP connected.");
+
+        let request = {
+            let mut request = url.as_str().into_client_request()?;
+
+            request
+                .headers_mut()
+                .insert(TUNNEL_ID_HEADER, tunnel_id.to_string().parse()?);
+
+            request.headers_mut().insert(
+                TUNNEL_AUTH_HEADER,
+                base64::engine::general_purpose::STANDARD
+                    .encode(sign_tunnel_id(&key, tunnel_id))
+                    .parse()?,
+            );
+
+            request
+        };
+
+        let connection = match url.scheme() {
+            "wss" => {
+                let tls_connector =
+                    tokio_rustls::TlsConnector::from(self.tls_client_config.clone());
+
+                let server_name = sni.unwrap_or_else(|| host.to_owned());
+
+                let stream = tls_connector
+                    .connect(server_name.try_into()?, stream)
+                    .await?;
+
+                log::debug!("websocket tunnel {tunnel_id} underlying TLS connection established.");
+
+                let (stream, _) = tokio_tungstenite::client_async(request, stream).await?;
+
+                YamuxConnection::new(WebSocketByteStream::new(stream), yamux::Mode::Client)
+            }
+            "ws" => {
+                let (stream, _) = tokio_tungstenite::client_async(request, stream).await?;
+
+                YamuxConnection::new(WebSocketByteStream::new(stream), yamux::Mode::Client)
+            }
+            scheme => anyhow::bail!("unsupported websocket url scheme {scheme}."),
+        };
+
+        let tunnel = ByteStreamInTunnel::new(
+            TUNNEL_NAME,
+            tunnel_id,
+            id,
+            tunnel_labels,
+            self.config
+                .priority
+                .unwrap_or(tunnel_priority.unwrap_or(self.config.priority_default)),
+            protocol,
+            WebSocketInTunnelConnection::new(connection),
+            self.config.stream_pool_size,
+        );
+
+        log::info!("tunnel {tunnel} established.");
+
+        Ok(Some((
+            Box::new(tunnel),
+            (routing_rules, routing_priority),
+            bandwidth_limit,
+            identity,
+        )))
+    }
+}
+
+pub struct WebSocketOutTunnelConfig {
+    pub listen_address: SocketAddr,
+    pub url: String,
+    pub sni: Option<String>,
+    pub priority: Option<i64>,
+    pub routing_rules: Vec<OutRuleConfig>,
+    pub routing_priority: i64,
+}
+
+struct PendingStream {
+    accepted_at: Instant,
+    /// Authentication claimed by the connection, verified with the key of the
+    /// matched IN once the tunnel is claimed.
+    auth: Vec<u8>,
+    stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+}
+
+/// Connections by the tunnel they claim, several of them kept so that one with
+/// a forged tunnel id does not take the place of the genuine one.
+type PendingStreamMap = HashMap<TunnelId, Vec<PendingStream>>;
+
+pub struct WebSocketOutTunnelProvider {
+    match_server: Arc<OutMatchServer>,
+    config: WebSocketOutTunnelConfig,
+    pending_stream_map: Arc<tokio::sync::Mutex<PendingStreamMap>>,
+    /// Notified whenever a connection is added to `pending_stream_map`.
+    pending_stream_notify: Arc<tokio::sync::Notify>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl WebSocketOutTunnelProvider {
+    pub fn new(
+        match_server: Arc<OutMatchServer>,
+        config: WebSocketOutTunnelConfig,
+    ) -> anyhow::Result<Self> {
+        let listener = bind_tcp_listener_reuseaddr(config.listen_address)?;
+
+        log::info!(
+            "websocket tunnel listening on {} for {}...",
+            config.listen_address,
+            config.url
+        );
+
+        let pending_stream_map = Arc::new(tokio::sync::Mutex::new(PendingStreamMap::new()));
+        let pending_stream_notify = Arc::new(tokio::sync::Notify::new());
+
+        let handle = tokio::spawn({
+            let pending_stream_map = pending_stream_map.clone();
+            let pending_stream_notify = pending_stream_notify.clone();
+
+            async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(error) => {
+                            log::error!("error accepting websocket socket: {:?}", error);
+                            tokio::time::sleep(duration!("1s")).await;
+                            continue;
+                        }
+                    };
+
+                    tokio::spawn({
+                        let pending_stream_map = pending_stream_map.clone();
+                        let pending_stream_notify = pending_stream_notify.clone();
+
+                        async move {
+                            stream.set_nodelay(true).ok();
+
+                            set_keepalive_options(&stream, 5, 5, 3).ok();
+
+                            let (tunnel_id, auth, stream) = match tokio::time::timeout(
+                                duration!("5s"),
+                                accept_web_socket_stream(stream),
+                            )
+                            .await
+                            {
+                                Ok(Ok(tuple)) => tuple,
+                                Ok(Err(error)) => {
+                                    log::error!("error accepting websocket stream: {:?}", error);
+                                    return;
+                                }
+                                Err(_) => {
+                                    log::error!("websocket handshake timed out.");
+                                    return;
+                                }
+                            };
+
+                            let mut pending_stream_map = pending_stream_map.lock().await;
+
+                            pending_stream_map.retain(|_, streams| {
+                                streams.retain(|stream| {
+                                    stream.accepted_at.elapsed() < duration!("10s")
+                                });
+
+                                !streams.is_empty()
+                            });
+
+                            pending_stream_map
+                                .entry(tunnel_id)
+                                .or_default()
+                                .push(PendingStream {
+                                    accepted_at: Instant::now(),
+                                    auth,
+                                    stream,
+                                });
+
+                            pending_stream_notify.notify_waiters();
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(Self {
+            match_server,
+            config,
+            pending_stream_map,
+            pending_stream_notify,
+            handle,
+        })
+    }
+
+    async fn wait_for_pending_stream(
+        &self,
+        tunnel_id: TunnelId,
+        key: &[u8],
+    ) -> tokio_tungstenite::WebSocketStream<tokio::net::TcpStream> {
+        loop {
+            let notified = self.pending_stream_notify.notified();
+
+            tokio::pin!(notified);
+
+            // Enabled before looking for the connection, so that one added in
+            // between is not missed.
+            notified.as_mut().enable();
+
+            let streams = self.pending_stream_map.lock().await.remove(&tunnel_id);
+
+            for PendingStream { auth, stream, .. } in streams.into_iter().flatten() {
+                if verify_tunnel_id(key, tunnel_id, &auth) {
+                    return stream;
+                }
+
+                log::warn!(
+                    "dropped websocket connection with invalid auth for tunnel {tunnel_id}."
+                );
+            }
+
+            notified.await;
+        }
+    }
+}
+
+impl Drop for WebSocketOutTunnelProvider {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[async_trait::async_trait]
+impl OutTunnelProvider for WebSocketOutTunnelProvider {
+    async fn accept(&self) -> anyhow::Result<Box<dyn OutTunnel>> {
+        let MatchIn {
+            id: _,
+            tunnel_id,
+            protocol,
+            data: WebSocketInData { key },
+        } = self
+            .match_server
+            .match_in(
+                WebSocketOutData {
+                    url: self.config.url.clone(),
+                    sni: self.config.sni.clone(),
+                },
+                self.config.priority,
+                &self.config.routing_rules,
+                self.config.routing_priority,
+            )
+            .await?;
+
+        let protocol = TunnelProtocol::current().negotiate(&protocol)?;
+
+        let key = base64::engine::general_purpose::STANDARD.decode(key)?;
+
+        let stream = tokio::time::timeout(
+            duration!("10s"),
+            self.wait_for_pending_stream(tunnel_id, &key),
+        )
+        .await?;
+
+        let connection =
+            YamuxConnection::new(WebSocketByteStream::new(stream), yamux::Mode::Server);
+
+        let tunnel = ByteStreamOutTunnel::new(
+            TUNNEL_NAME,
+            tunnel_id,
+            protocol,
+            WebSocketOutTunnelConnection::new(connection),
+        );
+
+        log::info!("tunnel {tunnel} established.");
+
+        Ok(Box::new(tunnel))
+    }
+}
+
+async fn accept_web_socket_stream(
+    stream: tokio::net::TcpStream,
+) -> anyhow::Result<(
+    TunnelId,
+    Vec<u8>,
+    tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+)> {
+    let mut headers = TunnelHeaders::default();
+
+    let stream = tokio_tungstenite::accept_hdr_async(stream, &mut headers).await?;
+
+    let tunnel_id = headers
+        .tunnel_id
+        .ok_or_else(|| anyhow::anyhow!("missing {TUNNEL_ID_HEADER} header."))?;
+
+    let auth = headers
+        .auth
+        .ok_or_else(|| anyhow::anyhow!("missing {TUNNEL_AUTH_HEADER} header."))?;
+
+    Ok((tunnel_id, auth, stream))
+}
+
+/// Tunnel headers of a WebSocket handshake, read by implementing `Callback` as
+/// its error response type is fixed by tungstenite.
+#[derive(Default)]
+struct TunnelHeaders {
+    tunnel_id: Option<TunnelId>,
+    auth: Option<Vec<u8>>,
+}
+
+impl Callback for &mut TunnelHeaders {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let get_header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+
+        self.tunnel_id = get_header(TUNNEL_ID_HEADER)
+            .and_then(|value| value.parse::<uuid::Uuid>().ok())
+            .map(TunnelId);
+
+        self.auth = get_header(TUNNEL_AUTH_HEADER)
+            .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value).ok());
+
+        Ok(response)
+    }
+}
+
+fn sign_tunnel_id(key: &[u8], tunnel_id: TunnelId) -> Vec<u8> {
+    ring::hmac::sign(
+        &ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key),
+        format!("{TUNNEL_AUTH_CONTEXT}:{tunnel_id}").as_bytes(),
+    )
+    .as_ref()
+    .to_vec()
+}
+
+fn verify_tunnel_id(key: &[u8], tunnel_id: TunnelId, auth: &[u8]) -> bool {
+    ring::hmac::verify(
+        &ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key),
+        format!("{TUNNEL_AUTH_CONTEXT}:{tunnel_id}").as_bytes(),
+        auth,
+    )
+    .is_ok()
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt as _, TokioAsyncReadCompatExt as _};
+
+use crate::utils::io::AsyncWriteReset;
+
+type YamuxStreamResult = Result<yamux::Stream, yamux::ConnectionError>;
+
+/// Drives a yamux connection in background and hands out its streams as tokio
+/// read/write halves.
+pub struct YamuxConnection {
+    open_sender:
+        tokio::sync::mpsc::UnboundedSender<tokio::sync::oneshot::Sender<YamuxStreamResult>>,
+    inbound_receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<yamux::Stream>>,
+    closed: Arc<AtomicBool>,
+    closed_notify: Arc<tokio::sync::Notify>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl YamuxConnection {
+    pub fn new<TStream>(stream: TStream, mode: yamux::Mode) -> Self
+    where
+        TStream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut config = yamux::Config::default();
+
+        config.set_max_num_streams(1024);
+
+        let mut connection = yamux::Connection::new(stream.compat(), config, mode);
+
+        let (open_sender, mut open_receiver) =
+            tokio::sync::mpsc::unbounded_channel::<tokio::sync::oneshot::Sender<_>>();
+        let (inbound_sender, inbound_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_notify = Arc::new(tokio::sync::Notify::new());
+
+        let handle = tokio::spawn({
+            let closed = closed.clone();
+            let closed_notify = closed_notify.clone();
+
+            async move {
+                let mut pending_opens = VecDeque::new();
+
+                let result = futures::future::poll_fn(|context| loop {
+                    while let Poll::Ready(Some(reply_sender)) = open_receiver.poll_recv(context) {
+                        pending_opens.push_back(reply_sender);
+                    }
+
+                    if !pending_opens.is_empty() {
+                        if let Poll::Ready(result) = connection.poll_new_outbound(context) {
+                            let reply_sender: tokio::sync::oneshot::Sender<_> =
+                                pending_opens.pop_front().unwrap();
+
+                            reply_sender.send(result).ok();
+
+                            continue;
+                        }
+                    }
+
+                    match connection.poll_next_inbound(context) {
+                        Poll::Ready(Some(Ok(stream))) => {
+                            inbound_sender.send(stream).ok();
+                        }
+                        Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(error)),
+                        Poll::Ready(None) => return Poll::Ready(Ok(())),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                })
+                .await;
+
+                if let Err(error) = result {
+                    log::debug!("yamux connection errored: {error}");
+                }
+
+                closed.store(true, atomic::Ordering::Relaxed);
+                closed_notify.notify_waiters();
+            }
+        });
+
+        Self {
+            open_sender,
+            inbound_receiver: tokio::sync::Mutex::new(inbound_receiver),
+            closed,
+            closed_notify,
+            handle,
+        }
+    }
+
+    pub async fn open(
+        &self,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+    )> {
+        let (reply_sender, reply_receiver) = tokio::sync::oneshot::channel();
+
+        self.open_sender
+            .send(reply_sender)
+            .map_err(|_| anyhow::anyhow!("yamux connection closed."))?;
+
+        let stream = reply_receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("yamux connection closed."))??;
+
+        Ok(split_stream(stream))
+    }
+
+    pub async fn accept(
+        &self,
+    ) -> anyhow::Result<(
+        Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWriteReset>,
+    )> {
+        let stream = self
+            .inbound_receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("yamux connection closed."))?;
+
+        Ok(split_stream(stream))
+    }
+
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.closed_notify.notified();
+
+            if self.is_closed() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(atomic::Ordering::Relaxed)
+    }
+}
+
+impl Drop for YamuxConnection {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+type SharedYamuxStream = Arc<std::sync::Mutex<Option<Compat<yamux::Stream>>>>;
+
+/// Read half of a yamux stream, failing once the write half is reset.
+struct YamuxReadHalf {
+    stream: SharedYamuxStream,
+}
+
+impl tokio::io::AsyncRead for YamuxReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => Pin::new(stream).poll_read(context, buffer),
+            None => Poll::Ready(Err(tokio::io::ErrorKind::ConnectionReset.into())),
+        }
+    }
+}
+
+/// Write half of a yamux stream. Yamux has no explicit reset, but dropping a
+/// stream that is open in both directions sends a RST, so resetting drops the
+/// stream shared with the read half. A stream the peer has already ended is
+/// ended instead.
+struct YamuxWriteHalf {
+    stream: SharedYamuxStream,
+}
+
+impl YamuxWriteHalf {
+    fn poll_with<T>(
+        &self,
+        poll: impl FnOnce(Pin<&mut Compat<yamux::Stream>>) -> Poll<Result<T, tokio::io::Error>>,
+    ) -> Poll<Result<T, tokio::io::Error>> {
+        match self.stream.lock().unwrap().as_mut() {
+            Some(stream) => poll(Pin::new(stream)),
+            None => Poll::Ready(Err(tokio::io::ErrorKind::NotConnected.into())),
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for YamuxWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<Result<usize, tokio::io::Error>> {
+        self.poll_with(|stream| stream.poll_write(context, buffer))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        self.poll_with(|stream| stream.poll_flush(context))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        self.poll_with(|stream| stream.poll_shutdown(context))
+    }
+}
+
+impl AsyncWriteReset for YamuxWriteHalf {
+    fn reset(&mut self) {
+        self.stream.lock().unwrap().take();
+    }
+}
+
+fn split_stream(
+    stream: yamux::Stream,
+) -> (
+    Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+    Box<dyn AsyncWriteReset>,
+) {
+    let stream = Arc::new(std::sync::Mutex::new(Some(stream.compat())));
+
+    (
+        Box::new(YamuxReadHalf {
+            stream: stream.clone(),
+        }),
+        Box::new(YamuxWriteHalf { stream }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::*;
+
+    async fn open_stream_pair() -> (
+        (YamuxConnection, YamuxConnection),
+        (
+            Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+            Box<dyn AsyncWriteReset>,
+        ),
+        (
+            Box<dyn tokio::io::AsyncRead + Send + Unpin>,
+            Box<dyn AsyncWriteReset>,
+        ),
+    ) {
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+
+        let client = YamuxConnection::new(client_stream, yamux::Mode::Client);
+        let server = YamuxConnection::new(server_stream, yamux::Mode::Server);
+
+        let (read_stream, mut write_stream) = client.open().await.unwrap();
+
+        // Streams are announced with their first frame.
+        write_stream.write_all(b"open").await.unwrap();
+
+        let (mut peer_read_stream, peer_write_stream) = server.accept().await.unwrap();
+
+        let mut buffer = [0; 4];
+        peer_read_stream.read_exact(&mut buffer).await.unwrap();
+
+        (
+            (client, server),
+            (read_stream, write_stream),
+            (peer_read_stream, peer_write_stream),
+        )
+    }
+
+    #[tokio::test]
+    async fn resets_stream() {
+        let (
+            _connections,
+            (mut read_stream, mut write_stream),
+            (mut peer_read_stream, mut peer_write_stream),
+        ) = open_stream_pair().await;
+
+        write_stream.reset();
+
+        assert!(write_stream.write_all(b"data").await.is_err());
+        assert!(read_stream.read(&mut [0; 4]).await.is_err());
+
+        // The peer reads an end, but can no longer write.
+        assert_eq!(peer_read_stream.read(&mut [0; 4]).await.unwrap(), 0);
+        assert!(peer_write_stream.write_all(b"data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ends_stream() {
+        let (
+            _connections,
+            (_read_stream, mut write_stream),
+            (mut peer_read_stream, mut peer_write_stream),
+        ) = open_stream_pair().await;
+
+        write_stream.shutdown().await.unwrap();
+
+        assert_eq!(peer_read_stream.read(&mut [0; 4]).await.unwrap(), 0);
+        peer_write_stream.write_all(b"data").await.unwrap();
+    }
+}
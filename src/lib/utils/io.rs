@@ -1,58 +1,752 @@
-use std::time::Instant;
+use std::{
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+    pin::Pin,
+    sync::atomic::{self, AtomicBool, AtomicU64},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 
-use tokio::io::AsyncWriteExt as _;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, Interest};
 
+use crate::bandwidth::{BandwidthLimiter, BandwidthLimiters};
+
+/// Chunk size of limited copies, small enough for limiters to interleave
+/// concurrent streams.
+const LIMITED_COPY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Bytes moved by a single splice, the default pipe capacity.
+const SPLICE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Write half of a relayed stream that can be reset, so that the peer sees the
+/// stream aborted rather than ended.
+pub trait AsyncWriteReset: tokio::io::AsyncWrite + Send + Unpin {
+    /// Aborts the stream. Writing afterwards is not expected, and the reset may
+    /// only be sent once the stream is dropped.
+    fn reset(&mut self);
+}
+
+impl<T: AsyncWriteReset + ?Sized> AsyncWriteReset for Box<T> {
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+}
+
+impl<T: AsyncWriteReset + ?Sized> AsyncWriteReset for &mut T {
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+}
+
+/// Write half of a TCP stream, reset with `SO_LINGER` 0.
+pub struct TcpWriteHalf {
+    inner: Option<tokio::net::tcp::OwnedWriteHalf>,
+}
+
+impl TcpWriteHalf {
+    pub fn new(inner: tokio::net::tcp::OwnedWriteHalf) -> Self {
+        Self { inner: Some(inner) }
+    }
+
+    fn inner(&mut self) -> Result<Pin<&mut tokio::net::tcp::OwnedWriteHalf>, tokio::io::Error> {
+        self.inner
+            .as_mut()
+            .map(Pin::new)
+            .ok_or_else(|| tokio::io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl tokio::io::AsyncWrite for TcpWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<Result<usize, tokio::io::Error>> {
+        self.get_mut().inner()?.poll_write(context, buffer)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        self.get_mut().inner()?.poll_flush(context)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        self.get_mut().inner()?.poll_shutdown(context)
+    }
+}
+
+impl AsyncWriteReset for TcpWriteHalf {
+    fn reset(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            let _ = socket2::SockRef::from(inner.as_ref()).set_linger(Some(Duration::ZERO));
+
+            // Otherwise dropping the half shuts down writing, and the peer sees a FIN
+            // before the RST sent on close.
+            inner.forget();
+        }
+    }
+}
+
+/// Timeouts of relayed streams, `None` for no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamTimeoutConfig {
+    /// Closes a stream without data in either direction for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Closes a stream without upload data for this long, unless upload ended.
+    pub upload_idle_timeout: Option<Duration>,
+    /// Closes a stream without download data for this long, unless download
+    /// ended.
+    pub download_idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Upload = 0,
+    Download = 1,
+}
+
+/// Data and ends of both directions of a stream, for enforcing timeouts.
+struct StreamActivity {
+    started_at: Instant,
+    /// Milliseconds since start of the last data or end of each direction.
+    last_active_at: [AtomicU64; 2],
+    ended: [AtomicBool; 2],
+    ended_notify: tokio::sync::Notify,
+}
+
+impl StreamActivity {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            last_active_at: Default::default(),
+            ended: Default::default(),
+            ended_notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn touch(&self, direction: Direction) {
+        self.last_active_at[direction as usize].store(
+            self.started_at.elapsed().as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    fn end(&self, direction: Direction) {
+        self.touch(direction);
+
+        self.ended[direction as usize].store(true, atomic::Ordering::Relaxed);
+        self.ended_notify.notify_waiters();
+    }
+
+    fn last_active_at(&self, direction: Direction) -> Duration {
+        Duration::from_millis(
+            self.last_active_at[direction as usize].load(atomic::Ordering::Relaxed),
+        )
+    }
+
+    fn is_ended(&self, direction: Direction) -> bool {
+        self.ended[direction as usize].load(atomic::Ordering::Relaxed)
+    }
+
+    /// Resolves with an error once any of the timeouts is exceeded.
+    async fn timed_out(&self, timeouts: &StreamTimeoutConfig) -> tokio::io::Error {
+        loop {
+            let ended_notified = self.ended_notify.notified();
+
+            let upload_active_at = self.last_active_at(Direction::Upload);
+            let download_active_at = self.last_active_at(Direction::Download);
+
+            let deadlines = [
+                timeouts
+                    .max_lifetime
+                    .map(|timeout| (timeout, "max lifetime")),
+                timeouts.idle_timeout.map(|timeout| {
+                    (
+                        upload_active_at.max(download_active_at) + timeout,
+                        "idle timeout",
+                    )
+                }),
+                timeouts
+                    .upload_idle_timeout
+                    .filter(|_| !self.is_ended(Direction::Upload))
+                    .map(|timeout| (upload_active_at + timeout, "upload idle timeout")),
+                timeouts
+                    .download_idle_timeout
+                    .filter(|_| !self.is_ended(Direction::Download))
+                    .map(|timeout| (download_active_at + timeout, "download idle timeout")),
+            ];
+
+            let deadline = deadlines
+                .into_iter()
+                .flatten()
+                .min_by_key(|(deadline, _)| *deadline);
+
+            match deadline {
+                Some((deadline, reason)) => {
+                    if self.started_at.elapsed() >= deadline {
+                        return tokio::io::Error::new(
+                            tokio::io::ErrorKind::TimedOut,
+                            format!("{reason} exceeded."),
+                        );
+                    }
+
+                    tokio::select! {
+                        _ = tokio::time::sleep_until((self.started_at + deadline).into()) => {}
+                        _ = ended_notified => {}
+                    }
+                }
+                None => ended_notified.await,
+            }
+        }
+    }
+}
+
+/// Reader marking the activity of a direction on data.
+struct ActivityRead<'a, TRead> {
+    inner: TRead,
+    activity: &'a StreamActivity,
+    direction: Direction,
+}
+
+impl<TRead: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for ActivityRead<'_, TRead> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<(), tokio::io::Error>> {
+        let this = self.get_mut();
+
+        let filled = buffer.filled().len();
+
+        let poll = Pin::new(&mut this.inner).poll_read(context, buffer);
+
+        if buffer.filled().len() > filled {
+            this.activity.touch(this.direction);
+        }
+
+        poll
+    }
+}
+
+/// Copies a to b and b to a, a to b being limited by upload limiters and b to a
+/// by download limiters. Ends are propagated as shutdowns, while on errors
+/// (including timeouts) both a and b are reset. Resolves with the bytes copied a
+/// to b and b to a.
 pub async fn copy_bidirectional(
     label: &str,
     a_b: (
         impl tokio::io::AsyncRead + Send + Unpin,
-        impl tokio::io::AsyncWrite + Send + Unpin,
+        impl AsyncWriteReset,
         bool,
     ),
     b_a: (
         impl tokio::io::AsyncRead + Send + Unpin,
-        impl tokio::io::AsyncWrite + Send + Unpin,
+        impl AsyncWriteReset,
     ),
-) -> Result<(), tokio::io::Error> {
-    let (mut a_read, mut b_write, a_b_end) = a_b;
-    let (mut b_read, mut a_write) = b_a;
+    limiters: &BandwidthLimiters,
+    timeouts: &StreamTimeoutConfig,
+) -> Result<(u64, u64), tokio::io::Error> {
+    let (a_read, mut b_write, a_b_end) = a_b;
+    let (b_read, mut a_write) = b_a;
+
+    let activity = StreamActivity::new();
 
-    let started_at = Instant::now();
+    let mut a_read = ActivityRead {
+        inner: a_read,
+        activity: &activity,
+        direction: Direction::Upload,
+    };
+
+    let mut b_read = ActivityRead {
+        inner: b_read,
+        activity: &activity,
+        direction: Direction::Download,
+    };
 
     let mut a_to_b_bytes = 0;
     let mut b_to_a_bytes = 0;
 
     let a_to_b_task = async {
-        if a_b_end {
-            let _ = b_write.shutdown().await;
-        } else {
-            let result = tokio::io::copy(&mut a_read, &mut b_write).await;
+        if !a_b_end {
+            a_to_b_bytes = copy(&mut a_read, &mut b_write, &limiters.upload).await?;
+        }
 
-            let _ = b_write.shutdown().await;
+        let _ = b_write.shutdown().await;
 
-            a_to_b_bytes = result?;
-        }
+        activity.end(Direction::Upload);
 
         tokio::io::Result::Ok(())
     };
 
     let b_to_a_task = async {
-        let result = tokio::io::copy(&mut b_read, &mut a_write).await;
+        b_to_a_bytes = copy(&mut b_read, &mut a_write, &limiters.download).await?;
 
         let _ = a_write.shutdown().await;
 
-        b_to_a_bytes = result?;
+        activity.end(Direction::Download);
 
         tokio::io::Result::Ok(())
     };
 
-    let result = tokio::try_join!(a_to_b_task, b_to_a_task);
+    let result = tokio::select! {
+        result = async { tokio::try_join!(a_to_b_task, b_to_a_task) } => result.map(|_| ()),
+        error = activity.timed_out(timeouts) => Err(error),
+    };
 
-    let elapsed = started_at.elapsed();
+    if result.is_err() {
+        a_write.reset();
+        b_write.reset();
+    }
+
+    let elapsed = activity.started_at.elapsed();
 
     log::debug!("[{label}] copy bidirectional took {elapsed:?}, {a_to_b_bytes} / {b_to_a_bytes}");
 
-    result?;
+    result.map(|_| (a_to_b_bytes, b_to_a_bytes))
+}
+
+/// Same as `copy_bidirectional` for two plain TCP streams, but moves data with
+/// `splice(2)` through a pipe so that it never enters userspace. Falls back to
+/// `copy_bidirectional` if limited, as limiters need to see every chunk, or if a
+/// pipe cannot be created.
+///
+/// Only DIRECT connections on IN qualify, OUT always relays between a tunnel
+/// stream and a socket, and tunnel streams are framed in userspace.
+pub async fn copy_bidirectional_tcp(
+    label: &str,
+    a: (tokio::net::TcpStream, bool),
+    b: tokio::net::TcpStream,
+    limiters: &BandwidthLimiters,
+    timeouts: &StreamTimeoutConfig,
+) -> Result<(u64, u64), tokio::io::Error> {
+    let (a, a_b_end) = a;
+
+    let pipes = if limiters.is_empty() {
+        match (Pipe::new(), Pipe::new()) {
+            (Ok(a_b_pipe), Ok(b_a_pipe)) => Some((a_b_pipe, b_a_pipe)),
+            (Err(error), _) | (_, Err(error)) => {
+                log::debug!("[{label}] failed to create pipe for splice: {error}");
+
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let Some((a_b_pipe, b_a_pipe)) = pipes else {
+        let (a_read, a_write) = a.into_split();
+        let (b_read, b_write) = b.into_split();
+
+        return copy_bidirectional(
+            label,
+            (a_read, TcpWriteHalf::new(b_write), a_b_end),
+            (b_read, TcpWriteHalf::new(a_write)),
+            limiters,
+            timeouts,
+        )
+        .await;
+    };
+
+    let activity = StreamActivity::new();
+
+    let mut a_to_b_bytes = 0;
+    let mut b_to_a_bytes = 0;
+
+    let a_to_b_task = async {
+        if !a_b_end {
+            a_to_b_bytes = splice_copy(&a, &b, &a_b_pipe, &activity, Direction::Upload).await?;
+        }
+
+        let _ = shutdown_write(&b);
+
+        activity.end(Direction::Upload);
+
+        tokio::io::Result::Ok(())
+    };
+
+    let b_to_a_task = async {
+        b_to_a_bytes = splice_copy(&b, &a, &b_a_pipe, &activity, Direction::Download).await?;
+
+        let _ = shutdown_write(&a);
+
+        activity.end(Direction::Download);
+
+        tokio::io::Result::Ok(())
+    };
+
+    let result = tokio::select! {
+        result = async { tokio::try_join!(a_to_b_task, b_to_a_task) } => result.map(|_| ()),
+        error = activity.timed_out(timeouts) => Err(error),
+    };
+
+    if result.is_err() {
+        // Both streams are closed on drop, with a RST instead of a FIN.
+        for stream in [&a, &b] {
+            let _ = stream.set_linger(Some(Duration::ZERO));
+        }
+    }
+
+    let elapsed = activity.started_at.elapsed();
+
+    log::debug!("[{label}] splice bidirectional took {elapsed:?}, {a_to_b_bytes} / {b_to_a_bytes}");
+
+    result.map(|_| (a_to_b_bytes, b_to_a_bytes))
+}
+
+async fn splice_copy(
+    reader: &tokio::net::TcpStream,
+    writer: &tokio::net::TcpStream,
+    pipe: &Pipe,
+    activity: &StreamActivity,
+    direction: Direction,
+) -> Result<u64, tokio::io::Error> {
+    let mut bytes = 0;
+
+    loop {
+        // The pipe is always drained before splicing into it again, so `EAGAIN`
+        // here only means that the socket has nothing to read.
+        let length = loop {
+            reader.readable().await?;
+
+            match reader.try_io(Interest::READABLE, || {
+                splice(
+                    reader.as_raw_fd(),
+                    pipe.write.as_raw_fd(),
+                    SPLICE_CHUNK_SIZE,
+                )
+            }) {
+                Ok(length) => break length,
+                Err(error) if error.kind() == tokio::io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            }
+        };
+
+        if length == 0 {
+            break;
+        }
+
+        activity.touch(direction);
+
+        let mut remaining = length;
+
+        while remaining > 0 {
+            writer.writable().await?;
+
+            match writer.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), remaining)
+            }) {
+                Ok(length) => remaining -= length,
+                Err(error) if error.kind() == tokio::io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            }
+        }
+
+        bytes += length as u64;
+    }
+
+    Ok(bytes)
+}
+
+fn splice(from: RawFd, to: RawFd, length: usize) -> Result<usize, tokio::io::Error> {
+    let length = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            length,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if length < 0 {
+        return Err(tokio::io::Error::last_os_error());
+    }
+
+    Ok(length as usize)
+}
+
+fn shutdown_write(stream: &tokio::net::TcpStream) -> Result<(), tokio::io::Error> {
+    socket2::SockRef::from(stream).shutdown(std::net::Shutdown::Write)
+}
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> Result<Self, tokio::io::Error> {
+        let mut fds = [0; 2];
+
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(tokio::io::Error::last_os_error());
+        }
+
+        Ok(unsafe {
+            Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+async fn copy(
+    reader: &mut (impl tokio::io::AsyncRead + Send + Unpin),
+    writer: &mut (impl tokio::io::AsyncWrite + Send + Unpin),
+    limiters: &[std::sync::Arc<BandwidthLimiter>],
+) -> Result<u64, tokio::io::Error> {
+    if limiters.is_empty() {
+        return tokio::io::copy(reader, writer).await;
+    }
+
+    let mut buffer = vec![0; LIMITED_COPY_CHUNK_SIZE];
+
+    let mut bytes = 0;
+
+    loop {
+        let length = reader.read(&mut buffer).await?;
+
+        if length == 0 {
+            break;
+        }
+
+        for limiter in limiters {
+            limiter.consume(length).await;
+        }
+
+        writer.write_all(&buffer[..length]).await?;
+
+        bytes += length as u64;
+    }
+
+    writer.flush().await?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bandwidth::BandwidthLimit;
+
+    use super::*;
+
+    /// Connected pair of loopback TCP streams.
+    async fn create_tcp_pair() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (client, server) = tokio::join!(
+            tokio::net::TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    async fn assert_copies_with_half_close(limiters: BandwidthLimiters) {
+        let (mut a_peer, a) = create_tcp_pair().await;
+        let (b, mut b_peer) = create_tcp_pair().await;
+
+        let copy = tokio::spawn(async move {
+            copy_bidirectional_tcp(
+                "test",
+                (a, false),
+                b,
+                &limiters,
+                &StreamTimeoutConfig::default(),
+            )
+            .await
+        });
+
+        a_peer.write_all(b"upload").await.unwrap();
+        a_peer.shutdown().await.unwrap();
+
+        let mut upload = Vec::new();
+        b_peer.read_to_end(&mut upload).await.unwrap();
+        assert_eq!(upload, b"upload");
+
+        // The other direction still flows after the upload ended.
+        b_peer.write_all(b"download data").await.unwrap();
+        b_peer.shutdown().await.unwrap();
+
+        let mut download = Vec::new();
+        a_peer.read_to_end(&mut download).await.unwrap();
+        assert_eq!(download, b"download data");
+
+        assert_eq!(copy.await.unwrap().unwrap(), (6, 13));
+    }
+
+    #[tokio::test]
+    async fn splices_with_half_close() {
+        assert_copies_with_half_close(BandwidthLimiters::default()).await;
+    }
+
+    /// Relays a pair of TCP streams with the timeouts, returning the peers and the
+    /// relay.
+    async fn relay_with_timeouts(
+        timeouts: StreamTimeoutConfig,
+    ) -> (
+        tokio::net::TcpStream,
+        tokio::net::TcpStream,
+        tokio::task::JoinHandle<Result<(u64, u64), tokio::io::Error>>,
+    ) {
+        let (a_peer, a) = create_tcp_pair().await;
+        let (b, b_peer) = create_tcp_pair().await;
+
+        let copy = tokio::spawn(async move {
+            copy_bidirectional_tcp(
+                "test",
+                (a, false),
+                b,
+                &BandwidthLimiters::default(),
+                &timeouts,
+            )
+            .await
+        });
+
+        (a_peer, b_peer, copy)
+    }
+
+    async fn assert_timed_out(
+        copy: tokio::task::JoinHandle<Result<(u64, u64), tokio::io::Error>>,
+        reason: &str,
+        peers: [&mut tokio::net::TcpStream; 2],
+    ) {
+        let error = tokio::time::timeout(Duration::from_secs(5), copy)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+
+        assert_eq!(error.kind(), tokio::io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), format!("{reason} exceeded."));
+
+        // Both sides are reset rather than ended, a peer of an ended stream could
+        // still write.
+        for peer in peers {
+            assert!(peer.write_all(b"data").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_idle() {
+        let (mut a_peer, mut b_peer, copy) = relay_with_timeouts(StreamTimeoutConfig {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        })
+        .await;
+
+        // Data in either direction keeps the stream alive.
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+
+            a_peer.write_all(b"ping").await.unwrap();
+            b_peer.read_exact(&mut [0; 4]).await.unwrap();
+        }
+
+        assert!(!copy.is_finished());
+
+        assert_timed_out(copy, "idle timeout", [&mut a_peer, &mut b_peer]).await;
+    }
+
+    #[tokio::test]
+    async fn times_out_upload_idle() {
+        let (mut a_peer, mut b_peer, copy) = relay_with_timeouts(StreamTimeoutConfig {
+            upload_idle_timeout: Some(Duration::from_millis(300)),
+            download_idle_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        })
+        .await;
+
+        // Download data does not keep upload alive.
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            b_peer.write_all(b"pong").await.unwrap();
+            a_peer.read_exact(&mut [0; 4]).await.unwrap();
+        }
+
+        assert!(!copy.is_finished());
+
+        assert_timed_out(copy, "upload idle timeout", [&mut a_peer, &mut b_peer]).await;
+    }
+
+    #[tokio::test]
+    async fn times_out_download_idle() {
+        let (mut a_peer, mut b_peer, copy) = relay_with_timeouts(StreamTimeoutConfig {
+            upload_idle_timeout: Some(Duration::from_secs(60)),
+            download_idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        })
+        .await;
+
+        // Upload data does not keep download alive.
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            a_peer.write_all(b"ping").await.unwrap();
+            b_peer.read_exact(&mut [0; 4]).await.unwrap();
+        }
+
+        assert!(!copy.is_finished());
+
+        assert_timed_out(copy, "download idle timeout", [&mut a_peer, &mut b_peer]).await;
+    }
+
+    #[tokio::test]
+    async fn ignores_idle_timeout_of_ended_direction() {
+        let (mut a_peer, mut b_peer, copy) = relay_with_timeouts(StreamTimeoutConfig {
+            upload_idle_timeout: Some(Duration::from_millis(100)),
+            download_idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        })
+        .await;
+
+        a_peer.shutdown().await.unwrap();
+        assert_eq!(b_peer.read(&mut [0; 16]).await.unwrap(), 0);
+
+        // Upload ended, download is still active.
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+
+            b_peer.write_all(b"pong").await.unwrap();
+            a_peer.read_exact(&mut [0; 4]).await.unwrap();
+        }
+
+        b_peer.shutdown().await.unwrap();
+        assert_eq!(a_peer.read(&mut [0; 16]).await.unwrap(), 0);
+
+        assert_eq!(copy.await.unwrap().unwrap(), (0, 12));
+    }
+
+    #[tokio::test]
+    async fn times_out_max_lifetime() {
+        let (mut a_peer, mut b_peer, copy) = relay_with_timeouts(StreamTimeoutConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            max_lifetime: Some(Duration::from_millis(300)),
+            ..Default::default()
+        })
+        .await;
+
+        a_peer.write_all(b"ping").await.unwrap();
+        b_peer.read_exact(&mut [0; 4]).await.unwrap();
+
+        assert_timed_out(copy, "max lifetime", [&mut a_peer, &mut b_peer]).await;
+    }
 
-    Ok(())
+    #[tokio::test]
+    async fn copies_limited_with_half_close() {
+        assert_copies_with_half_close(BandwidthLimiters::new(BandwidthLimit {
+            upload: Some(1024 * 1024),
+            download: Some(1024 * 1024),
+        }))
+        .await;
+    }
 }
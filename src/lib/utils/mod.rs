@@ -1,7 +1,9 @@
 pub mod io;
 pub mod log;
 mod miscellaneous;
+pub mod nat;
 pub mod net;
+pub mod port_mapping;
 pub mod semaphore_rate_limiter;
 pub mod stun;
 pub mod time;
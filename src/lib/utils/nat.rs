@@ -0,0 +1,350 @@
+use std::{net::SocketAddr, time::Duration};
+
+use itertools::Itertools as _;
+use rand::Rng as _;
+
+use super::{
+    net::{get_any_address, get_local_ip_for},
+    stun::{stun_binding, StunBindingResponse, STUN_CHANGE_IP, STUN_CHANGE_PORT},
+};
+
+/// STUN servers probed for the addresses a socket is mapped to.
+const MAPPING_PROBE_SERVER_COUNT_MAX: usize = 3;
+
+/// Port deltas between consecutive mappings beyond this are considered random
+/// allocation.
+const PREDICTABLE_PORT_DELTA_MAX: u16 = 64;
+
+const PREDICTED_PORT_COUNT: usize = 16;
+const BIRTHDAY_PORT_COUNT: usize = 256;
+
+/// Addresses `predict_mapped_addresses` returns at most, plus one mapped on the
+/// gateway.
+pub const MAPPED_ADDRESS_COUNT_MAX: usize =
+    MAPPING_PROBE_SERVER_COUNT_MAX + BIRTHDAY_PORT_COUNT + 1;
+
+const EPHEMERAL_PORT_MIN: u16 = 1024;
+
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+const PUNCH_ROUNDS: usize = 25;
+
+/// Mapping behavior (RFC 5780 section 4.3).
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatMapping {
+    #[display("no NAT")]
+    NoNat,
+    #[display("endpoint-independent")]
+    EndpointIndependent,
+    #[display("address-dependent")]
+    AddressDependent,
+    #[display("address-and-port-dependent")]
+    AddressAndPortDependent,
+    #[display("unknown")]
+    Unknown,
+}
+
+/// Filtering behavior (RFC 5780 section 4.4).
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatFiltering {
+    #[display("endpoint-independent")]
+    EndpointIndependent,
+    #[display("address-dependent")]
+    AddressDependent,
+    #[display("address-and-port-dependent")]
+    AddressAndPortDependent,
+    #[display("unknown")]
+    Unknown,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[display("mapping {mapping}, filtering {filtering}")]
+pub struct NatBehavior {
+    pub mapping: NatMapping,
+    pub filtering: NatFiltering,
+}
+
+impl NatBehavior {
+    /// Whether the NAT maps a socket to different ports for different
+    /// destinations, in which case the peer needs predicted addresses.
+    pub fn is_symmetric(&self) -> bool {
+        matches!(
+            self.mapping,
+            NatMapping::AddressDependent | NatMapping::AddressAndPortDependent
+        )
+    }
+}
+
+/// Detects NAT behavior once and keeps the result for advertising it through the
+/// match server.
+pub struct NatBehaviorDetector {
+    stun_server_addresses: Vec<SocketAddr>,
+    behavior: tokio::sync::OnceCell<Option<NatBehavior>>,
+}
+
+impl NatBehaviorDetector {
+    pub fn new(stun_server_addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            stun_server_addresses,
+            behavior: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    pub async fn get(&self) -> Option<NatBehavior> {
+        *self
+            .behavior
+            .get_or_init(|| async {
+                match detect_nat_behavior(&self.stun_server_addresses).await {
+                    Ok(behavior) => {
+                        log::info!("NAT behavior: {behavior}.");
+
+                        Some(behavior)
+                    }
+                    Err(error) => {
+                        log::warn!("failed to detect NAT behavior: {error}");
+
+                        None
+                    }
+                }
+            })
+            .await
+    }
+}
+
+/// Discovers NAT mapping and filtering behavior the RFC 5780 way. Servers that do
+/// not provide OTHER-ADDRESS are compared against each other instead, in which
+/// case a varying mapping is considered address-and-port-dependent and filtering
+/// is unknown.
+pub async fn detect_nat_behavior(
+    stun_server_addresses: &[SocketAddr],
+) -> anyhow::Result<NatBehavior> {
+    let mut servers = stun_server_addresses.iter().copied();
+
+    let socket = create_socket(stun_server_addresses).await?;
+
+    let (server, response) = loop {
+        let server = servers
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no STUN server responded."))?;
+
+        if server.is_ipv4() != socket.local_addr()?.is_ipv4() {
+            continue;
+        }
+
+        match stun_binding(&socket, server, None).await {
+            Ok(Some(response)) => break (server, response),
+            Ok(None) => log::debug!("STUN request to {server} timed out."),
+            Err(error) => log::debug!("STUN request to {server} failed: {error}"),
+        }
+    };
+
+    let mapping = detect_mapping(&socket, server, &response, servers).await?;
+
+    let filtering = match response.other_address {
+        Some(_) => detect_filtering(server).await?,
+        None => NatFiltering::Unknown,
+    };
+
+    Ok(NatBehavior { mapping, filtering })
+}
+
+async fn detect_mapping(
+    socket: &tokio::net::UdpSocket,
+    server: SocketAddr,
+    response: &StunBindingResponse,
+    other_servers: impl Iterator<Item = SocketAddr>,
+) -> anyhow::Result<NatMapping> {
+    let mapped_address = response.mapped_address;
+
+    let local_address = SocketAddr::new(get_local_ip_for(server)?, socket.local_addr()?.port());
+
+    if mapped_address == local_address {
+        return Ok(NatMapping::NoNat);
+    }
+
+    if let Some(other_address) = response.other_address {
+        let Some(StunBindingResponse {
+            mapped_address: address_changed_mapped_address,
+            ..
+        }) = stun_binding(
+            socket,
+            SocketAddr::new(other_address.ip(), server.port()),
+            None,
+        )
+        .await?
+        else {
+            return Ok(NatMapping::Unknown);
+        };
+
+        if address_changed_mapped_address == mapped_address {
+            return Ok(NatMapping::EndpointIndependent);
+        }
+
+        let Some(StunBindingResponse {
+            mapped_address: port_changed_mapped_address,
+            ..
+        }) = stun_binding(socket, other_address, None).await?
+        else {
+            return Ok(NatMapping::Unknown);
+        };
+
+        return Ok(
+            if port_changed_mapped_address == address_changed_mapped_address {
+                NatMapping::AddressDependent
+            } else {
+                NatMapping::AddressAndPortDependent
+            },
+        );
+    }
+
+    for other_server in other_servers {
+        if other_server.ip() == server.ip() || other_server.is_ipv4() != server.is_ipv4() {
+            continue;
+        }
+
+        if let Ok(Some(other_response)) = stun_binding(socket, other_server, None).await {
+            return Ok(if other_response.mapped_address == mapped_address {
+                NatMapping::EndpointIndependent
+            } else {
+                NatMapping::AddressAndPortDependent
+            });
+        }
+    }
+
+    Ok(NatMapping::Unknown)
+}
+
+/// Uses a fresh socket, as mapping tests open the filter for the alternate
+/// address.
+async fn detect_filtering(server: SocketAddr) -> anyhow::Result<NatFiltering> {
+    let socket = tokio::net::UdpSocket::bind(get_any_address(&server)).await?;
+
+    let filtering =
+        match stun_binding(&socket, server, Some(STUN_CHANGE_IP | STUN_CHANGE_PORT)).await {
+            Ok(Some(_)) => NatFiltering::EndpointIndependent,
+            Ok(None) => match stun_binding(&socket, server, Some(STUN_CHANGE_PORT)).await {
+                Ok(Some(_)) => NatFiltering::AddressDependent,
+                Ok(None) => NatFiltering::AddressAndPortDependent,
+                Err(_) => NatFiltering::Unknown,
+            },
+            // Typically CHANGE-REQUEST not supported.
+            Err(_) => NatFiltering::Unknown,
+        };
+
+    Ok(filtering)
+}
+
+/// Binds a socket of the address family of the first STUN server.
+pub async fn create_socket(
+    stun_server_addresses: &[SocketAddr],
+) -> anyhow::Result<tokio::net::UdpSocket> {
+    let server = stun_server_addresses
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no STUN server configured."))?;
+
+    Ok(tokio::net::UdpSocket::bind(get_any_address(server)).await?)
+}
+
+/// Probes addresses the socket is mapped to for different STUN servers, in the
+/// order of servers.
+pub async fn probe_mapped_addresses(
+    socket: &tokio::net::UdpSocket,
+    stun_server_addresses: &[SocketAddr],
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let is_ipv4 = socket.local_addr()?.is_ipv4();
+
+    let mut mapped_addresses = Vec::new();
+
+    for &server in stun_server_addresses
+        .iter()
+        .filter(|server| server.is_ipv4() == is_ipv4)
+        .take(MAPPING_PROBE_SERVER_COUNT_MAX)
+    {
+        match stun_binding(socket, server, None).await {
+            Ok(Some(response)) => mapped_addresses.push(response.mapped_address),
+            Ok(None) => log::debug!("STUN request to {server} timed out."),
+            Err(error) => log::debug!("STUN request to {server} failed: {error}"),
+        }
+    }
+
+    anyhow::ensure!(
+        !mapped_addresses.is_empty(),
+        "failed to get public address from stun server."
+    );
+
+    Ok(mapped_addresses)
+}
+
+/// Predicts addresses the socket is mapped to for a new destination, from
+/// addresses mapped for consecutive destinations. Sequential allocation is
+/// extrapolated with the observed port delta, while random allocation falls back
+/// to birthday-style random ports, which only succeeds by chance.
+pub fn predict_mapped_addresses(mapped_addresses: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mapped_addresses = mapped_addresses.iter().copied().unique().collect_vec();
+
+    let [.., previous, last] = mapped_addresses[..] else {
+        return mapped_addresses;
+    };
+
+    if previous.ip() != last.ip() {
+        return mapped_addresses;
+    }
+
+    let ip = last.ip();
+
+    let delta = i32::from(last.port()) - i32::from(previous.port());
+
+    let ports = if delta.unsigned_abs() <= u32::from(PREDICTABLE_PORT_DELTA_MAX) {
+        (1..=PREDICTED_PORT_COUNT as i32)
+            .filter_map(|index| u16::try_from(i32::from(last.port()) + delta * index).ok())
+            .filter(|&port| port >= EPHEMERAL_PORT_MIN)
+            .collect_vec()
+    } else {
+        let mut rng = rand::thread_rng();
+
+        (0..BIRTHDAY_PORT_COUNT)
+            .map(|_| rng.gen_range(EPHEMERAL_PORT_MIN..=u16::MAX))
+            .collect_vec()
+    };
+
+    mapped_addresses
+        .into_iter()
+        .chain(ports.into_iter().map(|port| SocketAddr::new(ip, port)))
+        .unique()
+        .collect()
+}
+
+/// Binds a socket for a peer-to-peer connection and returns it with the
+/// addresses (probed and predicted) it is mapped to for the peer.
+pub async fn create_socket_and_predict_mapped_addresses(
+    stun_server_addresses: &[SocketAddr],
+) -> anyhow::Result<(tokio::net::UdpSocket, Vec<SocketAddr>)> {
+    let socket = create_socket(stun_server_addresses).await?;
+
+    let mapped_addresses = probe_mapped_addresses(&socket, stun_server_addresses).await?;
+
+    Ok((socket, predict_mapped_addresses(&mapped_addresses)))
+}
+
+/// Sends packets to the peer addresses for a while, opening the NAT filter for
+/// the peer to connect in.
+pub async fn punch(socket: &tokio::net::UdpSocket, peer_addresses: &[SocketAddr]) {
+    let is_ipv4 = socket.local_addr().is_ok_and(|address| address.is_ipv4());
+
+    for _ in 0..PUNCH_ROUNDS {
+        for &address in peer_addresses {
+            if address.is_ipv4() == is_ipv4 {
+                socket.send_to(&[0], address).await.ok();
+            }
+        }
+
+        tokio::time::sleep(PUNCH_INTERVAL).await;
+    }
+}
@@ -19,6 +19,43 @@ pub fn bind_tcp_listener_reuseaddr(address: SocketAddr) -> anyhow::Result<tokio:
     Ok(listener)
 }
 
+/// Listener sharing its port with sockets connecting out of it, for TCP hole
+/// punching.
+pub fn bind_tcp_listener_reuseport(address: SocketAddr) -> anyhow::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(address),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+
+    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+
+    Ok(listener)
+}
+
+/// Socket bound to an address shared with a listener or other sockets. Both ends
+/// connecting to each other at once from such sockets establish a connection
+/// through NATs (TCP simultaneous open).
+pub fn bind_tcp_socket_reuseport(address: SocketAddr) -> anyhow::Result<tokio::net::TcpSocket> {
+    let socket = match address {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
+    }?;
+
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(address)?;
+
+    Ok(socket)
+}
+
 pub const ANY_ADDRESS_IPV4: SocketAddr =
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
 
@@ -32,6 +69,15 @@ pub fn get_any_address(address: &SocketAddr) -> SocketAddr {
     }
 }
 
+/// Local IP used to reach the remote address, without sending anything.
+pub fn get_local_ip_for(remote_address: SocketAddr) -> anyhow::Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind(get_any_address(&remote_address))?;
+
+    socket.connect(remote_address)?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
 pub fn get_any_port_address(ip: &IpAddr) -> SocketAddr {
     match ip {
         IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(*ip, 0)),
@@ -50,3 +96,46 @@ pub fn parse_ip_net(ip_net: &str) -> anyhow::Result<ipnet::IpNet> {
         anyhow::Ok(ipnet::IpNet::new(ip, prefix_length)?)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn punches_listener_port() {
+        let listener = bind_tcp_listener_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listen_address = listener.local_addr().unwrap();
+
+        let peer_socket = bind_tcp_socket_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+        let peer_address = peer_socket.local_addr().unwrap();
+
+        let punch_socket = bind_tcp_socket_reuseport(listen_address).unwrap();
+
+        // Either the punch connects (simultaneous open), or the peer connection
+        // reaches the listener first.
+        let (punch_result, peer_result) = tokio::join!(
+            tokio::time::timeout(Duration::from_secs(3), punch_socket.connect(peer_address)),
+            tokio::time::timeout(Duration::from_secs(3), peer_socket.connect(listen_address)),
+        );
+
+        let peer_stream = peer_result.unwrap().unwrap();
+
+        assert_eq!(peer_stream.peer_addr().unwrap(), listen_address);
+
+        match punch_result {
+            Ok(Ok(punch_stream)) => {
+                assert_eq!(punch_stream.peer_addr().unwrap(), peer_address);
+            }
+            _ => {
+                let (_, address) = tokio::time::timeout(Duration::from_secs(1), listener.accept())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(address, peer_address);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::utils::net::get_any_address;
+
+/// Server port of both NAT-PMP and PCP.
+pub const NAT_PMP_PCP_SERVER_PORT: u16 = 5351;
+
+const GATEWAY_REQUEST_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const GATEWAY_REQUEST_ATTEMPTS: usize = 4;
+
+const ROUTE_TABLE_PATH: &str = "/proc/net/route";
+const RTF_GATEWAY: u32 = 0x0002;
+
+/// Reads the IPv4 default gateway from the routing table.
+pub fn get_default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let route_table = std::fs::read_to_string(ROUTE_TABLE_PATH)?;
+
+    for line in route_table.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let [_, destination, gateway, flags, ..] = fields[..] else {
+            continue;
+        };
+
+        let flags = u32::from_str_radix(flags, 16)?;
+
+        if destination == "00000000" && flags & RTF_GATEWAY != 0 {
+            // Stored in network byte order, printed as a native u32.
+            let gateway = u32::from_str_radix(gateway, 16)?;
+
+            return Ok(Ipv4Addr::from(gateway.to_le_bytes()));
+        }
+    }
+
+    anyhow::bail!("no default gateway found.")
+}
+
+/// Sends a NAT-PMP or PCP request to the gateway, retransmitting it with
+/// exponential backoff until a response accepted by `is_response` arrives.
+pub(super) async fn request_gateway(
+    gateway: SocketAddr,
+    request: &[u8],
+    is_response: impl Fn(&[u8]) -> bool,
+) -> anyhow::Result<Vec<u8>> {
+    let socket = tokio::net::UdpSocket::bind(get_any_address(&gateway)).await?;
+
+    // Connected so that ICMP port unreachable fails the request right away.
+    socket.connect(gateway).await?;
+
+    let mut buffer = [0; 1100];
+
+    let mut timeout = GATEWAY_REQUEST_INITIAL_TIMEOUT;
+
+    for _ in 0..GATEWAY_REQUEST_ATTEMPTS {
+        socket.send(request).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        while let Ok(result) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let length = result?;
+
+            if is_response(&buffer[..length]) {
+                return Ok(buffer[..length].to_vec());
+            }
+        }
+
+        timeout *= 2;
+    }
+
+    anyhow::bail!("no response from gateway {gateway}.")
+}
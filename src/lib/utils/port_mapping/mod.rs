@@ -0,0 +1,13 @@
+mod gateway;
+mod nat_pmp;
+mod pcp;
+mod port_mapper;
+mod port_mapping_client;
+mod upnp;
+
+pub use gateway::*;
+pub use nat_pmp::*;
+pub use pcp::*;
+pub use port_mapper::*;
+pub use port_mapping_client::*;
+pub use upnp::*;
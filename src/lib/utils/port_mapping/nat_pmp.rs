@@ -0,0 +1,127 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf as _, BufMut as _};
+
+use super::{gateway::request_gateway, PortMapping, PortMappingProtocol, PortMappingRequest};
+
+const NAT_PMP_VERSION: u8 = 0;
+
+const NAT_PMP_OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OPCODE_MAP_UDP: u8 = 1;
+const NAT_PMP_OPCODE_MAP_TCP: u8 = 2;
+
+const NAT_PMP_RESPONSE_OPCODE_OFFSET: u8 = 128;
+
+/// Maps a port through NAT-PMP (RFC 6886).
+pub async fn map_port_via_nat_pmp(
+    gateway: SocketAddr,
+    request: &PortMappingRequest,
+    lifetime: Duration,
+) -> anyhow::Result<PortMapping> {
+    let external_ip = {
+        let mut response =
+            request_nat_pmp(gateway, NAT_PMP_OPCODE_EXTERNAL_ADDRESS, &[], 12).await?;
+
+        Ipv4Addr::from(response.get_u32())
+    };
+
+    let (external_port, lifetime) = request_nat_pmp_mapping(
+        gateway,
+        request.protocol,
+        request.local_port,
+        request.external_port,
+        lifetime,
+    )
+    .await?;
+
+    Ok(PortMapping {
+        external_address: SocketAddr::new(external_ip.into(), external_port),
+        lifetime,
+    })
+}
+
+pub async fn unmap_port_via_nat_pmp(
+    gateway: SocketAddr,
+    request: &PortMappingRequest,
+) -> anyhow::Result<()> {
+    request_nat_pmp_mapping(
+        gateway,
+        request.protocol,
+        request.local_port,
+        0,
+        Duration::ZERO,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn request_nat_pmp_mapping(
+    gateway: SocketAddr,
+    protocol: PortMappingProtocol,
+    local_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> anyhow::Result<(u16, Duration)> {
+    let opcode = match protocol {
+        PortMappingProtocol::Tcp => NAT_PMP_OPCODE_MAP_TCP,
+        PortMappingProtocol::Udp => NAT_PMP_OPCODE_MAP_UDP,
+    };
+
+    let mut payload = Vec::with_capacity(10);
+
+    payload.put_u16(0);
+    payload.put_u16(local_port);
+    payload.put_u16(external_port);
+    payload.put_u32(lifetime.as_secs().try_into().unwrap_or(u32::MAX));
+
+    let mut response = request_nat_pmp(gateway, opcode, &payload, 16).await?;
+
+    anyhow::ensure!(
+        response.get_u16() == local_port,
+        "unexpected NAT-PMP mapping response."
+    );
+
+    let external_port = response.get_u16();
+    let lifetime = Duration::from_secs(response.get_u32().into());
+
+    Ok((external_port, lifetime))
+}
+
+/// Returns the response after the result code and the epoch.
+async fn request_nat_pmp(
+    gateway: SocketAddr,
+    opcode: u8,
+    payload: &[u8],
+    response_length: usize,
+) -> anyhow::Result<bytes::Bytes> {
+    let mut request = vec![NAT_PMP_VERSION, opcode];
+
+    request.extend_from_slice(payload);
+
+    let response = request_gateway(gateway, &request, |response| {
+        response.len() >= response_length
+            && response[0] == NAT_PMP_VERSION
+            && response[1] == NAT_PMP_RESPONSE_OPCODE_OFFSET + opcode
+    })
+    .await?;
+
+    let mut response = bytes::Bytes::from(response);
+
+    response.advance(2);
+
+    let result_code = response.get_u16();
+
+    anyhow::ensure!(
+        result_code == 0,
+        "NAT-PMP request failed with result code {result_code}."
+    );
+
+    // Seconds since start of epoch.
+    response.advance(4);
+
+    Ok(response)
+}
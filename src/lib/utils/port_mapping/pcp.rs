@@ -0,0 +1,111 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf as _, BufMut as _};
+
+use crate::utils::net::get_local_ip_for;
+
+use super::{gateway::request_gateway, PortMapping, PortMappingProtocol, PortMappingRequest};
+
+const PCP_VERSION: u8 = 2;
+
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0b_1000_0000;
+
+const PCP_HEADER_LENGTH: usize = 24;
+const PCP_MAP_PAYLOAD_LENGTH: usize = 36;
+
+const IANA_PROTOCOL_TCP: u8 = 6;
+const IANA_PROTOCOL_UDP: u8 = 17;
+
+/// Maps a port through PCP (RFC 6887) with the MAP opcode.
+pub async fn map_port_via_pcp(
+    gateway: SocketAddr,
+    request: &PortMappingRequest,
+    lifetime: Duration,
+) -> anyhow::Result<PortMapping> {
+    request_pcp_mapping(gateway, request, lifetime).await
+}
+
+/// Deletes the mapping with the nonce it was created with.
+pub async fn unmap_port_via_pcp(
+    gateway: SocketAddr,
+    request: &PortMappingRequest,
+) -> anyhow::Result<()> {
+    request_pcp_mapping(gateway, request, Duration::ZERO).await?;
+
+    Ok(())
+}
+
+async fn request_pcp_mapping(
+    gateway: SocketAddr,
+    request: &PortMappingRequest,
+    lifetime: Duration,
+) -> anyhow::Result<PortMapping> {
+    let client_ip = to_pcp_address(get_local_ip_for(gateway)?);
+
+    let mut message = Vec::with_capacity(PCP_HEADER_LENGTH + PCP_MAP_PAYLOAD_LENGTH);
+
+    message.put_u8(PCP_VERSION);
+    message.put_u8(PCP_OPCODE_MAP);
+    message.put_u16(0);
+    message.put_u32(lifetime.as_secs().try_into().unwrap_or(u32::MAX));
+    message.put_slice(&client_ip.octets());
+
+    message.put_slice(&request.nonce);
+    message.put_u8(match request.protocol {
+        PortMappingProtocol::Tcp => IANA_PROTOCOL_TCP,
+        PortMappingProtocol::Udp => IANA_PROTOCOL_UDP,
+    });
+    message.put_slice(&[0; 3]);
+    message.put_u16(request.local_port);
+    message.put_u16(request.external_port);
+    message.put_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    let response = request_gateway(gateway, &message, |response| {
+        response.len() >= PCP_HEADER_LENGTH + PCP_MAP_PAYLOAD_LENGTH
+            && response[0] == PCP_VERSION
+            && response[1] == PCP_RESPONSE_BIT | PCP_OPCODE_MAP
+            && response[PCP_HEADER_LENGTH..PCP_HEADER_LENGTH + 12] == request.nonce
+    })
+    .await?;
+
+    let mut response = bytes::Bytes::from(response);
+
+    response.advance(3);
+
+    let result_code = response.get_u8();
+
+    anyhow::ensure!(
+        result_code == 0,
+        "PCP request failed with result code {result_code}."
+    );
+
+    let lifetime = Duration::from_secs(response.get_u32().into());
+
+    // Epoch time, reserved, nonce, protocol, reserved and internal port.
+    response.advance(4 + 12 + 12 + 1 + 3 + 2);
+
+    let external_port = response.get_u16();
+
+    let external_ip = Ipv6Addr::from(response.get_u128());
+
+    let external_ip = match external_ip.to_ipv4_mapped() {
+        Some(external_ip) => IpAddr::V4(external_ip),
+        None => IpAddr::V6(external_ip),
+    };
+
+    Ok(PortMapping {
+        external_address: SocketAddr::new(external_ip, external_port),
+        lifetime,
+    })
+}
+
+fn to_pcp_address(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
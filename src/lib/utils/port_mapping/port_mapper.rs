@@ -0,0 +1,116 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{PortMappingClient, PortMappingProtocol, PortMappingRequest};
+
+const PORT_MAPPING_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const PORT_MAPPING_RENEWAL_INTERVAL_MIN: Duration = Duration::from_secs(10);
+
+/// Keeps a local port mapped on the gateway, renewing the lease at half its
+/// lifetime. The mapping is removed on drop.
+pub struct PortMapper {
+    client: Arc<PortMappingClient>,
+    /// `None` before the first attempt completes.
+    external_address_receiver: tokio::sync::watch::Receiver<Option<Option<SocketAddr>>>,
+    mapped_request: Arc<Mutex<Option<PortMappingRequest>>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl PortMapper {
+    pub fn new(
+        client: Arc<PortMappingClient>,
+        protocol: PortMappingProtocol,
+        local_port: u16,
+    ) -> Self {
+        let (external_address_sender, external_address_receiver) =
+            tokio::sync::watch::channel(None);
+
+        let mapped_request = Arc::new(Mutex::new(None));
+
+        let handle = tokio::spawn({
+            let client = client.clone();
+            let mapped_request = mapped_request.clone();
+
+            async move {
+                let mut request = PortMappingRequest::new(protocol, local_port);
+
+                loop {
+                    let interval = match client.map_port(&request).await {
+                        Ok(mapping) => {
+                            let external_address = Some(mapping.external_address);
+
+                            if *external_address_sender.borrow() != Some(external_address) {
+                                log::info!(
+                                    "{protocol} port {local_port} mapped to {}.",
+                                    mapping.external_address
+                                );
+                            }
+
+                            // Keep the external port on renewal.
+                            request.external_port = mapping.external_address.port();
+
+                            mapped_request.lock().unwrap().replace(request.clone());
+
+                            external_address_sender.send_replace(Some(external_address));
+
+                            (mapping.lifetime / 2).max(PORT_MAPPING_RENEWAL_INTERVAL_MIN)
+                        }
+                        Err(error) => {
+                            if *external_address_sender.borrow() != Some(None) {
+                                log::warn!("failed to map {protocol} port {local_port}: {error}");
+                            }
+
+                            external_address_sender.send_replace(Some(None));
+
+                            PORT_MAPPING_RETRY_INTERVAL
+                        }
+                    };
+
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+
+        Self {
+            client,
+            external_address_receiver,
+            mapped_request,
+            handle,
+        }
+    }
+
+    /// Waits for the first attempt and returns the current external address, if
+    /// mapped.
+    pub async fn external_address(&self) -> Option<SocketAddr> {
+        let mut receiver = self.external_address_receiver.clone();
+
+        let external_address = receiver.wait_for(Option::is_some).await.ok()?;
+
+        external_address.flatten()
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.handle.abort();
+
+        if let Some(request) = self.mapped_request.lock().unwrap().take() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                let client = self.client.clone();
+
+                runtime.spawn(async move {
+                    if let Err(error) = client.unmap_port(&request).await {
+                        log::debug!(
+                            "failed to unmap {} port {}: {error}",
+                            request.protocol,
+                            request.local_port
+                        );
+                    }
+                });
+            }
+        }
+    }
+}
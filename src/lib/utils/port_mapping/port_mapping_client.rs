@@ -0,0 +1,434 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{
+    get_default_gateway, map_port_via_nat_pmp, map_port_via_pcp, map_port_via_upnp,
+    search_upnp_gateway, unmap_port_via_nat_pmp, unmap_port_via_pcp, unmap_port_via_upnp,
+    UpnpGateway, NAT_PMP_PCP_SERVER_PORT,
+};
+
+/// Interval before trying all methods again once none works.
+const PORT_MAPPING_UNAVAILABLE_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, derive_more::Display)]
+pub enum PortMappingProtocol {
+    #[display("TCP")]
+    Tcp,
+    #[display("UDP")]
+    Udp,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, derive_more::Display,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum PortMappingMethod {
+    #[display("UPnP")]
+    Upnp,
+    #[display("NAT-PMP")]
+    NatPmp,
+    #[display("PCP")]
+    Pcp,
+}
+
+#[derive(Clone)]
+pub struct PortMappingConfig {
+    /// Methods tried in order until one works.
+    pub methods: Vec<PortMappingMethod>,
+    /// Defaults to the default gateway for NAT-PMP and PCP, and to SSDP multicast
+    /// for UPnP.
+    pub gateway: Option<IpAddr>,
+    /// Requested lease lifetime, mappings are renewed at half of the granted one.
+    pub lifetime: Duration,
+}
+
+#[derive(Clone)]
+pub struct PortMappingRequest {
+    pub protocol: PortMappingProtocol,
+    pub local_port: u16,
+    /// Suggested external port, zero for any.
+    pub external_port: u16,
+    /// Identifies the mapping for PCP.
+    pub nonce: [u8; 12],
+}
+
+impl PortMappingRequest {
+    pub fn new(protocol: PortMappingProtocol, local_port: u16) -> Self {
+        Self {
+            protocol,
+            local_port,
+            external_port: local_port,
+            nonce: rand::random(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PortMapping {
+    pub external_address: SocketAddr,
+    pub lifetime: Duration,
+}
+
+enum PortMappingGateway {
+    Upnp(UpnpGateway),
+    NatPmp(SocketAddr),
+    Pcp(SocketAddr),
+}
+
+impl PortMappingGateway {
+    async fn discover(
+        method: PortMappingMethod,
+        gateway: Option<IpAddr>,
+    ) -> anyhow::Result<PortMappingGateway> {
+        let gateway_address = || -> anyhow::Result<SocketAddr> {
+            let gateway = match gateway {
+                Some(gateway) => gateway,
+                None => get_default_gateway()?.into(),
+            };
+
+            Ok(SocketAddr::new(gateway, NAT_PMP_PCP_SERVER_PORT))
+        };
+
+        Ok(match method {
+            PortMappingMethod::Upnp => {
+                PortMappingGateway::Upnp(search_upnp_gateway(gateway).await?)
+            }
+            PortMappingMethod::NatPmp => PortMappingGateway::NatPmp(gateway_address()?),
+            PortMappingMethod::Pcp => PortMappingGateway::Pcp(gateway_address()?),
+        })
+    }
+
+    async fn map_port(
+        &self,
+        request: &PortMappingRequest,
+        lifetime: Duration,
+    ) -> anyhow::Result<PortMapping> {
+        match self {
+            PortMappingGateway::Upnp(gateway) => {
+                map_port_via_upnp(gateway, request, lifetime).await
+            }
+            PortMappingGateway::NatPmp(gateway) => {
+                map_port_via_nat_pmp(*gateway, request, lifetime).await
+            }
+            PortMappingGateway::Pcp(gateway) => map_port_via_pcp(*gateway, request, lifetime).await,
+        }
+    }
+
+    async fn unmap_port(&self, request: &PortMappingRequest) -> anyhow::Result<()> {
+        match self {
+            PortMappingGateway::Upnp(gateway) => unmap_port_via_upnp(gateway, request).await,
+            PortMappingGateway::NatPmp(gateway) => unmap_port_via_nat_pmp(*gateway, request).await,
+            PortMappingGateway::Pcp(gateway) => unmap_port_via_pcp(*gateway, request).await,
+        }
+    }
+}
+
+enum PortMappingGatewayState {
+    Undiscovered,
+    Available(Arc<PortMappingGateway>),
+    Unavailable(tokio::time::Instant),
+}
+
+/// Discovers the first working method once and maps ports through it.
+pub struct PortMappingClient {
+    config: PortMappingConfig,
+    /// Only locked to read or update the state, never across gateway requests.
+    gateway: Mutex<PortMappingGatewayState>,
+    /// Serializes discovery, so that concurrent requests discover once.
+    discovery: tokio::sync::Mutex<()>,
+}
+
+impl PortMappingClient {
+    pub fn new(config: PortMappingConfig) -> Self {
+        Self {
+            config,
+            gateway: Mutex::new(PortMappingGatewayState::Undiscovered),
+            discovery: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Returns the discovered gateway, `Ok(None)` if it is to be discovered.
+    fn get_gateway(&self) -> anyhow::Result<Option<Arc<PortMappingGateway>>> {
+        match &*self.gateway.lock().unwrap() {
+            PortMappingGatewayState::Available(gateway) => Ok(Some(gateway.clone())),
+            PortMappingGatewayState::Unavailable(since)
+                if since.elapsed() < PORT_MAPPING_UNAVAILABLE_RETRY_INTERVAL =>
+            {
+                anyhow::bail!("no port mapping method available.");
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn map_port(&self, request: &PortMappingRequest) -> anyhow::Result<PortMapping> {
+        if let Some(gateway) = self.get_gateway()? {
+            return gateway.map_port(request, self.config.lifetime).await;
+        }
+
+        let _discovery = self.discovery.lock().await;
+
+        // Discovered by a concurrent request meanwhile.
+        if let Some(gateway) = self.get_gateway()? {
+            return gateway.map_port(request, self.config.lifetime).await;
+        }
+
+        for &method in &self.config.methods {
+            let result = async {
+                let method_gateway =
+                    PortMappingGateway::discover(method, self.config.gateway).await?;
+
+                let mapping = method_gateway
+                    .map_port(request, self.config.lifetime)
+                    .await?;
+
+                anyhow::Ok((method_gateway, mapping))
+            }
+            .await;
+
+            match result {
+                Ok((method_gateway, mapping)) => {
+                    log::info!("port mapping via {method} available.");
+
+                    *self.gateway.lock().unwrap() =
+                        PortMappingGatewayState::Available(Arc::new(method_gateway));
+
+                    return Ok(mapping);
+                }
+                Err(error) => {
+                    log::debug!("port mapping via {method} failed: {error}");
+                }
+            }
+        }
+
+        *self.gateway.lock().unwrap() =
+            PortMappingGatewayState::Unavailable(tokio::time::Instant::now());
+
+        anyhow::bail!("no port mapping method available.")
+    }
+
+    pub async fn unmap_port(&self, request: &PortMappingRequest) -> anyhow::Result<()> {
+        let gateway = match &*self.gateway.lock().unwrap() {
+            PortMappingGatewayState::Available(gateway) => gateway.clone(),
+            _ => return Ok(()),
+        };
+
+        gateway.unmap_port(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bytes::{Buf as _, BufMut as _};
+
+    use crate::utils::port_mapping::PortMapper;
+
+    use super::*;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+    const ALLOCATED_PORT: u16 = 50000;
+
+    /// Mapping requests a fake NAT-PMP gateway received.
+    #[derive(Debug, PartialEq)]
+    struct MappingRequest {
+        opcode: u8,
+        local_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    }
+
+    /// Answers NAT-PMP requests, granting the requested external port or
+    /// `ALLOCATED_PORT` for any, after `delay`.
+    async fn spawn_fake_nat_pmp_gateway(
+        delay: Duration,
+    ) -> (
+        SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<MappingRequest>,
+    ) {
+        let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let address = socket.local_addr().unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+
+            loop {
+                let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+
+                let mut request = &buffer[..length];
+
+                let opcode = request[1];
+
+                request.advance(2);
+
+                let mut response = vec![0, 128 + opcode];
+
+                response.put_u16(0);
+                response.put_u32(0);
+
+                if opcode == 0 {
+                    response.put_u32(EXTERNAL_IP.into());
+                } else {
+                    request.advance(2);
+
+                    let local_port = request.get_u16();
+                    let external_port = request.get_u16();
+                    let lifetime = request.get_u32();
+
+                    sender
+                        .send(MappingRequest {
+                            opcode,
+                            local_port,
+                            external_port,
+                            lifetime,
+                        })
+                        .ok();
+
+                    response.put_u16(local_port);
+                    response.put_u16(if external_port == 0 || lifetime == 0 {
+                        ALLOCATED_PORT
+                    } else {
+                        external_port
+                    });
+                    response.put_u32(lifetime);
+                }
+
+                let socket = socket.clone();
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+
+                    socket.send_to(&response, peer).await.ok();
+                });
+            }
+        });
+
+        (address, receiver)
+    }
+
+    fn create_client(gateway: SocketAddr) -> PortMappingClient {
+        let client = PortMappingClient::new(PortMappingConfig {
+            methods: vec![PortMappingMethod::NatPmp],
+            gateway: Some(gateway.ip()),
+            lifetime: Duration::from_secs(120),
+        });
+
+        // The fake gateway does not listen on the NAT-PMP port.
+        *client.gateway.lock().unwrap() =
+            PortMappingGatewayState::Available(Arc::new(PortMappingGateway::NatPmp(gateway)));
+
+        client
+    }
+
+    #[tokio::test]
+    async fn maps_and_renews_port() {
+        let (gateway, mut requests) = spawn_fake_nat_pmp_gateway(Duration::ZERO).await;
+
+        let client = create_client(gateway);
+
+        let mut request = PortMappingRequest::new(PortMappingProtocol::Udp, 40000);
+
+        request.external_port = 0;
+
+        let mapping = client.map_port(&request).await.unwrap();
+
+        assert_eq!(
+            mapping.external_address,
+            SocketAddr::new(EXTERNAL_IP.into(), ALLOCATED_PORT)
+        );
+        assert_eq!(mapping.lifetime, Duration::from_secs(120));
+
+        // Renewal asks for the external port granted.
+        request.external_port = mapping.external_address.port();
+
+        let renewed_mapping = client.map_port(&request).await.unwrap();
+
+        assert_eq!(renewed_mapping.external_address, mapping.external_address);
+
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            MappingRequest {
+                opcode: 1,
+                local_port: 40000,
+                external_port: 0,
+                lifetime: 120,
+            }
+        );
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            MappingRequest {
+                opcode: 1,
+                local_port: 40000,
+                external_port: ALLOCATED_PORT,
+                lifetime: 120,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn unmaps_port_on_drop() {
+        let (gateway, mut requests) = spawn_fake_nat_pmp_gateway(Duration::ZERO).await;
+
+        let port_mapper = PortMapper::new(
+            Arc::new(create_client(gateway)),
+            PortMappingProtocol::Tcp,
+            40000,
+        );
+
+        assert_eq!(
+            port_mapper.external_address().await,
+            Some(SocketAddr::new(EXTERNAL_IP.into(), 40000))
+        );
+
+        assert_eq!(requests.recv().await.unwrap().opcode, 2);
+
+        drop(port_mapper);
+
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            MappingRequest {
+                opcode: 2,
+                local_port: 40000,
+                external_port: 0,
+                lifetime: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_block_requests_on_pending_ones() {
+        let (gateway, _requests) = spawn_fake_nat_pmp_gateway(Duration::from_secs(1)).await;
+
+        let client = Arc::new(create_client(gateway));
+
+        let pending = tokio::spawn({
+            let client = client.clone();
+
+            async move {
+                client
+                    .map_port(&PortMappingRequest::new(PortMappingProtocol::Udp, 40000))
+                    .await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let started_at = tokio::time::Instant::now();
+
+        client
+            .map_port(&PortMappingRequest::new(PortMappingProtocol::Udp, 40001))
+            .await
+            .unwrap();
+
+        // Both NAT-PMP requests of a mapping are delayed, waiting on the pending
+        // mapping would take about twice as long.
+        assert!(started_at.elapsed() < Duration::from_secs(3));
+
+        pending.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use crate::utils::net::get_local_ip_for;
+
+use super::{PortMapping, PortMappingProtocol, PortMappingRequest};
+
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+const UPNP_SSDP_PORT: u16 = 1900;
+
+const UPNP_PORT_MAPPING_DESCRIPTION: &str = "plug2proxy";
+
+pub type UpnpGateway = igd_next::aio::Gateway<igd_next::aio::tokio::Tokio>;
+
+/// Searches the UPnP IGD, through unicast SSDP if the gateway is specified.
+pub async fn search_upnp_gateway(gateway: Option<IpAddr>) -> anyhow::Result<UpnpGateway> {
+    let mut options = igd_next::SearchOptions {
+        timeout: Some(UPNP_SEARCH_TIMEOUT),
+        single_search_timeout: Some(UPNP_SEARCH_TIMEOUT),
+        ..Default::default()
+    };
+
+    if let Some(gateway) = gateway {
+        options.broadcast_address = SocketAddr::new(gateway, UPNP_SSDP_PORT);
+    }
+
+    Ok(igd_next::aio::tokio::search_gateway(options).await?)
+}
+
+/// Maps a port through UPnP IGD, the requested external port is preferred but a
+/// random one is accepted.
+pub async fn map_port_via_upnp(
+    gateway: &UpnpGateway,
+    request: &PortMappingRequest,
+    lifetime: Duration,
+) -> anyhow::Result<PortMapping> {
+    let external_ip = gateway.get_external_ip().await?;
+
+    let local_address = SocketAddr::new(get_local_ip_for(gateway.addr)?, request.local_port);
+
+    let protocol = request.protocol.into();
+
+    // Zero means infinite for UPnP.
+    let lease_duration = lifetime.as_secs().clamp(1, u32::MAX.into()) as u32;
+
+    let external_port = match gateway
+        .add_port(
+            protocol,
+            request.external_port,
+            local_address,
+            lease_duration,
+            UPNP_PORT_MAPPING_DESCRIPTION,
+        )
+        .await
+    {
+        Ok(()) => request.external_port,
+        Err(_) => {
+            gateway
+                .add_any_port(
+                    protocol,
+                    local_address,
+                    lease_duration,
+                    UPNP_PORT_MAPPING_DESCRIPTION,
+                )
+                .await?
+        }
+    };
+
+    Ok(PortMapping {
+        external_address: SocketAddr::new(external_ip, external_port),
+        lifetime,
+    })
+}
+
+pub async fn unmap_port_via_upnp(
+    gateway: &UpnpGateway,
+    request: &PortMappingRequest,
+) -> anyhow::Result<()> {
+    gateway
+        .remove_port(request.protocol.into(), request.external_port)
+        .await?;
+
+    Ok(())
+}
+
+impl From<PortMappingProtocol> for igd_next::PortMappingProtocol {
+    fn from(protocol: PortMappingProtocol) -> Self {
+        match protocol {
+            PortMappingProtocol::Tcp => igd_next::PortMappingProtocol::TCP,
+            PortMappingProtocol::Udp => igd_next::PortMappingProtocol::UDP,
+        }
+    }
+}
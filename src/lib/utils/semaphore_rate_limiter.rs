@@ -28,10 +28,10 @@ impl SemaphoreRateLimiter {
         let acquired_at = Instant::now();
 
         loop {
-            let acquired_permits = (self.initial_permits
-                - self.semaphore.available_permits()
-                - self.boostable_permits)
-                .max(0);
+            let acquired_permits = self
+                .initial_permits
+                .saturating_sub(self.semaphore.available_permits())
+                .saturating_sub(self.boostable_permits);
 
             if acquired_permits == 0 {
                 break;
@@ -4,9 +4,125 @@ use std::{
     time::Duration,
 };
 
-use stun::message::Getter as _;
+use stun::message::{Getter as _, Setter as _};
 
 const STUN_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+const STUN_RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(500);
+
+pub const STUN_CHANGE_IP: u8 = 0b_0100;
+pub const STUN_CHANGE_PORT: u8 = 0b_0010;
+
+pub struct StunBindingResponse {
+    pub mapped_address: SocketAddr,
+    /// Alternate address of the server (RFC 5780), used for NAT behavior
+    /// discovery.
+    pub other_address: Option<SocketAddr>,
+}
+
+/// Sends a binding request from an unconnected socket, so that responses from
+/// the alternate address of the server (requested through `change_request`) are
+/// received as well. Returns `None` if no response arrives before the timeout.
+pub async fn stun_binding(
+    socket: &tokio::net::UdpSocket,
+    stun_server_address: SocketAddr,
+    change_request: Option<u8>,
+) -> anyhow::Result<Option<StunBindingResponse>> {
+    let mut request = stun::message::Message::new();
+
+    request.build(&[
+        Box::new(stun::agent::TransactionId::new()),
+        Box::new(stun::message::BINDING_REQUEST),
+    ])?;
+
+    if let Some(change_request) = change_request {
+        stun::attributes::RawAttribute {
+            typ: stun::attributes::ATTR_CHANGE_REQUEST,
+            length: 0,
+            value: vec![0, 0, 0, change_request],
+        }
+        .add_to(&mut request)?;
+    }
+
+    let mut buffer = [0; 1024];
+
+    let receive = async {
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await?;
+
+            if let Some(response) =
+                parse_binding_response(&buffer[..length], request.transaction_id)?
+            {
+                return anyhow::Ok(response);
+            }
+        }
+    };
+
+    let send = async {
+        loop {
+            socket.send_to(&request.raw, stun_server_address).await?;
+
+            tokio::time::sleep(STUN_RETRANSMISSION_INTERVAL).await;
+        }
+    };
+
+    let transaction = async {
+        tokio::select! {
+            response = receive => response,
+            result = send => result,
+        }
+    };
+
+    match tokio::time::timeout(STUN_RESPONSE_TIMEOUT, transaction).await {
+        Ok(response) => response.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses a binding response of the transaction, `None` if the data is not a
+/// STUN message of the transaction (e.g. stray or late packets).
+fn parse_binding_response(
+    data: &[u8],
+    transaction_id: stun::agent::TransactionId,
+) -> anyhow::Result<Option<StunBindingResponse>> {
+    let mut response = stun::message::Message::new();
+
+    if response.unmarshal_binary(data).is_err() || response.transaction_id != transaction_id {
+        return Ok(None);
+    }
+
+    anyhow::ensure!(
+        response.typ == stun::message::BINDING_SUCCESS,
+        "unexpected STUN response {}.",
+        response.typ
+    );
+
+    let mapped_address = {
+        let mut xor_addr = stun::xoraddr::XorMappedAddress::default();
+
+        if xor_addr.get_from(&response).is_ok() {
+            SocketAddr::new(xor_addr.ip, xor_addr.port)
+        } else {
+            let mut addr = stun::addr::MappedAddress::default();
+
+            addr.get_from(&response)?;
+
+            SocketAddr::new(addr.ip, addr.port)
+        }
+    };
+
+    let other_address = {
+        let mut addr = stun::addr::MappedAddress::default();
+
+        addr.get_from_as(&response, stun::attributes::ATTR_OTHER_ADDRESS)
+            .ok()
+            .map(|_| SocketAddr::new(addr.ip, addr.port))
+    };
+
+    Ok(Some(StunBindingResponse {
+        mapped_address,
+        other_address,
+    }))
+}
 
 pub async fn create_socket_and_probe_external_address(
     stun_server_addresses: &[SocketAddr],
@@ -16,6 +132,53 @@ pub async fn create_socket_and_probe_external_address(
     Ok((socket.unwrap(), address))
 }
 
+/// Probes the external IP periodically and broadcasts changes as `(previous,
+/// current)`, so that tunnels relying on the STUN mapping can migrate or rematch.
+pub struct ExternalIpWatcher {
+    sender: tokio::sync::broadcast::Sender<(IpAddr, IpAddr)>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl ExternalIpWatcher {
+    pub fn new(stun_server_addresses: Vec<SocketAddr>, interval: Duration) -> Self {
+        let (sender, _) = tokio::sync::broadcast::channel(1);
+
+        let handle = tokio::spawn({
+            let sender = sender.clone();
+
+            async move {
+                let mut last_ip = None;
+
+                loop {
+                    if let Ok(ip) = probe_external_ip(&stun_server_addresses).await {
+                        if let Some(previous_ip) = last_ip.filter(|&last_ip| last_ip != ip) {
+                            log::info!("external IP changed from {previous_ip} to {ip}.");
+
+                            sender.send((previous_ip, ip)).ok();
+                        }
+
+                        last_ip = Some(ip);
+                    }
+
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        });
+
+        Self { sender, handle }
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<(IpAddr, IpAddr)> {
+        self.sender.subscribe()
+    }
+}
+
+impl Drop for ExternalIpWatcher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
 pub async fn probe_external_ip(stun_server_addresses: &[SocketAddr]) -> anyhow::Result<IpAddr> {
     let (_, address) = create_and_probe(stun_server_addresses, false).await?;
 
@@ -106,3 +269,188 @@ async fn create_and_probe(
         Ok((None, address))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_response(
+        transaction_id: stun::agent::TransactionId,
+        typ: stun::message::MessageType,
+        setters: Vec<Box<dyn stun::message::Setter>>,
+    ) -> Vec<u8> {
+        let mut response = stun::message::Message::new();
+
+        response
+            .build(&[Box::new(transaction_id), Box::new(typ)])
+            .unwrap();
+
+        for setter in setters {
+            setter.add_to(&mut response).unwrap();
+        }
+
+        response.raw
+    }
+
+    fn mapped_address(address: &str) -> stun::addr::MappedAddress {
+        let address: SocketAddr = address.parse().unwrap();
+
+        stun::addr::MappedAddress {
+            ip: address.ip(),
+            port: address.port(),
+        }
+    }
+
+    #[test]
+    fn parses_xor_mapped_address() {
+        let transaction_id = stun::agent::TransactionId::new();
+
+        let data = build_response(
+            transaction_id,
+            stun::message::BINDING_SUCCESS,
+            vec![
+                Box::new(stun::xoraddr::XorMappedAddress {
+                    ip: "203.0.113.1".parse().unwrap(),
+                    port: 40000,
+                }),
+                // Ignored in favor of XOR-MAPPED-ADDRESS.
+                Box::new(mapped_address("198.51.100.1:1000")),
+            ],
+        );
+
+        let response = parse_binding_response(&data, transaction_id)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            response.mapped_address,
+            "203.0.113.1:40000".parse().unwrap()
+        );
+        assert_eq!(response.other_address, None);
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address() {
+        let transaction_id = stun::agent::TransactionId::new();
+
+        let data = build_response(
+            transaction_id,
+            stun::message::BINDING_SUCCESS,
+            vec![Box::new(mapped_address("198.51.100.1:1000"))],
+        );
+
+        let response = parse_binding_response(&data, transaction_id)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            response.mapped_address,
+            "198.51.100.1:1000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_other_address() {
+        let transaction_id = stun::agent::TransactionId::new();
+
+        let mut response = stun::message::Message::new();
+
+        response
+            .build(&[
+                Box::new(transaction_id),
+                Box::new(stun::message::BINDING_SUCCESS),
+                Box::new(stun::xoraddr::XorMappedAddress {
+                    ip: "203.0.113.1".parse().unwrap(),
+                    port: 40000,
+                }),
+            ])
+            .unwrap();
+
+        mapped_address("192.0.2.2:3479")
+            .add_to_as(&mut response, stun::attributes::ATTR_OTHER_ADDRESS)
+            .unwrap();
+
+        let response = parse_binding_response(&response.raw, transaction_id)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            response.other_address,
+            Some("192.0.2.2:3479".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_other_transactions_and_garbage() {
+        let transaction_id = stun::agent::TransactionId::new();
+
+        let data = build_response(
+            stun::agent::TransactionId::new(),
+            stun::message::BINDING_SUCCESS,
+            vec![Box::new(mapped_address("198.51.100.1:1000"))],
+        );
+
+        assert!(parse_binding_response(&data, transaction_id)
+            .unwrap()
+            .is_none());
+        assert!(parse_binding_response(&[0], transaction_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_error_and_address_less_responses() {
+        let transaction_id = stun::agent::TransactionId::new();
+
+        let data = build_response(
+            transaction_id,
+            stun::message::MessageType::new(
+                stun::message::METHOD_BINDING,
+                stun::message::CLASS_ERROR_RESPONSE,
+            ),
+            vec![],
+        );
+
+        assert!(parse_binding_response(&data, transaction_id).is_err());
+
+        let data = build_response(transaction_id, stun::message::BINDING_SUCCESS, vec![]);
+
+        assert!(parse_binding_response(&data, transaction_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn binds_through_server() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+
+            let (length, address) = server.recv_from(&mut buffer).await.unwrap();
+
+            let mut request = stun::message::Message::new();
+
+            request.unmarshal_binary(&buffer[..length]).unwrap();
+
+            let data = build_response(
+                request.transaction_id,
+                stun::message::BINDING_SUCCESS,
+                vec![Box::new(stun::xoraddr::XorMappedAddress {
+                    ip: address.ip(),
+                    port: address.port(),
+                })],
+            );
+
+            server.send_to(&data, address).await.unwrap();
+        });
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let response = stun_binding(&socket, server_address, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response.mapped_address, socket.local_addr().unwrap());
+    }
+}
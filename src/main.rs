@@ -4,21 +4,26 @@ mod constants;
 use std::sync::Arc;
 
 use clap::Parser as _;
-use config::{InConfig, OutConfig};
+use config::{AllInOneConfig, InConfig, MatchConfig, OutConfig, TunnelingTlsConfig};
 use constants::{
     dns_server_addresses_default, fake_ip_dns_db_path_default, fake_ipv4_net_default,
     fake_ipv6_net_default, geolite2_cache_path_default, geolite2_update_interval_default,
-    stun_server_addresses_default, tunneling_http2_priority_default,
+    out_identity_path_default, stun_server_addresses_default, tunneling_http2_priority_default,
     tunneling_quic_priority_default, DATA_DIR_DEFAULT,
 };
 use plug2proxy::{
+    match_server::standalone_match_server,
     out,
     r#in::{self, dns_resolver::create_dns_resolver},
+    tunnel::TunnelTlsConfig,
     utils::{log::init_log, OneOrMany},
 };
 use tokio::fs;
 
-use crate::constants::tunneling_plug_http2_priority_default;
+use crate::constants::{
+    tunneling_plug_http2_priority_default, tunneling_tls_identity_paths_default,
+    tunneling_websocket_priority_default,
+};
 
 #[derive(clap::Parser)]
 struct Cli {
@@ -31,9 +36,13 @@ struct Cli {
 #[serde(tag = "mode")]
 enum Config {
     #[serde(rename = "in")]
-    In(InConfig),
+    In(Box<InConfig>),
     #[serde(rename = "out")]
-    Out(OutConfig),
+    Out(Box<OutConfig>),
+    #[serde(rename = "match")]
+    Match(MatchConfig),
+    #[serde(rename = "all-in-one")]
+    AllInOne(Box<AllInOneConfig>),
 }
 
 #[tokio::main]
@@ -54,98 +63,195 @@ async fn main() -> anyhow::Result<()> {
     };
 
     match config {
-        Config::In(InConfig {
-            dns_resolver,
-            fake_ip_dns,
-            transparent_proxy,
-            tunneling,
-            routing,
+        Config::In(config) => up_in(*config, cli.data_dir.as_deref()).await?,
+        Config::Out(config) => up_out(*config, cli.data_dir.as_deref()).await?,
+        Config::AllInOne(config) => {
+            let AllInOneConfig { r#in, out } = *config;
+
+            tokio::try_join!(
+                up_in(r#in, cli.data_dir.as_deref()),
+                up_out(out, cli.data_dir.as_deref()),
+            )?;
+        }
+        Config::Match(MatchConfig {
+            listen,
+            http_listen,
+            tls,
+            access_key,
         }) => {
-            fs::create_dir_all(DATA_DIR_DEFAULT).await?;
+            standalone_match_server::up(standalone_match_server::Options {
+                listen_address: listen,
+                http_listen_address: http_listen,
+                tls: tls.into_tunnel_tls_config(tunneling_tls_identity_paths_default(
+                    cli.data_dir.as_deref(),
+                    "match",
+                )),
+                access_keys: access_key.into_vec(),
+            })
+            .await?
+        }
+    }
 
-            let dns_resolver = Arc::new(create_dns_resolver(
-                &dns_resolver
-                    .server
-                    .map_or_else(dns_server_addresses_default, |server| server.into_vec()),
-            ));
+    Ok(())
+}
 
-            let fake_ip_dns_db_path = fake_ip_dns_db_path_default(cli.data_dir.as_deref());
+async fn up_in(
+    InConfig {
+        dns_resolver,
+        fake_ip_dns,
+        transparent_proxy,
+        tunneling,
+        routing,
+    }: InConfig,
+    data_dir: Option<&str>,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(DATA_DIR_DEFAULT).await?;
 
-            // ensure db file exists.
-            drop(rusqlite::Connection::open(&fake_ip_dns_db_path));
+    let dns_resolver = Arc::new(create_dns_resolver(
+        &dns_resolver
+            .server
+            .map_or_else(dns_server_addresses_default, |server| server.into_vec()),
+    ));
 
-            let geolite2_cache_path = geolite2_cache_path_default(cli.data_dir.as_deref());
+    let fake_ip_dns_db_path = fake_ip_dns_db_path_default(data_dir);
 
-            tokio::try_join!(
-                r#in::fake_ip_dns::up(
-                    dns_resolver.clone(),
-                    r#in::fake_ip_dns::Options {
-                        listen_address: fake_ip_dns.listen,
-                        db_path: &fake_ip_dns_db_path
-                    }
-                ),
-                r#in::transparent_proxy::up(
-                    dns_resolver,
-                    r#in::transparent_proxy::Options {
-                        listen_address: transparent_proxy.listen,
-                        traffic_mark: transparent_proxy.traffic_mark,
-                        fake_ip_dns_db_path: &fake_ip_dns_db_path,
-                        fake_ipv4_net: fake_ipv4_net_default(),
-                        fake_ipv6_net: fake_ipv6_net_default(),
-                        stun_server_addresses: tunneling
-                            .stun_server
-                            .map_or_else(stun_server_addresses_default, |address| address
-                                .into_vec()),
-                        match_server_config: tunneling.match_server.into_config(),
-                        tunneling_http2_enabled: tunneling.http2.enabled,
-                        tunneling_http2_connections: tunneling.http2.connections,
-                        tunneling_http2_priority: tunneling.http2.priority,
-                        tunneling_http2_priority_default: tunneling_http2_priority_default(),
-                        tunneling_plug_http2_enabled: tunneling.plug_http2.enabled,
-                        tunneling_plug_http2_listen_address: tunneling.plug_http2.listen_address,
-                        tunneling_plug_http2_external_port: tunneling.plug_http2.external_port,
-                        tunneling_plug_http2_connections: tunneling.plug_http2.connections,
-                        tunneling_plug_http2_priority: tunneling.plug_http2.priority,
-                        tunneling_plug_http2_priority_default:
-                            tunneling_plug_http2_priority_default(),
-                        tunneling_quic_enabled: tunneling.quic.enabled,
-                        tunneling_quic_priority: tunneling.quic.priority,
-                        tunneling_quic_priority_default: tunneling_quic_priority_default(),
-                        routing_rules: routing.rules,
-                        geolite2_cache_path: &geolite2_cache_path,
-                        geolite2_url: routing.geolite2.url,
-                        geolite2_update_interval: routing.geolite2.update_interval.map_or_else(
-                            geolite2_update_interval_default,
-                            |duration| {
-                                humantime::parse_duration(&duration)
-                                    .expect("invalid GeoLite2 database update interval.")
-                            },
-                        ),
-                    }
-                ),
-            )?;
-        }
-        Config::Out(OutConfig {
-            tunneling,
-            routing,
-            outputs,
-        }) => {
-            out::up(out::Options {
-                labels: tunneling.label.map_or_else(Vec::new, OneOrMany::into_vec),
-                http2_priority: tunneling.http2.priority,
-                plug_http2_priority: tunneling.plug_http2.priority,
-                quic_priority: tunneling.quic.priority,
+    // ensure db file exists.
+    drop(rusqlite::Connection::open(&fake_ip_dns_db_path));
+
+    let geolite2_cache_path = geolite2_cache_path_default(data_dir);
+
+    tokio::try_join!(
+        r#in::fake_ip_dns::up(
+            dns_resolver.clone(),
+            r#in::fake_ip_dns::Options {
+                listen_address: fake_ip_dns.listen,
+                db_path: &fake_ip_dns_db_path
+            }
+        ),
+        r#in::transparent_proxy::up(
+            dns_resolver.clone(),
+            r#in::transparent_proxy::Options {
+                listen_address: transparent_proxy.listen,
+                traffic_mark: transparent_proxy.traffic_mark,
+                fake_ip_dns_db_path: &fake_ip_dns_db_path,
+                fake_ipv4_net: fake_ipv4_net_default(),
+                fake_ipv6_net: fake_ipv6_net_default(),
                 stun_server_addresses: tunneling
                     .stun_server
                     .map_or_else(stun_server_addresses_default, |address| address.into_vec()),
-                match_server_config: tunneling.match_server.into_config(),
+                match_server_config: tunneling.match_server.into_config()?,
+                out_policy: tunneling.out_policy.into_out_policy()?,
+                tunneling_http2_enabled: tunneling.http2.enabled,
+                tunneling_http2_connections: tunneling.http2.connections,
+                tunneling_http2_priority: tunneling.http2.priority,
+                tunneling_http2_priority_default: tunneling_http2_priority_default(),
+                tunneling_http2_transport: tunneling
+                    .http2
+                    .transport
+                    .into_http2_transport_config()?,
+                tunneling_plug_http2_enabled: tunneling.plug_http2.enabled,
+                tunneling_plug_http2_listen_address: tunneling.plug_http2.listen_address,
+                tunneling_plug_http2_external_port: tunneling.plug_http2.external_port,
+                tunneling_plug_http2_connections: tunneling.plug_http2.connections,
+                tunneling_plug_http2_priority: tunneling.plug_http2.priority,
+                tunneling_plug_http2_tls: tunneling.plug_http2.tls.into_tunnel_tls_config(
+                    &["h2"],
+                    tunneling_tls_identity_paths_default(data_dir, "plug-http2"),
+                ),
+                tunneling_plug_http2_priority_default: tunneling_plug_http2_priority_default(),
+                tunneling_plug_http2_transport: tunneling
+                    .plug_http2
+                    .transport
+                    .into_http2_transport_config()?,
+                tunneling_quic_enabled: tunneling.quic.enabled,
+                tunneling_quic_priority: tunneling.quic.priority,
+                tunneling_quic_stream_pool: tunneling.quic.stream_pool,
+                tunneling_quic_priority_default: tunneling_quic_priority_default(),
+                tunneling_quic_transport: tunneling.quic.transport.into_quic_transport_config()?,
+                tunneling_static_tls: static_tls_config(data_dir),
+                tunneling_websocket_enabled: tunneling.websocket.enabled,
+                tunneling_websocket_connections: tunneling.websocket.connections,
+                tunneling_websocket_priority: tunneling.websocket.priority,
+                tunneling_websocket_stream_pool: tunneling.websocket.stream_pool,
+                tunneling_websocket_priority_default: tunneling_websocket_priority_default(),
+                tunneling_port_mapping: tunneling.port_mapping.into_port_mapping_config()?,
+                bandwidth: tunneling.bandwidth.into_bandwidth_config()?,
+                stream_timeout: tunneling.timeout.into_stream_timeout_config()?,
                 routing_rules: routing.rules,
-                routing_priority: routing.priority,
-                output_configs: outputs,
-            })
-            .await?
-        }
-    }
+                geolite2_cache_path: &geolite2_cache_path,
+                geolite2_url: routing.geolite2.url,
+                geolite2_update_interval: routing.geolite2.update_interval.map_or_else(
+                    geolite2_update_interval_default,
+                    |duration| {
+                        humantime::parse_duration(&duration)
+                            .expect("invalid GeoLite2 database update interval.")
+                    },
+                ),
+            }
+        ),
+    )?;
 
     Ok(())
 }
+
+async fn up_out(
+    OutConfig {
+        tunneling,
+        routing,
+        outputs,
+    }: OutConfig,
+    data_dir: Option<&str>,
+) -> anyhow::Result<()> {
+    out::up(out::Options {
+        labels: tunneling.label.map_or_else(Vec::new, OneOrMany::into_vec),
+        http2_priority: tunneling.http2.priority,
+        http2_tls: tunneling.http2.tls.into_tunnel_tls_config(
+            &["h2"],
+            tunneling_tls_identity_paths_default(data_dir, "http2"),
+        ),
+        http2_transport: tunneling.http2.transport.into_http2_transport_config()?,
+        plug_http2_priority: tunneling.plug_http2.priority,
+        plug_http2_transport: tunneling
+            .plug_http2
+            .transport
+            .into_http2_transport_config()?,
+        quic_priority: tunneling.quic.priority,
+        quic_tls: tunneling.quic.tls.into_tunnel_tls_config(
+            &["h3"],
+            tunneling_tls_identity_paths_default(data_dir, "quic"),
+        ),
+        quic_transport: tunneling.quic.transport.into_quic_transport_config()?,
+        static_tls: static_tls_config(data_dir),
+        websocket_url: tunneling.websocket.url,
+        websocket_listen_address: tunneling.websocket.listen,
+        websocket_sni: tunneling.websocket.sni,
+        websocket_priority: tunneling.websocket.priority,
+        port_mapping: tunneling.port_mapping.into_port_mapping_config()?,
+        bandwidth: tunneling.bandwidth.into_bandwidth_config()?,
+        stream_timeout: tunneling.timeout.into_stream_timeout_config()?,
+        stun_server_addresses: tunneling
+            .stun_server
+            .map_or_else(stun_server_addresses_default, |address| address.into_vec()),
+        match_server_config: tunneling.match_server.into_config()?,
+        identity_path: tunneling
+            .identity
+            .into_identity_path(out_identity_path_default(data_dir)),
+        routing_rules: routing.rules,
+        routing_priority: routing.priority,
+        output_configs: outputs,
+    })
+    .await
+}
+
+/// Identity of static peering without a shared key, persisted as it is pinned
+/// by the peer.
+fn static_tls_config(data_dir: Option<&str>) -> TunnelTlsConfig {
+    TunnelingTlsConfig {
+        persistent: true,
+        ..Default::default()
+    }
+    .into_tunnel_tls_config(
+        &["h3"],
+        tunneling_tls_identity_paths_default(data_dir, "static"),
+    )
+}
@@ -0,0 +1,464 @@
+//! End-to-end matching of IN and OUT through the memory match server, over
+//! loopback with a local STUN responder, so no external service is needed.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use plug2proxy::{
+    bandwidth::{BandwidthConfig, BandwidthManager},
+    config::{MatchServerConfig, MemoryMatchServerConfig},
+    match_server::{
+        memory_match_server::{MemoryInMatchServer, MemoryMatchHub},
+        AnyInMatchServer, MatchOutId,
+    },
+    out,
+    r#in::{out_policy::OutPolicy, tunnel_manager::TunnelManager},
+    route::{router::Router, rule::Label},
+    tunnel::{
+        http2::{
+            Http2InTunnelConfig, Http2InTunnelProvider, Http2TransportConfig,
+            PlugHttp2InTunnelConfig, PlugHttp2InTunnelProvider,
+        },
+        quic::{QuicInTunnelConfig, QuicInTunnelProvider, QuicTransportConfig},
+        tls_name_default, AnyInTunnelLikeArc, InTunnelLike as _, InTunnelProvider, TunnelTlsConfig,
+    },
+    utils::io::StreamTimeoutConfig,
+};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Copy)]
+enum Tunneling {
+    Http2,
+    PlugHttp2,
+    Quic,
+}
+
+// OUT is not `Send`, thus tests run on a `LocalSet` to spawn it.
+
+#[tokio::test]
+async fn relays_through_http2() -> anyhow::Result<()> {
+    run_local(relays_through(Tunneling::Http2)).await
+}
+
+#[tokio::test]
+async fn relays_through_plug_http2() -> anyhow::Result<()> {
+    run_local(relays_through(Tunneling::PlugHttp2)).await
+}
+
+#[tokio::test]
+async fn relays_through_quic() -> anyhow::Result<()> {
+    run_local(relays_through(Tunneling::Quic)).await
+}
+
+#[tokio::test]
+async fn routes_by_out_labels() -> anyhow::Result<()> {
+    run_local(routes_by_out_labels_local()).await
+}
+
+#[tokio::test]
+async fn fails_over_to_remaining_out() -> anyhow::Result<()> {
+    run_local(fails_over_to_remaining_out_local()).await
+}
+
+async fn routes_by_out_labels_local() -> anyhow::Result<()> {
+    init();
+
+    let stun_server_address = start_stun_server().await?;
+    let echo_server_address = start_echo_server().await?;
+
+    let hub = MemoryMatchHub::new();
+
+    spawn_out(&hub, stun_server_address, "us");
+    spawn_out(&hub, stun_server_address, "jp");
+
+    let tunnel_manager = new_tunnel_manager(&hub, stun_server_address, Tunneling::Http2).await?;
+
+    let us_out_id = wait_for_tunnel(&tunnel_manager, "us", |_| true).await?;
+    let jp_out_id = wait_for_tunnel(&tunnel_manager, "jp", |_| true).await?;
+
+    assert!(us_out_id != jp_out_id);
+
+    for (label, out_id) in [("us", us_out_id), ("jp", jp_out_id)] {
+        let tunnels = select_tunnels(&tunnel_manager, label).await;
+
+        assert!(!tunnels.is_empty());
+
+        for tunnel in &tunnels {
+            let AnyInTunnelLikeArc::InTunnel(in_tunnel) = tunnel else {
+                panic!("unexpected direct tunnel for {label}.");
+            };
+
+            assert!(in_tunnel.out_id() == out_id);
+            assert!(in_tunnel.labels().contains(&custom_label(label)));
+        }
+
+        assert_echo(&tunnels[0], echo_server_address).await?;
+    }
+
+    Ok(())
+}
+
+async fn fails_over_to_remaining_out_local() -> anyhow::Result<()> {
+    init();
+
+    let stun_server_address = start_stun_server().await?;
+    let echo_server_address = start_echo_server().await?;
+
+    let hub = MemoryMatchHub::new();
+
+    let failing_out_shutdown_sender = spawn_out_thread(&hub, stun_server_address, "us");
+
+    let tunnel_manager = new_tunnel_manager(&hub, stun_server_address, Tunneling::Http2).await?;
+
+    let failing_out_id = wait_for_tunnel(&tunnel_manager, "us", |_| true).await?;
+
+    spawn_out(&hub, stun_server_address, "us");
+
+    let remaining_out_id =
+        wait_for_tunnel(&tunnel_manager, "us", |out_id| out_id != failing_out_id).await?;
+
+    for tunnel in select_tunnels(&tunnel_manager, "us").await {
+        assert_echo(&tunnel, echo_server_address).await?;
+    }
+
+    drop(failing_out_shutdown_sender);
+
+    tokio::time::timeout(TUNNEL_TIMEOUT, async {
+        loop {
+            let tunnels = select_tunnels(&tunnel_manager, "us").await;
+
+            if !tunnels.is_empty()
+                && tunnels
+                    .iter()
+                    .all(|tunnel| get_out_id(tunnel) != failing_out_id)
+            {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+
+    let tunnels = select_tunnels(&tunnel_manager, "us").await;
+
+    assert!(get_out_id(&tunnels[0]) == remaining_out_id);
+
+    assert_echo(&tunnels[0], echo_server_address).await?;
+
+    Ok(())
+}
+
+async fn relays_through(tunneling: Tunneling) -> anyhow::Result<()> {
+    init();
+
+    let stun_server_address = start_stun_server().await?;
+    let echo_server_address = start_echo_server().await?;
+
+    let hub = MemoryMatchHub::new();
+
+    spawn_out(&hub, stun_server_address, "us");
+
+    let tunnel_manager = new_tunnel_manager(&hub, stun_server_address, tunneling).await?;
+
+    wait_for_tunnel(&tunnel_manager, "us", |_| true).await?;
+
+    let tunnels = select_tunnels(&tunnel_manager, "us").await;
+
+    for _ in 0..3 {
+        assert_echo(&tunnels[0], echo_server_address).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_local(
+    future: impl std::future::Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    tokio::task::LocalSet::new().run_until(future).await
+}
+
+fn spawn_out(hub: &MemoryMatchHub, stun_server_address: SocketAddr, label: &str) {
+    tokio::task::spawn_local(out::up(out_options(hub, stun_server_address, label)));
+}
+
+/// Runs OUT on a runtime of its own until the returned sender is dropped, and
+/// then drops the runtime with all tasks and connections of OUT, like a crash.
+fn spawn_out_thread(
+    hub: &MemoryMatchHub,
+    stun_server_address: SocketAddr,
+    label: &str,
+) -> tokio::sync::oneshot::Sender<()> {
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+
+    let options = out_options(hub, stun_server_address, label);
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        tokio::task::LocalSet::new().block_on(&runtime, async move {
+            tokio::select! {
+                _ = out::up(options) => {}
+                _ = shutdown_receiver => {}
+            }
+        });
+    });
+
+    shutdown_sender
+}
+
+fn init() {
+    // Installed by whichever test runs first.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+fn custom_label(label: &str) -> Label {
+    Label::Custom(label.to_owned())
+}
+
+fn out_options(hub: &MemoryMatchHub, stun_server_address: SocketAddr, label: &str) -> out::Options {
+    let tls = |alpn: &str| TunnelTlsConfig {
+        server_name: tls_name_default(),
+        alpn_protocols: vec![alpn.to_owned()],
+        identity_paths: None,
+    };
+
+    out::Options {
+        labels: vec![custom_label(label)],
+        stun_server_addresses: vec![stun_server_address.to_string()],
+        match_server_config: MatchServerConfig::Memory(MemoryMatchServerConfig {
+            hub: hub.clone(),
+        }),
+        identity_path: None,
+        http2_priority: None,
+        http2_tls: tls("h2"),
+        http2_transport: Http2TransportConfig::default(),
+        plug_http2_priority: None,
+        plug_http2_transport: Http2TransportConfig::default(),
+        quic_priority: None,
+        quic_tls: tls("h3"),
+        quic_transport: QuicTransportConfig::default(),
+        static_tls: tls("h3"),
+        websocket_url: None,
+        websocket_listen_address: "127.0.0.1:0".parse().unwrap(),
+        websocket_sni: None,
+        websocket_priority: None,
+        port_mapping: None,
+        bandwidth: BandwidthConfig::default(),
+        stream_timeout: StreamTimeoutConfig::default(),
+        routing_rules: Vec::new(),
+        routing_priority: 0,
+        output_configs: Vec::new(),
+    }
+}
+
+async fn new_tunnel_manager(
+    hub: &MemoryMatchHub,
+    stun_server_address: SocketAddr,
+    tunneling: Tunneling,
+) -> anyhow::Result<TunnelManager> {
+    let match_server = Arc::new(AnyInMatchServer::from(MemoryInMatchServer::new(
+        hub.clone(),
+    )));
+
+    let tunnel_provider: Box<dyn InTunnelProvider + Send> = match tunneling {
+        Tunneling::Http2 => Box::new(
+            Http2InTunnelProvider::new(
+                match_server,
+                Http2InTunnelConfig {
+                    connections: 1,
+                    priority: None,
+                    priority_default: 0,
+                    traffic_mark: 0,
+                    transport: Http2TransportConfig::default(),
+                },
+            )
+            .await?,
+        ),
+        Tunneling::PlugHttp2 => Box::new(
+            PlugHttp2InTunnelProvider::new(
+                match_server,
+                PlugHttp2InTunnelConfig {
+                    listen_address: get_free_tcp_address()?,
+                    external_port: None,
+                    port_mapping: None,
+                    tls: TunnelTlsConfig {
+                        server_name: tls_name_default(),
+                        alpn_protocols: vec!["h2".to_owned()],
+                        identity_paths: None,
+                    },
+                    connections: 1,
+                    priority: None,
+                    priority_default: 0,
+                    stun_server_addresses: vec![stun_server_address],
+                    traffic_mark: 0,
+                    transport: Http2TransportConfig::default(),
+                },
+            )
+            .await?,
+        ),
+        Tunneling::Quic => Box::new(QuicInTunnelProvider::new(
+            match_server,
+            QuicInTunnelConfig {
+                priority: None,
+                priority_default: 0,
+                stream_pool_size: 0,
+                stun_server_addresses: vec![stun_server_address],
+                transport: QuicTransportConfig::default(),
+                port_mapping: None,
+                traffic_mark: 0,
+            },
+        )?),
+    };
+
+    Ok(TunnelManager::new(
+        vec![tunnel_provider],
+        Arc::new(Router::new(Vec::new())),
+        Arc::new(BandwidthManager::new(BandwidthConfig::default())),
+        OutPolicy::default(),
+        0,
+    ))
+}
+
+async fn select_tunnels(tunnel_manager: &TunnelManager, label: &str) -> Vec<AnyInTunnelLikeArc> {
+    tunnel_manager
+        .select_tunnels(&[vec![(custom_label(label), None)]])
+        .await
+        .into_iter()
+        .map(|(tunnel, _)| tunnel)
+        .collect()
+}
+
+/// Waits for a tunnel with the label to an OUT accepted by `filter`, returning
+/// the OUT id.
+async fn wait_for_tunnel(
+    tunnel_manager: &TunnelManager,
+    label: &str,
+    filter: impl Fn(MatchOutId) -> bool,
+) -> anyhow::Result<MatchOutId> {
+    let out_id = tokio::time::timeout(TUNNEL_TIMEOUT, async {
+        loop {
+            let out_id = select_tunnels(tunnel_manager, label)
+                .await
+                .iter()
+                .map(get_out_id)
+                .find(|&out_id| filter(out_id));
+
+            if let Some(out_id) = out_id {
+                break out_id;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+
+    Ok(out_id)
+}
+
+fn get_out_id(tunnel: &AnyInTunnelLikeArc) -> MatchOutId {
+    match tunnel {
+        AnyInTunnelLikeArc::InTunnel(tunnel) => tunnel.out_id(),
+        AnyInTunnelLikeArc::Direct(_) => panic!("unexpected direct tunnel."),
+    }
+}
+
+async fn assert_echo(
+    tunnel: &AnyInTunnelLikeArc,
+    echo_server_address: SocketAddr,
+) -> anyhow::Result<()> {
+    let (mut read, mut write, _) = tunnel
+        .connect(echo_server_address, None, None, None)
+        .await?;
+
+    let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+
+    let write_task = async {
+        write.write_all(&data).await?;
+        write.flush().await?;
+
+        anyhow::Ok(())
+    };
+
+    let read_task = async {
+        let mut echoed = vec![0; data.len()];
+
+        read.read_exact(&mut echoed).await?;
+
+        anyhow::Ok(echoed)
+    };
+
+    let ((), echoed) = tokio::time::timeout(TUNNEL_TIMEOUT, async {
+        tokio::try_join!(write_task, read_task)
+    })
+    .await??;
+
+    assert!(echoed == data, "echoed data mismatch.");
+
+    Ok(())
+}
+
+fn get_free_tcp_address() -> anyhow::Result<SocketAddr> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?)
+}
+
+async fn start_echo_server() -> anyhow::Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+    let address = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+
+                tokio::io::copy(&mut read, &mut write).await
+            });
+        }
+    });
+
+    Ok(address)
+}
+
+/// Answers binding requests with the source address, ignoring change requests
+/// as a server without an alternate address would.
+async fn start_stun_server() -> anyhow::Result<SocketAddr> {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+
+    let address = socket.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut buffer = [0; 1024];
+
+        while let Ok((length, source)) = socket.recv_from(&mut buffer).await {
+            let mut request = stun::message::Message::new();
+
+            if request.unmarshal_binary(&buffer[..length]).is_err()
+                || request.typ != stun::message::BINDING_REQUEST
+            {
+                continue;
+            }
+
+            let mut response = stun::message::Message::new();
+
+            let built = response.build(&[
+                Box::new(request.transaction_id),
+                Box::new(stun::message::BINDING_SUCCESS),
+                Box::new(stun::xoraddr::XorMappedAddress {
+                    ip: source.ip(),
+                    port: source.port(),
+                }),
+            ]);
+
+            if built.is_ok() {
+                socket.send_to(&response.raw, source).await.ok();
+            }
+        }
+    });
+
+    Ok(address)
+}